@@ -6,6 +6,7 @@ pub struct Program {
 #[derive(Debug, Clone)]
 pub struct Package {
     pub name: String,
+    pub version: Option<String>, // From package.toml; single-file packages have none
     pub extensions: Vec<String>,
     pub dependencies: Vec<String>,
     pub items: Vec<Item>,
@@ -23,6 +24,10 @@ pub enum Item {
     Stmt(Stmt),
     Package(Package),
     Use(String),
+    Mod(String), // `mod name;` includes `name.sr` next to the current file
+    Struct(StructDef),
+    Enum(EnumDef),
+    Message(MessageDef), // `message Hit(damage: number);`
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -35,16 +40,40 @@ pub enum Visibility {
 #[derive(Debug, Clone)]
 pub struct ProcedureDef {
     pub name: String,
+    pub attributes: Vec<Attribute>,
+    pub visibility: Visibility, // `pub proc` is callable from outside its package
     pub params: Vec<Param>,
     pub body: Vec<Stmt>,
     pub return_type: Option<Type>,
-    pub is_warp: bool,          // Run without screen refresh
+    pub is_warp: bool, // Run without screen refresh
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<Param>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnumDef {
+    pub name: String,
+    pub variants: Vec<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageDef {
+    pub name: String,
+    pub params: Vec<Param>,
     pub comment: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct VariableDecl {
     pub name: String,
+    pub attributes: Vec<Attribute>,
     pub ty: Type,
     pub init: Expr,
     pub visibility: Visibility,
@@ -80,6 +109,7 @@ pub struct Attribute {
 pub struct Param {
     pub name: String,
     pub ty: Type,
+    pub default: Option<Expr>, // Procedure parameters only
 }
 
 #[derive(Debug, Clone)]
@@ -91,18 +121,30 @@ pub enum Stmt {
     Repeat(Expr, Vec<Stmt>, Option<String>),
     Forever(Vec<Stmt>, Option<String>),
     Until(Expr, Vec<Stmt>, Option<String>),
-    Match(
-        Expr,
-        Vec<(Expr, Vec<Stmt>)>,
-        Option<Vec<Stmt>>,
-        Option<String>,
-    ),
+    Match(Expr, Vec<MatchArm>, Option<Vec<Stmt>>, Option<String>),
     #[allow(dead_code)]
     Let(String, Expr, Option<String>),
+    LetTuple(Vec<String>, Expr, Option<String>), // `let (a, b) = value;`, `_` skips an element
     #[allow(dead_code)]
     Return(Option<Expr>, Option<String>),
     CBlock(String, Vec<Expr>, Vec<Stmt>, Option<String>),
     Comment(String),
+    AssignPlace(Expr, Expr, Option<String>), // `place = value;` where place is an index or field
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub patterns: Vec<Pattern>, // `A | B` matches if any pattern does
+    pub guard: Option<Expr>,
+    pub body: Vec<Stmt>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Value(Expr),
+    Range(Expr, Expr, bool), // lo, hi, inclusive
+    Binding(String),
+    Wildcard,
 }
 
 #[derive(Debug, Clone)]
@@ -110,6 +152,7 @@ pub enum Expr {
     Number(f64),
     String(String),
     Bool(bool),
+    Color(String), // `#rrggbb`
     Variable(String),
     Call(String, Vec<Expr>),
     #[allow(dead_code)]
@@ -117,6 +160,13 @@ pub enum Expr {
     BinOp(Box<Expr>, Op, Box<Expr>),
     UnOp(UnOp, Box<Expr>),
     List(Vec<Expr>),
+    Index(Box<Expr>, Box<Expr>),              // base[index]
+    Field(Box<Expr>, String),                 // base.field
+    MethodCall(Box<Expr>, String, Vec<Expr>), // base.method(args)
+    StructLit(String, Vec<(String, Expr)>),   // Name { field: value, .. }
+    If(Box<Expr>, Box<Expr>, Box<Expr>),      // if cond { a } else { b }
+    NamedArg(String, Box<Expr>),              // name: value, in a call
+    Tuple(Vec<Expr>),                         // (a, b), only as a return value or `let` source
 }
 
 #[derive(Debug, Clone)]
@@ -132,6 +182,12 @@ pub enum Op {
     Mul,
     Div,
     Mod,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
     Eq,
     Gt,
     Lt,
@@ -140,6 +196,8 @@ pub enum Op {
     Le,
     And,
     Or,
+    StrictEq,
+    StrictNe,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -148,5 +206,7 @@ pub enum Type {
     String,
     Boolean,
     List,
+    StructList(String), // list<Name>
+    Tuple(Vec<Type>),   // (number, number), procedure return types only
     Unknown,
 }
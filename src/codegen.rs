@@ -32,14 +32,17 @@ impl CodeGenerator {
         match item {
             Item::Variable(var) => {
                 self.indent();
+                for attr in &var.attributes {
+                    self.generate_attribute(attr);
+                }
                 let vis = match var.visibility {
                     Visibility::Public => "public ",
                     Visibility::Private => "",
                     Visibility::Default => "",
                 };
-                let kind = match var.ty {
-                    Type::List => "list",
-                    _ => "var",
+                let kind = match &var.ty {
+                    Type::List | Type::StructList(_) => type_str(&var.ty),
+                    _ => "var".to_string(),
                 };
                 write!(self.buffer, "{} {} {} = ", vis, kind, var.name).unwrap();
                 self.generate_expr(&var.init);
@@ -53,7 +56,7 @@ impl CodeGenerator {
             }
             Item::Costume(c) => {
                 self.indent();
-                write!(self.buffer, "costume \"{}\", \"{}\"", c.name, c.path).unwrap();
+                write!(self.buffer, "costume {:?}, {:?}", c.name, c.path).unwrap();
                 if let Some(x) = c.x {
                     if let Some(y) = c.y {
                         write!(self.buffer, ", {}, {}", x, y).unwrap();
@@ -63,7 +66,7 @@ impl CodeGenerator {
             }
             Item::Sound(s) => {
                 self.indent();
-                write!(self.buffer, "sound \"{}\", \"{}\";\n", s.name, s.path).unwrap();
+                writeln!(self.buffer, "sound {:?}, {:?};", s.name, s.path).unwrap();
             }
             Item::Comment(c) => {
                 self.indent();
@@ -81,6 +84,54 @@ impl CodeGenerator {
                 self.indent();
                 writeln!(self.buffer, "use {};", u).unwrap();
             }
+            Item::Mod(m) => {
+                self.indent();
+                writeln!(self.buffer, "mod {};", m).unwrap();
+            }
+            Item::Enum(def) => {
+                self.indent();
+                if let Some(comment) = &def.comment {
+                    writeln!(self.buffer, "/// {}", comment).unwrap();
+                    self.indent();
+                }
+                writeln!(
+                    self.buffer,
+                    "enum {} {{ {} }}",
+                    def.name,
+                    def.variants.join(", ")
+                )
+                .unwrap();
+            }
+            Item::Struct(def) => {
+                self.indent();
+                if let Some(comment) = &def.comment {
+                    writeln!(self.buffer, "/// {}", comment).unwrap();
+                    self.indent();
+                }
+                write!(self.buffer, "struct {} {{ ", def.name).unwrap();
+                for (i, field) in def.fields.iter().enumerate() {
+                    if i > 0 {
+                        self.buffer.push_str(", ");
+                    }
+                    write!(self.buffer, "{}: {}", field.name, type_str(&field.ty)).unwrap();
+                }
+                self.buffer.push_str(" }\n");
+            }
+            Item::Message(def) => {
+                self.indent();
+                if let Some(comment) = &def.comment {
+                    writeln!(self.buffer, "/// {}", comment).unwrap();
+                    self.indent();
+                }
+                write!(self.buffer, "message {}(", def.name).unwrap();
+                for (i, param) in def.params.iter().enumerate() {
+                    if i > 0 {
+                        self.buffer.push_str(", ");
+                    }
+                    write!(self.buffer, "{}: {}", param.name, type_str(&param.ty)).unwrap();
+                }
+                self.buffer.push_str(");\n");
+            }
             _ => {
                 self.indent();
                 self.buffer.push_str("// Unknown item\n");
@@ -91,19 +142,33 @@ impl CodeGenerator {
     fn generate_proc(&mut self, proc: &ProcedureDef) {
         self.indent();
         if let Some(comment) = &proc.comment {
-            writeln!(self.buffer, "{}", comment).unwrap();
+            writeln!(self.buffer, "/// {}", comment).unwrap();
             self.indent();
         }
+        // `is_warp` is printed on its own, since transforms may set it
+        // without a matching attribute.
+        for attr in &proc.attributes {
+            if attr.name != "warp" && attr.name != "nowarp" {
+                self.generate_attribute(attr);
+            }
+        }
         if proc.is_warp {
             self.buffer.push_str("#[warp]\n");
             self.indent();
         }
+        if proc.visibility == Visibility::Public {
+            self.buffer.push_str("pub ");
+        }
         write!(self.buffer, "proc {}(", proc.name).unwrap();
         for (i, param) in proc.params.iter().enumerate() {
             if i > 0 {
                 self.buffer.push_str(", ");
             }
             write!(self.buffer, "{}: {}", param.name, type_str(&param.ty)).unwrap();
+            if let Some(default) = &param.default {
+                self.buffer.push_str(" = ");
+                self.generate_expr(default);
+            }
         }
         self.buffer.push(')');
         if let Some(rt) = &proc.return_type {
@@ -124,26 +189,19 @@ impl CodeGenerator {
     fn generate_func(&mut self, func: &Function) {
         self.indent();
         if let Some(comment) = &func.comment {
-            writeln!(self.buffer, "{}", comment).unwrap();
+            writeln!(self.buffer, "/// {}", comment).unwrap();
             self.indent();
         }
         for attr in &func.attributes {
-            write!(self.buffer, "#[{}", attr.name).unwrap();
-            if !attr.args.is_empty() {
-                self.buffer.push('(');
-                for (i, arg) in attr.args.iter().enumerate() {
-                    if i > 0 {
-                        self.buffer.push_str(", ");
-                    }
-                    self.generate_expr(arg);
-                }
-                self.buffer.push(')');
-            }
-            self.buffer.push_str("]\n");
-            self.indent();
+            self.generate_attribute(attr);
         }
         write!(self.buffer, "fn {}(", func.name).unwrap();
-        // params
+        for (i, param) in func.params.iter().enumerate() {
+            if i > 0 {
+                self.buffer.push_str(", ");
+            }
+            write!(self.buffer, "{}: {}", param.name, type_str(&param.ty)).unwrap();
+        }
         self.buffer.push_str(") {\n");
         self.indent += 1;
         for stmt in &func.body {
@@ -156,6 +214,22 @@ impl CodeGenerator {
         self.buffer.push_str("}\n");
     }
 
+    fn generate_attribute(&mut self, attr: &Attribute) {
+        write!(self.buffer, "#[{}", attr.name).unwrap();
+        if !attr.args.is_empty() {
+            self.buffer.push('(');
+            for (i, arg) in attr.args.iter().enumerate() {
+                if i > 0 {
+                    self.buffer.push_str(", ");
+                }
+                self.generate_expr(arg);
+            }
+            self.buffer.push(')');
+        }
+        self.buffer.push_str("]\n");
+        self.indent();
+    }
+
     fn generate_stmt(&mut self, stmt: &Stmt) {
         match stmt {
             Stmt::Assign(name, expr, _) => {
@@ -163,6 +237,19 @@ impl CodeGenerator {
                 self.generate_expr(expr);
                 self.buffer.push(';');
             }
+            Stmt::AssignPlace(place, expr, _) => {
+                self.generate_expr(place);
+                self.buffer.push_str(" = ");
+                self.generate_expr(expr);
+                self.buffer.push(';');
+            }
+            Stmt::Expr(Expr::Call(name, args), _)
+                if name == "broadcast" && matches!(&args[..], [Expr::Call(..)]) =>
+            {
+                self.buffer.push_str("broadcast ");
+                self.generate_expr(&args[0]);
+                self.buffer.push(';');
+            }
             Stmt::Expr(expr, _) => {
                 self.generate_expr(expr);
                 self.buffer.push(';');
@@ -172,6 +259,11 @@ impl CodeGenerator {
                 self.generate_expr(expr);
                 self.buffer.push(';');
             }
+            Stmt::LetTuple(names, expr, _) => {
+                write!(self.buffer, "let ({}) = ", names.join(", ")).unwrap();
+                self.generate_expr(expr);
+                self.buffer.push(';');
+            }
             Stmt::Return(expr, _) => {
                 self.buffer.push_str("return");
                 if let Some(e) = expr {
@@ -249,16 +341,38 @@ impl CodeGenerator {
             Stmt::Comment(c) => {
                 write!(self.buffer, "{}", c).unwrap();
             }
-            Stmt::Match(expr, arms, _, _) => {
+            Stmt::Match(expr, arms, default, _) => {
                 self.buffer.push_str("match ");
                 self.generate_expr(expr);
                 self.buffer.push_str(" {\n");
                 self.indent += 1;
-                for (pat, stmts) in arms {
+                for arm in arms {
                     self.indent();
-                    self.generate_expr(pat);
+                    for (i, pattern) in arm.patterns.iter().enumerate() {
+                        if i > 0 {
+                            self.buffer.push_str(" | ");
+                        }
+                        self.generate_pattern(pattern);
+                    }
+                    if let Some(guard) = &arm.guard {
+                        self.buffer.push_str(" if ");
+                        self.generate_expr(guard);
+                    }
                     self.buffer.push_str(" => {\n");
                     self.indent += 1;
+                    for s in &arm.body {
+                        self.indent();
+                        self.generate_stmt(s);
+                        self.buffer.push('\n');
+                    }
+                    self.indent -= 1;
+                    self.indent();
+                    self.buffer.push_str("}\n");
+                }
+                if let Some(stmts) = default {
+                    self.indent();
+                    self.buffer.push_str("_ => {\n");
+                    self.indent += 1;
                     for s in stmts {
                         self.indent();
                         self.generate_stmt(s);
@@ -294,31 +408,80 @@ impl CodeGenerator {
         }
     }
 
+    fn generate_pattern(&mut self, pattern: &Pattern) {
+        // Patterns are parsed at `+`/`-` precedence
+        let sum_prec = op_precedence(&Op::Add);
+        match pattern {
+            Pattern::Value(e) => self.generate_operand(e, sum_prec, false),
+            Pattern::Range(lo, hi, inclusive) => {
+                self.generate_operand(lo, sum_prec, false);
+                self.buffer.push_str(if *inclusive { "..=" } else { ".." });
+                self.generate_operand(hi, sum_prec, false);
+            }
+            Pattern::Binding(name) => self.buffer.push_str(name),
+            Pattern::Wildcard => self.buffer.push('_'),
+        }
+    }
+
+    fn generate_operand(&mut self, expr: &Expr, parent_prec: u8, is_right: bool) {
+        let needs_parens = match expr {
+            Expr::BinOp(_, op, _) => {
+                let prec = op_precedence(op);
+                prec < parent_prec || (is_right && prec == parent_prec)
+            }
+            Expr::UnOp(..) => parent_prec == u8::MAX,
+            Expr::If(..) => true,
+            _ => false,
+        };
+        if needs_parens {
+            self.buffer.push('(');
+            self.generate_expr(expr);
+            self.buffer.push(')');
+        } else {
+            self.generate_expr(expr);
+        }
+    }
+
     fn generate_expr(&mut self, expr: &Expr) {
         match expr {
             Expr::Number(f) => write!(self.buffer, "{}", f).unwrap(),
             Expr::String(s) => write!(self.buffer, "{:?}", s).unwrap(),
             Expr::Bool(b) => write!(self.buffer, "{}", b).unwrap(),
+            Expr::Color(c) => self.buffer.push_str(c),
             Expr::Variable(v) => write!(self.buffer, "{}", v).unwrap(),
             Expr::BinOp(l, op, r) => {
-                self.generate_expr(l);
-                let op_str = match op {
-                    Op::Add => "+",
-                    Op::Sub => "-",
-                    Op::Mul => "*",
-                    Op::Div => "/",
-                    Op::Mod => "%",
-                    Op::Eq => "==",
-                    Op::Gt => ">",
-                    Op::Lt => "<",
-                    Op::And => "&&",
-                    Op::Or => "||",
-                    Op::Ne => "!=",
-                    Op::Ge => ">=",
-                    Op::Le => "<=",
-                };
-                write!(self.buffer, " {} ", op_str).unwrap();
-                self.generate_expr(r);
+                // Parenthesize operands that bind looser than `op` so the
+                // generated source parses back to the same tree.
+                let prec = op_precedence(op);
+                if let Op::Pow = op {
+                    // `**` groups to the right, and a negated base needs
+                    // parentheses since `-x ** 2` means `-(x ** 2)`.
+                    let negated = match l.as_ref() {
+                        Expr::UnOp(UnOp::Neg, _) => true,
+                        Expr::Number(n) => *n < 0.0,
+                        _ => false,
+                    };
+                    if negated {
+                        self.buffer.push('(');
+                        self.generate_expr(l);
+                        self.buffer.push(')');
+                    } else {
+                        self.generate_operand(l, prec, true);
+                    }
+                    self.buffer.push_str(" ** ");
+                    self.generate_operand(r, prec, false);
+                    return;
+                }
+                self.generate_operand(l, prec, false);
+                write!(self.buffer, " {} ", op_str(op)).unwrap();
+                self.generate_operand(r, prec, true);
+            }
+            Expr::UnOp(op, e) => {
+                self.buffer.push(match op {
+                    UnOp::Not => '!',
+                    UnOp::Neg => '-',
+                });
+                self.generate_operand(e, u8::MAX, false);
             }
             Expr::Call(name, args) => {
                 write!(self.buffer, "{}(", name).unwrap();
@@ -340,6 +503,66 @@ impl CodeGenerator {
                 }
                 self.buffer.push(']');
             }
+            Expr::Tuple(items) => {
+                self.buffer.push('(');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        self.buffer.push_str(", ");
+                    }
+                    self.generate_expr(item);
+                }
+                self.buffer.push(')');
+            }
+            Expr::Index(base, index) => {
+                self.generate_operand(base, u8::MAX, false);
+                self.buffer.push('[');
+                self.generate_expr(index);
+                self.buffer.push(']');
+            }
+            Expr::Field(base, field) => {
+                self.generate_operand(base, u8::MAX, false);
+                write!(self.buffer, ".{}", field).unwrap();
+            }
+            Expr::MethodCall(base, method, args) => {
+                self.generate_operand(base, u8::MAX, false);
+                write!(self.buffer, ".{}(", method).unwrap();
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.buffer.push_str(", ");
+                    }
+                    self.generate_expr(arg);
+                }
+                self.buffer.push(')');
+            }
+            Expr::StructLit(name, fields) => {
+                write!(self.buffer, "{} {{ ", name).unwrap();
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.buffer.push_str(", ");
+                    }
+                    write!(self.buffer, "{}: ", field).unwrap();
+                    self.generate_expr(value);
+                }
+                self.buffer.push_str(" }");
+            }
+            Expr::NamedArg(name, value) => {
+                write!(self.buffer, "{}: ", name).unwrap();
+                self.generate_expr(value);
+            }
+            Expr::If(cond, then, otherwise) => {
+                self.buffer.push_str("if ");
+                self.generate_expr(cond);
+                self.buffer.push_str(" { ");
+                self.generate_expr(then);
+                self.buffer.push_str(" } else ");
+                if let Expr::If(..) = otherwise.as_ref() {
+                    self.generate_expr(otherwise);
+                } else {
+                    self.buffer.push_str("{ ");
+                    self.generate_expr(otherwise);
+                    self.buffer.push_str(" }");
+                }
+            }
             _ => {
                 self.buffer.push_str("/* unknown expr */");
             }
@@ -347,12 +570,59 @@ impl CodeGenerator {
     }
 }
 
-fn type_str(t: &Type) -> &'static str {
+fn op_precedence(op: &Op) -> u8 {
+    match op {
+        Op::Or => 1,
+        Op::And => 2,
+        Op::Eq | Op::Ne | Op::StrictEq | Op::StrictNe => 3,
+        Op::Gt | Op::Lt | Op::Ge | Op::Le => 4,
+        Op::BitOr => 5,
+        Op::BitXor => 6,
+        Op::BitAnd => 7,
+        Op::Shl | Op::Shr => 8,
+        Op::Add | Op::Sub => 9,
+        Op::Mul | Op::Div | Op::Mod => 10,
+        Op::Pow => 11,
+    }
+}
+
+fn op_str(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "+",
+        Op::Sub => "-",
+        Op::Mul => "*",
+        Op::Div => "/",
+        Op::Mod => "%",
+        Op::Pow => "**",
+        Op::BitAnd => "&",
+        Op::BitOr => "|",
+        Op::BitXor => "^",
+        Op::Shl => "<<",
+        Op::Shr => ">>",
+        Op::Eq => "==",
+        Op::Gt => ">",
+        Op::Lt => "<",
+        Op::And => "&&",
+        Op::Or => "||",
+        Op::Ne => "!=",
+        Op::Ge => ">=",
+        Op::Le => "<=",
+        Op::StrictEq => "===",
+        Op::StrictNe => "!==",
+    }
+}
+
+fn type_str(t: &Type) -> String {
     match t {
-        Type::Number => "number",
-        Type::String => "string",
-        Type::Boolean => "boolean",
-        Type::List => "list",
-        Type::Unknown => "unknown",
+        Type::Number => "number".to_string(),
+        Type::String => "string".to_string(),
+        Type::Boolean => "boolean".to_string(),
+        Type::List => "list".to_string(),
+        Type::StructList(name) => format!("list<{}>", name),
+        Type::Tuple(types) => format!(
+            "({})",
+            types.iter().map(type_str).collect::<Vec<_>>().join(", ")
+        ),
+        Type::Unknown => "unknown".to_string(),
     }
 }
@@ -1,19 +1,36 @@
 use crate::ast::*;
 use crate::extension::{BlockType, Extension};
 use crate::sb3::{Block, Comment, Costume, Field, Input, Mutation, NormalBlock, Sound, Target};
+use crate::source::{ItemContext, Origins};
+use crate::visit::{walk_stmts_exprs, walk_stmts_exprs_mut};
 use colored::*;
-use md5;
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use uuid::Uuid;
 
+/// Costume path for compiler-generated costumes that don't need artwork.
+pub const BLANK_COSTUME: &str = "builtin:blank.svg";
+/// Asset file contents and the name they are stored under in the project.
+pub type Assets = Vec<(Vec<u8>, String)>;
+
+/// How much of the project's shared code went into a target.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SharedUsage {
+    pub procedures: usize,
+    pub blocks: usize,
+}
+
+const BLANK_SVG: &str =
+    r#"<svg xmlns="http://www.w3.org/2000/svg" width="2" height="2" viewBox="0 0 2 2"></svg>"#;
+
 #[derive(Clone)]
 pub struct ProcedureInfo {
     pub proccode: String,
     pub arg_ids: Vec<String>,
     pub arg_names: Vec<String>,
+    pub arg_defaults: Vec<String>,
     pub param_ids: Vec<String>,
     pub warp: bool,
     pub return_type: Option<Type>,
@@ -29,7 +46,7 @@ pub struct CompilerContext<'a> {
     pub comments: HashMap<String, Comment>,
     pub global_variables: Option<&'a HashMap<String, (String, Value)>>,
     pub global_lists: Option<&'a HashMap<String, (String, Vec<Value>)>>,
-    pub asset_instructions: Vec<(PathBuf, String)>,
+    pub asset_instructions: Assets,
     pub procedures: HashMap<String, ProcedureInfo>,
     pub current_proc_args: Option<HashMap<String, Type>>,
     pub local_variables: HashMap<String, String>,
@@ -114,14 +131,18 @@ impl<'a> CompilerContext<'a> {
         project_root: &Path,
     ) {
         let source_path = project_root.join(&path);
-        let content = fs::read(&source_path).unwrap_or_else(|_| {
-            panic!(
-                "{}",
-                format!("Failed to read asset: {}", crate::format_path(&source_path))
-                    .red()
-                    .bold()
-            )
-        });
+        let content = if path == BLANK_COSTUME {
+            BLANK_SVG.as_bytes().to_vec()
+        } else {
+            fs::read(&source_path).unwrap_or_else(|_| {
+                panic!(
+                    "{}",
+                    format!("Failed to read asset: {}", crate::format_path(&source_path))
+                        .red()
+                        .bold()
+                )
+            })
+        };
         let ext = source_path
             .extension()
             .and_then(|s| s.to_str())
@@ -139,7 +160,7 @@ impl<'a> CompilerContext<'a> {
             rotation_center_y: y.unwrap_or(0.0),
         });
 
-        self.asset_instructions.push((source_path, filename));
+        self.asset_instructions.push((content, filename));
     }
 
     pub fn add_sound(&mut self, name: String, path: String, project_root: &Path) {
@@ -168,7 +189,7 @@ impl<'a> CompilerContext<'a> {
             sample_count: Some(0),
         });
 
-        self.asset_instructions.push((source_path, filename));
+        self.asset_instructions.push((content, filename));
     }
 
     pub fn add_menu_block(&mut self, opcode: &str, field_name: &str, value: String) -> String {
@@ -197,6 +218,28 @@ impl<'a> CompilerContext<'a> {
     }
 }
 
+/// Reports the item being compiled when a compile error panics.
+struct Compiling(ItemContext);
+
+impl Drop for Compiling {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!("{}", self.0.to_string().red().bold());
+        }
+    }
+}
+
+/// The context for errors in procedure `name`, which may be the inner half
+/// the transform splits off a procedure or function with a stack frame.
+fn procedure_context(origins: &Origins, name: &str) -> ItemContext {
+    match name.strip_prefix("_inner_") {
+        Some(outer) if !origins.contains("procedure", outer) => origins.context("function", outer),
+        Some(outer) => origins.context("procedure", outer),
+        None => origins.context("procedure", name),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn compile_target(
     program: &Program,
     is_stage: bool,
@@ -205,8 +248,9 @@ pub fn compile_target(
     project_root: &Path,
     extensions: &[Extension],
     packages: &HashMap<String, Package>,
+    origins: &Origins,
     debug: bool,
-) -> anyhow::Result<(Target, Vec<(PathBuf, String)>)> {
+) -> anyhow::Result<(Target, Assets, SharedUsage)> {
     let mut ctx = CompilerContext::new(global_variables, global_lists, extensions, debug);
 
     // Pre-scan for imports/use statements and implement tree shaking
@@ -215,68 +259,11 @@ pub fn compile_target(
     let mut used_procedures = std::collections::HashSet::new();
 
     fn scan_stmts(stmts: &[Stmt], used: &mut std::collections::HashSet<String>) {
-        for stmt in stmts {
-            match stmt {
-                Stmt::Expr(expr, _) => scan_expr(expr, used),
-                Stmt::If(cond, then_b, else_b, _) => {
-                    scan_expr(cond, used);
-                    scan_stmts(then_b, used);
-                    if let Some(else_b) = else_b {
-                        scan_stmts(else_b, used);
-                    }
-                }
-                Stmt::Repeat(count, body, _) => {
-                    scan_expr(count, used);
-                    scan_stmts(body, used);
-                }
-                Stmt::Forever(body, _) => scan_stmts(body, used),
-                Stmt::Until(cond, body, _) => {
-                    scan_expr(cond, used);
-                    scan_stmts(body, used);
-                }
-                Stmt::Match(expr, cases, default, _) => {
-                    scan_expr(expr, used);
-                    for (case_expr, case_body) in cases {
-                        scan_expr(case_expr, used);
-                        scan_stmts(case_body, used);
-                    }
-                    if let Some(def) = default {
-                        scan_stmts(def, used);
-                    }
-                }
-
-                Stmt::CBlock(_, args, body, _) => {
-                    for arg in args {
-                        scan_expr(arg, used);
-                    }
-                    scan_stmts(body, used);
-                }
-                Stmt::Assign(_, expr, _) => scan_expr(expr, used),
-                _ => {}
-            }
-        }
-    }
-
-    fn scan_expr(expr: &Expr, used: &mut std::collections::HashSet<String>) {
-        match expr {
-            Expr::Call(name, args) | Expr::ProcCall(name, args) => {
+        walk_stmts_exprs(stmts, &mut |expr| {
+            if let Expr::Call(name, _) | Expr::ProcCall(name, _) = expr {
                 used.insert(name.clone());
-                for arg in args {
-                    scan_expr(arg, used);
-                }
             }
-            Expr::BinOp(lhs, _, rhs) => {
-                scan_expr(lhs, used);
-                scan_expr(rhs, used);
-            }
-            Expr::UnOp(_, val) => scan_expr(val, used),
-            Expr::List(items) => {
-                for item in items {
-                    scan_expr(item, used);
-                }
-            }
-            _ => {}
-        }
+        });
     }
 
     fn namespace_stmts(
@@ -284,80 +271,23 @@ pub fn compile_target(
         pkg_name: &str,
         pkg_procs: &std::collections::HashSet<String>,
     ) {
-        for stmt in stmts {
-            match stmt {
-                Stmt::Expr(expr, _) => namespace_expr(expr, pkg_name, pkg_procs),
-                Stmt::If(cond, then_b, else_b, _) => {
-                    namespace_expr(cond, pkg_name, pkg_procs);
-                    namespace_stmts(then_b, pkg_name, pkg_procs);
-                    if let Some(else_b) = else_b {
-                        namespace_stmts(else_b, pkg_name, pkg_procs);
-                    }
-                }
-                Stmt::Repeat(count, body, _) => {
-                    namespace_expr(count, pkg_name, pkg_procs);
-                    namespace_stmts(body, pkg_name, pkg_procs);
-                }
-                Stmt::Forever(body, _) => namespace_stmts(body, pkg_name, pkg_procs),
-                Stmt::Until(cond, body, _) => {
-                    namespace_expr(cond, pkg_name, pkg_procs);
-                    namespace_stmts(body, pkg_name, pkg_procs);
-                }
-                Stmt::Match(expr, cases, default, _) => {
-                    namespace_expr(expr, pkg_name, pkg_procs);
-                    for (case_expr, case_body) in cases {
-                        namespace_expr(case_expr, pkg_name, pkg_procs);
-                        namespace_stmts(case_body, pkg_name, pkg_procs);
-                    }
-                    if let Some(def) = default {
-                        namespace_stmts(def, pkg_name, pkg_procs);
-                    }
-                }
-                // Return statements removed
-                Stmt::CBlock(_, args, body, _) => {
-                    for arg in args {
-                        namespace_expr(arg, pkg_name, pkg_procs);
-                    }
-                    namespace_stmts(body, pkg_name, pkg_procs);
-                }
-                Stmt::Assign(_, expr, _) => namespace_expr(expr, pkg_name, pkg_procs),
-                _ => {}
-            }
-        }
-    }
-
-    fn namespace_expr(
-        expr: &mut Expr,
-        pkg_name: &str,
-        pkg_procs: &std::collections::HashSet<String>,
-    ) {
-        match expr {
-            Expr::Call(name, args) | Expr::ProcCall(name, args) => {
+        walk_stmts_exprs_mut(stmts, &mut |expr| {
+            if let Expr::Call(name, _) | Expr::ProcCall(name, _) = expr {
                 if pkg_procs.contains(name) {
                     *name = format!("{}::{}", pkg_name, name);
                 }
-                for arg in args {
-                    namespace_expr(arg, pkg_name, pkg_procs);
-                }
-            }
-            Expr::BinOp(lhs, _, rhs) => {
-                namespace_expr(lhs, pkg_name, pkg_procs);
-                namespace_expr(rhs, pkg_name, pkg_procs);
-            }
-            Expr::UnOp(_, val) => namespace_expr(val, pkg_name, pkg_procs),
-            Expr::List(items) => {
-                for item in items {
-                    namespace_expr(item, pkg_name, pkg_procs);
-                }
             }
-            _ => {}
-        }
+        });
     }
 
     // Initial scan of program
     for item in &program.items {
         match item {
             Item::Function(func) => scan_stmts(&func.body, &mut used_procedures),
+            // Kept procedures are compiled even if nothing calls them
+            Item::Procedure(proc) if proc.attributes.iter().any(|a| a.name == "keep") => {
+                used_procedures.insert(proc.name.clone());
+            }
             Item::Procedure(_proc) => {
                 // Procedures are scanned when referenced by events (Functions) or other procedures.
             }
@@ -522,6 +452,7 @@ pub fn compile_target(
                     proccode,
                     arg_ids,
                     arg_names,
+                    arg_defaults: proc.params.iter().map(argument_default).collect(),
                     param_ids,
                     warp: proc.is_warp,
                     return_type: proc.return_type.clone(),
@@ -559,6 +490,7 @@ pub fn compile_target(
                         proccode,
                         arg_ids,
                         arg_names,
+                        arg_defaults: proc.params.iter().map(argument_default).collect(),
                         param_ids,
                         warp: proc.is_warp,
                         return_type: proc.return_type.clone(),
@@ -584,6 +516,45 @@ pub fn compile_target(
         );
     }
 
+    // Register variables and lists before compiling any code that refers to them
+    for item in &program.items {
+        let Item::Variable(decl) = item else {
+            continue;
+        };
+        if !is_stage && decl.visibility == Visibility::Public {
+            continue;
+        }
+
+        if let Some(comment) = &decl.comment {
+            ctx.add_comment(None, comment.clone(), 0.0, 0.0);
+        }
+
+        if let Type::List = decl.ty {
+            let mut initial_values = Vec::new();
+            if let Expr::List(exprs) = &decl.init {
+                for e in exprs {
+                    match e {
+                        Expr::Number(n) => initial_values.push(json!(n)),
+                        Expr::String(s) => initial_values.push(json!(s)),
+                        Expr::Bool(b) => initial_values.push(json!(b)),
+                        Expr::Color(c) => initial_values.push(json!(c)),
+                        _ => (),
+                    }
+                }
+            }
+            ctx.add_list(decl.name.clone(), initial_values);
+        } else {
+            let val = match &decl.init {
+                Expr::Number(n) => json!(n),
+                Expr::String(s) => json!(s),
+                Expr::Bool(b) => json!(b),
+                Expr::Color(c) => json!(c),
+                _ => json!(0),
+            };
+            ctx.add_variable(decl.name.clone(), val);
+        }
+    }
+
     // Compile used PACKAGE procedures
     for proc_name in &sorted_used {
         if let Some((proc, is_external)) = available_procedures.get(proc_name) {
@@ -621,6 +592,7 @@ pub fn compile_target(
 
     // Process Globals/Variables/Local Items
     let mut last_stmt_id: Option<String> = None;
+    let mut shared = SharedUsage::default();
 
     for item in &program.items {
         match item {
@@ -646,49 +618,33 @@ pub fn compile_target(
                 }
             }
             Item::Function(func) => {
+                let _compiling = Compiling(origins.context("function", &func.name));
                 compile_function(func, &mut ctx);
                 last_stmt_id = None; // Break chain
             }
-            Item::Procedure(proc) => {
-                // Local procedures: only compile if used
-                if sorted_used.contains(&proc.name) {
-                    compile_procedure(proc, &mut ctx);
-                    last_stmt_id = None;
-                }
-            }
-            Item::Variable(decl) => {
-                if !is_stage && decl.visibility == Visibility::Public {
-                    continue;
-                }
-
-                if let Some(comment) = &decl.comment {
-                    ctx.add_comment(None, comment.clone(), 0.0, 0.0);
-                }
-
-                if let Type::List = decl.ty {
-                    let mut initial_values = Vec::new();
-                    if let Expr::List(exprs) = &decl.init {
-                        for e in exprs {
-                            match e {
-                                Expr::Number(n) => initial_values.push(json!(n)),
-                                Expr::String(s) => initial_values.push(json!(s)),
-                                Expr::Bool(b) => initial_values.push(json!(b)),
-                                _ => (),
-                            }
-                        }
+            // Local procedures: only compile if used
+            Item::Procedure(proc) if sorted_used.contains(&proc.name) => {
+                let blocks_before = ctx.blocks.len();
+                let _compiling = Compiling(procedure_context(origins, &proc.name));
+                compile_procedure(proc, &mut ctx);
+                last_stmt_id = None;
+                if proc.attributes.iter().any(|a| a.name == "shared") {
+                    // A procedure with a return value is also split into an `_inner_` one
+                    if !proc.name.starts_with("_inner_") {
+                        shared.procedures += 1;
                     }
-                    ctx.add_list(decl.name.clone(), initial_values);
-                } else {
-                    let val = match &decl.init {
-                        Expr::Number(n) => json!(n),
-                        Expr::String(s) => json!(s),
-                        Expr::Bool(b) => json!(b),
-                        _ => json!(0),
-                    };
-                    ctx.add_variable(decl.name.clone(), val);
+                    shared.blocks += ctx.blocks.len() - blocks_before;
                 }
             }
             Item::Costume(decl) => {
+                if is_stage && decl.path == BLANK_COSTUME {
+                    panic!(
+                        "{}",
+                        "`===` and `!==` switch costumes, so they are only supported in sprites"
+                            .red()
+                            .bold()
+                    );
+                }
                 ctx.add_costume(
                     decl.name.clone(),
                     decl.path.clone(),
@@ -743,9 +699,21 @@ pub fn compile_target(
             },
         },
         ctx.asset_instructions,
+        shared,
     ))
 }
 
+/// The value the editor puts in an argument slot when a new call block is
+/// dragged out. Only literal defaults can be shown.
+fn argument_default(param: &Param) -> String {
+    match &param.default {
+        Some(Expr::Number(n)) => n.to_string(),
+        Some(Expr::String(s)) => s.clone(),
+        Some(Expr::Bool(b)) => b.to_string(),
+        _ => String::new(),
+    }
+}
+
 fn compile_procedure(proc: &ProcedureDef, ctx: &mut CompilerContext) -> Option<String> {
     // Retrieve pre-calculated info
     let info = match ctx.procedures.get(&proc.name) {
@@ -806,7 +774,7 @@ fn compile_procedure(proc: &ProcedureDef, ctx: &mut CompilerContext) -> Option<S
         proccode: Some(proccode),
         argumentids: Some(serde_json::to_string(&info.arg_ids).unwrap()),
         argumentnames: Some(serde_json::to_string(&info.arg_names).unwrap()),
-        argumentdefaults: Some(serde_json::to_string(&vec![""; info.arg_names.len()]).unwrap()),
+        argumentdefaults: Some(serde_json::to_string(&info.arg_defaults).unwrap()),
         warp: Some(info.warp.to_string()),
         return_: None,
     };
@@ -938,7 +906,7 @@ fn compile_function(func: &Function, ctx: &mut CompilerContext) -> Option<String
             }
             "on_greater_than" => {
                 hat_opcode = Some("event_whengreaterthan");
-                if let Some(Expr::String(menu)) = attr.args.get(0) {
+                if let Some(Expr::String(menu)) = attr.args.first() {
                     hat_fields.insert(
                         "WHENGREATERTHANMENU".to_string(),
                         Field::Generic(vec![json!(menu.to_uppercase()), Value::Null]),
@@ -1020,6 +988,20 @@ fn compile_stmt(
     ctx: &mut CompilerContext,
 ) -> Option<String> {
     match stmt {
+        Stmt::Expr(method @ Expr::MethodCall(..), comment) => compile_stmt(
+            &Stmt::Expr(desugar_access(method, ctx), comment.clone()),
+            parent_id,
+            ctx,
+        ),
+        Stmt::AssignPlace(Expr::Index(base, index), value, comment) => {
+            // `list[i] = v`; letters of a string can't be assigned
+            list_field(base, ctx);
+            let call = Expr::Call(
+                "replace_item_of_list".to_string(),
+                vec![*base.clone(), *index.clone(), value.clone()],
+            );
+            compile_stmt(&Stmt::Expr(call, comment.clone()), parent_id, ctx)
+        }
         Stmt::Expr(Expr::Call(name, args), comment)
         | Stmt::Expr(Expr::ProcCall(name, args), comment) => {
             let (opcode, inputs, fields, mutation, block_type) = map_call(name, args, ctx);
@@ -1276,47 +1258,6 @@ fn compile_stmt(
             // Becomes:
             // if expr == case1 { block1 } else { if expr == case2 { block2 } else { default } }
 
-            // Recursively build the if-else structure
-            fn build_match_tree(
-                expr: &Expr,
-                cases: &[(Expr, Vec<Stmt>)],
-                default_case: &Option<Vec<Stmt>>,
-            ) -> Stmt {
-                if let Some((case_expr, case_body)) = cases.first() {
-                    let cond =
-                        Expr::BinOp(Box::new(expr.clone()), Op::Eq, Box::new(case_expr.clone()));
-                    let remaining_cases = &cases[1..];
-                    let else_block = if remaining_cases.is_empty() {
-                        default_case.clone()
-                    } else {
-                        Some(vec![build_match_tree(expr, remaining_cases, default_case)])
-                    };
-                    Stmt::If(cond, case_body.clone(), else_block, None)
-                } else if let Some(def) = default_case {
-                    // Should not be reached if cases is not empty initially,
-                    // but if match has no cases but has default, we just run default.
-                    // However, Stmt::If structure requires a condition.
-                    // If we are here, it means we have no cases left.
-                    // We can't represent "just run this block" as a single Stmt::If.
-                    // But since build_match_tree returns a Stmt, and we need to wrap the default block in something...
-                    // Actually, if we run out of cases, we shouldn't be calling build_match_tree unless we are inside an else block.
-                    // Wait, the top level call might have empty cases.
-                    // If cases are empty, we can't return Stmt::If.
-                    // We might need to handle this logic before calling this function or change return type.
-                    // For now let's assume we only call this when we have at least one case.
-                    // But wait, we need to return a Stmt.
-                    // If we have 0 cases and a default, it's just the default block.
-                    // But Stmt is one statement. A block is Vec<Stmt>.
-                    // We can't easily return a block as a single Stmt without a wrapper.
-                    // Let's assume the parser ensures at least one case or we handle 0 cases specially.
-                    // If 0 cases and default, we could use "if true { default }".
-                    Stmt::If(Expr::Bool(true), def.clone(), None, None)
-                } else {
-                    // No cases, no default. Do nothing.
-                    Stmt::If(Expr::Bool(false), vec![], None, None)
-                }
-            }
-
             let stmt_tree = if cases.is_empty() {
                 if let Some(def) = default_case {
                     // execute default unconditionally
@@ -1415,6 +1356,142 @@ fn compile_stmt(
     }
 }
 
+// Recursively build the if-else structure
+fn build_match_tree(expr: &Expr, cases: &[MatchArm], default_case: &Option<Vec<Stmt>>) -> Stmt {
+    if let Some(mut values) = numeric_dispatch_values(cases, default_case) {
+        values.sort_by(|a, b| a.0.total_cmp(&b.0));
+        return build_search_tree(expr, &values, default_case);
+    }
+
+    let (arm, remaining_cases) = cases.split_first().unwrap();
+    let else_block = if remaining_cases.is_empty() {
+        default_case.clone()
+    } else {
+        Some(vec![build_match_tree(expr, remaining_cases, default_case)])
+    };
+    Stmt::If(arm_condition(expr, arm), arm.body.clone(), else_block, None)
+}
+
+/// Below this many values a plain if/else ladder is just as short.
+const MIN_SEARCH_TREE_VALUES: usize = 4;
+
+/// The search tree repeats the body of an `A | B` arm once per value and the
+/// default once per leaf. Above this many repeated blocks the ladder is used.
+const MAX_SEARCH_TREE_COPIES: usize = 64;
+
+/// `(value, body)` for every pattern if all arms are unguarded numeric literals
+/// and the search tree wouldn't copy too many blocks.
+fn numeric_dispatch_values<'a>(
+    cases: &'a [MatchArm],
+    default_case: &Option<Vec<Stmt>>,
+) -> Option<Vec<(f64, &'a [Stmt])>> {
+    let mut values = Vec::new();
+    let mut copies = 0;
+    for arm in cases {
+        if arm.guard.is_some() {
+            return None;
+        }
+        for pattern in &arm.patterns {
+            match pattern {
+                Pattern::Value(Expr::Number(n)) => values.push((*n, arm.body.as_slice())),
+                _ => return None,
+            }
+        }
+        copies += (arm.patterns.len() - 1) * block_count(&arm.body);
+    }
+    if let Some(default) = default_case {
+        copies += values.len().saturating_sub(1) * block_count(default);
+    }
+    (values.len() >= MIN_SEARCH_TREE_VALUES && copies <= MAX_SEARCH_TREE_COPIES).then_some(values)
+}
+
+/// Number of statements in `stmts`, counting nested ones.
+fn block_count(stmts: &[Stmt]) -> usize {
+    stmts
+        .iter()
+        .map(|stmt| {
+            1 + match stmt {
+                Stmt::If(_, then, otherwise, _) => {
+                    block_count(then) + otherwise.as_deref().map_or(0, block_count)
+                }
+                Stmt::Repeat(_, body, _)
+                | Stmt::Forever(body, _)
+                | Stmt::Until(_, body, _)
+                | Stmt::CBlock(_, _, body, _) => block_count(body),
+                Stmt::Match(_, arms, default, _) => {
+                    arms.iter().map(|arm| block_count(&arm.body)).sum::<usize>()
+                        + default.as_deref().map_or(0, block_count)
+                }
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+/// Binary search over sorted `values` with `<`, ending in one `=` test per leaf.
+/// Takes about log2(n) + 1 comparisons instead of up to n.
+fn build_search_tree(
+    expr: &Expr,
+    values: &[(f64, &[Stmt])],
+    default_case: &Option<Vec<Stmt>>,
+) -> Stmt {
+    let binop = |op: Op, n: f64| Expr::BinOp(Box::new(expr.clone()), op, Box::new(Expr::Number(n)));
+
+    if let [(n, body)] = values {
+        return Stmt::If(binop(Op::Eq, *n), body.to_vec(), default_case.clone(), None);
+    }
+    let (low, high) = values.split_at(values.len() / 2);
+    Stmt::If(
+        binop(Op::Lt, high[0].0),
+        vec![build_search_tree(expr, low, default_case)],
+        Some(vec![build_search_tree(expr, high, default_case)]),
+        None,
+    )
+}
+
+/// Condition under which `arm` is taken: any of its patterns matches, and the guard holds.
+fn arm_condition(expr: &Expr, arm: &MatchArm) -> Expr {
+    let binop = |l: Expr, op: Op, r: Expr| Expr::BinOp(Box::new(l), op, Box::new(r));
+    match (pattern_condition(expr, &arm.patterns), &arm.guard) {
+        (Some(c), Some(g)) => binop(c, Op::And, g.clone()),
+        (Some(c), None) => c,
+        (None, Some(g)) => g.clone(),
+        (None, None) => Expr::Bool(true),
+    }
+}
+
+/// Condition under which any of `patterns` matches `expr`, or `None` if one
+/// of them matches anything.
+pub fn pattern_condition(expr: &Expr, patterns: &[Pattern]) -> Option<Expr> {
+    let binop = |l: Expr, op: Op, r: Expr| Expr::BinOp(Box::new(l), op, Box::new(r));
+
+    let mut cond: Option<Expr> = None;
+    for pattern in patterns {
+        let test = match pattern {
+            Pattern::Value(value) => binop(expr.clone(), Op::Eq, value.clone()),
+            Pattern::Range(lo, hi, inclusive) => binop(
+                binop(expr.clone(), Op::Ge, lo.clone()),
+                Op::And,
+                binop(
+                    expr.clone(),
+                    if *inclusive { Op::Le } else { Op::Lt },
+                    hi.clone(),
+                ),
+            ),
+            // Catch-all patterns make the whole arm unconditional
+            Pattern::Binding(_) | Pattern::Wildcard => {
+                cond = None;
+                break;
+            }
+        };
+        cond = Some(match cond {
+            Some(c) => binop(c, Op::Or, test),
+            None => test,
+        });
+    }
+    cond
+}
+
 fn compile_sequence(stmts: &Vec<Stmt>, ctx: &mut CompilerContext) -> Option<String> {
     if stmts.is_empty() {
         return None;
@@ -1470,9 +1547,108 @@ fn find_list_arg(expr: &Expr, ctx: &CompilerContext) -> Option<(String, String)>
     None
 }
 
+/// The `LIST` field for a list argument. Unknown lists are a compile error
+/// rather than a block pointing at a list that doesn't exist.
+fn list_field(expr: &Expr, ctx: &CompilerContext) -> Field {
+    match find_list_arg(expr, ctx) {
+        Some((list_name, list_id)) => Field::Generic(vec![json!(list_name), json!(list_id)]),
+        None => match expr {
+            Expr::String(name) | Expr::Variable(name) => {
+                panic!("{}", format!("Unknown list `{}`", name).red().bold())
+            }
+            _ => panic!(
+                "{}",
+                "Expected a list name, found an expression".red().bold()
+            ),
+        },
+    }
+}
+
+/// Whether `base` of `x[i]` or `x.method(..)` is a list. Otherwise it must be
+/// a string: a name that isn't a variable, local or parameter either is
+/// reported as an unknown list, so a misspelt list doesn't become a string.
+fn is_list_access(base: &Expr, ctx: &CompilerContext) -> bool {
+    if find_list_arg(base, ctx).is_some() {
+        return true;
+    }
+    if let Expr::Variable(name) = base {
+        let is_value = ctx.local_variables.contains_key(name)
+            || find_variable_arg(base, ctx).is_some()
+            || ctx
+                .current_proc_args
+                .as_ref()
+                .is_some_and(|args| args.contains_key(name));
+        if !is_value {
+            list_field(base, ctx);
+        }
+    }
+    false
+}
+
+/// Rewrites `x[i]` and `x.method(..)` into list blocks when `x` names a list,
+/// or into string blocks otherwise.
+fn desugar_access(expr: &Expr, ctx: &CompilerContext) -> Expr {
+    let call = |name: &str, args: Vec<Expr>| Expr::Call(name.to_string(), args);
+    match expr {
+        Expr::Index(base, index) => {
+            let name = if is_list_access(base, ctx) {
+                "item_of_list"
+            } else {
+                "letter_of"
+            };
+            call(name, vec![*base.clone(), *index.clone()])
+        }
+        Expr::MethodCall(base, method, args) => {
+            let is_list = is_list_access(base, ctx);
+            let (name, arity) = match (is_list, method.as_str()) {
+                (true, "push") => ("add_to_list", 1),
+                (true, "insert") => ("insert_at_list", 2),
+                (true, "remove") => ("delete_of_list", 1),
+                (true, "clear") => ("delete_all_of_list", 0),
+                (true, "len") => ("length_of_list", 0),
+                (true, "contains") => ("list_contains", 1),
+                (true, "index_of") => ("item_num_of_list", 1),
+                (false, "len") => ("length_of", 0),
+                (false, "contains") => ("contains", 1),
+                (false, "push" | "insert" | "remove" | "clear" | "index_of") => {
+                    // Only lists have these, so report the list lookup failure
+                    list_field(base, ctx);
+                    unreachable!()
+                }
+                _ => panic!(
+                    "{}",
+                    format!(
+                        "Unknown method `{}`. Lists have push, insert, remove, clear, len, contains and index_of; strings have len and contains",
+                        method
+                    )
+                    .red()
+                    .bold()
+                ),
+            };
+            if args.len() != arity {
+                panic!(
+                    "{}",
+                    format!(
+                        "`.{}()` expects {} arguments but {} were given",
+                        method,
+                        arity,
+                        args.len()
+                    )
+                    .red()
+                    .bold()
+                );
+            }
+            let mut call_args = vec![*base.clone()];
+            call_args.extend(args.iter().cloned());
+            call(name, call_args)
+        }
+        _ => expr.clone(),
+    }
+}
+
 fn map_args_to_block(
     block_def: &crate::extension::BlockDef,
-    args: &Vec<Expr>,
+    args: &[Expr],
     ctx: &mut CompilerContext,
 ) -> (HashMap<String, Input>, HashMap<String, Field>) {
     let mut inputs = HashMap::new();
@@ -1480,9 +1656,14 @@ fn map_args_to_block(
 
     for (input_name, mapping) in &block_def.inputs {
         match mapping {
-            crate::extension::InputMapping::Arg { arg } => {
+            crate::extension::InputMapping::Arg { arg, color } => {
                 if let Some(expr) = args.get(*arg) {
-                    inputs.insert(input_name.clone(), compile_expr_input(expr, ctx));
+                    let input = if *color {
+                        compile_color_input(expr, ctx)
+                    } else {
+                        compile_expr_input(expr, ctx)
+                    };
+                    inputs.insert(input_name.clone(), input);
                 }
             }
         }
@@ -1502,9 +1683,10 @@ fn map_args_to_block(
     (inputs, fields)
 }
 
+#[allow(clippy::type_complexity)]
 fn map_call(
     name: &str,
-    args: &Vec<Expr>,
+    args: &[Expr],
     ctx: &mut CompilerContext,
 ) -> (
     String,
@@ -1534,82 +1716,42 @@ fn map_call(
 
     let opcode = match name {
         "add_to_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("ITEM".to_string(), compile_expr_input(&args[1], ctx));
             "data_addtolist"
         }
         "delete_of_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("INDEX".to_string(), compile_expr_input(&args[1], ctx));
             "data_deleteoflist"
         }
         "delete_all_of_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             "data_deletealloflist"
         }
         "insert_at_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("INDEX".to_string(), compile_expr_input(&args[1], ctx));
             inputs.insert("ITEM".to_string(), compile_expr_input(&args[2], ctx));
             "data_insertatlist"
         }
         "replace_item_of_list" | "replace_item_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("INDEX".to_string(), compile_expr_input(&args[1], ctx));
             inputs.insert("ITEM".to_string(), compile_expr_input(&args[2], ctx));
             "data_replaceitemoflist"
         }
         "item_of_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("INDEX".to_string(), compile_expr_input(&args[1], ctx));
             "data_itemoflist"
         }
         "length_of_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             "data_lengthoflist"
         }
         "list_contains" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("ITEM".to_string(), compile_expr_input(&args[1], ctx));
             "data_listcontainsitem"
         }
@@ -1958,12 +2100,12 @@ fn map_call(
             "sensing_touchingobject"
         }
         "touching_color" => {
-            inputs.insert("COLOR".to_string(), compile_expr_input(&args[0], ctx));
+            inputs.insert("COLOR".to_string(), compile_color_input(&args[0], ctx));
             "sensing_touchingcolor"
         }
         "color_touching_color" => {
-            inputs.insert("COLOR".to_string(), compile_expr_input(&args[0], ctx));
-            inputs.insert("COLOR2".to_string(), compile_expr_input(&args[1], ctx));
+            inputs.insert("COLOR".to_string(), compile_color_input(&args[0], ctx));
+            inputs.insert("COLOR2".to_string(), compile_color_input(&args[1], ctx));
             "sensing_coloristouchingcolor"
         }
         "distance_to" => {
@@ -2178,10 +2320,15 @@ fn map_call(
             "operator_round"
         }
         "abs" | "floor" | "sqrt" | "sin" | "cos" | "tan" | "asin" | "acos" | "atan" | "ln"
-        | "log" | "e^" | "10^" => {
+        | "log" | "exp" | "exp10" => {
+            let operator = match name {
+                "exp" => "e ^",
+                "exp10" => "10 ^",
+                _ => name,
+            };
             fields.insert(
                 "OPERATOR".to_string(),
-                Field::Generic(vec![json!(name), Value::Null]),
+                Field::Generic(vec![json!(operator), Value::Null]),
             );
             inputs.insert("NUM".to_string(), compile_expr_input(&args[0], ctx));
             "operator_mathop"
@@ -2257,30 +2404,15 @@ fn map_call(
             "data_hidevariable"
         }
         "show_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             "data_showlist"
         }
         "hide_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             "data_hidelist"
         }
         "item_num_of_list" => {
-            if let Some((list_name, list_id)) = find_list_arg(&args[0], ctx) {
-                fields.insert(
-                    "LIST".to_string(),
-                    Field::Generic(vec![json!(list_name), json!(list_id)]),
-                );
-            }
+            fields.insert("LIST".to_string(), list_field(&args[0], ctx));
             inputs.insert("ITEM".to_string(), compile_expr_input(&args[1], ctx));
             "data_itemnumoflist"
         }
@@ -2293,9 +2425,6 @@ fn map_call(
                     }
                 }
 
-                // Create default values array (empty strings)
-                let defaults: Vec<String> = vec!["".to_string(); info.arg_ids.len()];
-
                 let return_val = if info.return_type.is_some() {
                     Some("1".to_string())
                 } else {
@@ -2308,7 +2437,7 @@ fn map_call(
                     proccode: Some(info.proccode),
                     argumentids: Some(serde_json::to_string(&info.arg_ids).unwrap()),
                     argumentnames: Some(serde_json::to_string(&info.arg_names).unwrap()),
-                    argumentdefaults: Some(serde_json::to_string(&defaults).unwrap()),
+                    argumentdefaults: Some(serde_json::to_string(&info.arg_defaults).unwrap()),
                     warp: Some(info.warp.to_string()),
                     return_: return_val,
                 });
@@ -2345,9 +2474,10 @@ fn map_call(
             _ => BlockType::Reporter,
         },
         op if op.starts_with("data_") => match op {
-            "data_itemoflist" | "data_lengthoflist" | "data_listcontainsitem" => {
-                BlockType::Reporter
-            }
+            "data_itemoflist"
+            | "data_itemnumoflist"
+            | "data_lengthoflist"
+            | "data_listcontainsitem" => BlockType::Reporter,
             _ => BlockType::Command,
         },
         "looks_size" | "looks_costumenumbername" | "looks_backdropnumbername" => {
@@ -2420,6 +2550,20 @@ fn compile_bool_arg(expr: &Expr, ctx: &mut CompilerContext) -> Input {
     }
 }
 
+/// Input for a color slot: literals become a color picker, anything else is
+/// dropped onto one.
+fn compile_color_input(expr: &Expr, ctx: &mut CompilerContext) -> Input {
+    match expr {
+        Expr::Color(c) => Input::Generic(vec![json!(1), json!([9, c])]), // 9 is Color primitive
+        _ => match compile_expr_input(expr, ctx) {
+            Input::Generic(v) if v[0] == json!(2) => {
+                Input::Generic(vec![json!(3), v[1].clone(), json!([9, "#000000"])])
+            }
+            input => input,
+        },
+    }
+}
+
 fn compile_expr_input(expr: &Expr, ctx: &mut CompilerContext) -> Input {
     match expr {
         Expr::Number(n) => Input::Generic(vec![json!(1), json!([4, n])]), // 4 is Number primitive
@@ -2427,6 +2571,8 @@ fn compile_expr_input(expr: &Expr, ctx: &mut CompilerContext) -> Input {
             // Handle special menu inputs if needed, but for now generic string
             Input::Generic(vec![json!(1), json!([10, s])]) // 10 is String primitive
         }
+        // Outside a color input a color is just its hex string
+        Expr::Color(c) => Input::Generic(vec![json!(1), json!([10, c])]),
         Expr::Bool(b) => {
             // Create a boolean reporter block (e.g., 1 = 1 for true, 1 = 0 for false)
             // This is necessary because boolean inputs (hexagonal) cannot take shadow values
@@ -2559,6 +2705,24 @@ fn compile_expr_input(expr: &Expr, ctx: &mut CompilerContext) -> Input {
                 Op::Ne => ("operator_equals", true),
                 Op::Ge => ("operator_lt", true), // >= is not <
                 Op::Le => ("operator_gt", true), // <= is not >
+                Op::StrictEq | Op::StrictNe => panic!(
+                    "{}",
+                    "Strict comparisons must be lowered before compilation"
+                        .red()
+                        .bold()
+                ),
+                Op::Pow => panic!(
+                    "{}",
+                    "Exponentiation must be lowered before compilation"
+                        .red()
+                        .bold()
+                ),
+                Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr => panic!(
+                    "{}",
+                    "Integer and bitwise operators must be lowered before compilation"
+                        .red()
+                        .bold()
+                ),
             };
 
             let is_bool_op = opcode == "operator_and" || opcode == "operator_or";
@@ -2674,6 +2838,41 @@ fn compile_expr_input(expr: &Expr, ctx: &mut CompilerContext) -> Input {
             Input::Generic(vec![json!(2), json!(id)])
         }
         Expr::List(_) => Input::Generic(vec![json!(1), json!([10, ""])]), // Lists not supported as inputs
+        Expr::Index(..) | Expr::MethodCall(..) => {
+            compile_expr_input(&desugar_access(expr, ctx), ctx)
+        }
+        Expr::Field(..) | Expr::StructLit(..) => {
+            panic!(
+                "{}",
+                "Error: Struct expressions must be lowered before compilation. Compilation terminated."
+                    .red()
+                    .bold()
+            );
+        }
+        Expr::If(..) => {
+            panic!(
+                "{}",
+                "Error: `if` expressions are only supported inside functions and procedures. Compilation terminated."
+                    .red()
+                    .bold()
+            );
+        }
+        Expr::NamedArg(..) => {
+            panic!(
+                "{}",
+                "Error: Named arguments must be matched to parameters before compilation. Compilation terminated."
+                    .red()
+                    .bold()
+            );
+        }
+        Expr::Tuple(..) => {
+            panic!(
+                "{}",
+                "Error: Tuples can only be returned from procedures and unpacked with `let (a, b) = ...`. Compilation terminated."
+                    .red()
+                    .bold()
+            );
+        }
     }
 }
 
@@ -2696,3 +2895,237 @@ fn fix_input_parents(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn compile(src: &str) -> Target {
+        let (_, program) = parse_program(src).unwrap();
+        compile_target(
+            &program,
+            false,
+            None,
+            None,
+            Path::new("."),
+            &[],
+            &HashMap::new(),
+            &Origins::default(),
+            false,
+        )
+        .unwrap()
+        .0
+    }
+
+    fn opcodes(target: &Target) -> Vec<&str> {
+        let mut ops: Vec<&str> = target
+            .blocks
+            .values()
+            .filter_map(|b| match b {
+                Block::Normal(b) => Some(b.opcode.as_str()),
+                _ => None,
+            })
+            .collect();
+        ops.sort();
+        ops
+    }
+
+    #[test]
+    fn test_list_and_string_sugar() {
+        let target = compile(
+            r#"
+            list names = [];
+            var text = "hi";
+            #[on_flag_clicked]
+            fn go() {
+                names.push(text[1]);
+                names[1] = text.len();
+                say(names[names.len()]);
+            }
+            "#,
+        );
+        let ops = opcodes(&target);
+        for op in [
+            "data_addtolist",
+            "operator_letter_of",
+            "data_replaceitemoflist",
+            "operator_length",
+            "data_itemoflist",
+            "data_lengthoflist",
+        ] {
+            assert!(ops.contains(&op), "missing {} in {:?}", op, ops);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown list `nmaes`")]
+    fn test_unknown_list_is_an_error() {
+        compile(
+            r#"
+            list names = [];
+            #[on_flag_clicked]
+            fn go() {
+                nmaes.push(1);
+            }
+            "#,
+        );
+    }
+
+    #[test]
+    fn test_unknown_name_is_not_a_string() {
+        for access in ["nmaes[1]", "nmaes.len()", "nmaes.contains(1)"] {
+            let src = format!(
+                "list names = [];\n#[on_flag_clicked]\nfn go() {{ say({}); }}",
+                access
+            );
+            let err = std::panic::catch_unwind(|| compile(&src)).unwrap_err();
+            let message = err.downcast_ref::<String>().unwrap();
+            assert!(message.contains("Unknown list `nmaes`"), "{}", access);
+        }
+
+        // Variables, parameters and expressions are still strings
+        let target = compile(
+            r#"
+            var text = "hi";
+            proc show(s: string) {
+                say(s[1]);
+                say(join(s, text).len());
+                say(text.contains("h"));
+            }
+            #[on_flag_clicked]
+            fn go() { show("a"); }
+            "#,
+        );
+        let ops = opcodes(&target);
+        for op in ["operator_letter_of", "operator_length", "operator_contains"] {
+            assert!(ops.contains(&op), "missing {} in {:?}", op, ops);
+        }
+    }
+
+    /// Runs a lowered match tree for `x`, returning the `say` argument reached
+    /// and how many comparisons it took.
+    fn dispatch(stmt: &Stmt, x: f64, comparisons: &mut usize) -> Option<f64> {
+        match stmt {
+            Stmt::If(Expr::BinOp(_, op, rhs), then, otherwise, _) => {
+                let Expr::Number(n) = rhs.as_ref() else {
+                    panic!("Expected a numeric comparison");
+                };
+                *comparisons += 1;
+                let taken = match op {
+                    Op::Lt => x < *n,
+                    Op::Eq => x == *n,
+                    other => panic!("Unexpected operator {:?}", other),
+                };
+                match (taken, otherwise) {
+                    (true, _) => dispatch(&then[0], x, comparisons),
+                    (false, Some(otherwise)) => dispatch(&otherwise[0], x, comparisons),
+                    (false, None) => None,
+                }
+            }
+            Stmt::Expr(Expr::Call(_, args), _) => match args[0] {
+                Expr::Number(n) => Some(n),
+                _ => None,
+            },
+            other => panic!("Unexpected statement {:?}", other),
+        }
+    }
+
+    fn say(n: f64) -> Vec<Stmt> {
+        vec![Stmt::Expr(
+            Expr::Call("say".to_string(), vec![Expr::Number(n)]),
+            None,
+        )]
+    }
+
+    #[test]
+    fn test_large_numeric_match_is_balanced() {
+        let scrutinee = Expr::Variable("x".to_string());
+        let arms: Vec<MatchArm> = (0..40)
+            .rev()
+            .map(|i| MatchArm {
+                patterns: vec![Pattern::Value(Expr::Number(i as f64))],
+                guard: None,
+                body: say(i as f64),
+            })
+            .collect();
+        let default = Some(say(-1.0));
+
+        let tree = build_match_tree(&scrutinee, &arms, &default);
+
+        let mut worst = 0;
+        for i in 0..40 {
+            let mut comparisons = 0;
+            assert_eq!(dispatch(&tree, i as f64, &mut comparisons), Some(i as f64));
+            worst = worst.max(comparisons);
+        }
+        // A 40-step ladder would need up to 40 comparisons
+        assert!(worst <= 7, "took {} comparisons", worst);
+
+        let mut comparisons = 0;
+        assert_eq!(dispatch(&tree, 12.5, &mut comparisons), Some(-1.0));
+        assert_eq!(dispatch(&tree, 99.0, &mut comparisons), Some(-1.0));
+    }
+
+    #[test]
+    fn test_search_tree_limits_copies() {
+        let scrutinee = Expr::Variable("x".to_string());
+        let arms = |patterns: usize, body: Vec<Stmt>| -> Vec<MatchArm> {
+            (0..8)
+                .map(|i| MatchArm {
+                    patterns: (0..patterns)
+                        .map(|j| Pattern::Value(Expr::Number((i * patterns + j) as f64)))
+                        .collect(),
+                    guard: None,
+                    body: body.clone(),
+                })
+                .collect()
+        };
+        let is_ladder =
+            |tree: &Stmt| matches!(tree, Stmt::If(Expr::BinOp(_, Op::Or | Op::Eq, _), ..));
+
+        // A short default repeated at every leaf is fine
+        let tree = build_match_tree(&scrutinee, &arms(1, say(1.0)), &Some(say(-1.0)));
+        assert!(!is_ladder(&tree));
+
+        // A long default or long `A | B` bodies would be copied too often
+        let long: Vec<Stmt> = (0..10).flat_map(|i| say(i as f64)).collect();
+        let tree = build_match_tree(&scrutinee, &arms(1, say(1.0)), &Some(long.clone()));
+        assert!(is_ladder(&tree));
+        let tree = build_match_tree(&scrutinee, &arms(2, long), &None);
+        assert!(is_ladder(&tree));
+    }
+
+    #[test]
+    fn test_only_reachable_shared_procedures_are_counted() {
+        let (_, program) = parse_program(
+            "#[shared]\nproc fall() { change_y_by(-1); }\n\
+             #[shared]\nproc unused() { say(1); }\n\
+             proc step() { fall(); }\n\
+             #[on_flag_clicked]\nfn start() { step(); }\n",
+        )
+        .unwrap();
+        let (target, _, shared) = compile_target(
+            &program,
+            false,
+            None,
+            None,
+            Path::new("."),
+            &[],
+            &HashMap::new(),
+            &Origins::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(shared.procedures, 1);
+        // The definition, its prototype and `change y by` with its input
+        assert!(shared.blocks >= 3);
+        assert_eq!(
+            opcodes(&target)
+                .iter()
+                .filter(|op| **op == "procedures_definition")
+                .count(),
+            2
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 #[derive(Deserialize, Debug)]
@@ -6,6 +7,7 @@ pub struct ScrustConfig {
     pub project: ProjectConfig,
     pub stage: TargetConfig,
     pub sprite: Option<Vec<TargetConfig>>,
+    pub dependencies: Option<BTreeMap<String, DependencySpec>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -15,6 +17,14 @@ pub struct ProjectConfig {
     pub output: PathBuf,
     pub extensions: Option<Vec<ExtensionConfig>>,
     pub packages: Option<Vec<String>>,
+    /// Inline procedures of at most this many blocks
+    pub inline_threshold: Option<usize>,
+    /// Directory holding downloaded packages, as `<name>/<version>/package.toml`
+    pub package_cache: Option<PathBuf>,
+    /// Directory of source files every target includes, instead of `shared/`
+    pub shared: Option<PathBuf>,
+    /// Reset variables and lists to their declared values on the green flag
+    pub reset_on_flag: Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -32,8 +42,26 @@ pub struct DetailedExtensionConfig {
     pub definition: Option<PathBuf>,
 }
 
+/// A dependency in `scrust.toml` or a `package.toml`: either a version
+/// requirement such as `"^1.2"`, or a table with an optional `version` and
+/// `path`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DependencySpec {
+    Version(String),
+    Detailed(DetailedDependencySpec),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DetailedDependencySpec {
+    pub version: Option<String>,
+    pub path: Option<PathBuf>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct TargetConfig {
     pub name: Option<String>,
-    pub path: PathBuf,
+    pub path: Option<PathBuf>,
+    /// Several files making up one target, instead of `path`
+    pub paths: Option<Vec<PathBuf>>,
 }
@@ -17,9 +17,10 @@ pub struct Extension {
     pub project_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum BlockType {
+    #[default]
     Command,
     Reporter,
     Hat,
@@ -28,12 +29,6 @@ pub enum BlockType {
     Boolean,
 }
 
-impl Default for BlockType {
-    fn default() -> Self {
-        BlockType::Command
-    }
-}
-
 #[derive(Debug, Deserialize, Clone)]
 pub struct BlockDef {
     pub opcode: String,
@@ -48,7 +43,12 @@ pub struct BlockDef {
 #[derive(Debug, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum InputMapping {
-    Arg { arg: usize },
+    Arg {
+        arg: usize,
+        /// Compile color literals to a color picker
+        #[serde(default)]
+        color: bool,
+    },
     // We can add more types later if needed, e.g., specific values
 }
 
@@ -82,7 +82,7 @@ pub fn load_extensions(
         for entry in fs::read_dir(extensions_dir)? {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().map_or(false, |ext| ext == "toml") {
+            if path.extension().is_some_and(|ext| ext == "toml") {
                 if let Ok(ext) = load_file(&path) {
                     dir_extensions.insert(ext.id.clone(), ext);
                 }
@@ -3,14 +3,24 @@ mod codegen;
 mod compiler;
 mod config;
 mod extension;
+mod message;
+mod optimize;
+mod package;
 mod parser;
+mod remote;
+mod reset;
 mod sb3;
+mod source;
+mod spawn;
+#[cfg(test)]
+mod testing;
 mod transform;
+mod visit;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use colored::*;
-use config::{ExtensionConfig, ScrustConfig};
+use config::{ExtensionConfig, ScrustConfig, TargetConfig};
 use nom::error::Error;
 use std::collections::HashMap;
 use std::fs;
@@ -56,10 +66,18 @@ fn main() -> Result<()> {
 fn generate_internal(
     config_path: &PathBuf,
     write_to_disk: bool,
-) -> Result<HashMap<PathBuf, String>> {
+) -> Result<HashMap<PathBuf, (String, source::Origins)>> {
     let mut generated_files = HashMap::new();
     let config_str = fs::read_to_string(config_path)?;
     let config: ScrustConfig = toml::from_str(&config_str)?;
+    let options = transform::TransformOptions {
+        inline_threshold: config.project.inline_threshold,
+        is_stage: false,
+    };
+    let stage_options = transform::TransformOptions {
+        is_stage: true,
+        ..options.clone()
+    };
     let config_dir = config_path.parent().unwrap();
     let dist_dir = config_dir.join("dist");
 
@@ -68,112 +86,110 @@ fn generate_internal(
     }
 
     // Load Packages
-    let mut packages_map = HashMap::new();
-    if let Some(package_paths) = &config.project.packages {
-        for package_path_str in package_paths {
-            let package_path = if PathBuf::from(package_path_str).is_absolute() {
-                PathBuf::from(package_path_str)
-            } else {
-                config_dir.join(package_path_str)
-            };
+    let packages_map = package::load_packages(&config, config_dir)?;
 
-            let src = fs::read_to_string(&package_path)
-                .with_context(|| format!("Failed to read package file: {}", package_path_str))?;
+    let shared_files = source::shared_files(&config, config_dir)?;
 
-            let (rest, program) = parser::parse_program(&src).map_err(|e| {
-                anyhow::anyhow!(
-                    "In package '{}': {}",
-                    package_path_str,
-                    format_parse_error(e, &src)
-                )
-            })?;
-            if !rest.trim().is_empty() {
-                println!(
-                    "{}",
+    // Load Stage
+    let stage_files = source::target_files(&config.stage, "[stage]", config_dir)?;
+    let stage_path = stage_files[0].clone();
+    let (mut stage_ast, stage_origins) =
+        source::load_target(&shared_files, &stage_files).context("In stage")?;
+
+    // Load Sprites
+    let mut sprite_data = Vec::new();
+    if let Some(sprites) = &config.sprite {
+        for sprite in sprites {
+            let label = format!("Sprite '{}'", sprite.name.as_deref().unwrap_or("unknown"));
+            let sprite_files = source::target_files(sprite, &label, config_dir)?;
+            let sprite_path = sprite_files[0].clone();
+            let (ast, origins) =
+                source::load_target(&shared_files, &sprite_files).with_context(|| {
                     format!(
-                        "Warning: Package {} parsing stopped early. Remaining: {:.50}...",
-                        package_path_str, rest
+                        "In sprite '{}'",
+                        sprite.name.as_deref().unwrap_or("unknown")
                     )
-                    .yellow()
-                );
-            }
-
-            let mut package_def = None;
-            let mut items = Vec::new();
-
-            for item in program.items {
-                if let ast::Item::Package(pkg) = item {
-                    package_def = Some(pkg);
-                } else {
-                    items.push(item);
-                }
-            }
-
-            if let Some(mut pkg) = package_def {
-                pkg.items = items;
-                packages_map.insert(pkg.name.clone(), pkg);
-            }
+                })?;
+            sprite_data.push((sprite, sprite_path, ast, origins));
         }
     }
 
-    // Process Stage
-    let stage_path = if config.stage.path.is_absolute() {
-        config.stage.path.clone()
-    } else {
-        config_dir.join(&config.stage.path)
-    };
-    let stage_src = fs::read_to_string(&stage_path)?;
-    let (_, mut stage_ast) = parser::parse_program(&stage_src)
-        .map_err(|e| anyhow::anyhow!("{}", format_parse_error(e, &stage_src)))?;
-
-    transform::transform_program(&mut stage_ast, &packages_map);
-
-    let gen = codegen::CodeGenerator::new();
-    let stage_out = gen.generate(&stage_ast);
-
-    generated_files.insert(stage_path.clone(), stage_out.clone());
-
-    if write_to_disk {
-        let stage_out_path = dist_dir.join(stage_path.file_name().unwrap());
-        fs::write(&stage_out_path, stage_out)?;
-        println!("Generated {}", stage_out_path.display());
+    // Turn calls like `Player::jump()`, clone spawns from other sprites and
+    // typed messages into broadcasts, and reads like `Player.x` into
+    // `sensing_of` blocks
+    {
+        let mut targets = all_targets(
+            &mut stage_ast,
+            sprite_data
+                .iter_mut()
+                .map(|(sprite, _, ast, _)| (*sprite, ast)),
+        );
+        spawn::lower_clone_spawns(&mut targets)?;
+        remote::lower_remote_calls(&mut targets, &packages_map)?;
+        remote::lower_remote_fields(&mut targets)?;
+        message::lower_messages(&mut targets)?;
     }
 
-    // Process Sprites
-    if let Some(sprites) = &config.sprite {
-        for sprite in sprites {
-            let sprite_path = if sprite.path.is_absolute() {
-                sprite.path.clone()
-            } else {
-                config_dir.join(&sprite.path)
-            };
-            let src = fs::read_to_string(&sprite_path)?;
-            let (_, mut ast) = parser::parse_program(&src).map_err(|e| {
-                anyhow::anyhow!(
-                    "In sprite '{}': {}",
-                    sprite.name.as_deref().unwrap_or("unknown"),
-                    format_parse_error(e, &src)
+    transform::transform_program(&mut stage_ast, &packages_map, &stage_options)
+        .map_err(|e| stage_origins.locate(e))
+        .context("In stage")?;
+    for (sprite, _, ast, origins) in sprite_data.iter_mut() {
+        transform::transform_program(ast, &packages_map, &options)
+            .map_err(|e| origins.locate(e))
+            .with_context(|| {
+                format!(
+                    "In sprite '{}'",
+                    sprite.name.as_deref().unwrap_or("unknown")
                 )
             })?;
+    }
 
-            transform::transform_program(&mut ast, &packages_map);
+    if config.project.reset_on_flag == Some(true) {
+        let mut targets = all_targets(
+            &mut stage_ast,
+            sprite_data
+                .iter_mut()
+                .map(|(sprite, _, ast, _)| (*sprite, ast)),
+        );
+        reset::add_flag_reset(&mut targets);
+    }
 
-            let gen = codegen::CodeGenerator::new();
-            let out = gen.generate(&ast);
+    let mut outputs = vec![(stage_path, stage_ast, stage_origins)];
+    outputs.extend(
+        sprite_data
+            .into_iter()
+            .map(|(_, path, ast, origins)| (path, ast, origins)),
+    );
+    for (path, ast, origins) in outputs {
+        let gen = codegen::CodeGenerator::new();
+        let out = gen.generate(&ast);
 
-            generated_files.insert(sprite_path.clone(), out.clone());
+        generated_files.insert(path.clone(), (out.clone(), origins));
 
-            if write_to_disk {
-                let out_path = dist_dir.join(sprite_path.file_name().unwrap());
-                fs::write(&out_path, out)?;
-                println!("Generated {}", out_path.display());
-            }
+        if write_to_disk {
+            let out_path = dist_dir.join(path.file_name().unwrap());
+            fs::write(&out_path, out)?;
+            println!("Generated {}", out_path.display());
         }
     }
 
     Ok(generated_files)
 }
 
+/// The Stage and every sprite as `(name, program)`, the form the passes
+/// that work across targets take.
+fn all_targets<'a>(
+    stage: &'a mut ast::Program,
+    sprites: impl Iterator<Item = (&'a TargetConfig, &'a mut ast::Program)>,
+) -> Vec<(String, &'a mut ast::Program)> {
+    let mut targets = vec![("Stage".to_string(), stage)];
+    for (sprite, ast) in sprites {
+        let name = sprite.name.clone().unwrap_or_else(|| "unknown".to_string());
+        targets.push((name, ast));
+    }
+    targets
+}
+
 fn format_parse_error(e: nom::Err<Error<&str>>, src: &str) -> String {
     match e {
         nom::Err::Error(e) | nom::Err::Failure(e) => {
@@ -198,108 +214,70 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
     println!("{}", "Building project...".blue().bold());
     let config_str = fs::read_to_string(&config_path)?;
     let config: ScrustConfig = toml::from_str(&config_str)?;
+    let options = transform::TransformOptions {
+        inline_threshold: config.project.inline_threshold,
+        is_stage: false,
+    };
+    let stage_options = transform::TransformOptions {
+        is_stage: true,
+        ..options.clone()
+    };
     let config_dir = config_path.parent().unwrap();
 
-    let mut packages_map = HashMap::new();
+    let packages_map = package::load_packages(&config, config_dir)?;
+    let mut package_names: Vec<&String> = packages_map.keys().collect();
+    package_names.sort();
     let mut package_extensions = Vec::new();
-
-    if let Some(package_paths) = &config.project.packages {
-        // ... (Package loading logic remains same as it reads external files)
-        for package_path_str in package_paths {
-            if debug {
-                println!("included packages {}", package_path_str);
-            }
-
-            let package_path = if PathBuf::from(package_path_str).is_absolute() {
-                PathBuf::from(package_path_str)
-            } else {
-                config_dir.join(package_path_str)
-            };
-
-            let src = fs::read_to_string(&package_path)
-                .with_context(|| format!("Failed to read package file: {}", package_path_str))?;
-
-            let (rest, program) = parser::parse_program(&src).map_err(|e| {
-                anyhow::anyhow!(
-                    "In package '{}': {}",
-                    package_path_str,
-                    format_parse_error(e, &src)
-                )
-            })?;
-
-            if !rest.trim().is_empty() {
-                println!(
-                    "{}",
-                    format!(
-                        "Warning: Package {} parsing stopped early. Remaining: {:.50}...",
-                        package_path_str, rest
-                    )
-                    .yellow()
-                );
-            }
-
-            let mut package_def = None;
-            let mut items = Vec::new();
-
-            for item in program.items {
-                if let ast::Item::Package(pkg) = item {
-                    if package_def.is_some() {
-                        return Err(anyhow::anyhow!(
-                            "Package file '{}' contains multiple package declarations",
-                            package_path_str
-                        ));
-                    }
-                    package_def = Some(pkg);
-                } else {
-                    items.push(item);
-                }
-            }
-
-            if let Some(mut pkg) = package_def {
-                package_extensions.extend(pkg.extensions.clone());
-                pkg.items = items;
-                packages_map.insert(pkg.name.clone(), pkg);
-            } else {
-                return Err(anyhow::anyhow!(
-                    "Package file '{}' must contain a package declaration",
-                    package_path_str
-                ));
+    for name in package_names {
+        let pkg = &packages_map[name];
+        if debug {
+            match &pkg.version {
+                Some(version) => println!("included package {} {}", pkg.name, version),
+                None => println!("included package {}", pkg.name),
             }
         }
+        package_extensions.extend(pkg.extensions.clone());
     }
 
     let mut targets = Vec::new();
     let mut assets_to_pack = Vec::new();
 
     // Compile Stage
-    let stage_path = if config.stage.path.is_absolute() {
-        config.stage.path.clone()
-    } else {
-        config_dir.join(&config.stage.path)
-    };
-
-    // Use generated source if available, otherwise read from file (fallback)
-    let stage_src = if let Some(src) = generated_files.get(&stage_path) {
-        src.clone()
-    } else {
-        fs::read_to_string(&stage_path)?
-    };
+    let shared_files = source::shared_files(&config, config_dir)?;
+    let stage_files = source::target_files(&config.stage, "[stage]", config_dir)?;
+
+    // Use generated source if available, otherwise read from file (fallback).
+    // Generated source has already been transformed.
+    let (mut stage_ast, stage_origins, stage_needs_transform) =
+        match generated_files.get(&stage_files[0]) {
+            Some((stage_src, origins)) => {
+                let (rest, ast) = parser::parse_program(stage_src)
+                    .map_err(|e| anyhow::anyhow!("{}", format_parse_error(e, stage_src)))?;
+                if !rest.trim().is_empty() {
+                    println!(
+                        "{}",
+                        format!(
+                            "Warning: Stage parsing stopped early. Remaining: {:.50}...",
+                            rest
+                        )
+                        .yellow()
+                    );
+                }
+                (ast, origins.clone(), false)
+            }
+            None => {
+                let (ast, origins) =
+                    source::load_target(&shared_files, &stage_files).context("In stage")?;
+                (ast, origins, true)
+            }
+        };
 
-    let (rest, mut stage_ast) = parser::parse_program(&stage_src)
-        .map_err(|e| anyhow::anyhow!("{}", format_parse_error(e, &stage_src)))?;
-    if !rest.trim().is_empty() {
-        println!(
-            "{}",
-            format!(
-                "Warning: Stage parsing stopped early. Remaining: {:.50}...",
-                rest
-            )
-            .yellow()
-        );
+    if stage_needs_transform {
+        transform::transform_program(&mut stage_ast, &packages_map, &stage_options)
+            .map_err(|e| stage_origins.locate(e))
+            .context("In stage")?;
     }
 
-    transform::transform_program(&mut stage_ast, &packages_map);
-
     // Pre-load sprites to extract public variables
     let mut sprite_data = Vec::new();
     if let Some(sprites) = &config.sprite {
@@ -309,40 +287,55 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
         );
         for sprite in sprites {
             println!("{}", format!("Processing sprite: {:?}", sprite.name).cyan());
-            let sprite_path = if sprite.path.is_absolute() {
-                sprite.path.clone()
-            } else {
-                config_dir.join(&sprite.path)
-            };
+            let label = format!("Sprite '{}'", sprite.name.as_deref().unwrap_or("unknown"));
+            let sprite_files = source::target_files(sprite, &label, config_dir)?;
 
             // Use generated source if available
-            let src = if let Some(s) = generated_files.get(&sprite_path) {
-                s.clone()
-            } else {
-                fs::read_to_string(&sprite_path)?
+            let (mut ast, origins, needs_transform) = match generated_files.get(&sprite_files[0]) {
+                Some((src, origins)) => {
+                    let (rest, ast) = parser::parse_program(src).map_err(|e| {
+                        anyhow::anyhow!(
+                            "In sprite '{}': {}",
+                            sprite.name.as_deref().unwrap_or("unknown"),
+                            format_parse_error(e, src)
+                        )
+                    })?;
+                    if !rest.trim().is_empty() {
+                        println!(
+                            "{}",
+                            format!(
+                                "Warning: Sprite {} parsing stopped early. Remaining: {:.50}...",
+                                sprite.name.as_deref().unwrap_or("unknown"),
+                                rest
+                            )
+                            .yellow()
+                        );
+                    }
+                    (ast, origins.clone(), false)
+                }
+                None => {
+                    let (ast, origins) = source::load_target(&shared_files, &sprite_files)
+                        .with_context(|| {
+                            format!(
+                                "In sprite '{}'",
+                                sprite.name.as_deref().unwrap_or("unknown")
+                            )
+                        })?;
+                    (ast, origins, true)
+                }
             };
 
-            let (rest, mut ast) = parser::parse_program(&src).map_err(|e| {
-                anyhow::anyhow!(
-                    "In sprite '{}': {}",
-                    sprite.name.as_deref().unwrap_or("unknown"),
-                    format_parse_error(e, &src)
-                )
-            })?;
-            if !rest.trim().is_empty() {
-                println!(
-                    "{}",
-                    format!(
-                        "Warning: Sprite {} parsing stopped early. Remaining: {:.50}...",
-                        sprite.name.as_deref().unwrap_or("unknown"),
-                        rest
-                    )
-                    .yellow()
-                );
+            if needs_transform {
+                transform::transform_program(&mut ast, &packages_map, &options)
+                    .map_err(|e| origins.locate(e))
+                    .with_context(|| {
+                        format!(
+                            "In sprite '{}'",
+                            sprite.name.as_deref().unwrap_or("unknown")
+                        )
+                    })?;
             }
 
-            transform::transform_program(&mut ast, &packages_map);
-
             // Extract public variables and add to stage_ast
             for item in &ast.items {
                 if let ast::Item::Variable(decl) = item {
@@ -361,10 +354,30 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
                     }
                 }
             }
-            sprite_data.push((sprite, ast));
+            // Stage variables are visible to every sprite, so a package the
+            // stage also uses shares the stage's copy of its data
+            ast.items.retain(|item| match item {
+                ast::Item::Variable(decl) if decl.name.contains("::") => !stage_ast
+                    .items
+                    .iter()
+                    .any(|i| matches!(i, ast::Item::Variable(v) if v.name == decl.name)),
+                _ => true,
+            });
+            sprite_data.push((sprite, ast, origins));
         }
     }
 
+    // Remove unreachable code and unused declarations across all targets
+    {
+        let mut targets = all_targets(
+            &mut stage_ast,
+            sprite_data
+                .iter_mut()
+                .map(|(sprite, ast, _)| (*sprite, ast)),
+        );
+        optimize::eliminate_dead_code(&mut targets, debug);
+    }
+
     // Load extensions
     // We assume the extensions folder is in the current working directory (repo root)
     // or relative to where the compiler is expected to find them.
@@ -397,7 +410,7 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
         );
     }
 
-    let (stage_target, stage_assets) = compiler::compile_target(
+    let (stage_target, stage_assets, stage_shared) = compiler::compile_target(
         &stage_ast,
         true,
         None,
@@ -405,19 +418,21 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
         config_dir,
         &extensions,
         &packages_map,
+        &stage_origins,
         debug,
     )?;
 
     for (path, filename) in stage_assets {
         assets_to_pack.push((path, filename));
     }
+    report_shared("Stage", stage_shared);
 
     let global_vars = stage_target.variables.clone();
     let global_lists = stage_target.lists.clone();
     targets.push(stage_target);
 
-    for (sprite, ast) in sprite_data {
-        let (mut target, sprite_assets) = compiler::compile_target(
+    for (sprite, ast, origins) in sprite_data {
+        let (mut target, sprite_assets, sprite_shared) = compiler::compile_target(
             &ast,
             false,
             Some(&global_vars),
@@ -425,9 +440,11 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
             config_dir,
             &extensions,
             &packages_map,
+            &origins,
             debug,
         )?;
         target.name = sprite.name.clone().unwrap_or("Sprite".to_string());
+        report_shared(&target.name, sprite_shared);
 
         for (path, filename) in sprite_assets {
             assets_to_pack.push((path, filename));
@@ -522,9 +539,12 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
     zip.start_file("project.json", options)?;
     serde_json::to_writer(&mut zip, &project)?;
 
-    for (path, filename) in assets_to_pack {
-        let content =
-            fs::read(&path).context(format!("Failed to read asset {}", format_path(&path)))?;
+    // Assets are named by content hash, so identical files only need one entry
+    let mut packed = std::collections::HashSet::new();
+    for (content, filename) in assets_to_pack {
+        if !packed.insert(filename.clone()) {
+            continue;
+        }
         zip.start_file(filename, options)?;
         zip.write_all(&content)?;
     }
@@ -540,6 +560,19 @@ fn build(config_path: PathBuf, debug: bool) -> Result<()> {
     Ok(())
 }
 
+fn report_shared(target: &str, usage: compiler::SharedUsage) {
+    if usage.procedures > 0 {
+        println!(
+            "{}",
+            format!(
+                "{}: {} shared procedures added {} blocks",
+                target, usage.procedures, usage.blocks
+            )
+            .cyan()
+        );
+    }
+}
+
 fn create(name: String) -> Result<()> {
     let root = PathBuf::from(&name);
     if root.exists() {
@@ -563,6 +596,7 @@ fn create(name: String) -> Result<()> {
         r#"[project]
 name = "{}"
 output = "dist/project.sb3"
+reset_on_flag = true
 
 [stage]
 path = "src/stage.sr"
@@ -0,0 +1,494 @@
+use crate::ast::*;
+use crate::visit::{rewrite_stmts, walk_stmts};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Lowers typed messages, declared as `message Hit(damage: number);`, into
+/// broadcasts. `targets` holds each target's name and program, Stage first,
+/// before they are transformed.
+///
+/// `broadcast Hit(5);` adds the values to queues, one public list per
+/// parameter, and moves on. A script on the stage empties the queues when
+/// the flag is clicked, so values left from an earlier run are dropped. It
+/// then takes them off the queues in the order they were sent, stores them
+/// in globals and broadcasts `Hit` with `broadcast_and_wait`. Every handler
+/// has started before the next values are stored, so messages sent in the
+/// same frame are all delivered. A handler, `#[on_message(Hit)] fn
+/// on_hit(damage: number)`, becomes a script for `Hit` that passes the
+/// stored values to a procedure holding its body, so they stay the same
+/// while it waits.
+///
+/// Waiting for the handlers also means a message is only delivered once
+/// every handler of the one before has finished, so a handler can't loop
+/// `forever`.
+///
+/// Messages without parameters have nothing to queue and are plain
+/// broadcasts.
+pub fn lower_messages(targets: &mut [(String, &mut Program)]) -> Result<()> {
+    // Shared files declare their messages in every target, so the same
+    // declaration may appear several times
+    let mut messages: BTreeMap<String, (MessageDef, String)> = BTreeMap::new();
+    for (target, program) in targets.iter_mut() {
+        let mut declared = Vec::new();
+        program.items.retain(|item| match item {
+            Item::Message(def) => {
+                declared.push(def.clone());
+                false
+            }
+            _ => true,
+        });
+        for def in declared {
+            match messages.get(&def.name) {
+                Some((first, other)) if !same_params(&first.params, &def.params) => bail!(
+                    "The message `{}` is declared differently in {} and {}",
+                    def.name,
+                    other,
+                    target
+                ),
+                Some(_) => {}
+                None => {
+                    check_params(&def)?;
+                    messages.insert(def.name.clone(), (def, target.clone()));
+                }
+            }
+        }
+    }
+
+    let mut sent = BTreeSet::new();
+    for (_, program) in targets.iter_mut() {
+        let items = std::mem::take(&mut program.items);
+        for item in items {
+            match item {
+                Item::Procedure(mut proc) => {
+                    proc.body = lower_sends(proc.body, &messages, &mut sent)?;
+                    program.items.push(Item::Procedure(proc));
+                }
+                Item::Function(mut func) => {
+                    func.body = lower_sends(func.body, &messages, &mut sent)?;
+                    match handled_message(&func)? {
+                        Some(name) => {
+                            let Some((def, _)) = messages.get(&name) else {
+                                bail!(
+                                    "`{}` handles `{}`, which isn't a declared message. Declare it with `message {}(..);`",
+                                    func.name,
+                                    name,
+                                    name
+                                );
+                            };
+                            program.items.extend(handler(func, def)?);
+                        }
+                        None => program.items.push(Item::Function(func)),
+                    }
+                }
+                Item::Stmt(stmt) => {
+                    for stmt in lower_sends(vec![stmt], &messages, &mut sent)? {
+                        program.items.push(Item::Stmt(stmt));
+                    }
+                }
+                item => program.items.push(item),
+            }
+        }
+    }
+
+    let stage = &mut targets[0].1;
+    for name in sent {
+        let def = &messages[&name].0;
+        if !def.params.is_empty() {
+            stage.items.extend(dispatcher(def));
+        }
+    }
+    Ok(())
+}
+
+fn same_params(a: &[Param], b: &[Param]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.name == b.name && a.ty == b.ty)
+}
+
+/// Only values that fit in a Scratch variable can be sent.
+fn check_params(def: &MessageDef) -> Result<()> {
+    for param in &def.params {
+        if !matches!(
+            param.ty,
+            Type::Number | Type::String | Type::Boolean | Type::Unknown
+        ) {
+            bail!(
+                "The message `{}` can't carry `{}`, because it isn't a number, string or boolean",
+                def.name,
+                param.name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn queue(def: &MessageDef, param: &Param) -> String {
+    format!("_msg_{}_queue_{}", def.name, param.name)
+}
+
+fn value(def: &MessageDef, param: &Param) -> String {
+    format!("_msg_{}_{}", def.name, param.name)
+}
+
+/// Rewrites `broadcast Hit(..);` into pushes onto the queues of `Hit`.
+fn lower_sends(
+    stmts: Vec<Stmt>,
+    messages: &BTreeMap<String, (MessageDef, String)>,
+    sent: &mut BTreeSet<String>,
+) -> Result<Vec<Stmt>> {
+    rewrite_stmts(stmts, &mut |stmt| {
+        let Stmt::Expr(Expr::Call(broadcast, args), comment) = &stmt else {
+            return Ok(vec![stmt]);
+        };
+        // Anything else is a message name computed by a reporter
+        let (def, values) = match &args[..] {
+            [Expr::Call(name, values)] if broadcast == "broadcast" => match messages.get(name) {
+                Some((def, _)) => (def, values),
+                None => return Ok(vec![stmt]),
+            },
+            _ => return Ok(vec![stmt]),
+        };
+        if values.iter().any(|v| matches!(v, Expr::NamedArg(..))) {
+            bail!(
+                "The values of `{}` are passed in order, without names",
+                def.name
+            );
+        }
+        if values.len() != def.params.len() {
+            let params: Vec<&str> = def.params.iter().map(|p| p.name.as_str()).collect();
+            bail!(
+                "`{}` carries {} value(s) ({}), but {} were given",
+                def.name,
+                def.params.len(),
+                params.join(", "),
+                values.len()
+            );
+        }
+        sent.insert(def.name.clone());
+
+        if def.params.is_empty() {
+            return Ok(vec![Stmt::Expr(
+                Expr::Call(
+                    "broadcast".to_string(),
+                    vec![Expr::String(def.name.clone())],
+                ),
+                comment.clone(),
+            )]);
+        }
+        let mut out: Vec<Stmt> = def
+            .params
+            .iter()
+            .zip(values)
+            .map(|(param, value)| {
+                Stmt::Expr(
+                    Expr::Call(
+                        "add_to_list".to_string(),
+                        vec![Expr::Variable(queue(def, param)), value.clone()],
+                    ),
+                    None,
+                )
+            })
+            .collect();
+        if let Some(Stmt::Expr(_, c)) = out.first_mut() {
+            *c = comment.clone();
+        }
+        Ok(out)
+    })
+}
+
+/// The message named in a function's `#[on_message(..)]` attribute.
+fn handled_message(func: &Function) -> Result<Option<String>> {
+    let Some(attr) = func.attributes.iter().find(|a| a.name == "on_message") else {
+        return Ok(None);
+    };
+    match &attr.args[..] {
+        [Expr::Variable(name)] | [Expr::String(name)] => Ok(Some(name.clone())),
+        _ => bail!(
+            "`{}` needs the message it handles, e.g. `#[on_message(Hit)]`",
+            func.name
+        ),
+    }
+}
+
+/// The script a handler runs as, and the procedure holding its body.
+fn handler(func: Function, def: &MessageDef) -> Result<Vec<Item>> {
+    let matches = func.params.len() == def.params.len()
+        && func
+            .params
+            .iter()
+            .zip(&def.params)
+            .all(|(a, b)| a.ty == b.ty || a.ty == Type::Unknown || b.ty == Type::Unknown);
+    if !matches {
+        let params: Vec<String> = def
+            .params
+            .iter()
+            .map(|p| format!("{}: {}", p.name, type_name(&p.ty)))
+            .collect();
+        bail!(
+            "`{}` handles `{}`, so it must take its values: `fn {}({})`",
+            func.name,
+            def.name,
+            func.name,
+            params.join(", ")
+        );
+    }
+
+    let mut attributes: Vec<Attribute> = func
+        .attributes
+        .iter()
+        .filter(|a| a.name != "on_message")
+        .cloned()
+        .collect();
+    attributes.insert(
+        0,
+        Attribute {
+            name: "on_broadcast_received".to_string(),
+            args: vec![Expr::String(def.name.clone())],
+        },
+    );
+    if def.params.is_empty() {
+        return Ok(vec![Item::Function(Function { attributes, ..func })]);
+    }
+    if runs_forever(&func.body) {
+        bail!(
+            "`{}` handles `{}` but loops `forever`. Messages with values are delivered one at a time, once the handlers of the one before have finished, so no later `{}` would arrive",
+            func.name,
+            def.name,
+            def.name
+        );
+    }
+
+    let body_name = format!("_msg_{}", func.name);
+    let call = Expr::Call(
+        body_name.clone(),
+        def.params
+            .iter()
+            .map(|p| Expr::Variable(value(def, p)))
+            .collect(),
+    );
+    Ok(vec![
+        Item::Procedure(ProcedureDef {
+            name: body_name,
+            attributes: vec![],
+            visibility: Visibility::Default,
+            params: func.params,
+            body: func.body,
+            return_type: None,
+            is_warp: func.is_warp,
+            comment: None,
+        }),
+        Item::Function(Function {
+            name: func.name,
+            attributes: attributes
+                .into_iter()
+                .filter(|a| a.name != "warp" && a.name != "nowarp")
+                .collect(),
+            params: vec![],
+            body: vec![Stmt::Expr(call, None)],
+            is_warp: false,
+            comment: func.comment,
+        }),
+    ])
+}
+
+fn runs_forever(stmts: &[Stmt]) -> bool {
+    let mut found = false;
+    walk_stmts(stmts, &mut |stmt| {
+        found |= matches!(stmt, Stmt::Forever(..))
+    });
+    found
+}
+
+fn type_name(ty: &Type) -> &'static str {
+    match ty {
+        Type::Number => "number",
+        Type::String => "string",
+        Type::Boolean => "boolean",
+        _ => "unknown",
+    }
+}
+
+/// The stage's queues and values for a message, and the script delivering
+/// them.
+fn dispatcher(def: &MessageDef) -> Vec<Item> {
+    let global = |name: String, ty: Type, init: Expr| {
+        Item::Variable(VariableDecl {
+            name,
+            attributes: vec![],
+            ty,
+            init,
+            visibility: Visibility::Public,
+            comment: None,
+        })
+    };
+    let mut items = Vec::new();
+    let mut body = Vec::new();
+    let mut deliver = Vec::new();
+    for param in &def.params {
+        items.push(global(queue(def, param), Type::List, Expr::List(vec![])));
+        body.push(Stmt::Expr(
+            Expr::Call(
+                "delete_all_of_list".to_string(),
+                vec![Expr::Variable(queue(def, param))],
+            ),
+            None,
+        ));
+        items.push(global(value(def, param), Type::Unknown, Expr::Number(0.0)));
+        deliver.push(Stmt::Assign(
+            value(def, param),
+            Expr::Call(
+                "item_of_list".to_string(),
+                vec![Expr::Variable(queue(def, param)), Expr::Number(1.0)],
+            ),
+            None,
+        ));
+        deliver.push(Stmt::Expr(
+            Expr::Call(
+                "delete_of_list".to_string(),
+                vec![Expr::Variable(queue(def, param)), Expr::Number(1.0)],
+            ),
+            None,
+        ));
+    }
+    deliver.insert(
+        0,
+        Stmt::Expr(
+            Expr::Call(
+                "wait_until".to_string(),
+                vec![Expr::BinOp(
+                    Box::new(Expr::Call(
+                        "length_of_list".to_string(),
+                        vec![Expr::Variable(queue(def, &def.params[0]))],
+                    )),
+                    Op::Gt,
+                    Box::new(Expr::Number(0.0)),
+                )],
+            ),
+            None,
+        ),
+    );
+    deliver.push(Stmt::Expr(
+        Expr::Call(
+            "broadcast_and_wait".to_string(),
+            vec![Expr::String(def.name.clone())],
+        ),
+        None,
+    ));
+    body.push(Stmt::Forever(deliver, None));
+    items.push(Item::Function(Function {
+        name: format!("_msg_{}", def.name),
+        attributes: vec![Attribute {
+            name: "on_flag_clicked".to_string(),
+            args: vec![],
+        }],
+        params: vec![],
+        body,
+        is_warp: false,
+        comment: None,
+    }));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{lower_targets, squashed_source};
+
+    fn lower(sources: &[(&str, &str)]) -> Result<Vec<String>> {
+        let programs = lower_targets(sources, lower_messages)?;
+        Ok(programs.iter().map(squashed_source).collect())
+    }
+
+    #[test]
+    fn test_messages() {
+        let message = "message Hit(damage: number, from: string);\nmessage Ping();\n";
+        let out = lower(&[
+            ("Stage", message),
+            (
+                "Enemy",
+                &format!(
+                    "{}#[on_flag_clicked]\nfn attack() {{ broadcast Hit(5, \"enemy\"); broadcast Ping(); broadcast(\"plain\"); }}",
+                    message
+                ),
+            ),
+            (
+                "Player",
+                &format!(
+                    "{}#[on_message(Hit)]\nfn on_hit(amount: number, from: string) {{ wait(1); say(amount); }}\n#[on_message(Ping)]\nfn pong() {{}}",
+                    message
+                ),
+            ),
+        ])
+        .unwrap();
+        let [stage, enemy, player] = &out[..] else {
+            panic!("Expected three targets");
+        };
+
+        assert!(enemy.contains(
+            "add_to_list(_msg_Hit_queue_damage, 5); add_to_list(_msg_Hit_queue_from, \"enemy\"); broadcast(\"Ping\"); broadcast(\"plain\");"
+        ));
+        assert!(!enemy.contains("message "));
+
+        // The handler's values are parameters, which stay put across waits
+        assert!(player
+            .contains("proc _msg_on_hit(amount: number, from: string) { wait(1); say(amount); }"));
+        assert!(player.contains(
+            "#[on_broadcast_received(\"Hit\")] fn on_hit() { _msg_on_hit(_msg_Hit_damage, _msg_Hit_from); }"
+        ));
+        assert!(player.contains("#[on_broadcast_received(\"Ping\")] fn pong() { }"));
+
+        assert!(stage.contains("public list _msg_Hit_queue_damage = [];"));
+        // Values left from an earlier run are dropped when the flag is clicked
+        assert!(stage.contains(
+            "#[on_flag_clicked] fn _msg_Hit() { delete_all_of_list(_msg_Hit_queue_damage); delete_all_of_list(_msg_Hit_queue_from); forever {"
+        ));
+        assert!(stage.contains("wait_until(length_of_list(_msg_Hit_queue_damage) > 0);"));
+        assert!(stage.contains(
+            "_msg_Hit_damage = item_of_list(_msg_Hit_queue_damage, 1); delete_of_list(_msg_Hit_queue_damage, 1);"
+        ));
+        assert!(stage.contains("broadcast_and_wait(\"Hit\");"));
+        assert!(!stage.contains("_msg_Ping"));
+    }
+
+    #[test]
+    fn test_message_errors() {
+        let err = |sources: &[(&str, &str)]| lower(sources).unwrap_err().to_string();
+        let hit = "message Hit(damage: number);\n";
+        assert_eq!(
+            err(&[("Stage", hit), ("Player", "message Hit(damage: string);")]),
+            "The message `Hit` is declared differently in Stage and Player"
+        );
+        assert_eq!(
+            err(&[(
+                "Stage",
+                &format!("{}#[on_flag_clicked]\nfn f() {{ broadcast Hit(); }}", hit)
+            )]),
+            "`Hit` carries 1 value(s) (damage), but 0 were given"
+        );
+        assert_eq!(
+            err(&[(
+                "Stage",
+                &format!("{}#[on_message(Hit)]\nfn f(a: string) {{}}", hit)
+            )]),
+            "`f` handles `Hit`, so it must take its values: `fn f(damage: number)`"
+        );
+        assert_eq!(
+            err(&[("Stage", "#[on_message(Hti)]\nfn f() {}")]),
+            "`f` handles `Hti`, which isn't a declared message. Declare it with `message Hti(..);`"
+        );
+        assert!(err(&[(
+            "Stage",
+            &format!(
+                "{}#[on_message(Hit)]\nfn f(d: number) {{ if d > 0 {{ forever {{ say(d); }} }} }}",
+                hit
+            )
+        )])
+        .starts_with("`f` handles `Hit` but loops `forever`."));
+        assert_eq!(
+            err(&[("Stage", "message Hit(items: list);")]),
+            "The message `Hit` can't carry `items`, because it isn't a number, string or boolean"
+        );
+    }
+}
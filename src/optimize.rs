@@ -0,0 +1,351 @@
+use crate::ast::*;
+use crate::visit::{rewrite_stmts, walk_stmts, walk_stmts_exprs};
+use colored::*;
+use std::collections::HashSet;
+
+/// Removes code that can never run or be observed from every target: `if`
+/// branches on a literal condition, statements after `forever` or a
+/// `stop("all")` / `stop("this script")`, broadcast handlers for messages
+/// nothing sends, broadcasts nothing receives, and variables and lists no
+/// code refers to. Items marked `#[keep]` stay, for things only used from
+/// the Scratch editor.
+///
+/// All targets are optimized together, because public variables and
+/// broadcasts are shared between them. `targets` holds each target's name
+/// and program, Stage first.
+pub fn eliminate_dead_code(targets: &mut [(String, &mut Program)], debug: bool) {
+    let mut removed = Vec::new();
+
+    for (name, program) in targets.iter_mut() {
+        let mut count = 0;
+        for item in &mut program.items {
+            let body = match item {
+                Item::Procedure(p) => &mut p.body,
+                Item::Function(f) => &mut f.body,
+                _ => continue,
+            };
+            *body = prune_stmts(std::mem::take(body), &mut count);
+        }
+        if count > 0 {
+            removed.push(format!("{}: {} unreachable statements", name, count));
+        }
+    }
+
+    // Handlers for messages nothing sends. A broadcast of a computed name
+    // could reach any handler, so then all of them stay.
+    let mut sent = HashSet::new();
+    let mut dynamic = false;
+    for (_, program) in targets.iter() {
+        for_each_body(program, &mut |stmts| {
+            walk_stmts_exprs(stmts, &mut |e| {
+                if let Some(message) = broadcast_message(e) {
+                    match message {
+                        Some(m) => {
+                            sent.insert(m.to_string());
+                        }
+                        None => dynamic = true,
+                    }
+                }
+            })
+        });
+    }
+    let mut received = HashSet::new();
+    for (name, program) in targets.iter_mut() {
+        program.items.retain(|item| {
+            let Item::Function(f) = item else {
+                return true;
+            };
+            let Some(message) = received_message(f) else {
+                return true;
+            };
+            if dynamic || sent.contains(message) || is_kept(&f.attributes) {
+                received.insert(message.to_string());
+                return true;
+            }
+            removed.push(format!("{}: handler `{}` for `{}`", name, f.name, message));
+            false
+        });
+    }
+
+    // Broadcasts nothing receives
+    for (name, program) in targets.iter_mut() {
+        let mut count = 0;
+        for item in &mut program.items {
+            let body = match item {
+                Item::Procedure(p) => &mut p.body,
+                Item::Function(f) => &mut f.body,
+                _ => continue,
+            };
+            let stmts = std::mem::take(body);
+            *body = rewrite_stmts(stmts, &mut |stmt| {
+                if let Stmt::Expr(e, _) = &stmt {
+                    if let Some(Some(message)) = broadcast_message(e) {
+                        if !received.contains(message) {
+                            count += 1;
+                            return Ok(vec![]);
+                        }
+                    }
+                }
+                Ok(vec![stmt])
+            })
+            .unwrap();
+        }
+        if count > 0 {
+            removed.push(format!("{}: {} broadcasts nobody receives", name, count));
+        }
+    }
+
+    // Variables and lists. Any string literal may name one (`add_to_list`,
+    // `set_variable`, ...), so those count as references everywhere.
+    let mut strings = HashSet::new();
+    let mut names_by_target = Vec::new();
+    for (_, program) in targets.iter() {
+        let mut names = HashSet::new();
+        for_each_body(program, &mut |stmts| {
+            collect_references(stmts, &mut names, &mut strings)
+        });
+        names_by_target.push(names);
+    }
+    let all_names: HashSet<&String> = names_by_target.iter().flatten().collect();
+    for (i, ((name, program), names)) in targets.iter_mut().zip(&names_by_target).enumerate() {
+        program.items.retain(|item| {
+            let Item::Variable(decl) = item else {
+                return true;
+            };
+            // Stage variables are public, so any sprite may use them
+            let used = if decl.visibility == Visibility::Public || i == 0 {
+                all_names.contains(&decl.name)
+            } else {
+                names.contains(&decl.name)
+            };
+            if used || strings.contains(&decl.name) || is_kept(&decl.attributes) {
+                return true;
+            }
+            let kind = match decl.ty {
+                Type::List | Type::StructList(_) => "list",
+                _ => "variable",
+            };
+            removed.push(format!("{}: {} `{}`", name, kind, decl.name));
+            false
+        });
+    }
+
+    // The compiler reads a script's first attribute as its hat block
+    for (_, program) in targets.iter_mut() {
+        for item in &mut program.items {
+            if let Item::Function(f) = item {
+                f.attributes.retain(|a| a.name != "keep");
+            }
+        }
+    }
+
+    if debug {
+        println!("{}", "===== DeadCode =====".magenta().bold());
+        println!(
+            "{}",
+            format!("Removed ({}) → [{}]", removed.len(), removed.join(", ")).magenta()
+        );
+    }
+}
+
+fn is_kept(attributes: &[Attribute]) -> bool {
+    attributes.iter().any(|a| a.name == "keep")
+}
+
+fn for_each_body(program: &Program, f: &mut dyn FnMut(&[Stmt])) {
+    for item in &program.items {
+        match item {
+            Item::Procedure(p) => f(&p.body),
+            Item::Function(func) => f(&func.body),
+            Item::Stmt(stmt) => f(std::slice::from_ref(stmt)),
+            _ => {}
+        }
+    }
+}
+
+/// For a `broadcast` or `broadcast_and_wait` call, the message if it is a
+/// literal.
+fn broadcast_message(expr: &Expr) -> Option<Option<&str>> {
+    match expr {
+        Expr::Call(name, args) if name == "broadcast" || name == "broadcast_and_wait" => {
+            match args.first() {
+                Some(Expr::String(m)) => Some(Some(m)),
+                _ => Some(None),
+            }
+        }
+        _ => None,
+    }
+}
+
+fn received_message(func: &Function) -> Option<&str> {
+    let attr = func
+        .attributes
+        .iter()
+        .find(|a| a.name == "on_broadcast_received")?;
+    match attr.args.first() {
+        Some(Expr::String(m)) => Some(m),
+        _ => None,
+    }
+}
+
+fn collect_references(stmts: &[Stmt], names: &mut HashSet<String>, strings: &mut HashSet<String>) {
+    walk_stmts_exprs(stmts, &mut |e| match e {
+        Expr::Variable(v) => {
+            names.insert(v.clone());
+        }
+        Expr::String(s) => {
+            strings.insert(s.clone());
+        }
+        _ => {}
+    });
+    walk_stmts(stmts, &mut |stmt| {
+        if let Stmt::Assign(name, _, _) = stmt {
+            names.insert(name.clone());
+        }
+    });
+}
+
+/// Drops `if` branches that can't run and statements after one that never
+/// finishes, counting the statements removed.
+fn prune_stmts(stmts: Vec<Stmt>, removed: &mut usize) -> Vec<Stmt> {
+    let mut out = Vec::new();
+    let mut stmts = stmts.into_iter();
+    while let Some(stmt) = stmts.next() {
+        let stmt = match stmt {
+            Stmt::If(Expr::Bool(cond), then, otherwise, _) => {
+                let (taken, skipped) = if cond {
+                    (Some(then), otherwise)
+                } else {
+                    (otherwise, Some(then))
+                };
+                *removed += skipped.map_or(0, |s| s.len()) + 1;
+                // The taken branch is spliced in and pruned with the rest
+                let rest: Vec<Stmt> = taken.into_iter().flatten().chain(stmts).collect();
+                out.extend(prune_stmts(rest, removed));
+                return out;
+            }
+            Stmt::Until(Expr::Bool(true), body, _) => {
+                *removed += body.len() + 1;
+                continue;
+            }
+            Stmt::If(c, t, e, comment) => Stmt::If(
+                c,
+                prune_stmts(t, removed),
+                e.map(|e| prune_stmts(e, removed)),
+                comment,
+            ),
+            Stmt::Repeat(c, b, comment) => Stmt::Repeat(c, prune_stmts(b, removed), comment),
+            Stmt::Forever(b, comment) => Stmt::Forever(prune_stmts(b, removed), comment),
+            Stmt::Until(c, b, comment) => Stmt::Until(c, prune_stmts(b, removed), comment),
+            Stmt::Match(e, arms, default, comment) => Stmt::Match(
+                e,
+                arms.into_iter()
+                    .map(|arm| MatchArm {
+                        body: prune_stmts(arm.body, removed),
+                        ..arm
+                    })
+                    .collect(),
+                default.map(|d| prune_stmts(d, removed)),
+                comment,
+            ),
+            Stmt::CBlock(n, args, b, comment) => {
+                Stmt::CBlock(n, args, prune_stmts(b, removed), comment)
+            }
+            other => other,
+        };
+        let ends_script = match &stmt {
+            Stmt::Forever(..) => true,
+            Stmt::Expr(Expr::Call(name, args), _) if name == "stop" => matches!(
+                args.first(),
+                Some(Expr::String(option)) if option == "all" || option == "this script"
+            ),
+            _ => false,
+        };
+        out.push(stmt);
+        if ends_script {
+            *removed += stmts.count();
+            break;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+
+    fn optimize(sources: &[&str]) -> Vec<Program> {
+        let mut programs: Vec<Program> = sources
+            .iter()
+            .map(|src| parse_program(src).unwrap().1)
+            .collect();
+        let mut targets: Vec<(String, &mut Program)> = programs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, p)| (if i == 0 { "Stage" } else { "Sprite" }.to_string(), p))
+            .collect();
+        eliminate_dead_code(&mut targets, false);
+        programs
+    }
+
+    fn item_names(program: &Program) -> Vec<&str> {
+        program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Variable(v) => Some(v.name.as_str()),
+                Item::Function(f) => Some(f.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_removes_dead_code() {
+        let programs = optimize(&[
+            "public var score = 0;\npublic var unused = 0;\n",
+            "var hp = 3;\n\
+             var spare = 1;\n\
+             #[keep]\nvar shown = 0;\n\
+             list log = [];\n\
+             #[on_flag_clicked]\n\
+             fn start() {\n\
+                 if false { say(1); } else { say(2); }\n\
+                 add_to_list(\"log\", hp);\n\
+                 score = 1;\n\
+                 broadcast(\"go\");\n\
+                 broadcast(\"nobody\");\n\
+                 forever { wait(1); }\n\
+                 say(\"never\");\n\
+             }\n\
+             #[on_broadcast_received(\"go\")]\nfn go() { stop(\"this script\"); say(3); }\n\
+             #[keep]\n#[on_broadcast_received(\"debug\")]\nfn debug() {}\n\
+             #[on_broadcast_received(\"never sent\")]\nfn never() {}\n",
+        ]);
+        assert_eq!(item_names(&programs[0]), vec!["score"]);
+        assert_eq!(
+            item_names(&programs[1]),
+            vec!["hp", "shown", "log", "start", "go", "debug"]
+        );
+
+        let Some(Item::Function(start)) = programs[1]
+            .items
+            .iter()
+            .find(|i| matches!(i, Item::Function(f) if f.name == "start"))
+        else {
+            panic!("Expected start");
+        };
+        assert_eq!(start.body.len(), 5);
+        assert!(
+            matches!(&start.body[0], Stmt::Expr(Expr::Call(_, args), _) if matches!(args[0], Expr::Number(n) if n == 2.0))
+        );
+        assert!(matches!(start.body.last(), Some(Stmt::Forever(..))));
+
+        // `#[keep]` is dropped so the hat is the first attribute again
+        let Some(Item::Function(debug)) = programs[1].items.last() else {
+            panic!("Expected debug");
+        };
+        assert_eq!(debug.attributes[0].name, "on_broadcast_received");
+    }
+}
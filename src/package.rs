@@ -0,0 +1,702 @@
+use crate::ast::{Item, Package};
+use crate::config::{DependencySpec, ScrustConfig};
+use crate::parser;
+use anyhow::{bail, Context, Result};
+use colored::*;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "package.toml";
+pub const LOCK_FILE: &str = "scrust.lock";
+
+/// Upper bound on the versions tried while resolving, so requirements that
+/// can't be met fail instead of trying every combination.
+const MAX_RESOLVE_STEPS: usize = 10_000;
+
+/// A package's `package.toml`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub package: ManifestPackage,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, DependencySpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ManifestPackage {
+    pub name: String,
+    pub version: String,
+    /// Source files, relative to the manifest. Their items are merged.
+    #[serde(default = "default_entry")]
+    pub entry: Vec<PathBuf>,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+}
+
+fn default_entry() -> Vec<PathBuf> {
+    vec![PathBuf::from("lib.sr")]
+}
+
+/// `scrust.lock`: the versions picked by the last resolution.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct LockFile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: String,
+    hash: String,
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Path(PathBuf),
+    Cache,
+}
+
+struct Requirement {
+    name: String,
+    req: VersionReq,
+    source: Source,
+    by: String,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    manifest: Manifest,
+    version: Version,
+    dir: PathBuf,
+    source: Source,
+}
+
+/// Loads every package the project uses: single `.sr` files and package
+/// directories listed in `project.packages`, and the `[dependencies]` of
+/// `scrust.toml` with everything they depend on. Versions are resolved
+/// against path dependencies and the local package cache, and recorded in
+/// `scrust.lock`.
+pub fn load_packages(config: &ScrustConfig, config_dir: &Path) -> Result<HashMap<String, Package>> {
+    let mut packages = HashMap::new();
+    let mut root = Vec::new();
+
+    for package_path_str in config.project.packages.iter().flatten() {
+        let package_path = config_dir.join(package_path_str);
+        if package_path.join(MANIFEST_FILE).is_file() {
+            let candidate = load_candidate(&package_path, Source::Path(package_path.clone()))?;
+            root.push(Requirement {
+                name: candidate.manifest.package.name.clone(),
+                req: VersionReq::STAR,
+                source: candidate.source,
+                by: "scrust.toml".to_string(),
+            });
+            continue;
+        }
+        let pkg = load_file_package(&package_path, package_path_str)?;
+        packages.insert(pkg.name.clone(), pkg);
+    }
+    for (name, spec) in config.dependencies.iter().flatten() {
+        root.push(requirement(name, spec, config_dir, "scrust.toml")?);
+    }
+    if root.is_empty() {
+        return Ok(packages);
+    }
+
+    let cache = package_cache(config, config_dir);
+    let lock_path = config_dir.join(LOCK_FILE);
+    let lock: LockFile = match fs::read_to_string(&lock_path) {
+        Ok(text) => toml::from_str(&text)
+            .with_context(|| format!("Invalid lock file: {}", lock_path.display()))?,
+        Err(_) => LockFile::default(),
+    };
+
+    let resolved = resolve(root, &cache, &lock)?;
+    let mut new_lock = LockFile::default();
+    for candidate in &resolved {
+        let name = &candidate.manifest.package.name;
+        if packages.contains_key(name) {
+            bail!(
+                "Package `{}` is listed both as a file in `packages` and as a dependency",
+                name
+            );
+        }
+        let hash = content_hash(candidate)?;
+        let source = match &candidate.source {
+            Source::Path(p) => {
+                format!("path+{}", p.strip_prefix(config_dir).unwrap_or(p).display())
+            }
+            Source::Cache => "cache".to_string(),
+        };
+        // A cached version must never change; a path package is being edited
+        if let Some(old) = lock.package.iter().find(|l| {
+            &l.name == name && l.version == candidate.version.to_string() && l.source == source
+        }) {
+            if candidate.source == Source::Cache && old.hash != hash {
+                bail!(
+                    "Package `{}` {} in the package cache ({}) changed since {} was written. \
+                     If this is expected, remove its entry from {}",
+                    name,
+                    candidate.version,
+                    candidate.dir.display(),
+                    LOCK_FILE,
+                    LOCK_FILE
+                );
+            }
+        }
+        let dependencies = candidate
+            .manifest
+            .dependencies
+            .keys()
+            .map(|dep| {
+                let version = resolved
+                    .iter()
+                    .find(|c| &c.manifest.package.name == dep)
+                    .map(|c| c.version.to_string())
+                    .unwrap_or_default();
+                format!("{} {}", dep, version)
+            })
+            .collect();
+        new_lock.package.push(LockedPackage {
+            name: name.clone(),
+            version: candidate.version.to_string(),
+            source,
+            hash,
+            dependencies,
+        });
+        packages.insert(name.clone(), load_manifest_package(candidate)?);
+    }
+
+    if new_lock != lock {
+        let text = format!(
+            "# Generated by scrust. Records the exact package versions used to build this project.\n{}",
+            toml::to_string(&new_lock)?
+        );
+        fs::write(&lock_path, text)
+            .with_context(|| format!("Failed to write {}", lock_path.display()))?;
+    }
+    Ok(packages)
+}
+
+/// `project.package_cache`, else `$SCRUST_PACKAGE_CACHE`, else
+/// `~/.scrust/packages`.
+fn package_cache(config: &ScrustConfig, config_dir: &Path) -> PathBuf {
+    if let Some(cache) = &config.project.package_cache {
+        return config_dir.join(cache);
+    }
+    if let Some(cache) = std::env::var_os("SCRUST_PACKAGE_CACHE") {
+        return PathBuf::from(cache);
+    }
+    match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home).join(".scrust").join("packages"),
+        None => config_dir.join(".scrust").join("packages"),
+    }
+}
+
+fn requirement(
+    name: &str,
+    spec: &DependencySpec,
+    base_dir: &Path,
+    by: &str,
+) -> Result<Requirement> {
+    let (version, path) = match spec {
+        DependencySpec::Version(v) => (Some(v), None),
+        DependencySpec::Detailed(d) => (d.version.as_ref(), d.path.as_ref()),
+    };
+    let req = match version {
+        Some(v) => VersionReq::parse(v).with_context(|| {
+            format!(
+                "Invalid version requirement `{}` for `{}` in {}",
+                v, name, by
+            )
+        })?,
+        None => VersionReq::STAR,
+    };
+    Ok(Requirement {
+        name: name.to_string(),
+        req,
+        source: match path {
+            Some(p) => Source::Path(base_dir.join(p)),
+            None => Source::Cache,
+        },
+        by: by.to_string(),
+    })
+}
+
+fn load_candidate(dir: &Path, source: Source) -> Result<Candidate> {
+    let path = dir.join(MANIFEST_FILE);
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read package manifest: {}", path.display()))?;
+    let manifest: Manifest = toml::from_str(&text)
+        .with_context(|| format!("Invalid package manifest: {}", path.display()))?;
+    let version = Version::parse(&manifest.package.version).with_context(|| {
+        format!(
+            "Invalid version `{}` in {}",
+            manifest.package.version,
+            path.display()
+        )
+    })?;
+    Ok(Candidate {
+        manifest,
+        version,
+        dir: dir.to_path_buf(),
+        source,
+    })
+}
+
+/// Every version of `name` in the cache, oldest first.
+fn cache_candidates(cache: &Path, name: &str) -> Result<Vec<Candidate>> {
+    let dir = cache.join(name);
+    let mut candidates = Vec::new();
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if !path.join(MANIFEST_FILE).is_file() {
+                continue;
+            }
+            let candidate = load_candidate(&path, Source::Cache)?;
+            if candidate.manifest.package.name != name {
+                bail!(
+                    "{} is in the cache folder for `{}` but is named `{}`",
+                    path.join(MANIFEST_FILE).display(),
+                    name,
+                    candidate.manifest.package.name
+                );
+            }
+            candidates.push(candidate);
+        }
+    }
+    candidates.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(candidates)
+}
+
+/// Requirements that can't all be met by the versions picked so far.
+/// Resolution backtracks on these, while other errors stop it.
+#[derive(Debug)]
+struct Conflict(String);
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+fn resolve(root: Vec<Requirement>, cache: &Path, lock: &LockFile) -> Result<Vec<Candidate>> {
+    let mut resolver = Resolver {
+        root: &root,
+        cache,
+        lock,
+        steps: 0,
+    };
+    let mut selected = BTreeMap::new();
+    resolver.search(&mut selected)?;
+    Ok(selected.into_values().collect())
+}
+
+struct Resolver<'a> {
+    root: &'a [Requirement],
+    cache: &'a Path,
+    lock: &'a LockFile,
+    steps: usize,
+}
+
+impl Resolver<'_> {
+    /// Picks a version for the first package that is required but not picked
+    /// yet, then resolves the rest with that pick. When the rest can't be
+    /// resolved the pick is undone and the next candidate is tried, so an
+    /// older version is used when a newer one needs something that can't be
+    /// met.
+    fn search(&mut self, selected: &mut BTreeMap<String, Candidate>) -> Result<()> {
+        let mut transitive = Vec::new();
+        for candidate in selected.values() {
+            let by = format!("{} {}", candidate.manifest.package.name, candidate.version);
+            for (name, spec) in &candidate.manifest.dependencies {
+                transitive.push(requirement(name, spec, &candidate.dir, &by)?);
+            }
+        }
+        let mut requirements: BTreeMap<String, Vec<&Requirement>> = BTreeMap::new();
+        for r in self.root.iter().chain(&transitive) {
+            requirements.entry(r.name.clone()).or_default().push(r);
+        }
+
+        // A later pick can add requirements on a package picked earlier
+        for (name, reqs) in &requirements {
+            let Some(picked) = selected.get(name) else {
+                continue;
+            };
+            if let Some(r) = reqs.iter().find(|r| !accepts(r, picked)) {
+                return Err(Conflict(format!(
+                    "{} requires {} {}, but {} {} was picked",
+                    r.by, name, r.req, name, picked.version
+                ))
+                .into());
+            }
+        }
+
+        let Some((name, reqs)) = requirements
+            .iter()
+            .find(|(name, _)| !selected.contains_key(*name))
+        else {
+            return Ok(());
+        };
+        let mut conflict = None;
+        for candidate in candidates(name, reqs, self.cache, self.lock)? {
+            self.steps += 1;
+            if self.steps > MAX_RESOLVE_STEPS {
+                bail!(
+                    "Gave up resolving package versions after trying {} of them",
+                    MAX_RESOLVE_STEPS
+                );
+            }
+            selected.insert(name.clone(), candidate);
+            match self.search(selected) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is::<Conflict>() => {
+                    conflict.get_or_insert(e);
+                }
+                Err(e) => return Err(e),
+            }
+            selected.remove(name);
+        }
+        Err(conflict.expect("candidates returns at least one version"))
+    }
+}
+
+/// Whether `picked` satisfies `r`, including coming from the path it names.
+fn accepts(r: &Requirement, picked: &Candidate) -> bool {
+    let same_source = match (&r.source, &picked.source) {
+        (Source::Path(want), Source::Path(got)) => {
+            fs::canonicalize(want).ok() == fs::canonicalize(got).ok()
+        }
+        (Source::Path(_), Source::Cache) => false,
+        (Source::Cache, _) => true,
+    };
+    same_source && r.req.matches(&picked.version)
+}
+
+/// The versions of `name` that every requirement accepts, in the order to try
+/// them: the locked one if it still fits, then newest first.
+fn candidates(
+    name: &str,
+    reqs: &[&Requirement],
+    cache: &Path,
+    lock: &LockFile,
+) -> Result<Vec<Candidate>> {
+    let paths: Vec<&PathBuf> = reqs
+        .iter()
+        .filter_map(|r| match &r.source {
+            Source::Path(p) => Some(p),
+            Source::Cache => None,
+        })
+        .collect();
+    let candidates = match paths.first() {
+        Some(first) => {
+            let same = |p: &PathBuf| fs::canonicalize(p).ok() == fs::canonicalize(first).ok();
+            if let Some(other) = paths.iter().find(|p| !same(p)) {
+                return Err(Conflict(format!(
+                    "`{}` is required from two different paths: {} and {}",
+                    name,
+                    first.display(),
+                    other.display()
+                ))
+                .into());
+            }
+            let candidate = load_candidate(first, Source::Path((*first).clone()))?;
+            if candidate.manifest.package.name != name {
+                bail!(
+                    "{} is named `{}`, not `{}`",
+                    first.join(MANIFEST_FILE).display(),
+                    candidate.manifest.package.name,
+                    name
+                );
+            }
+            vec![candidate]
+        }
+        None => cache_candidates(cache, name)?,
+    };
+
+    let mut matching: Vec<Candidate> = candidates
+        .iter()
+        .filter(|c| reqs.iter().all(|r| r.req.matches(&c.version)))
+        .rev()
+        .cloned()
+        .collect();
+    let locked = lock.package.iter().find(|l| l.name == name).and_then(|l| {
+        matching
+            .iter()
+            .position(|c| c.version.to_string() == l.version)
+    });
+    if let Some(i) = locked {
+        let candidate = matching.remove(i);
+        matching.insert(0, candidate);
+    }
+    if !matching.is_empty() {
+        return Ok(matching);
+    }
+
+    let mut message = format!(
+        "Can't find a version of `{}` that satisfies every requirement:",
+        name
+    );
+    for r in reqs {
+        message.push_str(&format!("\n  {} requires {} {}", r.by, name, r.req));
+    }
+    if candidates.is_empty() {
+        message.push_str(&format!(
+            "\nThe package cache at {} has no versions of `{}`",
+            cache.display(),
+            name
+        ));
+    } else {
+        let versions: Vec<String> = candidates.iter().map(|c| c.version.to_string()).collect();
+        let from = match paths.first() {
+            Some(path) => path.display().to_string(),
+            None => "the package cache".to_string(),
+        };
+        message.push_str(&format!(
+            "\nAvailable from {}: {}",
+            from,
+            versions.join(", ")
+        ));
+    }
+    Err(Conflict(message).into())
+}
+
+/// An md5 of the manifest and every entry file.
+fn content_hash(candidate: &Candidate) -> Result<String> {
+    let mut content = fs::read(candidate.dir.join(MANIFEST_FILE))?;
+    for entry in &candidate.manifest.package.entry {
+        content.extend(entry.to_string_lossy().as_bytes());
+        content.extend(fs::read(candidate.dir.join(entry))?);
+    }
+    Ok(format!("md5:{:x}", md5::compute(&content)))
+}
+
+fn parse_package_file(path: &Path, label: &str) -> Result<Vec<Item>> {
+    let src = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read package file: {}", label))?;
+    let (rest, program) = parser::parse_program(&src).map_err(|e| {
+        anyhow::anyhow!(
+            "In package '{}': {}",
+            label,
+            crate::format_parse_error(e, &src)
+        )
+    })?;
+    if !rest.trim().is_empty() {
+        println!(
+            "{}",
+            format!(
+                "Warning: Package {} parsing stopped early. Remaining: {:.50}...",
+                label, rest
+            )
+            .yellow()
+        );
+    }
+    // Asset paths are relative to the package, not the project using it
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let mut items = program.items;
+    for item in &mut items {
+        if let Item::Costume(asset) | Item::Sound(asset) = item {
+            if Path::new(&asset.path).is_relative() {
+                asset.path = dir.join(&asset.path).display().to_string();
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// A single `.sr` file starting with a `package` block.
+fn load_file_package(path: &Path, label: &str) -> Result<Package> {
+    let mut package_def = None;
+    let mut items = Vec::new();
+    for item in parse_package_file(path, label)? {
+        if let Item::Package(pkg) = item {
+            if package_def.is_some() {
+                bail!(
+                    "Package file '{}' contains multiple package declarations",
+                    label
+                );
+            }
+            package_def = Some(pkg);
+        } else {
+            items.push(item);
+        }
+    }
+    let Some(mut pkg) = package_def else {
+        bail!(
+            "Package file '{}' must contain a package declaration",
+            label
+        );
+    };
+    pkg.items = items;
+    Ok(pkg)
+}
+
+/// A package directory: the items of all its entry files.
+fn load_manifest_package(candidate: &Candidate) -> Result<Package> {
+    let manifest = &candidate.manifest.package;
+    let mut items = Vec::new();
+    for entry in &manifest.entry {
+        let path = candidate.dir.join(entry);
+        let label = path.display().to_string();
+        for item in parse_package_file(&path, &label)? {
+            if let Item::Package(pkg) = item {
+                bail!(
+                    "'{}' declares `package {}`, but a package with a {} is configured there instead",
+                    label,
+                    pkg.name,
+                    MANIFEST_FILE
+                );
+            }
+            items.push(item);
+        }
+    }
+    Ok(Package {
+        name: manifest.name.clone(),
+        version: Some(candidate.version.to_string()),
+        extensions: manifest.extensions.clone(),
+        dependencies: candidate.manifest.dependencies.keys().cloned().collect(),
+        items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_package(dir: &Path, name: &str, version: &str, deps: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"{}\"\n\n[dependencies]\n{}",
+                name, version, deps
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("lib.sr"), "proc f() {}\n").unwrap();
+    }
+
+    fn root(name: &str, req: &str) -> Requirement {
+        Requirement {
+            name: name.to_string(),
+            req: VersionReq::parse(req).unwrap(),
+            source: Source::Cache,
+            by: "scrust.toml".to_string(),
+        }
+    }
+
+    fn versions(resolved: &[Candidate]) -> Vec<String> {
+        resolved
+            .iter()
+            .map(|c| format!("{} {}", c.manifest.package.name, c.version))
+            .collect()
+    }
+
+    #[test]
+    fn test_resolves_versions() {
+        let cache = std::env::temp_dir().join(format!("scrust-cache-{}", std::process::id()));
+        for v in ["1.0.0", "1.4.0", "2.0.0"] {
+            write_package(&cache.join("vec").join(v), "vec", v, "");
+        }
+        write_package(
+            &cache.join("geo").join("0.3.0"),
+            "geo",
+            "0.3.0",
+            "vec = \"^1.2\"\n",
+        );
+
+        // The newest version that every requirement accepts
+        let resolved = resolve(vec![root("geo", "*")], &cache, &LockFile::default()).unwrap();
+        assert_eq!(versions(&resolved), vec!["geo 0.3.0", "vec 1.4.0"]);
+
+        // A locked version is kept while it still fits
+        let lock = LockFile {
+            package: vec![LockedPackage {
+                name: "vec".to_string(),
+                version: "1.0.0".to_string(),
+                source: "cache".to_string(),
+                hash: String::new(),
+                dependencies: vec![],
+            }],
+        };
+        let resolved = resolve(vec![root("vec", "1")], &cache, &lock).unwrap();
+        assert_eq!(versions(&resolved), vec!["vec 1.0.0"]);
+
+        let err = resolve(
+            vec![root("geo", "*"), root("vec", "^2")],
+            &cache,
+            &LockFile::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't find a version of `vec` that satisfies every requirement:\n  \
+             scrust.toml requires vec ^2\n  \
+             geo 0.3.0 requires vec ^1.2\n\
+             Available from the package cache: 1.0.0, 1.4.0, 2.0.0"
+        );
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_resolution_backtracks() {
+        let cache = std::env::temp_dir().join(format!("scrust-backtrack-{}", std::process::id()));
+        for v in ["1.4.0", "2.0.0"] {
+            write_package(&cache.join("vec").join(v), "vec", v, "");
+        }
+        write_package(
+            &cache.join("geo").join("0.3.0"),
+            "geo",
+            "0.3.0",
+            "vec = \"^1.2\"\n",
+        );
+        write_package(
+            &cache.join("geo").join("0.4.0"),
+            "geo",
+            "0.4.0",
+            "vec = \"^2\"\n",
+        );
+
+        // geo 0.4.0 needs vec 2, which the project rules out, so geo 0.3.0 is used
+        let resolved = resolve(
+            vec![root("geo", "*"), root("vec", "^1")],
+            &cache,
+            &LockFile::default(),
+        )
+        .unwrap();
+        assert_eq!(versions(&resolved), vec!["geo 0.3.0", "vec 1.4.0"]);
+
+        // Without the restriction the newest versions are still picked
+        let resolved = resolve(vec![root("geo", "*")], &cache, &LockFile::default()).unwrap();
+        assert_eq!(versions(&resolved), vec!["geo 0.4.0", "vec 2.0.0"]);
+
+        // A requirement added after its package was picked also backtracks
+        write_package(
+            &cache.join("app").join("1.0.0"),
+            "app",
+            "1.0.0",
+            "zip = \"*\"\nvec = \"*\"\n",
+        );
+        write_package(
+            &cache.join("zip").join("1.0.0"),
+            "zip",
+            "1.0.0",
+            "vec = \"^1\"\n",
+        );
+        let resolved = resolve(vec![root("app", "*")], &cache, &LockFile::default()).unwrap();
+        assert_eq!(
+            versions(&resolved),
+            vec!["app 1.0.0", "vec 1.4.0", "zip 1.0.0"]
+        );
+        fs::remove_dir_all(&cache).unwrap();
+    }
+}
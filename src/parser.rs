@@ -1,11 +1,11 @@
 use crate::ast::*;
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
-    character::complete::{alpha1, alphanumeric1, char, digit1, multispace1},
-    combinator::{map, map_res, opt, recognize, value, verify},
-    multi::{many0, separated_list0},
-    sequence::{delimited, pair, preceded, tuple},
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{alpha1, alphanumeric1, char, digit1, multispace1, one_of},
+    combinator::{cut, map, map_res, not, opt, recognize, value, verify},
+    multi::{many0, many0_count, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
@@ -48,15 +48,21 @@ fn mod_comment<'a, E: nom::error::ParseError<&'a str>>(
     )(input)
 }
 
-fn ws<'a, F: 'a, O, E: nom::error::ParseError<&'a str>>(
+fn ws<'a, F, O, E: nom::error::ParseError<&'a str>>(
     inner: F,
 ) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
 where
-    F: FnMut(&'a str) -> IResult<&'a str, O, E>,
+    F: FnMut(&'a str) -> IResult<&'a str, O, E> + 'a,
 {
     preceded(many0(alt((value((), multispace1), comment))), inner)
 }
 
+// A keyword that isn't the start of a longer name, so `modx` or `letter`
+// are read as identifiers rather than `mod x` or `let ter`.
+fn keyword<'a>(kw: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag(kw), not(alt((alphanumeric1, tag("_")))))
+}
+
 fn identifier(input: &str) -> IResult<&str, String> {
     map(
         recognize(pair(
@@ -73,32 +79,261 @@ fn type_spec(input: &str) -> IResult<&str, Type> {
         value(Type::Number, tag("number")),
         value(Type::String, tag("string")),
         value(Type::Boolean, tag("boolean")),
+        struct_list_type,
         value(Type::List, tag("list")),
+        tuple_type,
     ))(input)
 }
 
+// `(number, string)`. A tuple needs at least two elements.
+fn tuple_type(input: &str) -> IResult<&str, Type> {
+    map(
+        verify(
+            delimited(
+                char('('),
+                separated_list1(ws(char(',')), ws(type_spec)),
+                ws(char(')')),
+            ),
+            |types: &Vec<Type>| types.len() > 1,
+        ),
+        Type::Tuple,
+    )(input)
+}
+
+fn struct_list_type(input: &str) -> IResult<&str, Type> {
+    map(
+        preceded(
+            tag("list"),
+            delimited(ws(char('<')), ws(identifier), ws(char('>'))),
+        ),
+        Type::StructList,
+    )(input)
+}
+
 // Literals
 fn number_literal(input: &str) -> IResult<&str, f64> {
+    let (input, negative) = opt(char('-'))(input)?;
+    let (input, n) = alt((
+        radix_literal("0x", 16),
+        radix_literal("0b", 2),
+        radix_literal("0o", 8),
+        decimal_literal,
+    ))(input)?;
+    Ok((input, if negative.is_some() { -n } else { n }))
+}
+
+// `0xff`, `0b1010`, `0o17`. Underscores may separate digits.
+fn radix_literal(prefix: &'static str, radix: u32) -> impl Fn(&str) -> IResult<&str, f64> {
+    move |input| {
+        map_res(
+            preceded(
+                tag(prefix),
+                take_while1(|c: char| c.is_digit(radix) || c == '_'),
+            ),
+            |digits: &str| i64::from_str_radix(&digits.replace('_', ""), radix).map(|n| n as f64),
+        )(input)
+    }
+}
+
+// `12`, `1.5`, `.5`, `6.02e23`, `1e-3`
+fn decimal_literal(input: &str) -> IResult<&str, f64> {
     map_res(
         recognize(tuple((
-            opt(char('-')),
-            digit1,
-            opt(tuple((char('.'), digit1))),
+            alt((
+                recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+                recognize(pair(char('.'), digit1)),
+            )),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
         ))),
         |s: &str| s.parse::<f64>(),
     )(input)
 }
 
 fn string_literal(input: &str) -> IResult<&str, String> {
-    delimited(
-        char('"'),
-        map(take_while(|c| c != '"'), |s: &str| s.to_string()),
-        char('"'),
+    alt((raw_string_literal, escaped_string_literal))(input)
+}
+
+// `r"..."` or `r#"..."#`: no escapes, may span lines
+fn raw_string_literal(input: &str) -> IResult<&str, String> {
+    let (input, _) = char('r')(input)?;
+    let (input, hashes) = many0_count(char('#'))(input)?;
+    let (input, _) = char('"')(input)?;
+    let closing = format!("\"{}", "#".repeat(hashes));
+    match input.find(&closing) {
+        Some(end) => Ok((&input[end + closing.len()..], input[..end].to_string())),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Char,
+        ))),
+    }
+}
+
+fn escaped_string_literal(input: &str) -> IResult<&str, String> {
+    let (mut input, _) = char('"')(input)?;
+    let mut out = String::new();
+    loop {
+        if let Some(rest) = input.strip_prefix('"') {
+            return Ok((rest, out));
+        }
+        let (rest, c) = string_char(input)?;
+        out.extend(c);
+        input = rest;
+    }
+}
+
+// One character of a quoted string. Rust-style escapes: `\n \r \t \0 \\ \" \'`,
+// `\u{1F600}`, and `\` at the end of a line to skip the line break and the
+// next line's indentation (yielding no character).
+fn string_char(input: &str) -> IResult<&str, Option<char>> {
+    let fail = || {
+        nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Escaped,
+        ))
+    };
+    let mut chars = input.chars();
+    match chars.next() {
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Char,
+        ))),
+        Some('\\') => {
+            let escaped = chars.next().ok_or_else(fail)?;
+            let rest = chars.as_str();
+            let c = match escaped {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                '\\' => '\\',
+                '"' => '"',
+                '\'' => '\'',
+                '\n' => return Ok((rest.trim_start(), None)),
+                'u' => {
+                    let (rest, code) = delimited(
+                        char('{'),
+                        take_while1(|c: char| c.is_ascii_hexdigit()),
+                        char('}'),
+                    )(rest)
+                    .map_err(|_: nom::Err<nom::error::Error<&str>>| fail())?;
+                    let c = u32::from_str_radix(code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(fail)?;
+                    return Ok((rest, Some(c)));
+                }
+                _ => return Err(fail()),
+            };
+            Ok((rest, Some(c)))
+        }
+        Some(c) => Ok((chars.as_str(), Some(c))),
+    }
+}
+
+// `f"Score: {score} / {max:05}"`. Lowers to a balanced tree of `join` calls.
+// `{{` and `}}` are literal braces. A format spec after `:` may combine zero
+// padding (`05`), thousands separators (`,`) and fixed decimals (`.2`).
+fn fstring_literal(input: &str) -> IResult<&str, Expr> {
+    let (mut input, _) = tag("f\"")(input)?;
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    loop {
+        if let Some(rest) = input.strip_prefix('"') {
+            input = rest;
+            break;
+        } else if let Some(rest) = input.strip_prefix("{{") {
+            text.push('{');
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix("}}") {
+            text.push('}');
+            input = rest;
+        } else if input.starts_with('{') {
+            let (rest, part) = cut(delimited(
+                char('{'),
+                pair(ws(expr), opt(preceded(char(':'), format_spec))),
+                char('}'),
+            ))(input)?;
+            if !text.is_empty() {
+                parts.push(Expr::String(std::mem::take(&mut text)));
+            }
+            parts.push(apply_format_spec(part.0, part.1));
+            input = rest;
+        } else {
+            let (rest, c) = string_char(input)?;
+            text.extend(c);
+            input = rest;
+        }
+    }
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(Expr::String(text));
+    }
+    Ok((input, balanced_join(parts)))
+}
+
+struct FormatSpec {
+    zero_pad: Option<usize>,
+    thousands: bool,
+    decimals: Option<usize>,
+}
+
+fn format_spec(input: &str) -> IResult<&str, FormatSpec> {
+    let count = || map_res(digit1, |d: &str| d.parse::<usize>());
+    let (input, zero_pad) = opt(preceded(char('0'), count()))(input)?;
+    let (input, thousands) = opt(char(','))(input)?;
+    let (input, decimals) = opt(preceded(char('.'), count()))(input)?;
+    Ok((
+        input,
+        FormatSpec {
+            zero_pad,
+            thousands: thousands.is_some(),
+            decimals,
+        },
+    ))
+}
+
+fn apply_format_spec(mut value: Expr, spec: Option<FormatSpec>) -> Expr {
+    let Some(spec) = spec else {
+        return value;
+    };
+    let call = |name: &str, args: Vec<Expr>| Expr::Call(name.to_string(), args);
+    if let Some(decimals) = spec.decimals {
+        value = call("format_fixed", vec![value, Expr::Number(decimals as f64)]);
+    }
+    if spec.thousands {
+        value = call("format_thousands", vec![value]);
+    }
+    if let Some(width) = spec.zero_pad {
+        value = call("format_padded", vec![value, Expr::Number(width as f64)]);
+    }
+    value
+}
+
+fn balanced_join(mut parts: Vec<Expr>) -> Expr {
+    if parts.len() == 1 {
+        return parts.pop().unwrap();
+    }
+    let right = parts.split_off(parts.len() / 2);
+    Expr::Call(
+        "join".to_string(),
+        vec![balanced_join(parts), balanced_join(right)],
+    )
+}
+
+// `#rrggbb`
+fn color_literal(input: &str) -> IResult<&str, String> {
+    map(
+        recognize(pair(
+            char('#'),
+            verify(take_while1(|c: char| c.is_ascii_hexdigit()), |h: &str| {
+                h.len() == 6
+            }),
+        )),
+        |s: &str| s.to_ascii_lowercase(),
     )(input)
 }
 
 fn bool_literal(input: &str) -> IResult<&str, bool> {
-    alt((value(true, tag("true")), value(false, tag("false"))))(input)
+    alt((value(true, keyword("true")), value(false, keyword("false"))))(input)
 }
 
 fn list_literal(input: &str) -> IResult<&str, Vec<Expr>> {
@@ -116,18 +351,104 @@ fn expr(input: &str) -> IResult<&str, Expr> {
 fn expr_atom(input: &str) -> IResult<&str, Expr> {
     alt((
         map(number_literal, Expr::Number),
+        fstring_literal,
         map(string_literal, Expr::String),
+        map(color_literal, Expr::Color),
         map(bool_literal, Expr::Bool),
         map(list_literal, Expr::List),
+        struct_literal,
+        if_expr,
         map(func_call, |(name, args)| Expr::Call(name, args)),
         map(identifier, Expr::Variable),
-        delimited(char('('), expr, char(')')),
+        paren_or_tuple,
     ))(input)
 }
 
+// `(a)` is just `a`; `(a, b)` is a tuple.
+fn paren_or_tuple(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = char('(')(input)?;
+    let (input, first) = expr(input)?;
+    let (input, rest) = many0(preceded(ws(char(',')), ws(expr)))(input)?;
+    let (input, _) = ws(char(')'))(input)?;
+    if rest.is_empty() {
+        return Ok((input, first));
+    }
+    let mut items = vec![first];
+    items.extend(rest);
+    Ok((input, Expr::Tuple(items)))
+}
+
+// `if cond { a } else { b }` as a value. The `else` branch is required, and
+// may itself be another `if` expression.
+fn if_expr(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = keyword("if")(input)?;
+    let (input, cond) = ws(expr)(input)?;
+    let (input, then) = ws(expr_block)(input)?;
+    let (input, _) = ws(keyword("else"))(input)?;
+    let (input, otherwise) = alt((ws(expr_block), ws(if_expr)))(input)?;
+    Ok((
+        input,
+        Expr::If(Box::new(cond), Box::new(then), Box::new(otherwise)),
+    ))
+}
+
+fn expr_block(input: &str) -> IResult<&str, Expr> {
+    delimited(char('{'), ws(expr), ws(char('}')))(input)
+}
+
+// `Name { field: value, .. }`. Struct names must be capitalized so that
+// `if x { .. }` is never mistaken for a literal.
+fn struct_literal(input: &str) -> IResult<&str, Expr> {
+    let (input, name) = verify(identifier, |n: &str| {
+        n.starts_with(|c: char| c.is_ascii_uppercase())
+    })(input)?;
+    let (input, _) = ws(char('{'))(input)?;
+    let (input, first) = struct_field_init(input)?;
+    let (input, rest) = many0(preceded(ws(char(',')), struct_field_init))(input)?;
+    let (input, _) = opt(ws(char(',')))(input)?;
+    let (input, _) = ws(char('}'))(input)?;
+
+    let mut fields = vec![first];
+    fields.extend(rest);
+    Ok((input, Expr::StructLit(name, fields)))
+}
+
+fn struct_field_init(input: &str) -> IResult<&str, (String, Expr)> {
+    pair(ws(identifier), preceded(ws(char(':')), ws(expr)))(input)
+}
+
+enum Postfix {
+    Index(Expr),
+    Field(String),
+    Method(String, Vec<Expr>),
+}
+
+fn expr_postfix(input: &str) -> IResult<&str, Expr> {
+    let (input, init) = expr_atom(input)?;
+    let (input, ops) = many0(alt((
+        map(
+            delimited(char('['), ws(expr), ws(char(']'))),
+            Postfix::Index,
+        ),
+        map(preceded(char('.'), func_call), |(name, args)| {
+            Postfix::Method(name, args)
+        }),
+        map(preceded(char('.'), identifier), Postfix::Field),
+    )))(input)?;
+
+    Ok((
+        input,
+        ops.into_iter().fold(init, |acc, op| match op {
+            Postfix::Index(index) => Expr::Index(Box::new(acc), Box::new(index)),
+            Postfix::Field(field) => Expr::Field(Box::new(acc), field),
+            Postfix::Method(name, args) => Expr::MethodCall(Box::new(acc), name, args),
+        }),
+    ))
+}
+
 fn expr_unary(input: &str) -> IResult<&str, Expr> {
     alt((
-        expr_atom,
+        expr_pow,
         map(pair(ws(tag("!")), ws(expr_unary)), |(_, val)| {
             Expr::UnOp(UnOp::Not, Box::new(val))
         }),
@@ -137,22 +458,43 @@ fn expr_unary(input: &str) -> IResult<&str, Expr> {
     ))(input)
 }
 
+// `**` is right-associative and binds tighter than unary minus, so
+// `-x ** 2` is `-(x ** 2)` and `2 ** -1` is `2 ** (-1)`.
+fn expr_pow(input: &str) -> IResult<&str, Expr> {
+    let (rest, base) = expr_postfix(input)?;
+    let (rest, exponent) = opt(preceded(ws(tag("**")), ws(expr_unary)))(rest)?;
+    let Some(exponent) = exponent else {
+        return Ok((rest, base));
+    };
+    let expr = match base {
+        // A negative literal's sign is unary minus too
+        Expr::Number(n) if n < 0.0 && input.starts_with('-') => Expr::UnOp(
+            UnOp::Neg,
+            Box::new(Expr::BinOp(
+                Box::new(Expr::Number(-n)),
+                Op::Pow,
+                Box::new(exponent),
+            )),
+        ),
+        base => Expr::BinOp(Box::new(base), Op::Pow, Box::new(exponent)),
+    };
+    Ok((rest, expr))
+}
+
 fn expr_mul_div(input: &str) -> IResult<&str, Expr> {
     let (input, init) = ws(expr_unary)(input)?;
     let (input, rest) = many0(tuple((
-        ws(alt((char('*'), char('/'), char('%')))),
+        ws(alt((
+            map(char('*'), |_| Op::Mul),
+            map(char('/'), |_| Op::Div),
+            map(char('%'), |_| Op::Mod),
+        ))),
         ws(expr_unary),
     )))(input)?;
 
     Ok((
         input,
         rest.into_iter().fold(init, |acc, (op, val)| {
-            let op = match op {
-                '*' => Op::Mul,
-                '/' => Op::Div,
-                '%' => Op::Mod,
-                _ => unreachable!(),
-            };
             Expr::BinOp(Box::new(acc), op, Box::new(val))
         }),
     ))
@@ -186,6 +528,8 @@ fn expr_eq(input: &str) -> IResult<&str, Expr> {
     let (input, init) = ws(expr_cmp)(input)?;
     let (input, rest) = many0(tuple((
         ws(alt((
+            map(tag("==="), |_| Op::StrictEq),
+            map(tag("!=="), |_| Op::StrictNe),
             map(tag("=="), |_| Op::Eq),
             map(tag("!="), |_| Op::Ne),
             map(char('='), |_| Op::Eq),
@@ -202,7 +546,7 @@ fn expr_eq(input: &str) -> IResult<&str, Expr> {
 }
 
 fn expr_cmp(input: &str) -> IResult<&str, Expr> {
-    let (input, init) = ws(expr_sum)(input)?;
+    let (input, init) = ws(expr_bit_or)(input)?;
     let (input, rest) = many0(tuple((
         ws(alt((
             map(tag(">="), |_| Op::Ge),
@@ -210,6 +554,71 @@ fn expr_cmp(input: &str) -> IResult<&str, Expr> {
             map(char('>'), |_| Op::Gt),
             map(char('<'), |_| Op::Lt),
         ))),
+        ws(expr_bit_or),
+    )))(input)?;
+
+    Ok((
+        input,
+        rest.into_iter().fold(init, |acc, (op, val)| {
+            Expr::BinOp(Box::new(acc), op, Box::new(val))
+        }),
+    ))
+}
+
+// Bitwise operators bind tighter than comparisons, so `flags & 4 == 4`
+// tests a bit.
+fn expr_bit_or(input: &str) -> IResult<&str, Expr> {
+    let (input, init) = ws(expr_bit_xor)(input)?;
+    let (input, rest) = many0(tuple((
+        ws(map(terminated(char('|'), not(char('|'))), |_| Op::BitOr)),
+        ws(expr_bit_xor),
+    )))(input)?;
+
+    Ok((
+        input,
+        rest.into_iter().fold(init, |acc, (op, val)| {
+            Expr::BinOp(Box::new(acc), op, Box::new(val))
+        }),
+    ))
+}
+
+fn expr_bit_xor(input: &str) -> IResult<&str, Expr> {
+    let (input, init) = ws(expr_bit_and)(input)?;
+    let (input, rest) = many0(tuple((
+        ws(map(char('^'), |_| Op::BitXor)),
+        ws(expr_bit_and),
+    )))(input)?;
+
+    Ok((
+        input,
+        rest.into_iter().fold(init, |acc, (op, val)| {
+            Expr::BinOp(Box::new(acc), op, Box::new(val))
+        }),
+    ))
+}
+
+fn expr_bit_and(input: &str) -> IResult<&str, Expr> {
+    let (input, init) = ws(expr_shift)(input)?;
+    let (input, rest) = many0(tuple((
+        ws(map(terminated(char('&'), not(char('&'))), |_| Op::BitAnd)),
+        ws(expr_shift),
+    )))(input)?;
+
+    Ok((
+        input,
+        rest.into_iter().fold(init, |acc, (op, val)| {
+            Expr::BinOp(Box::new(acc), op, Box::new(val))
+        }),
+    ))
+}
+
+fn expr_shift(input: &str) -> IResult<&str, Expr> {
+    let (input, init) = ws(expr_sum)(input)?;
+    let (input, rest) = many0(tuple((
+        ws(alt((
+            map(terminated(tag("<<"), not(char('='))), |_| Op::Shl),
+            map(terminated(tag(">>"), not(char('='))), |_| Op::Shr),
+        ))),
         ws(expr_sum),
     )))(input)?;
 
@@ -239,12 +648,20 @@ fn expr_sum(input: &str) -> IResult<&str, Expr> {
     ))
 }
 
+// `name: value` in a call. The transform matches it to a parameter.
+fn named_arg(input: &str) -> IResult<&str, Expr> {
+    map(
+        pair(identifier, preceded(ws(char(':')), ws(expr))),
+        |(name, value)| Expr::NamedArg(name, Box::new(value)),
+    )(input)
+}
+
 fn func_call(input: &str) -> IResult<&str, (String, Vec<Expr>)> {
     pair(
         identifier,
         delimited(
             char('('),
-            separated_list0(ws(char(',')), ws(expr)),
+            separated_list0(ws(char(',')), ws(alt((named_arg, expr)))),
             char(')'),
         ),
     )(input)
@@ -261,8 +678,10 @@ fn attach_comment(stmt: Stmt, comment: String) -> Stmt {
         Stmt::Until(c, b, _) => Stmt::Until(c, b, Some(comment)),
         Stmt::Match(e, c, d, _) => Stmt::Match(e, c, d, Some(comment)),
         Stmt::Let(n, e, _) => Stmt::Let(n, e, Some(comment)),
+        Stmt::LetTuple(n, e, _) => Stmt::LetTuple(n, e, Some(comment)),
         Stmt::Return(e, _) => Stmt::Return(e, Some(comment)),
         Stmt::CBlock(n, a, b, _) => Stmt::CBlock(n, a, b, Some(comment)),
+        Stmt::AssignPlace(p, e, _) => Stmt::AssignPlace(p, e, Some(comment)),
         Stmt::Comment(_) => stmt,
     }
 }
@@ -276,10 +695,13 @@ fn stmt(input: &str) -> IResult<&str, Stmt> {
         stmt_repeat,
         stmt_forever,
         stmt_until,
+        stmt_let_tuple,
         stmt_let,
         stmt_assign,
+        stmt_assign_place,
         stmt_return,
         stmt_c_block,
+        stmt_broadcast,
         stmt_expr,
     ))(input)?;
 
@@ -290,7 +712,7 @@ fn stmt(input: &str) -> IResult<&str, Stmt> {
 }
 
 fn stmt_let(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("let"))(input)?;
+    let (input, _) = ws(keyword("let"))(input)?;
     let (input, name) = ws(identifier)(input)?;
     let (input, _) = ws(char('='))(input)?;
     let (input, val) = ws(expr)(input)?;
@@ -298,8 +720,25 @@ fn stmt_let(input: &str) -> IResult<&str, Stmt> {
     Ok((input, Stmt::Let(name, val, None)))
 }
 
+// `let (a, b) = value;`
+fn stmt_let_tuple(input: &str) -> IResult<&str, Stmt> {
+    let (input, _) = ws(keyword("let"))(input)?;
+    let (input, names) = verify(
+        delimited(
+            ws(char('(')),
+            separated_list1(ws(char(',')), ws(identifier)),
+            ws(char(')')),
+        ),
+        |names: &Vec<String>| names.len() > 1,
+    )(input)?;
+    let (input, _) = ws(char('='))(input)?;
+    let (input, val) = ws(expr)(input)?;
+    let (input, _) = ws(char(';'))(input)?;
+    Ok((input, Stmt::LetTuple(names, val, None)))
+}
+
 fn stmt_return(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("return"))(input)?;
+    let (input, _) = ws(keyword("return"))(input)?;
     let (input, val) = opt(ws(expr))(input)?;
     let (input, _) = ws(char(';'))(input)?;
     Ok((input, Stmt::Return(val, None)))
@@ -328,123 +767,145 @@ fn block(input: &str) -> IResult<&str, Vec<Stmt>> {
 }
 
 fn stmt_match(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("match"))(input)?;
+    let (input, _) = ws(keyword("match"))(input)?;
     let (input, expr) = ws(expr)(input)?;
-    let (input, cases) =
-        delimited(ws(char('{')), many0(ws(stmt_match_case)), ws(char('}')))(input)?;
+    let (input, arms) = delimited(ws(char('{')), many0(ws(stmt_match_arm)), ws(char('}')))(input)?;
 
-    let mut match_cases = Vec::new();
-    let mut default_case = None;
-
-    for (case_expr, body) in cases {
-        if let Some(e) = case_expr {
-            match_cases.push((e, body));
-        } else {
-            if default_case.is_some() {
-                // In a real compiler we should report error, but nom makes it hard.
-                // We'll just ignore subsequent default cases or take the last one.
-            }
-            default_case = Some(body);
-        }
-    }
-
-    Ok((input, Stmt::Match(expr, match_cases, default_case, None)))
+    // `_` arms stay in `arms`; the transform checks them and moves the
+    // catch-all into the default slot.
+    Ok((input, Stmt::Match(expr, arms, None, None)))
 }
 
-fn stmt_match_case(input: &str) -> IResult<&str, (Option<Expr>, Vec<Stmt>)> {
-    let (input, expr) = ws(alt((map(tag("_"), |_| None), map(expr, Some))))(input)?;
+fn stmt_match_arm(input: &str) -> IResult<&str, MatchArm> {
+    let (input, first) = ws(pattern)(input)?;
+    let (input, rest) = many0(preceded(
+        ws(terminated(char('|'), not(char('|')))),
+        ws(pattern),
+    ))(input)?;
+    let (input, guard) = opt(preceded(ws(keyword("if")), ws(expr)))(input)?;
     let (input, _) = ws(tag("=>"))(input)?;
     let (input, body) = ws(block)(input)?;
     let (input, _) = opt(ws(char(',')))(input)?; // Optional trailing comma
-    Ok((input, (expr, body)))
+
+    let mut patterns = vec![first];
+    patterns.extend(rest);
+    Ok((
+        input,
+        MatchArm {
+            patterns,
+            guard,
+            body,
+        },
+    ))
+}
+
+// Patterns are parsed at `+`/`-` precedence so `|`, `..` and `if` are left alone.
+fn pattern(input: &str) -> IResult<&str, Pattern> {
+    alt((
+        map(
+            tuple((
+                expr_sum,
+                ws(alt((value(true, tag("..=")), value(false, tag(".."))))),
+                ws(expr_sum),
+            )),
+            |(lo, inclusive, hi)| Pattern::Range(lo, hi, inclusive),
+        ),
+        map(expr_sum, |e| match e {
+            Expr::Variable(name) if name == "_" => Pattern::Wildcard,
+            Expr::Variable(name) if !name.contains("::") => Pattern::Binding(name),
+            e => Pattern::Value(e),
+        }),
+    ))(input)
 }
 
 fn stmt_if(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("if"))(input)?;
+    let (input, _) = ws(keyword("if"))(input)?;
     let (input, cond) = ws(expr)(input)?;
     let (input, then_block) = ws(block)(input)?;
-    let (input, else_block) = opt(preceded(ws(tag("else")), ws(block)))(input)?;
+    let (input, else_block) = opt(preceded(ws(keyword("else")), ws(block)))(input)?;
 
     Ok((input, Stmt::If(cond, then_block, else_block, None)))
 }
 
 fn stmt_repeat(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("repeat"))(input)?;
+    let (input, _) = ws(keyword("repeat"))(input)?;
     let (input, count) = ws(expr)(input)?;
     let (input, body) = ws(block)(input)?;
     Ok((input, Stmt::Repeat(count, body, None)))
 }
 
 fn stmt_forever(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("forever"))(input)?;
+    let (input, _) = ws(keyword("forever"))(input)?;
     let (input, body) = ws(block)(input)?;
     Ok((input, Stmt::Forever(body, None)))
 }
 
 fn stmt_until(input: &str) -> IResult<&str, Stmt> {
-    let (input, _) = ws(tag("until"))(input)?;
+    let (input, _) = ws(keyword("until"))(input)?;
     let (input, cond) = ws(expr)(input)?;
     let (input, body) = ws(block)(input)?;
     Ok((input, Stmt::Until(cond, body, None)))
 }
 
+fn assign_op(input: &str) -> IResult<&str, Option<Op>> {
+    alt((
+        value(None, tag("=")),
+        value(Some(Op::Add), tag("+=")),
+        value(Some(Op::Sub), tag("-=")),
+        value(Some(Op::Mul), tag("*=")),
+        value(Some(Op::Div), tag("/=")),
+        value(Some(Op::Mod), tag("%=")),
+        value(Some(Op::BitAnd), tag("&=")),
+        value(Some(Op::BitOr), tag("|=")),
+        value(Some(Op::BitXor), tag("^=")),
+        value(Some(Op::Shl), tag("<<=")),
+        value(Some(Op::Shr), tag(">>=")),
+    ))(input)
+}
+
+// `target op= val` becomes `target = target op val`
+fn desugar_assign(target: Expr, op: Option<Op>, val: Expr) -> Expr {
+    match op {
+        Some(op) => Expr::BinOp(Box::new(target), op, Box::new(val)),
+        None => val,
+    }
+}
+
 fn stmt_assign(input: &str) -> IResult<&str, Stmt> {
     let (input, name) = ws(identifier)(input)?;
-    let (input, op) = ws(alt((
-        tag("="),
-        tag("+="),
-        tag("-="),
-        tag("*="),
-        tag("/="),
-        tag("%="),
-    )))(input)?;
+    let (input, op) = ws(assign_op)(input)?;
     let (input, val) = ws(expr)(input)?;
     let (input, _) = ws(char(';'))(input)?;
 
-    match op {
-        "=" => Ok((input, Stmt::Assign(name, val, None))),
-        "+=" => Ok((
-            input,
-            Stmt::Assign(
-                name.clone(),
-                Expr::BinOp(Box::new(Expr::Variable(name)), Op::Add, Box::new(val)),
-                None,
-            ),
-        )),
-        "-=" => Ok((
-            input,
-            Stmt::Assign(
-                name.clone(),
-                Expr::BinOp(Box::new(Expr::Variable(name)), Op::Sub, Box::new(val)),
-                None,
-            ),
-        )),
-        "*=" => Ok((
-            input,
-            Stmt::Assign(
-                name.clone(),
-                Expr::BinOp(Box::new(Expr::Variable(name)), Op::Mul, Box::new(val)),
-                None,
-            ),
-        )),
-        "/=" => Ok((
-            input,
-            Stmt::Assign(
-                name.clone(),
-                Expr::BinOp(Box::new(Expr::Variable(name)), Op::Div, Box::new(val)),
-                None,
-            ),
-        )),
-        "%=" => Ok((
-            input,
-            Stmt::Assign(
-                name.clone(),
-                Expr::BinOp(Box::new(Expr::Variable(name)), Op::Mod, Box::new(val)),
-                None,
-            ),
-        )),
-        _ => unreachable!(),
-    }
+    let val = desugar_assign(Expr::Variable(name.clone()), op, val);
+    Ok((input, Stmt::Assign(name, val, None)))
+}
+
+fn stmt_assign_place(input: &str) -> IResult<&str, Stmt> {
+    let (input, place) = ws(verify(expr_postfix, |e: &Expr| {
+        matches!(e, Expr::Index(..) | Expr::Field(..))
+    }))(input)?;
+    let (input, op) = ws(assign_op)(input)?;
+    let (input, val) = ws(expr)(input)?;
+    let (input, _) = ws(char(';'))(input)?;
+
+    let val = desugar_assign(place.clone(), op, val);
+    Ok((input, Stmt::AssignPlace(place, val, None)))
+}
+
+/// `broadcast Hit(5, "enemy");` sends a typed message. It is kept as
+/// `broadcast(Hit(5, "enemy"))` until the messages are lowered.
+fn stmt_broadcast(input: &str) -> IResult<&str, Stmt> {
+    let (input, _) = ws(terminated(tag("broadcast"), multispace1))(input)?;
+    let (input, (name, args)) = ws(func_call)(input)?;
+    let (input, _) = ws(char(';'))(input)?;
+    Ok((
+        input,
+        Stmt::Expr(
+            Expr::Call("broadcast".to_string(), vec![Expr::Call(name, args)]),
+            None,
+        ),
+    ))
 }
 
 fn stmt_expr(input: &str) -> IResult<&str, Stmt> {
@@ -476,7 +937,7 @@ fn attribute(input: &str) -> IResult<&str, Attribute> {
 
 // Package definition
 fn parse_package(input: &str) -> IResult<&str, Package> {
-    let (input, _) = ws(tag("package"))(input)?;
+    let (input, _) = ws(keyword("package"))(input)?;
     let (input, name) = ws(identifier)(input)?;
     let (input, _) = ws(char('{'))(input)?;
 
@@ -506,6 +967,7 @@ fn parse_package(input: &str) -> IResult<&str, Package> {
         input,
         Package {
             name,
+            version: None,
             extensions,
             dependencies,
             items: Vec::new(),
@@ -514,7 +976,7 @@ fn parse_package(input: &str) -> IResult<&str, Package> {
 }
 
 fn parse_package_extensions(input: &str) -> IResult<&str, Vec<String>> {
-    let (input, _) = ws(tag("extensions"))(input)?;
+    let (input, _) = ws(keyword("extensions"))(input)?;
     let (input, _) = ws(char('='))(input)?;
     let (input, _) = ws(char('['))(input)?;
     let (input, exts) = separated_list0(ws(char(',')), ws(string_literal))(input)?;
@@ -524,7 +986,7 @@ fn parse_package_extensions(input: &str) -> IResult<&str, Vec<String>> {
 }
 
 fn parse_package_dependencies(input: &str) -> IResult<&str, Vec<String>> {
-    let (input, _) = ws(tag("dependencies"))(input)?;
+    let (input, _) = ws(keyword("dependencies"))(input)?;
     let (input, _) = ws(char('='))(input)?;
     let (input, _) = ws(char('['))(input)?;
     let (input, deps) = separated_list0(ws(char(',')), ws(string_literal))(input)?;
@@ -534,7 +996,14 @@ fn parse_package_dependencies(input: &str) -> IResult<&str, Vec<String>> {
 }
 
 fn parse_use(input: &str) -> IResult<&str, String> {
-    let (input, _) = ws(tag("use"))(input)?;
+    let (input, _) = ws(keyword("use"))(input)?;
+    let (input, name) = ws(identifier)(input)?;
+    let (input, _) = ws(char(';'))(input)?;
+    Ok((input, name))
+}
+
+fn parse_mod(input: &str) -> IResult<&str, String> {
+    let (input, _) = ws(keyword("mod"))(input)?;
     let (input, name) = ws(identifier)(input)?;
     let (input, _) = ws(char(';'))(input)?;
     Ok((input, name))
@@ -547,15 +1016,17 @@ fn item_mod_comment(input: &str) -> IResult<&str, Item> {
 
 fn item_var_decl(input: &str) -> IResult<&str, Item> {
     let (input, comment) = opt(ws(doc_comment))(input)?;
+    let (input, attributes) = many0(ws(attribute))(input)?;
     let (input, vis) = opt(ws(alt((
-        value(Visibility::Public, tag("public")),
-        value(Visibility::Private, tag("private")),
+        value(Visibility::Public, keyword("public")),
+        value(Visibility::Private, keyword("private")),
     ))))(input)?;
 
     // Handle "var" and "list" keywords
     let (input, decl_type) = ws(alt((
-        value(Type::Unknown, tag("var")),
-        value(Type::List, tag("list")),
+        value(Type::Unknown, keyword("var")),
+        struct_list_type,
+        value(Type::List, keyword("list")),
     )))(input)?;
 
     let (input, name) = ws(identifier)(input)?;
@@ -567,6 +1038,7 @@ fn item_var_decl(input: &str) -> IResult<&str, Item> {
         input,
         Item::Variable(VariableDecl {
             name,
+            attributes,
             ty: decl_type,
             init,
             visibility: vis.unwrap_or(Visibility::Default),
@@ -578,13 +1050,18 @@ fn item_var_decl(input: &str) -> IResult<&str, Item> {
 fn item_procedure(input: &str) -> IResult<&str, Item> {
     let (input, comment) = opt(ws(doc_comment))(input)?;
     let (input, attributes) = many0(ws(attribute))(input)?;
-    let (input, _) = ws(tag("proc"))(input)?;
+    let (input, is_pub) = opt(ws(keyword("pub")))(input)?;
+    let (input, _) = ws(keyword("proc"))(input)?;
     let (input, name) = ws(identifier)(input)?;
     let (input, params) = delimited(
         ws(char('(')),
         separated_list0(
             ws(char(',')),
-            pair(ws(identifier), preceded(ws(char(':')), ws(type_spec))),
+            tuple((
+                ws(identifier),
+                preceded(ws(char(':')), ws(type_spec)),
+                opt(preceded(ws(char('=')), ws(expr))),
+            )),
         ),
         ws(char(')')),
     )(input)?;
@@ -598,9 +1075,19 @@ fn item_procedure(input: &str) -> IResult<&str, Item> {
         input,
         Item::Procedure(ProcedureDef {
             name,
+            attributes,
+            visibility: if is_pub.is_some() {
+                Visibility::Public
+            } else {
+                Visibility::Default
+            },
             params: params
                 .into_iter()
-                .map(|(n, t)| Param { name: n, ty: t })
+                .map(|(n, t, d)| Param {
+                    name: n,
+                    ty: t,
+                    default: d,
+                })
                 .collect(),
             body,
             return_type,
@@ -610,9 +1097,90 @@ fn item_procedure(input: &str) -> IResult<&str, Item> {
     ))
 }
 
+fn item_enum(input: &str) -> IResult<&str, Item> {
+    let (input, comment) = opt(ws(doc_comment))(input)?;
+    let (input, _) = ws(keyword("enum"))(input)?;
+    let (input, name) = ws(identifier)(input)?;
+    let (input, variants) = delimited(
+        ws(char('{')),
+        separated_list0(ws(char(',')), ws(identifier)),
+        preceded(opt(ws(char(','))), ws(char('}'))),
+    )(input)?;
+
+    Ok((
+        input,
+        Item::Enum(EnumDef {
+            name,
+            variants,
+            comment,
+        }),
+    ))
+}
+
+fn item_struct(input: &str) -> IResult<&str, Item> {
+    let (input, comment) = opt(ws(doc_comment))(input)?;
+    let (input, _) = ws(keyword("struct"))(input)?;
+    let (input, name) = ws(identifier)(input)?;
+    let (input, fields) = delimited(
+        ws(char('{')),
+        separated_list0(
+            ws(char(',')),
+            pair(ws(identifier), preceded(ws(char(':')), ws(type_spec))),
+        ),
+        preceded(opt(ws(char(','))), ws(char('}'))),
+    )(input)?;
+
+    Ok((
+        input,
+        Item::Struct(StructDef {
+            name,
+            fields: fields
+                .into_iter()
+                .map(|(n, t)| Param {
+                    name: n,
+                    ty: t,
+                    default: None,
+                })
+                .collect(),
+            comment,
+        }),
+    ))
+}
+
+fn item_message(input: &str) -> IResult<&str, Item> {
+    let (input, comment) = opt(ws(doc_comment))(input)?;
+    let (input, _) = ws(terminated(tag("message"), multispace1))(input)?;
+    let (input, name) = ws(identifier)(input)?;
+    let (input, params) = delimited(
+        ws(char('(')),
+        separated_list0(
+            ws(char(',')),
+            pair(ws(identifier), preceded(ws(char(':')), ws(type_spec))),
+        ),
+        ws(char(')')),
+    )(input)?;
+    let (input, _) = ws(char(';'))(input)?;
+
+    Ok((
+        input,
+        Item::Message(MessageDef {
+            name,
+            params: params
+                .into_iter()
+                .map(|(n, t)| Param {
+                    name: n,
+                    ty: t,
+                    default: None,
+                })
+                .collect(),
+            comment,
+        }),
+    ))
+}
+
 fn item_costume(input: &str) -> IResult<&str, Item> {
     let (input, _comment) = opt(ws(doc_comment))(input)?;
-    let (input, _) = ws(tag("costume"))(input)?;
+    let (input, _) = ws(keyword("costume"))(input)?;
     let (input, name) = ws(string_literal)(input)?;
     let (input, _) = opt(ws(char(',')))(input)?; // Optional comma
     let (input, path) = ws(string_literal)(input)?;
@@ -635,7 +1203,7 @@ fn item_costume(input: &str) -> IResult<&str, Item> {
 
 fn item_sound(input: &str) -> IResult<&str, Item> {
     let (input, _comment) = opt(ws(doc_comment))(input)?;
-    let (input, _) = ws(tag("sound"))(input)?;
+    let (input, _) = ws(keyword("sound"))(input)?;
     let (input, name) = ws(string_literal)(input)?;
     let (input, _) = opt(ws(char(',')))(input)?; // Optional comma
     let (input, path) = ws(string_literal)(input)?;
@@ -655,7 +1223,7 @@ fn item_function(input: &str) -> IResult<&str, Item> {
     let (input, comment) = opt(ws(doc_comment))(input)?;
     let (input, attributes) = many0(ws(attribute))(input)?;
 
-    let (input, _) = ws(tag("fn"))(input)?;
+    let (input, _) = ws(keyword("fn"))(input)?;
     let (input, name) = ws(identifier)(input)?;
     let (input, params) = delimited(
         ws(char('(')),
@@ -677,7 +1245,11 @@ fn item_function(input: &str) -> IResult<&str, Item> {
             attributes,
             params: params
                 .into_iter()
-                .map(|(n, t)| Param { name: n, ty: t })
+                .map(|(n, t)| Param {
+                    name: n,
+                    ty: t,
+                    default: None,
+                })
                 .collect(),
             body,
             is_warp,
@@ -695,7 +1267,11 @@ fn item(input: &str) -> IResult<&str, Item> {
         item_mod_comment,
         map(parse_package, Item::Package),
         map(parse_use, Item::Use),
+        map(parse_mod, Item::Mod),
         item_var_decl,
+        item_struct,
+        item_enum,
+        item_message,
         item_costume,
         item_sound,
         item_function,
@@ -710,6 +1286,17 @@ enum BreakItem {
 }
 
 pub fn parse_program(input: &str) -> IResult<&str, Program> {
+    let (input, items) = parse_items(input)?;
+    Ok((
+        input,
+        Program {
+            items: items.into_iter().map(|(_, item)| item).collect(),
+        },
+    ))
+}
+
+/// Parses a program's items, each with the input it starts at.
+pub fn parse_items(input: &str) -> IResult<&str, Vec<(&str, Item)>> {
     let mut items = Vec::new();
     let mut input = input;
 
@@ -733,7 +1320,7 @@ pub fn parse_program(input: &str) -> IResult<&str, Program> {
         input = next_input;
 
         if newline_count >= 2 {
-            items.push(Item::BatchBreak);
+            items.push((input, Item::BatchBreak));
         }
 
         if input.is_empty() {
@@ -742,14 +1329,14 @@ pub fn parse_program(input: &str) -> IResult<&str, Program> {
 
         match item(input) {
             Ok((next_input, it)) => {
-                items.push(it);
+                items.push((input, it));
                 input = next_input;
             }
             Err(e) => return Err(e),
         }
     }
 
-    Ok((input, Program { items }))
+    Ok((input, items))
 }
 
 #[cfg(test)]
@@ -812,4 +1399,224 @@ mod tests {
             _ => panic!("Expected Expr stmt"),
         }
     }
+
+    #[test]
+    fn test_struct_field_assign() {
+        let (_, prog) = parse_program(
+            "struct Enemy { x: number, hp: number }\nlist<Enemy> enemies = [];\nenemies[i + 1].hp -= 1;\n",
+        )
+        .unwrap();
+        assert!(matches!(&prog.items[0], Item::Struct(s) if s.fields.len() == 2));
+        assert!(
+            matches!(&prog.items[1], Item::Variable(v) if v.ty == Type::StructList("Enemy".to_string()))
+        );
+        match &prog.items[2] {
+            Item::Stmt(Stmt::AssignPlace(
+                Expr::Field(base, field),
+                Expr::BinOp(_, Op::Sub, _),
+                _,
+            )) => {
+                assert_eq!(field, "hp");
+                assert!(matches!(base.as_ref(), Expr::Index(..)));
+            }
+            other => panic!("Expected field assignment, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_match_patterns() {
+        let (_, prog) =
+            parse_program("match x {\n1 | 2 => {}\n3..=9 => {}\nn if n > 10 => {}\n_ => {}\n}\n")
+                .unwrap();
+        match &prog.items[0] {
+            Item::Stmt(Stmt::Match(_, arms, _, _)) => {
+                assert_eq!(arms[0].patterns.len(), 2);
+                assert!(matches!(arms[1].patterns[0], Pattern::Range(_, _, true)));
+                assert!(matches!(&arms[2].patterns[0], Pattern::Binding(n) if n == "n"));
+                assert!(arms[2].guard.is_some());
+                assert!(matches!(arms[3].patterns[0], Pattern::Wildcard));
+            }
+            other => panic!("Expected match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_string_escapes_and_raw_strings() {
+        assert_eq!(
+            string_literal(r#""say \"hi\"\n\tok \u{e9}""#),
+            Ok(("", "say \"hi\"\n\tok é".to_string()))
+        );
+        assert_eq!(
+            string_literal("\"one \\\n      two\""),
+            Ok(("", "one two".to_string()))
+        );
+        assert_eq!(
+            string_literal("\"line 1\nline 2\""),
+            Ok(("", "line 1\nline 2".to_string()))
+        );
+        assert_eq!(
+            string_literal(r##"r#"C:\path "quoted""#"##),
+            Ok(("", r#"C:\path "quoted""#.to_string()))
+        );
+        assert!(matches!(
+            string_literal(r#""bad \q""#),
+            Err(nom::Err::Failure(_))
+        ));
+    }
+
+    #[test]
+    fn test_number_and_color_literals() {
+        for (src, n) in [
+            ("0xff", 255.0),
+            ("0b1010", 10.0),
+            ("0o17", 15.0),
+            ("0x_ff_ff", 65535.0),
+            (".5", 0.5),
+            ("6.02e23", 6.02e23),
+            ("1e-3", 0.001),
+            ("-2.5E2", -250.0),
+        ] {
+            assert_eq!(number_literal(src), Ok(("", n)), "{}", src);
+        }
+        assert_eq!(number_literal("1..5"), Ok(("..5", 1.0)));
+
+        assert!(matches!(expr("#FF8000"), Ok(("", Expr::Color(c))) if c == "#ff8000"));
+        assert!(expr("#ff80").is_err());
+    }
+
+    #[test]
+    fn test_fstring_lowers_to_balanced_joins() {
+        let (rest, e) = expr(r#"f"a{x}b{y}c{{}}""#).unwrap();
+        assert_eq!(rest, "");
+        // 5 parts: ("a" x) (b (y "c{}"))
+        match e {
+            Expr::Call(name, args) => {
+                assert_eq!(name, "join");
+                assert!(matches!(&args[0], Expr::Call(n, _) if n == "join"));
+                assert!(matches!(&args[1], Expr::Call(n, _) if n == "join"));
+            }
+            other => panic!("Expected join, got {:?}", other),
+        }
+
+        let (_, e) = expr(r#"f"{score:08,.2}""#).unwrap();
+        match e {
+            Expr::Call(pad, args) => {
+                assert_eq!(pad, "format_padded");
+                assert!(matches!(args[1], Expr::Number(n) if n == 8.0));
+                assert!(matches!(&args[0], Expr::Call(n, inner)
+                    if n == "format_thousands"
+                        && matches!(&inner[0], Expr::Call(f, _) if f == "format_fixed")));
+            }
+            other => panic!("Expected format call, got {:?}", other),
+        }
+
+        assert!(matches!(expr(r#"f"{x""#), Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_if_expressions() {
+        let (rest, e) = expr("if a { 1 } else if b { 2 } else { 3 }").unwrap();
+        assert_eq!(rest, "");
+        match e {
+            Expr::If(_, then, otherwise) => {
+                assert!(matches!(*then, Expr::Number(n) if n == 1.0));
+                assert!(matches!(*otherwise, Expr::If(..)));
+            }
+            other => panic!("Expected if expression, got {:?}", other),
+        }
+
+        // `else` is required, and identifiers starting with `if` are not keywords
+        assert!(!matches!(expr("if a { 1 }"), Ok(("", _))));
+        assert!(matches!(expr("iffy"), Ok(("", Expr::Variable(v))) if v == "iffy"));
+
+        let (_, prog) = parse_program("if a { say(1); }\n").unwrap();
+        assert!(matches!(prog.items[0], Item::Stmt(Stmt::If(..))));
+    }
+
+    #[test]
+    fn test_tuples() {
+        assert!(matches!(expr("(a, b + 1)"), Ok(("", Expr::Tuple(items))) if items.len() == 2));
+        assert!(matches!(expr("(a)"), Ok(("", Expr::Variable(_)))));
+
+        let (_, prog) =
+            parse_program("proc f() -> (number, string) { let (a, _) = g(); return (a, \"x\"); }")
+                .unwrap();
+        let Item::Procedure(p) = &prog.items[0] else {
+            panic!("Expected a procedure");
+        };
+        assert_eq!(
+            p.return_type,
+            Some(Type::Tuple(vec![Type::Number, Type::String]))
+        );
+        assert!(
+            matches!(&p.body[0], Stmt::LetTuple(names, Expr::Call(..), _) if names == &["a", "_"])
+        );
+        assert!(matches!(&p.body[1], Stmt::Return(Some(Expr::Tuple(_)), _)));
+    }
+
+    #[test]
+    fn test_bitwise_precedence_and_int_div() {
+        // `flags & 4 == 4` tests a bit, and `|` binds tighter than `&&`
+        assert!(matches!(
+            expr("flags & 4 == 4"),
+            Ok(("", Expr::BinOp(l, Op::Eq, _))) if matches!(*l, Expr::BinOp(_, Op::BitAnd, _))
+        ));
+        assert!(matches!(
+            expr("a | b && c"),
+            Ok(("", Expr::BinOp(l, Op::And, _))) if matches!(*l, Expr::BinOp(_, Op::BitOr, _))
+        ));
+        assert!(matches!(
+            expr("1 << 2 + 3"),
+            Ok(("", Expr::BinOp(_, Op::Shl, r))) if matches!(*r, Expr::BinOp(_, Op::Add, _))
+        ));
+
+        // `//` always starts a comment, even when what follows looks like an
+        // operand
+        let (_, prog) = parse_program("score = 0 // 0 = reset\n;\n").unwrap();
+        assert!(matches!(
+            &prog.items[0],
+            Item::Stmt(Stmt::Assign(_, Expr::Number(_), _))
+        ));
+    }
+
+    #[test]
+    fn test_messages() {
+        let (_, prog) = parse_program(
+            "message Hit(damage: number, from: string);\nbroadcast Hit(5, \"enemy\");\nbroadcast(\"plain\");\nbroadcast_and_wait(\"x\");\n",
+        )
+        .unwrap();
+        assert!(
+            matches!(&prog.items[0], Item::Message(m) if m.name == "Hit" && m.params.len() == 2)
+        );
+        match &prog.items[1] {
+            Item::Stmt(Stmt::Expr(Expr::Call(name, args), _)) => {
+                assert_eq!(name, "broadcast");
+                assert!(matches!(&args[..], [Expr::Call(m, v)] if m == "Hit" && v.len() == 2));
+            }
+            other => panic!("Expected a typed broadcast, got {:?}", other),
+        }
+        assert!(matches!(
+            &prog.items[2],
+            Item::Stmt(Stmt::Expr(Expr::Call(_, args), _)) if matches!(&args[..], [Expr::String(_)])
+        ));
+        assert!(matches!(
+            &prog.items[3],
+            Item::Stmt(Stmt::Expr(Expr::Call(name, _), _)) if name == "broadcast_and_wait"
+        ));
+    }
+
+    #[test]
+    fn test_keywords_need_a_boundary() {
+        let (rest, prog) =
+            parse_program("mod enemy;\nmodx = 1;\nletter = true_count;\nlet use_it = 2;\n")
+                .unwrap();
+        assert!(rest.trim().is_empty(), "unparsed: {:?}", rest);
+        assert!(matches!(&prog.items[0], Item::Mod(name) if name == "enemy"));
+        assert!(matches!(&prog.items[1], Item::Stmt(Stmt::Assign(name, _, _)) if name == "modx"));
+        assert!(matches!(
+            &prog.items[2],
+            Item::Stmt(Stmt::Assign(name, Expr::Variable(v), _)) if name == "letter" && v == "true_count"
+        ));
+        assert!(matches!(&prog.items[3], Item::Stmt(Stmt::Let(name, _, _)) if name == "use_it"));
+    }
 }
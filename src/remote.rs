@@ -0,0 +1,662 @@
+use crate::ast::*;
+use crate::visit::{for_each_body_mut, walk_stmts, walk_stmts_exprs_mut};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Lowers calls to procedures of other targets, written `Player::jump(10)`,
+/// into broadcasts. `targets` holds each target's name and program, Stage
+/// first, before they are transformed.
+///
+/// The caller gets a stub procedure named `Player::jump` with the same
+/// parameters, which stores the arguments in globals on the stage and
+/// broadcasts `Player::jump` with `broadcast_and_wait`. The callee gets a
+/// script for that broadcast, which calls `jump` with the stored arguments
+/// and stores its return value for the caller to read. Clones of the callee
+/// receive the broadcast too, but only the original sprite runs the call.
+///
+/// Every caller shares the globals of a procedure, so a busy flag makes
+/// calls take turns: a stub waits until no other call of the procedure is
+/// running before it stores its arguments, and clears the flag once the
+/// callee has finished. Clicking the flag stops every script, so the callee
+/// also clears its flags when the flag is clicked.
+pub fn lower_remote_calls(
+    targets: &mut [(String, &mut Program)],
+    packages: &HashMap<String, Package>,
+) -> Result<()> {
+    let names: HashSet<String> = targets.iter().map(|(name, _)| name.clone()).collect();
+
+    // Remote procedures each target calls, as (target, procedure)
+    let mut calls: Vec<BTreeSet<(String, String)>> = Vec::new();
+    for (caller, program) in targets.iter_mut() {
+        let mut called = BTreeSet::new();
+        let mut error = None;
+        for_each_body_mut(program, &mut |stmts| {
+            walk_stmts_exprs_mut(stmts, &mut |expr| {
+                let Expr::Call(name, _) = expr else {
+                    return;
+                };
+                let Some((target, proc_name)) = name.split_once("::") else {
+                    return;
+                };
+                let is_target = names.contains(target);
+                let is_package = packages.contains_key(target);
+                match (is_target, is_package) {
+                    (true, true) => {
+                        error.get_or_insert(anyhow::anyhow!(
+                            "`{}` is ambiguous, because `{}` is both a sprite and a package",
+                            name,
+                            target
+                        ));
+                    }
+                    (true, false) if target == caller.as_str() => {
+                        // A target calling itself needs no broadcast
+                        *name = proc_name.to_string();
+                    }
+                    (true, false) => {
+                        called.insert((target.to_string(), proc_name.to_string()));
+                    }
+                    (false, true) => {}
+                    (false, false) => {
+                        error.get_or_insert(anyhow::anyhow!(
+                            "`{}` calls `{}` in an unknown sprite or package `{}`",
+                            caller,
+                            name,
+                            target
+                        ));
+                    }
+                }
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        calls.push(called);
+    }
+
+    let mut received = HashSet::new();
+    let mut answering = BTreeSet::new();
+    for (caller_index, called) in calls.into_iter().enumerate() {
+        for (target, proc_name) in called {
+            let callee_index = targets
+                .iter()
+                .position(|(name, _)| *name == target)
+                .expect("called target exists");
+            let proc = find_procedure(targets[callee_index].1, &proc_name)
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "`{}` calls `{}::{}`, but `{}` has no procedure `{}`",
+                        targets[caller_index].0,
+                        target,
+                        proc_name,
+                        target,
+                        proc_name
+                    )
+                })?
+                .clone();
+            check_signature(&target, &proc)?;
+
+            let remote = Remote::new(&target, &proc);
+            targets[caller_index].1.items.push(remote.stub());
+            if received.insert((target.clone(), proc_name.clone())) {
+                targets[callee_index]
+                    .1
+                    .items
+                    .extend(remote.receiver(callee_index == 0));
+                answering.insert(callee_index);
+            }
+        }
+    }
+    // The stage has no clones to tell apart
+    for index in answering {
+        if index > 0 {
+            targets[index].1.items.extend(clone_marker());
+        }
+    }
+    Ok(())
+}
+
+/// Set in clones of a sprite that answers remote calls, so the original is
+/// the one whose copy is 0.
+const IS_CLONE: &str = "_is_clone";
+
+/// The local telling clones from the original sprite, and the script
+/// setting it in each clone. A clone's `#[on_clone_start]` scripts start
+/// when it is created, so they run before any broadcast sent after that.
+fn clone_marker() -> Vec<Item> {
+    vec![
+        Item::Variable(VariableDecl {
+            name: IS_CLONE.to_string(),
+            attributes: vec![],
+            ty: Type::Unknown,
+            init: Expr::Number(0.0),
+            visibility: Visibility::Private,
+            comment: None,
+        }),
+        Item::Function(Function {
+            name: "_rpc_mark_clone".to_string(),
+            attributes: vec![Attribute {
+                name: "on_clone_start".to_string(),
+                args: vec![],
+            }],
+            params: vec![],
+            body: vec![Stmt::Assign(IS_CLONE.to_string(), Expr::Number(1.0), None)],
+            is_warp: false,
+            comment: None,
+        }),
+    ]
+}
+
+/// Properties `Sprite.field` can read, and their names in `sensing_of`.
+const SPRITE_PROPERTIES: &[(&str, &str)] = &[
+    ("x", "x position"),
+    ("y", "y position"),
+    ("direction", "direction"),
+    ("costume_number", "costume #"),
+    ("costume_name", "costume name"),
+    ("size", "size"),
+    ("volume", "volume"),
+];
+
+const STAGE_PROPERTIES: &[(&str, &str)] = &[
+    ("backdrop_number", "backdrop #"),
+    ("backdrop_name", "backdrop name"),
+    ("volume", "volume"),
+];
+
+/// Lowers reads of another target's state, written `Player.x` or
+/// `Player.my_variable`, into `of("x position", "Player")`. The property
+/// must be one `sensing_of` supports, or a variable declared in that
+/// target. Scratch can't set another target's variables, so assigning to
+/// one is an error. `targets` holds each target's name and program, Stage
+/// first.
+pub fn lower_remote_fields(targets: &mut [(String, &mut Program)]) -> Result<()> {
+    // The variables `sensing_of` can read: each sprite's own, and all
+    // public ones for the stage
+    let mut variables: HashMap<String, HashMap<String, &'static str>> = HashMap::new();
+    for (i, (name, program)) in targets.iter().enumerate() {
+        for item in &program.items {
+            let Item::Variable(decl) = item else {
+                continue;
+            };
+            let kind = match decl.ty {
+                Type::List | Type::StructList(_) => "list",
+                _ if decl.visibility == Visibility::Public && i > 0 => "public",
+                _ => "variable",
+            };
+            variables
+                .entry(name.clone())
+                .or_default()
+                .insert(decl.name.clone(), kind);
+            if kind == "public" {
+                variables
+                    .entry(targets[0].0.clone())
+                    .or_default()
+                    .insert(decl.name.clone(), "variable");
+            }
+        }
+    }
+    let names: Vec<String> = targets.iter().map(|(name, _)| name.clone()).collect();
+
+    for (_, program) in targets.iter_mut() {
+        let mut error = None;
+        for_each_body_mut(program, &mut |stmts| {
+            walk_stmts(stmts, &mut |stmt| {
+                if let Stmt::AssignPlace(Expr::Field(base, field), _, _) = stmt {
+                    if let Expr::Variable(target) = base.as_ref() {
+                        if names.contains(target) {
+                            error.get_or_insert(anyhow::anyhow!(
+                                "Can't assign to `{}.{}`: another sprite's variables are read-only; call a procedure of `{}` instead",
+                                target,
+                                field,
+                                target
+                            ));
+                        }
+                    }
+                }
+            });
+            walk_stmts_exprs_mut(stmts, &mut |expr| {
+                let Expr::Field(base, field) = expr else {
+                    return;
+                };
+                let Expr::Variable(target) = base.as_ref() else {
+                    return;
+                };
+                match property(target, field, &names, &variables) {
+                    Ok(property) => {
+                        *expr = Expr::Call(
+                            "of".to_string(),
+                            vec![Expr::String(property), Expr::String(target.clone())],
+                        );
+                    }
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// The `sensing_of` property for `target.field`.
+fn property(
+    target: &str,
+    field: &str,
+    names: &[String],
+    variables: &HashMap<String, HashMap<String, &'static str>>,
+) -> Result<String> {
+    let Some(index) = names.iter().position(|n| n == target) else {
+        bail!(
+            "`{}.{}` reads from `{}`, which isn't a sprite. Fields can be read from sprites ({}) and from struct list elements, e.g. `list[i].{}`",
+            target,
+            field,
+            target,
+            names.join(", "),
+            field
+        );
+    };
+    let properties = if index == 0 {
+        STAGE_PROPERTIES
+    } else {
+        SPRITE_PROPERTIES
+    };
+    if let Some((_, property)) = properties.iter().find(|(name, _)| *name == field) {
+        return Ok(property.to_string());
+    }
+    match variables.get(target).and_then(|v| v.get(field)) {
+        Some(&"variable") => Ok(field.to_string()),
+        Some(&"list") => bail!(
+            "`{}.{}` is a list, and only variables can be read from another sprite",
+            target,
+            field
+        ),
+        Some(_) => bail!(
+            "`{}` is public, so use it directly instead of `{}.{}`",
+            field,
+            target,
+            field
+        ),
+        None => {
+            let known: Vec<&str> = properties.iter().map(|(name, _)| *name).collect();
+            bail!(
+                "`{}` has no property or variable `{}`. {} {}, plus the variables declared in it",
+                target,
+                field,
+                if index == 0 {
+                    "The stage has"
+                } else {
+                    "Sprites have"
+                },
+                known.join(", ")
+            )
+        }
+    }
+}
+
+fn find_procedure<'a>(program: &'a Program, name: &str) -> Option<&'a ProcedureDef> {
+    program.items.iter().find_map(|item| match item {
+        Item::Procedure(p) if p.name == name => Some(p),
+        _ => None,
+    })
+}
+
+/// Only values that fit in a Scratch variable can be passed between targets.
+fn check_signature(target: &str, proc: &ProcedureDef) -> Result<()> {
+    let passable = |ty: &Type| {
+        matches!(
+            ty,
+            Type::Number | Type::String | Type::Boolean | Type::Unknown
+        )
+    };
+    for param in &proc.params {
+        if !passable(&param.ty) {
+            bail!(
+                "`{}::{}` can't be called from another sprite, because its parameter `{}` isn't a number, string or boolean",
+                target,
+                proc.name,
+                param.name
+            );
+        }
+    }
+    if let Some(ty) = &proc.return_type {
+        if !passable(ty) {
+            bail!(
+                "`{}::{}` can't be called from another sprite, because it doesn't return a number, string or boolean",
+                target,
+                proc.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The names generated for one remote procedure.
+struct Remote<'a> {
+    proc: &'a ProcedureDef,
+    /// `Player::jump`, both the stub's name and the broadcast
+    message: String,
+    /// Prefix of the globals holding arguments and the return value
+    prefix: String,
+}
+
+impl<'a> Remote<'a> {
+    fn new(target: &str, proc: &'a ProcedureDef) -> Self {
+        Remote {
+            proc,
+            message: format!("{}::{}", target, proc.name),
+            prefix: format!("_rpc_{}_{}", target, proc.name),
+        }
+    }
+
+    fn arg(&self, param: &Param) -> String {
+        format!("{}_{}", self.prefix, param.name)
+    }
+
+    fn ret(&self) -> String {
+        format!("{}_ret", self.prefix)
+    }
+
+    fn busy(&self) -> String {
+        format!("{}_busy", self.prefix)
+    }
+
+    /// The caller's procedure standing in for the remote one. It waits for
+    /// the busy flag before it stores the arguments in the shared globals.
+    fn stub(&self) -> Item {
+        let set_busy = |value: f64| Stmt::Assign(self.busy(), Expr::Number(value), None);
+        let mut body = vec![
+            Stmt::Expr(
+                Expr::Call(
+                    "wait_until".to_string(),
+                    vec![Expr::BinOp(
+                        Box::new(Expr::Variable(self.busy())),
+                        Op::Eq,
+                        Box::new(Expr::Number(0.0)),
+                    )],
+                ),
+                None,
+            ),
+            set_busy(1.0),
+        ];
+        body.extend(
+            self.proc
+                .params
+                .iter()
+                .map(|p| Stmt::Assign(self.arg(p), Expr::Variable(p.name.clone()), None)),
+        );
+        body.push(Stmt::Expr(
+            Expr::Call(
+                "broadcast_and_wait".to_string(),
+                vec![Expr::String(self.message.clone())],
+            ),
+            None,
+        ));
+        // Nothing yields between here and reading the return value
+        body.push(set_busy(0.0));
+        if self.proc.return_type.is_some() {
+            body.push(Stmt::Return(Some(Expr::Variable(self.ret())), None));
+        }
+        Item::Procedure(ProcedureDef {
+            name: self.message.clone(),
+            visibility: Visibility::Default,
+            attributes: vec![],
+            params: self.proc.params.clone(),
+            body,
+            return_type: self.proc.return_type.clone(),
+            is_warp: false,
+            comment: None,
+        })
+    }
+
+    /// The globals, the script the remote target runs the call with, and
+    /// the script clearing the busy flag when the flag is clicked.
+    fn receiver(&self, is_stage: bool) -> Vec<Item> {
+        let global = |name: String| {
+            Item::Variable(VariableDecl {
+                name,
+                attributes: vec![],
+                ty: Type::Unknown,
+                init: Expr::Number(0.0),
+                visibility: Visibility::Public,
+                comment: None,
+            })
+        };
+        let mut items = vec![global(self.busy())];
+        items.extend(self.proc.params.iter().map(|p| global(self.arg(p))));
+
+        let call = Expr::Call(
+            self.proc.name.clone(),
+            self.proc
+                .params
+                .iter()
+                .map(|p| Expr::Variable(self.arg(p)))
+                .collect(),
+        );
+        let mut stmt = if self.proc.return_type.is_some() {
+            items.push(global(self.ret()));
+            Stmt::Assign(self.ret(), call, None)
+        } else {
+            Stmt::Expr(call, None)
+        };
+        if !is_stage {
+            stmt = Stmt::If(
+                Expr::BinOp(
+                    Box::new(Expr::Variable(IS_CLONE.to_string())),
+                    Op::Eq,
+                    Box::new(Expr::Number(0.0)),
+                ),
+                vec![stmt],
+                None,
+                None,
+            );
+        }
+        let script = |name: String, attribute: &str, args: Vec<Expr>, body: Vec<Stmt>| {
+            Item::Function(Function {
+                name,
+                attributes: vec![Attribute {
+                    name: attribute.to_string(),
+                    args,
+                }],
+                params: vec![],
+                body,
+                is_warp: false,
+                comment: None,
+            })
+        };
+        items.push(script(
+            self.prefix.clone(),
+            "on_broadcast_received",
+            vec![Expr::String(self.message.clone())],
+            vec![stmt],
+        ));
+        items.push(script(
+            format!("{}_unlock", self.prefix),
+            "on_flag_clicked",
+            vec![],
+            vec![Stmt::Assign(self.busy(), Expr::Number(0.0), None)],
+        ));
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_program;
+    use crate::testing::{lower_targets, squashed_source};
+
+    fn lower(sources: &[(&str, &str)]) -> Result<Vec<Program>> {
+        lower_targets(sources, |targets| {
+            lower_remote_calls(targets, &HashMap::new())
+        })
+    }
+
+    #[test]
+    fn test_remote_calls() {
+        let programs = lower(&[
+            ("Stage", ""),
+            (
+                "Player",
+                "var hp = 10;\n\
+                 proc take_damage(amount: number) { hp = hp - amount; }\n\
+                 proc hp_left() -> number { return hp; }\n\
+                 proc wave() { say(\"hi\"); Player::wave(); }\n",
+            ),
+            (
+                "Enemy",
+                "#[on_flag_clicked]\n\
+                 fn attack() { Player::take_damage(5); say(Player::hp_left()); Player::wave(); }\n",
+            ),
+        ])
+        .unwrap();
+
+        let enemy = squashed_source(&programs[2]);
+        assert!(enemy.contains(
+            "proc Player::take_damage(amount: number) { \
+             wait_until(_rpc_Player_take_damage_busy == 0); \
+             _rpc_Player_take_damage_busy = 1; \
+             _rpc_Player_take_damage_amount = amount; \
+             broadcast_and_wait(\"Player::take_damage\"); \
+             _rpc_Player_take_damage_busy = 0; }"
+        ));
+        // Calls always wait, even without arguments or a return value
+        assert!(
+            enemy.contains("broadcast_and_wait(\"Player::wave\"); _rpc_Player_wave_busy = 0; }")
+        );
+        assert!(enemy.contains("_rpc_Player_hp_left_busy = 0; return _rpc_Player_hp_left_ret; }"));
+
+        let player = squashed_source(&programs[1]);
+        assert!(player.contains(
+            "#[on_broadcast_received(\"Player::take_damage\")] \
+             fn _rpc_Player_take_damage() { \
+             if _is_clone == 0 { take_damage(_rpc_Player_take_damage_amount); } }"
+        ));
+        assert!(player.contains(
+            "#[on_flag_clicked] fn _rpc_Player_take_damage_unlock() { \
+             _rpc_Player_take_damage_busy = 0; }"
+        ));
+        assert!(player.contains("#[on_clone_start] fn _rpc_mark_clone() { _is_clone = 1; }"));
+        let globals: Vec<&str> = programs[1]
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Variable(v) if v.visibility == Visibility::Public => Some(v.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            globals,
+            vec![
+                "_rpc_Player_hp_left_busy",
+                "_rpc_Player_hp_left_ret",
+                "_rpc_Player_take_damage_busy",
+                "_rpc_Player_take_damage_amount",
+                "_rpc_Player_wave_busy",
+            ]
+        );
+        // The marker is added once, however many procedures are called
+        assert_eq!(player.matches("var _is_clone = 0;").count(), 1);
+
+        // Player's own call to `Player::wave` stays local
+        let Some(Item::Procedure(wave)) = programs[1].items.get(3) else {
+            panic!("Expected wave");
+        };
+        assert!(matches!(&wave.body[1], Stmt::Expr(Expr::Call(name, _), _) if name == "wave"));
+    }
+
+    #[test]
+    fn test_remote_fields() {
+        let src = "var hp = 3;\npublic var score = 0;\nlist items = [];\n";
+        let read = |field: &str| -> Result<Expr> {
+            let mut stage = parse_program("var level = 1;").unwrap().1;
+            let mut player = parse_program(src).unwrap().1;
+            let mut enemy = parse_program(&format!("proc f() {{ say({}); }}", field))
+                .unwrap()
+                .1;
+            let mut targets = vec![
+                ("Stage".to_string(), &mut stage),
+                ("Player".to_string(), &mut player),
+                ("Enemy".to_string(), &mut enemy),
+            ];
+            lower_remote_fields(&mut targets)?;
+            let Some(Item::Procedure(f)) = enemy.items.pop() else {
+                panic!("Expected f");
+            };
+            let Some(Stmt::Expr(Expr::Call(_, mut args), _)) = f.body.into_iter().next() else {
+                panic!("Expected say");
+            };
+            Ok(args.remove(0))
+        };
+        let of = |field: &str| match read(field).unwrap() {
+            Expr::Call(name, args) if name == "of" => match &args[..] {
+                [Expr::String(property), Expr::String(target)] => {
+                    format!("{} of {}", property, target)
+                }
+                _ => panic!("Expected literal arguments"),
+            },
+            other => panic!("Expected `of`, got {:?}", other),
+        };
+        assert_eq!(of("Player.x"), "x position of Player");
+        assert_eq!(of("Player.costume_name"), "costume name of Player");
+        assert_eq!(of("Player.hp"), "hp of Player");
+        assert_eq!(of("Stage.backdrop_number"), "backdrop # of Stage");
+        assert_eq!(of("Stage.score"), "score of Stage");
+
+        let err = |field: &str| read(field).unwrap_err().to_string();
+        assert_eq!(
+            err("Player.x_postion"),
+            "`Player` has no property or variable `x_postion`. Sprites have x, y, direction, costume_number, costume_name, size, volume, plus the variables declared in it"
+        );
+        assert!(err("Playr.x").starts_with("`Playr.x` reads from `Playr`, which isn't a sprite"));
+        assert!(
+            err("Stage.x").starts_with("`Stage` has no property or variable `x`. The stage has")
+        );
+        assert_eq!(
+            err("Player.items"),
+            "`Player.items` is a list, and only variables can be read from another sprite"
+        );
+        assert_eq!(
+            err("Player.score"),
+            "`score` is public, so use it directly instead of `Player.score`"
+        );
+
+        // Writes would compile to nothing, so they are rejected
+        let mut stage = parse_program("").unwrap().1;
+        let mut player = parse_program(src).unwrap().1;
+        let mut enemy = parse_program("proc f() { if true { Player.hp = 5; } }")
+            .unwrap()
+            .1;
+        let mut targets = vec![
+            ("Stage".to_string(), &mut stage),
+            ("Player".to_string(), &mut player),
+            ("Enemy".to_string(), &mut enemy),
+        ];
+        assert_eq!(
+            lower_remote_fields(&mut targets).unwrap_err().to_string(),
+            "Can't assign to `Player.hp`: another sprite's variables are read-only; call a procedure of `Player` instead"
+        );
+    }
+
+    #[test]
+    fn test_remote_call_errors() {
+        let err = lower(&[("Stage", ""), ("Enemy", "proc f() { Ghost::boo(); }")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`Enemy` calls `Ghost::boo` in an unknown sprite or package `Ghost`"
+        );
+        let err = lower(&[("Stage", "proc f() { Enemy::boo(); }"), ("Enemy", "")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`Stage` calls `Enemy::boo`, but `Enemy` has no procedure `boo`"
+        );
+        let err = lower(&[
+            ("Stage", "proc f() { Enemy::sort(); }"),
+            ("Enemy", "proc sort(l: list) {}"),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("its parameter `l` isn't a number"));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::ast::*;
+use crate::visit::walk_stmts_exprs;
+use std::collections::HashSet;
+
+/// Broadcast every target resets its variables on.
+const RESET_MESSAGE: &str = "_reset_variables";
+
+/// Broadcast the `#[on_flag_clicked]` scripts run on once the reset is done.
+const FLAG_MESSAGE: &str = "_flag_clicked";
+
+/// Builtins that change the variable or list named by their first argument.
+const MUTATING_BUILTINS: &[&str] = &[
+    "set_variable",
+    "change_variable_by",
+    "add_to_list",
+    "delete_of_list",
+    "delete_all_of_list",
+    "insert_at_list",
+    "replace_item_of_list",
+    "replace_item_list",
+];
+
+const MUTATING_METHODS: &[&str] = &["push", "insert", "remove", "clear"];
+
+/// Resets variables and lists to their declared values when the green flag
+/// is clicked, before any `#[on_flag_clicked]` script runs. `targets` holds
+/// each target's name and program, Stage first, after they are transformed.
+///
+/// Each target gets a script that resets the variables it declares, for a
+/// broadcast the stage sends with `broadcast_and_wait` when the flag is
+/// clicked. The `#[on_flag_clicked]` scripts run on a second broadcast sent
+/// after that. Clicking the flag stops every script first, so nothing runs
+/// while the values are reset. Variables and lists no script changes keep
+/// their saved values and aren't reset.
+pub fn add_flag_reset(targets: &mut [(String, &mut Program)]) {
+    let mut changed = HashSet::new();
+    for (_, program) in targets.iter() {
+        for item in &program.items {
+            match item {
+                Item::Procedure(p) => collect_changes(&p.body, &mut changed),
+                Item::Function(f) => collect_changes(&f.body, &mut changed),
+                Item::Stmt(stmt) => collect_changes(std::slice::from_ref(stmt), &mut changed),
+                _ => {}
+            }
+        }
+    }
+
+    // Globals may be declared by several targets, but are reset once
+    let mut reset_globals = HashSet::new();
+    for (i, (_, program)) in targets.iter_mut().enumerate() {
+        let mut body = Vec::new();
+        for item in &program.items {
+            let Item::Variable(decl) = item else {
+                continue;
+            };
+            let is_global = i == 0 || decl.visibility == Visibility::Public;
+            if !changed.contains(&decl.name)
+                || (is_global && !reset_globals.insert(decl.name.clone()))
+            {
+                continue;
+            }
+            body.extend(reset_stmts(decl));
+        }
+
+        for item in &mut program.items {
+            if let Item::Function(f) = item {
+                for attr in &mut f.attributes {
+                    if attr.name == "on_flag_clicked" {
+                        *attr = Attribute {
+                            name: "on_broadcast_received".to_string(),
+                            args: vec![Expr::String(FLAG_MESSAGE.to_string())],
+                        };
+                    }
+                }
+            }
+        }
+        if !body.is_empty() {
+            program.items.push(Item::Function(Function {
+                name: RESET_MESSAGE.to_string(),
+                attributes: vec![Attribute {
+                    name: "on_broadcast_received".to_string(),
+                    args: vec![Expr::String(RESET_MESSAGE.to_string())],
+                }],
+                params: vec![],
+                body,
+                is_warp: false,
+                comment: None,
+            }));
+        }
+    }
+
+    let broadcast = |name: &str, message: &str| {
+        Stmt::Expr(
+            Expr::Call(name.to_string(), vec![Expr::String(message.to_string())]),
+            None,
+        )
+    };
+    targets[0].1.items.push(Item::Function(Function {
+        name: "_green_flag".to_string(),
+        attributes: vec![Attribute {
+            name: "on_flag_clicked".to_string(),
+            args: vec![],
+        }],
+        params: vec![],
+        body: vec![
+            broadcast("broadcast_and_wait", RESET_MESSAGE),
+            broadcast("broadcast", FLAG_MESSAGE),
+        ],
+        is_warp: false,
+        comment: None,
+    }));
+}
+
+/// Names of the variables and lists `stmts` change.
+fn collect_changes(stmts: &[Stmt], changed: &mut HashSet<String>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(name, _, _) => {
+                changed.insert(name.clone());
+            }
+            Stmt::AssignPlace(Expr::Index(base, _), _, _) => {
+                if let Expr::Variable(name) = base.as_ref() {
+                    changed.insert(name.clone());
+                }
+            }
+            Stmt::If(_, then, otherwise, _) => {
+                collect_changes(then, changed);
+                if let Some(otherwise) = otherwise {
+                    collect_changes(otherwise, changed);
+                }
+            }
+            Stmt::Repeat(_, body, _)
+            | Stmt::Forever(body, _)
+            | Stmt::Until(_, body, _)
+            | Stmt::CBlock(_, _, body, _) => collect_changes(body, changed),
+            Stmt::Match(_, arms, default, _) => {
+                for arm in arms {
+                    collect_changes(&arm.body, changed);
+                }
+                if let Some(default) = default {
+                    collect_changes(default, changed);
+                }
+            }
+            _ => {}
+        }
+    }
+    walk_stmts_exprs(stmts, &mut |expr| match expr {
+        Expr::Call(name, args) if MUTATING_BUILTINS.contains(&name.as_str()) => {
+            if let Some(Expr::Variable(target) | Expr::String(target)) = args.first() {
+                changed.insert(target.clone());
+            }
+        }
+        Expr::MethodCall(base, method, _) if MUTATING_METHODS.contains(&method.as_str()) => {
+            if let Expr::Variable(target) = base.as_ref() {
+                changed.insert(target.clone());
+            }
+        }
+        _ => {}
+    });
+}
+
+/// Statements setting a variable or list back to its declared value.
+fn reset_stmts(decl: &VariableDecl) -> Vec<Stmt> {
+    let call = |name: &str, args: Vec<Expr>| Stmt::Expr(Expr::Call(name.to_string(), args), None);
+    let list = || Expr::Variable(decl.name.clone());
+    match &decl.ty {
+        Type::List | Type::StructList(_) => {
+            let mut stmts = vec![call("delete_all_of_list", vec![list()])];
+            if let Expr::List(items) = &decl.init {
+                for item in items {
+                    stmts.push(call("add_to_list", vec![list(), item.clone()]));
+                }
+            }
+            stmts
+        }
+        _ => vec![Stmt::Assign(decl.name.clone(), decl.init.clone(), None)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen::CodeGenerator;
+    use crate::parser::parse_program;
+
+    #[test]
+    fn test_flag_reset() {
+        let mut stage = parse_program("public var score = 0;\npublic var title = \"Game\";")
+            .unwrap()
+            .1;
+        let mut player = parse_program(
+            "public var score = 0;\nvar hp = 10;\nlist path = [1, 2];\nlist table = [5, 6];\n#[on_flag_clicked]\nfn start() { score += 1; hp = 3; path.push(table[1]); }",
+        )
+        .unwrap()
+        .1;
+        let mut targets = vec![
+            ("Stage".to_string(), &mut stage),
+            ("Player".to_string(), &mut player),
+        ];
+        add_flag_reset(&mut targets);
+        let squash = |p: &Program| {
+            CodeGenerator::new()
+                .generate(p)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let (stage, player) = (squash(&stage), squash(&player));
+
+        // `title` and `table` never change, and `score` is reset once
+        assert!(stage.contains(
+            "#[on_broadcast_received(\"_reset_variables\")] fn _reset_variables() { score = 0; }"
+        ));
+        assert!(stage.contains(
+            "#[on_flag_clicked] fn _green_flag() { broadcast_and_wait(\"_reset_variables\"); broadcast(\"_flag_clicked\"); }"
+        ));
+        assert!(player.contains("#[on_broadcast_received(\"_flag_clicked\")] fn start()"));
+        assert!(player.contains(
+            "fn _reset_variables() { hp = 10; delete_all_of_list(path); add_to_list(path, 1); add_to_list(path, 2); }"
+        ));
+    }
+}
@@ -69,6 +69,7 @@ pub struct Target {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum Block {
     Normal(NormalBlock),
     TopLevelPrimitive(TopLevelPrimitive), // For variables/lists dropped on workspace? Rarely used in JSON but possible
@@ -0,0 +1,409 @@
+use crate::ast::{Attribute, Item, Program, Visibility};
+use crate::config::{ScrustConfig, TargetConfig};
+use crate::{format_parse_error, format_path, parser};
+use anyhow::{bail, Context, Result};
+use colored::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Source files in this directory next to `scrust.toml` are part of every
+/// target, unless `shared` in `[project]` names another one.
+pub const SHARED_DIR: &str = "shared";
+
+/// The project's shared source files, sorted by name.
+pub fn shared_files(config: &ScrustConfig, config_dir: &Path) -> Result<Vec<PathBuf>> {
+    let dir = match &config.project.shared {
+        Some(dir) => {
+            let dir = config_dir.join(dir);
+            if !dir.is_dir() {
+                bail!("The shared directory {} doesn't exist", format_path(&dir));
+            }
+            dir
+        }
+        None => config_dir.join(SHARED_DIR),
+    };
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut files = Vec::new();
+    for entry in
+        fs::read_dir(&dir).with_context(|| format!("Failed to read {}", format_path(&dir)))?
+    {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "sr") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The files a stage or sprite is made of, from `path` or `paths`.
+pub fn target_files(target: &TargetConfig, label: &str, config_dir: &Path) -> Result<Vec<PathBuf>> {
+    let files = match (&target.path, &target.paths) {
+        (Some(path), None) => vec![path.clone()],
+        (None, Some(paths)) if !paths.is_empty() => paths.clone(),
+        (Some(_), Some(_)) => bail!("{} has both `path` and `paths`; use one of them", label),
+        _ => bail!("{} needs a `path` or a list of `paths`", label),
+    };
+    Ok(files
+        .into_iter()
+        .map(|f| {
+            if f.is_absolute() {
+                f
+            } else {
+                config_dir.join(f)
+            }
+        })
+        .collect())
+}
+
+/// Parses the shared files and a target's own files, and the modules their
+/// `mod` items include, into one program. Each file's items take the place
+/// of its `mod` item, and every file is included once. A module that
+/// includes itself, directly or through others, is an error.
+///
+/// Shared procedures are marked `#[shared]` so the compiler can tell how
+/// much they add to each target, and shared variables are made `public`, so
+/// all targets use the stage's copy.
+pub fn load_target(shared: &[PathBuf], files: &[PathBuf]) -> Result<(Program, Origins)> {
+    let mut loader = Loader {
+        in_shared: true,
+        ..Default::default()
+    };
+    for file in shared {
+        loader.load(file)?;
+    }
+    loader.in_shared = false;
+    for file in files {
+        loader.load(file)?;
+    }
+    Ok((
+        Program {
+            items: loader.items,
+        },
+        loader.origins,
+    ))
+}
+
+/// The kind and name errors use for an item, if it has a name.
+fn item_key(item: &Item) -> Option<(&'static str, &str)> {
+    Some(match item {
+        Item::Procedure(p) => ("procedure", &p.name),
+        Item::Function(f) => ("function", &f.name),
+        Item::Variable(v) => ("variable", &v.name),
+        Item::Struct(s) => ("struct", &s.name),
+        Item::Enum(e) => ("enum", &e.name),
+        Item::Message(m) => ("message", &m.name),
+        _ => return None,
+    })
+}
+
+/// Names the item an error is about. The transform and the compiler attach
+/// it to their errors, and `Origins` adds the file and line it came from.
+#[derive(Debug)]
+pub struct ItemContext {
+    kind: &'static str,
+    name: String,
+    origin: Option<(PathBuf, usize)>,
+}
+
+impl ItemContext {
+    pub fn of(item: &Item) -> Option<ItemContext> {
+        let (kind, name) = item_key(item)?;
+        Some(ItemContext::new(kind, name))
+    }
+
+    pub fn new(kind: &'static str, name: &str) -> ItemContext {
+        ItemContext {
+            kind,
+            name: name.to_string(),
+            origin: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ItemContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "In {} `{}`", self.kind, self.name)?;
+        if let Some((path, line)) = &self.origin {
+            write!(f, " at {}:{}", format_path(path), line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where each item of a target was defined. Functions may share a name, so
+/// one whose name is used twice has no origin.
+#[derive(Debug, Default, Clone)]
+pub struct Origins(HashMap<(&'static str, String), Option<(PathBuf, usize)>>);
+
+impl Origins {
+    /// Adds the file and line to the item `error` is about.
+    pub fn locate(&self, mut error: anyhow::Error) -> anyhow::Error {
+        if let Some(item) = error.downcast_mut::<ItemContext>() {
+            item.origin = self.origin(item.kind, &item.name);
+        }
+        error
+    }
+
+    /// The context for errors about the item `kind` `name`.
+    pub fn context(&self, kind: &'static str, name: &str) -> ItemContext {
+        ItemContext {
+            origin: self.origin(kind, name),
+            ..ItemContext::new(kind, name)
+        }
+    }
+
+    pub fn contains(&self, kind: &'static str, name: &str) -> bool {
+        self.0.contains_key(&(kind, name.to_string()))
+    }
+
+    fn origin(&self, kind: &'static str, name: &str) -> Option<(PathBuf, usize)> {
+        self.0.get(&(kind, name.to_string())).cloned().flatten()
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    items: Vec<Item>,
+    /// Files being loaded, outermost first
+    stack: Vec<PathBuf>,
+    loaded: HashSet<PathBuf>,
+    /// Where each item was defined
+    origins: Origins,
+    /// Loading the shared files and the modules they include
+    in_shared: bool,
+}
+
+impl Loader {
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let key = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(start) = self.stack.iter().position(|p| *p == key) {
+            let cycle: Vec<String> = self.stack[start..]
+                .iter()
+                .chain(std::iter::once(&key))
+                .map(|p| format_path(p))
+                .collect();
+            bail!("Modules include each other: {}", cycle.join(" → "));
+        }
+        if !self.loaded.insert(key.clone()) {
+            return Ok(());
+        }
+
+        let src = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", format_path(path)))?;
+        let (rest, items) = parser::parse_items(&src).map_err(|e| {
+            anyhow::anyhow!("In {}: {}", format_path(path), format_parse_error(e, &src))
+        })?;
+        if !rest.trim().is_empty() {
+            println!(
+                "{}",
+                format!(
+                    "Warning: {} parsing stopped early at line {}. Remaining: {:.50}...",
+                    format_path(path),
+                    src[..src.len() - rest.len()].lines().count().max(1),
+                    rest
+                )
+                .yellow()
+            );
+        }
+
+        self.stack.push(key);
+        for (at, item) in items {
+            if let Item::Mod(name) = &item {
+                let dir = path.parent().unwrap_or(Path::new("."));
+                let module = dir.join(format!("{}.sr", name));
+                if !module.is_file() {
+                    bail!(
+                        "In {}: `mod {};` needs {}, which doesn't exist",
+                        format_path(path),
+                        name,
+                        format_path(&module)
+                    );
+                }
+                self.load(&module)?;
+                continue;
+            }
+            let line = src[..src.len() - at.len()].matches('\n').count() + 1;
+            self.define(&item, path, line)?;
+            let item = if self.in_shared {
+                share(item, path)?
+            } else {
+                item
+            };
+            self.items.push(item);
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// Files of one target share a namespace, so a name defined in two of
+    /// them is an error.
+    fn define(&mut self, item: &Item, path: &Path, line: usize) -> Result<()> {
+        let Some((kind, name)) = item_key(item) else {
+            return Ok(());
+        };
+        let origin = (path.to_path_buf(), line);
+        match self.origins.0.get_mut(&(kind, name.to_string())) {
+            None => {
+                self.origins
+                    .0
+                    .insert((kind, name.to_string()), Some(origin));
+            }
+            Some(first) if kind == "function" => *first = None,
+            Some(Some((first, _))) if first != path => bail!(
+                "The {} `{}` is defined in both {} and {}",
+                kind,
+                name,
+                format_path(first),
+                format_path(path)
+            ),
+            Some(_) => {}
+        }
+        Ok(())
+    }
+}
+
+fn share(item: Item, path: &Path) -> Result<Item> {
+    Ok(match item {
+        Item::Procedure(mut proc) => {
+            proc.attributes.push(Attribute {
+                name: "shared".to_string(),
+                args: vec![],
+            });
+            Item::Procedure(proc)
+        }
+        Item::Variable(mut decl) => {
+            decl.visibility = Visibility::Public;
+            Item::Variable(decl)
+        }
+        Item::Struct(_)
+        | Item::Enum(_)
+        | Item::Message(_)
+        | Item::Use(_)
+        | Item::Comment(_)
+        | Item::BatchBreak => item,
+        Item::Function(func) => bail!(
+            "In {}: shared files can't have scripts like `fn {}`, since every sprite would run them. Put it in a sprite, or call a shared procedure from one",
+            format_path(path),
+            func.name
+        ),
+        _ => bail!(
+            "In {}: shared files can only have procedures, variables, lists, structs, enums and messages",
+            format_path(path)
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mod_includes() {
+        let dir = std::env::temp_dir().join(format!("scrust-mods-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, src: &str| fs::write(dir.join(name), src).unwrap();
+        write("main.sr", "mod physics;\nmod util;\nproc main() {}\n");
+        write("physics.sr", "mod util;\nvar vy = 0;\n");
+        write("util.sr", "proc clamp() {}\n");
+
+        // `util` is included once, where `physics` first asks for it
+        let (program, _) = load_target(&[], &[dir.join("main.sr")]).unwrap();
+        let names: Vec<&str> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Procedure(p) => Some(p.name.as_str()),
+                Item::Variable(v) => Some(v.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["clamp", "vy", "main"]);
+
+        write("extra.sr", "var vy = 1;\n");
+        let err = load_target(&[], &[dir.join("main.sr"), dir.join("extra.sr")]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("The variable `vy` is defined in both "));
+
+        write("util.sr", "mod main;\n");
+        let err = load_target(&[], &[dir.join("main.sr")]).unwrap_err();
+        let prefix = format!("{}/", format_path(&fs::canonicalize(&dir).unwrap()));
+        assert_eq!(
+            err.to_string().replace(&prefix, ""),
+            "Modules include each other: main.sr → physics.sr → util.sr → main.sr"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_shared_files() {
+        let dir = std::env::temp_dir().join(format!("scrust-shared-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("shared.sr"), "var GRAVITY = 2;\nproc fall() {}\n").unwrap();
+        fs::write(dir.join("sprite.sr"), "proc jump() {}\n").unwrap();
+
+        let (program, _) = load_target(&[dir.join("shared.sr")], &[dir.join("sprite.sr")]).unwrap();
+        assert!(
+            matches!(&program.items[0], Item::Variable(v) if v.visibility == Visibility::Public)
+        );
+        let shared: Vec<(&str, bool)> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Procedure(p) => Some((
+                    p.name.as_str(),
+                    p.attributes.iter().any(|a| a.name == "shared"),
+                )),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(shared, vec![("fall", true), ("jump", false)]);
+
+        fs::write(dir.join("shared.sr"), "#[on_flag_clicked]\nfn start() {}\n").unwrap();
+        let err = load_target(&[dir.join("shared.sr")], &[]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("shared files can't have scripts like `fn start`"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_errors_name_file_and_line() {
+        let dir = std::env::temp_dir().join(format!("scrust-origins-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("main.sr"),
+            "mod physics;\nproc main() { fall(); }\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("physics.sr"),
+            "var vy = 0;\n\n// Moves down\nproc fall(a: number = vy) {}\n",
+        )
+        .unwrap();
+
+        let (mut program, origins) = load_target(&[], &[dir.join("main.sr")]).unwrap();
+        let err =
+            crate::transform::transform_program(&mut program, &HashMap::new(), &Default::default())
+                .map_err(|e| origins.locate(e))
+                .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "In procedure `fall` at {}:4: Default for `a` in `fall` must be a literal or an enum variant",
+                format_path(&dir.join("physics.sr"))
+            )
+        );
+
+        // Functions may share a name, so those aren't located
+        assert_eq!(
+            origins.context("function", "go").to_string(),
+            "In function `go`"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
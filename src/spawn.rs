@@ -0,0 +1,361 @@
+use crate::ast::*;
+use crate::visit::{for_each_body_mut, walk_stmts_exprs_mut};
+use anyhow::{bail, Result};
+use std::collections::{BTreeSet, HashMap};
+
+/// Lowers `spawn_clone(Bullet { x: 10, dir: 90 })`, which creates a clone
+/// that receives the values in `#[on_clone_start] fn init(x: number, dir:
+/// number)`. `targets` holds each target's name and program, Stage first,
+/// before remote calls are lowered.
+///
+/// A clone starts with a copy of its sprite's local variables, so the sprite
+/// stores the values in generated locals just before `create_clone_of`, and
+/// the clone's `init` script passes them to a procedure holding its body.
+/// Another sprite can't set those locals, so its spawns become remote calls
+/// to `Bullet::_spawn_clone`, which take turns and which only the original
+/// sprite answers (see `lower_remote_calls`).
+pub fn lower_clone_spawns(targets: &mut [(String, &mut Program)]) -> Result<()> {
+    // The values each sprite's clones take
+    let mut clone_params: HashMap<String, Vec<Param>> = HashMap::new();
+    for (i, (name, program)) in targets.iter_mut().enumerate() {
+        let params = lower_clone_handler(program, i == 0)?;
+        if i > 0 {
+            clone_params.insert(name.clone(), params);
+        }
+    }
+
+    // Sprites spawned by any target
+    let mut spawned: BTreeSet<String> = BTreeSet::new();
+    for (caller, program) in targets.iter_mut() {
+        let mut error = None;
+        for_each_body_mut(program, &mut |stmts| {
+            walk_stmts_exprs_mut(stmts, &mut |expr| {
+                let Expr::Call(name, args) = expr else {
+                    return;
+                };
+                if name != "spawn_clone" {
+                    return;
+                }
+                match spawn_values(args, &clone_params) {
+                    Ok((sprite, values)) => {
+                        *expr = if sprite != *caller {
+                            Expr::Call(format!("{}::_spawn_clone", sprite), values)
+                        } else {
+                            Expr::Call("_spawn_clone".to_string(), values)
+                        };
+                        spawned.insert(sprite);
+                    }
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                }
+            })
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+
+    for (name, program) in targets.iter_mut() {
+        let Some(params) = clone_params.get(name.as_str()) else {
+            continue;
+        };
+        if spawned.contains(name) {
+            program.items.extend(spawner(params));
+        }
+    }
+    Ok(())
+}
+
+/// Splits the `#[on_clone_start]` handler taking parameters, if any, into a
+/// script and a procedure with its body, and returns the parameters.
+fn lower_clone_handler(program: &mut Program, is_stage: bool) -> Result<Vec<Param>> {
+    let mut handlers = program.items.iter().filter_map(|item| match item {
+        Item::Function(f)
+            if !f.params.is_empty() && f.attributes.iter().any(|a| a.name == "on_clone_start") =>
+        {
+            Some(f.name.clone())
+        }
+        _ => None,
+    });
+    let Some(handler) = handlers.next() else {
+        return Ok(vec![]);
+    };
+    if let Some(other) = handlers.next() {
+        bail!(
+            "`{}` and `{}` both take parameters in `#[on_clone_start]`; only one handler can receive the values from `spawn_clone`",
+            handler,
+            other
+        );
+    }
+    if is_stage {
+        bail!(
+            "The stage can't be cloned, so its `#[on_clone_start]` handler `{}` can't take parameters",
+            handler
+        );
+    }
+
+    let index = program
+        .items
+        .iter()
+        .position(|i| matches!(i, Item::Function(f) if f.name == handler))
+        .unwrap();
+    let Item::Function(func) = program.items.remove(index) else {
+        unreachable!()
+    };
+    for param in &func.params {
+        if !matches!(
+            param.ty,
+            Type::Number | Type::String | Type::Boolean | Type::Unknown
+        ) {
+            bail!(
+                "`{}` can't take `{}`, because clone values must be numbers, strings or booleans",
+                func.name,
+                param.name
+            );
+        }
+    }
+
+    let body_name = format!("_clone_{}", func.name);
+    let call = Expr::Call(
+        body_name.clone(),
+        func.params
+            .iter()
+            .map(|p| Expr::Variable(local(p)))
+            .collect(),
+    );
+    let params = func.params.clone();
+    program.items.insert(
+        index,
+        Item::Function(Function {
+            name: func.name,
+            attributes: func
+                .attributes
+                .into_iter()
+                .filter(|a| a.name != "warp" && a.name != "nowarp")
+                .collect(),
+            params: vec![],
+            body: vec![Stmt::Expr(call, None)],
+            is_warp: false,
+            comment: func.comment,
+        }),
+    );
+    program.items.insert(
+        index,
+        Item::Procedure(ProcedureDef {
+            name: body_name,
+            attributes: vec![],
+            visibility: Visibility::Default,
+            params: func.params,
+            body: func.body,
+            return_type: None,
+            is_warp: func.is_warp,
+            comment: None,
+        }),
+    );
+    Ok(params)
+}
+
+/// The local a clone value is handed over in.
+fn local(param: &Param) -> String {
+    format!("_clone_{}", param.name)
+}
+
+/// The sprite spawned by a `spawn_clone` call, and its values in the order
+/// of the clone handler's parameters.
+fn spawn_values(
+    args: &[Expr],
+    clone_params: &HashMap<String, Vec<Param>>,
+) -> Result<(String, Vec<Expr>)> {
+    let (sprite, fields) = match args {
+        [Expr::StructLit(name, fields)] => (name, fields.as_slice()),
+        [Expr::Variable(name)] | [Expr::String(name)] => (name, [].as_slice()),
+        _ => bail!(
+            "`spawn_clone` needs a sprite and the values for its clone, e.g. `spawn_clone(Bullet {{ x: 10 }})`"
+        ),
+    };
+    let Some(params) = clone_params.get(sprite) else {
+        if sprite == "Stage" {
+            bail!("`spawn_clone` can't clone the stage; only sprites have clones");
+        }
+        let mut sprites: Vec<&str> = clone_params.keys().map(|s| s.as_str()).collect();
+        sprites.sort();
+        bail!(
+            "`spawn_clone` names an unknown sprite `{}`. Sprites are: {}",
+            sprite,
+            sprites.join(", ")
+        );
+    };
+
+    for (field, _) in fields {
+        if !params.iter().any(|p| p.name == *field) {
+            let names: Vec<&str> = params.iter().map(|p| p.name.as_str()).collect();
+            bail!(
+                "`{}` clones have no value `{}`. Its `#[on_clone_start]` handler takes: {}",
+                sprite,
+                field,
+                if names.is_empty() {
+                    "nothing".to_string()
+                } else {
+                    names.join(", ")
+                }
+            );
+        }
+    }
+    let mut values = Vec::new();
+    for param in params {
+        match fields.iter().find(|(field, _)| *field == param.name) {
+            Some((_, value)) => values.push(value.clone()),
+            None => bail!(
+                "`spawn_clone({} {{ .. }})` is missing `{}`",
+                sprite,
+                param.name
+            ),
+        }
+    }
+    Ok((sprite.clone(), values))
+}
+
+/// The locals holding clone values, and `_spawn_clone`, which fills them in
+/// and creates the clone.
+fn spawner(params: &[Param]) -> Vec<Item> {
+    let local_var = |name: String| {
+        Item::Variable(VariableDecl {
+            name,
+            attributes: vec![],
+            ty: Type::Unknown,
+            init: Expr::Number(0.0),
+            visibility: Visibility::Private,
+            comment: None,
+        })
+    };
+    let mut items: Vec<Item> = params.iter().map(|p| local_var(local(p))).collect();
+
+    let mut body: Vec<Stmt> = params
+        .iter()
+        .map(|p| Stmt::Assign(local(p), Expr::Variable(p.name.clone()), None))
+        .collect();
+    body.push(Stmt::Expr(
+        Expr::Call(
+            "create_clone_of".to_string(),
+            vec![Expr::String("myself".to_string())],
+        ),
+        None,
+    ));
+    items.push(Item::Procedure(ProcedureDef {
+        name: "_spawn_clone".to_string(),
+        attributes: vec![],
+        visibility: Visibility::Default,
+        params: params.to_vec(),
+        body,
+        return_type: None,
+        is_warp: false,
+        comment: None,
+    }));
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{lower_targets, squashed_source};
+
+    fn lower(sources: &[(&str, &str)]) -> Result<Vec<String>> {
+        let programs = lower_targets(sources, lower_clone_spawns)?;
+        Ok(programs.iter().map(squashed_source).collect())
+    }
+
+    #[test]
+    fn test_clone_spawns() {
+        let out = lower(&[
+            ("Stage", ""),
+            (
+                "Bullet",
+                "#[on_clone_start]\nfn init(x: number, dir: number) { go_to(x, 0); }\n#[on_key_pressed(\"space\")]\nfn fire() { spawn_clone(Bullet { dir: 90, x: 1 }); }",
+            ),
+            (
+                "Player",
+                "#[on_flag_clicked]\nfn start() { spawn_clone(Bullet { x: x_position(), dir: 0 }); }",
+            ),
+        ])
+        .unwrap();
+
+        // Values are passed in the order of the handler's parameters
+        let bullet = &out[1];
+        assert!(bullet.contains("fire() { _spawn_clone(1, 90); }"));
+        assert!(bullet.contains(
+            "proc _clone_init(x: number, dir: number) { go_to(x, 0); } #[on_clone_start] fn init() { _clone_init(_clone_x, _clone_dir); }"
+        ));
+        assert!(bullet.contains(
+            "proc _spawn_clone(x: number, dir: number) { _clone_x = x; _clone_dir = dir; create_clone_of(\"myself\"); }"
+        ));
+        assert!(out[2].contains("Bullet::_spawn_clone(x_position(), 0);"));
+    }
+
+    #[test]
+    fn test_remote_spawns_in_one_frame() {
+        let programs = lower_targets(
+            &[
+                ("Stage", ""),
+                (
+                    "Bullet",
+                    "#[on_clone_start]\nfn init(x: number) { go_to(x, 0); }",
+                ),
+                (
+                    "Player",
+                    "#[on_key_pressed(\"space\")]\nfn fire() { spawn_clone(Bullet { x: 1 }); }",
+                ),
+                (
+                    "Enemy",
+                    "#[on_key_pressed(\"space\")]\nfn fire() { spawn_clone(Bullet { x: 2 }); }",
+                ),
+            ],
+            |targets| {
+                lower_clone_spawns(targets)?;
+                crate::remote::lower_remote_calls(targets, &HashMap::new())
+            },
+        )
+        .unwrap();
+        let out: Vec<String> = programs.iter().map(squashed_source).collect();
+
+        // Both sprites wait for the other's spawn before storing their value
+        for (caller, x) in [(&out[2], 1), (&out[3], 2)] {
+            assert!(caller.contains(&format!("fn fire() {{ Bullet::_spawn_clone({}); }}", x)));
+            assert!(caller.contains(
+                "proc Bullet::_spawn_clone(x: number) { \
+                 wait_until(_rpc_Bullet__spawn_clone_busy == 0); \
+                 _rpc_Bullet__spawn_clone_busy = 1; \
+                 _rpc_Bullet__spawn_clone_x = x;"
+            ));
+        }
+        // One receiver serves both, and only the original Bullet spawns
+        assert_eq!(out[1].matches("on_broadcast_received").count(), 1);
+        assert!(out[1].contains("if _is_clone == 0 { _spawn_clone(_rpc_Bullet__spawn_clone_x); }"));
+    }
+
+    #[test]
+    fn test_clone_spawn_errors() {
+        let bullet = "#[on_clone_start]\nfn init(x: number) {}";
+        let err = |src: &str| {
+            lower(&[("Stage", ""), ("Bullet", bullet), ("Player", src)])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("fn f() { spawn_clone(Bullet { y: 1 }); }"),
+            "`Bullet` clones have no value `y`. Its `#[on_clone_start]` handler takes: x"
+        );
+        assert_eq!(
+            err("fn f() { spawn_clone(Bullet); }"),
+            "`spawn_clone(Bullet { .. })` is missing `x`"
+        );
+        assert_eq!(
+            err("fn f() { spawn_clone(Bulet { x: 1 }); }"),
+            "`spawn_clone` names an unknown sprite `Bulet`. Sprites are: Bullet, Player"
+        );
+        assert_eq!(
+            err("#[on_clone_start]\nfn a(n: number) {}\n#[on_clone_start]\nfn b(n: number) {}"),
+            "`a` and `b` both take parameters in `#[on_clone_start]`; only one handler can receive the values from `spawn_clone`"
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! Fixtures shared by the tests of the passes that work across targets.
+use crate::ast::Program;
+use crate::codegen::CodeGenerator;
+use crate::parser::parse_program;
+use anyhow::Result;
+
+/// Parses `(name, source)` pairs, Stage first, and runs `pass` on them as
+/// the targets of a project. Returns the programs after the pass.
+pub fn lower_targets(
+    sources: &[(&str, &str)],
+    pass: impl FnOnce(&mut [(String, &mut Program)]) -> Result<()>,
+) -> Result<Vec<Program>> {
+    let mut programs: Vec<Program> = sources
+        .iter()
+        .map(|(_, src)| parse_program(src).unwrap().1)
+        .collect();
+    let mut targets: Vec<(String, &mut Program)> = sources
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .zip(programs.iter_mut())
+        .collect();
+    pass(&mut targets)?;
+    Ok(programs)
+}
+
+/// The generated source of `program` on one line, with every run of
+/// whitespace replaced by a single space.
+pub fn squashed_source(program: &Program) -> String {
+    CodeGenerator::new()
+        .generate(program)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,4 +1,11 @@
 use crate::ast::*;
+use crate::compiler::BLANK_COSTUME;
+use crate::parser::parse_program;
+use crate::source::ItemContext;
+use crate::visit::{
+    rewrite_stmts, walk_expr, walk_expr_mut, walk_stmts, walk_stmts_exprs, walk_stmts_exprs_mut,
+};
+use anyhow::bail;
 use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
 
@@ -8,77 +15,232 @@ fn get_unique_id() -> String {
     format!("pkg_proc_{}", val)
 }
 
+/// Tags an error about `item` with it, so it can be traced back to the file
+/// and line the item came from.
+fn in_item(item: &Item, error: anyhow::Error) -> anyhow::Error {
+    match ItemContext::of(item) {
+        Some(context) => error.context(context),
+        None => error,
+    }
+}
+
 fn qualify_calls(stmts: &mut [Stmt], pkg_name: &str, pkg_procs: &HashSet<String>) {
-    for stmt in stmts {
-        match stmt {
-            Stmt::Expr(e, _)
-            | Stmt::Let(_, e, _)
-            | Stmt::Assign(_, e, _)
-            | Stmt::Return(Some(e), _) => {
-                qualify_calls_in_expr(e, pkg_name, pkg_procs);
-            }
-            Stmt::If(c, t, e, _) => {
-                qualify_calls_in_expr(c, pkg_name, pkg_procs);
-                qualify_calls(t, pkg_name, pkg_procs);
-                if let Some(eb) = e {
-                    qualify_calls(eb, pkg_name, pkg_procs);
-                }
+    walk_stmts_exprs_mut(stmts, &mut |expr| {
+        if let Expr::Call(name, _) = expr {
+            if pkg_procs.contains(name) {
+                *name = format!("{}::{}", pkg_name, name);
             }
-            Stmt::Repeat(c, b, _) => {
-                qualify_calls_in_expr(c, pkg_name, pkg_procs);
-                qualify_calls(b, pkg_name, pkg_procs);
+        }
+    });
+}
+
+/// Builtins whose first argument names a variable or list.
+const DATA_BUILTINS: &[&str] = &[
+    "set_variable",
+    "change_variable_by",
+    "show_variable",
+    "hide_variable",
+    "add_to_list",
+    "delete_of_list",
+    "delete_all_of_list",
+    "insert_at_list",
+    "replace_item_of_list",
+    "replace_item_list",
+    "item_of_list",
+    "item_num_of_list",
+    "length_of_list",
+    "list_contains",
+    "show_list",
+    "hide_list",
+];
+
+/// Builtins whose first argument names a costume, backdrop or sound.
+const ASSET_BUILTINS: &[&str] = &[
+    "switch_costume_to",
+    "switch_backdrop_to",
+    "start_sound",
+    "play_sound_until_done",
+];
+
+/// Prefixes a package procedure's references to the package's own
+/// variables, lists, costumes and sounds with `pkg::`, leaving names a
+/// parameter or `let` shadows alone.
+fn qualify_data(proc: &mut ProcedureDef, pkg: &Package) {
+    let mut data = HashSet::new();
+    let mut assets = HashSet::new();
+    for item in &pkg.items {
+        match item {
+            Item::Variable(v) => {
+                data.insert(v.name.clone());
             }
-            Stmt::Forever(b, _) => qualify_calls(b, pkg_name, pkg_procs),
-            Stmt::Until(c, b, _) => {
-                qualify_calls_in_expr(c, pkg_name, pkg_procs);
-                qualify_calls(b, pkg_name, pkg_procs);
+            Item::Costume(a) | Item::Sound(a) => {
+                assets.insert(a.name.clone());
             }
-            Stmt::Match(expr, arms, else_block, _) => {
-                qualify_calls_in_expr(expr, pkg_name, pkg_procs);
-                for (pat, stmts) in arms {
-                    qualify_calls_in_expr(pat, pkg_name, pkg_procs);
-                    qualify_calls(stmts, pkg_name, pkg_procs);
-                }
-                if let Some(eb) = else_block {
-                    qualify_calls(eb, pkg_name, pkg_procs);
+            _ => {}
+        }
+    }
+    for name in proc
+        .params
+        .iter()
+        .map(|p| p.name.clone())
+        .chain(let_names(&proc.body))
+    {
+        data.remove(&name);
+    }
+    if data.is_empty() && assets.is_empty() {
+        return;
+    }
+
+    let qualify = |name: &mut String| *name = format!("{}::{}", pkg.name, name);
+    walk_stmts_exprs_mut(&mut proc.body, &mut |expr| match expr {
+        Expr::Variable(name) if data.contains(name.as_str()) => qualify(name),
+        Expr::Call(builtin, args) => {
+            let names = if DATA_BUILTINS.contains(&builtin.as_str()) {
+                &data
+            } else if ASSET_BUILTINS.contains(&builtin.as_str()) {
+                &assets
+            } else {
+                return;
+            };
+            if let Some(Expr::String(name)) = args.first_mut() {
+                if names.contains(name.as_str()) {
+                    qualify(name);
                 }
             }
-            Stmt::CBlock(_, args, stmts, _) => {
-                for arg in args {
-                    qualify_calls_in_expr(arg, pkg_name, pkg_procs);
-                }
-                qualify_calls(stmts, pkg_name, pkg_procs);
+        }
+        _ => {}
+    });
+    let body = std::mem::take(&mut proc.body);
+    proc.body = rewrite_stmts(body, &mut |mut stmt| {
+        if let Stmt::Assign(name, _, _) = &mut stmt {
+            if data.contains(name.as_str()) {
+                qualify(name);
             }
-            Stmt::Return(None, _) | Stmt::Comment(_) => {}
         }
-    }
+        Ok(vec![stmt])
+    })
+    .expect("renaming never fails");
 }
 
-fn qualify_calls_in_expr(expr: &mut Expr, pkg_name: &str, pkg_procs: &HashSet<String>) {
-    match expr {
-        Expr::Call(name, args) => {
-            if pkg_procs.contains(name) {
-                *name = format!("{}::{}", pkg_name, name);
+/// Checks that `stmts`, written inside package `from` (or outside any
+/// package), only call `pub` procedures and use `public` data of other
+/// packages.
+fn check_package_access(
+    stmts: &[Stmt],
+    from: Option<&str>,
+    packages: &HashMap<String, Package>,
+) -> anyhow::Result<()> {
+    let mut error = None;
+    walk_stmts_exprs(stmts, &mut |expr| {
+        let e = match expr {
+            Expr::Call(name, args) => {
+                let data = match args.first() {
+                    Some(Expr::String(data)) if DATA_BUILTINS.contains(&name.as_str()) => {
+                        private_member(data, false, from, packages)
+                    }
+                    _ => None,
+                };
+                data.or_else(|| private_member(name, true, from, packages))
             }
-            for arg in args {
-                qualify_calls_in_expr(arg, pkg_name, pkg_procs);
+            Expr::Variable(name) => private_member(name, false, from, packages),
+            _ => None,
+        };
+        if let Some(e) = e {
+            error.get_or_insert(e);
+        }
+    });
+    walk_stmts(stmts, &mut |stmt| {
+        if let Stmt::Assign(name, _, _) = stmt {
+            if let Some(e) = private_member(name, false, from, packages) {
+                error.get_or_insert(e);
             }
         }
-        Expr::BinOp(l, _, r) => {
-            qualify_calls_in_expr(l, pkg_name, pkg_procs);
-            qualify_calls_in_expr(r, pkg_name, pkg_procs);
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The error for using `path`, a `pkg::member` name, from outside the
+/// package when the member isn't public.
+fn private_member(
+    path: &str,
+    is_call: bool,
+    from: Option<&str>,
+    packages: &HashMap<String, Package>,
+) -> Option<anyhow::Error> {
+    let (pkg_name, member) = path.split_once("::")?;
+    if from == Some(pkg_name) {
+        return None;
+    }
+    let pkg = packages.get(pkg_name)?;
+    pkg.items.iter().find_map(|item| match item {
+        Item::Procedure(p) if is_call && p.name == member && p.visibility != Visibility::Public => {
+            Some(anyhow::anyhow!(
+                "`{}` is private to package `{}`. Mark it `pub proc` to call it from outside",
+                path,
+                pkg_name
+            ))
+        }
+        Item::Variable(v) if !is_call && v.name == member && v.visibility != Visibility::Public => {
+            Some(anyhow::anyhow!(
+                "`{}` is private to package `{}`. Only `public` variables and lists can be used from outside",
+                path,
+                pkg_name
+            ))
         }
-        Expr::UnOp(_, e) => qualify_calls_in_expr(e, pkg_name, pkg_procs),
-        Expr::List(items) => {
-            for item in items {
-                qualify_calls_in_expr(item, pkg_name, pkg_procs);
+        _ => None,
+    })
+}
+
+/// Adds the variables, lists, costumes and sounds of every package in
+/// `used`, named `pkg::name`. Public data is hoisted to the stage like any
+/// public variable; the rest is owned by this target.
+fn merge_package_data(
+    program: &mut Program,
+    packages: &HashMap<String, Package>,
+    used: &HashSet<String>,
+) {
+    let mut used: Vec<&String> = used.iter().collect();
+    used.sort();
+    for pkg_name in used {
+        let Some(pkg) = packages.get(pkg_name) else {
+            continue;
+        };
+        for item in &pkg.items {
+            let mut item = match item {
+                Item::Variable(_) | Item::Costume(_) | Item::Sound(_) => item.clone(),
+                _ => continue,
+            };
+            match &mut item {
+                Item::Variable(VariableDecl { name, .. })
+                | Item::Costume(AssetDecl { name, .. })
+                | Item::Sound(AssetDecl { name, .. }) => {
+                    *name = format!("{}::{}", pkg_name, name);
+                }
+                _ => unreachable!(),
             }
+            program.items.push(item);
         }
-        _ => {}
     }
 }
 
-pub fn transform_program(program: &mut Program, packages: &HashMap<String, Package>) {
+/// Project-wide settings that change how a program is lowered.
+#[derive(Debug, Clone, Default)]
+pub struct TransformOptions {
+    /// Inline every procedure of at most this many blocks, as if it were
+    /// marked `#[inline]`.
+    pub inline_threshold: Option<usize>,
+    /// The program is the Stage, which has backdrops instead of costumes.
+    pub is_stage: bool,
+}
+
+pub fn transform_program(
+    program: &mut Program,
+    packages: &HashMap<String, Package>,
+    options: &TransformOptions,
+) -> anyhow::Result<()> {
     let has_ram = program
         .items
         .iter()
@@ -92,6 +254,13 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
     // Initial scan of main program
     for item in &program.items {
         scan_item_for_calls(item, &mut pending_scan);
+        let body = match item {
+            Item::Procedure(p) => &p.body,
+            Item::Function(f) => &f.body,
+            Item::Stmt(stmt) => std::slice::from_ref(stmt),
+            _ => continue,
+        };
+        check_package_access(body, None, packages).map_err(|e| in_item(item, e))?;
     }
 
     let mut merged_items = Vec::new();
@@ -112,40 +281,45 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
 
                 if let Some(pkg) = packages.get(pkg_name) {
                     // Find the proc in the package
-                    if let Some(item) = pkg.items.iter().find(|i| {
+                    if let Some(Item::Procedure(proc)) = pkg.items.iter().find(|i| {
                         if let Item::Procedure(p) = i {
                             p.name == proc_name
                         } else {
                             false
                         }
                     }) {
-                        if let Item::Procedure(proc) = item {
-                            processed_package_procs.insert(full_name.clone());
-
-                            // Generate unique name
-                            let unique_name = get_unique_id();
-                            package_proc_mapping.insert(full_name.clone(), unique_name.clone());
-
-                            let mut new_proc = proc.clone();
-                            new_proc.name = unique_name.clone();
-                            
-                            // Collect package proc names
-                            let pkg_procs: HashSet<String> = pkg.items.iter().filter_map(|i| {
+                        processed_package_procs.insert(full_name.clone());
+
+                        // Generate unique name
+                        let unique_name = get_unique_id();
+                        package_proc_mapping.insert(full_name.clone(), unique_name.clone());
+
+                        check_package_access(&proc.body, Some(pkg_name), packages)?;
+                        let mut new_proc = proc.clone();
+                        new_proc.name = unique_name.clone();
+                        new_proc.visibility = Visibility::Default;
+
+                        // Collect package proc names
+                        let pkg_procs: HashSet<String> = pkg
+                            .items
+                            .iter()
+                            .filter_map(|i| {
                                 if let Item::Procedure(p) = i {
                                     Some(p.name.clone())
                                 } else {
                                     None
                                 }
-                            }).collect();
-                            
-                            // Qualify calls
-                            qualify_calls(&mut new_proc.body, pkg_name, &pkg_procs);
+                            })
+                            .collect();
 
-                            // Scan this new procedure for more calls
-                            scan_stmts_for_calls(&new_proc.body, &mut pending_scan);
+                        // Qualify calls
+                        qualify_calls(&mut new_proc.body, pkg_name, &pkg_procs);
+                        qualify_data(&mut new_proc, pkg);
 
-                            merged_items.push(Item::Procedure(new_proc));
-                        }
+                        // Scan this new procedure for more calls
+                        scan_stmts_for_calls(&new_proc.body, &mut pending_scan);
+
+                        merged_items.push(Item::Procedure(new_proc));
                     }
                 }
             }
@@ -157,53 +331,14 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
     // 1. Original program items
     // 2. Merged items (if they call other package procs)
 
-    // Helper to rename calls in statements
     fn rename_calls_in_stmts(stmts: &mut [Stmt], mapping: &HashMap<String, String>) {
-        for stmt in stmts {
-            match stmt {
-                Stmt::Expr(e, _)
-                | Stmt::Let(_, e, _)
-                | Stmt::Assign(_, e, _)
-                | Stmt::Return(Some(e), _) => {
-                    rename_calls_in_expr(e, mapping);
-                }
-                Stmt::If(c, t, e, _) => {
-                    rename_calls_in_expr(c, mapping);
-                    rename_calls_in_stmts(t, mapping);
-                    if let Some(eb) = e {
-                        rename_calls_in_stmts(eb, mapping);
-                    }
-                }
-                Stmt::Repeat(c, b, _) => {
-                    rename_calls_in_expr(c, mapping);
-                    rename_calls_in_stmts(b, mapping);
-                }
-                Stmt::Forever(b, _) => rename_calls_in_stmts(b, mapping),
-                Stmt::Until(c, b, _) => {
-                    rename_calls_in_expr(c, mapping);
-                    rename_calls_in_stmts(b, mapping);
-                }
-                _ => {}
-            }
-        }
-    }
-
-    fn rename_calls_in_expr(expr: &mut Expr, mapping: &HashMap<String, String>) {
-        match expr {
-            Expr::Call(name, args) => {
+        walk_stmts_exprs_mut(stmts, &mut |expr| {
+            if let Expr::Call(name, _) = expr {
                 if let Some(new_name) = mapping.get(name) {
                     *name = new_name.clone();
                 }
-                for arg in args {
-                    rename_calls_in_expr(arg, mapping);
-                }
             }
-            Expr::BinOp(l, _, r) => {
-                rename_calls_in_expr(l, mapping);
-                rename_calls_in_expr(r, mapping);
-            }
-            _ => {}
-        }
+        });
     }
 
     // Apply renaming
@@ -222,9 +357,66 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
 
     program.items.extend(merged_items);
 
+    // Merge the data of packages this program uses, and of their dependencies
+    let mut used_packages = HashSet::new();
+    let mut package_queue: Vec<String> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Use(name) => Some(name.clone()),
+            _ => None,
+        })
+        .chain(
+            processed_package_procs
+                .iter()
+                .filter_map(|name| name.split("::").next().map(str::to_string)),
+        )
+        .collect();
+    while let Some(pkg_name) = package_queue.pop() {
+        if let Some(pkg) = packages.get(&pkg_name) {
+            if used_packages.insert(pkg_name) {
+                package_queue.extend(pkg.dependencies.iter().cloned());
+            }
+        }
+    }
+    merge_package_data(program, packages, &used_packages);
+
     // Remove `use` statements
     program.items.retain(|item| !matches!(item, Item::Use(_)));
 
+    // Match named arguments to parameters and fill in defaults
+    resolve_call_arguments(program)?;
+
+    // Tuples may only be returned or unpacked straight into `let`
+    check_tuples(program)?;
+
+    // Replace calls to `#[inline]` and small procedures with their bodies
+    inline_procedures(program, options.inline_threshold)?;
+
+    // Specialize procedures that take or return lists
+    monomorphize_list_procs(program)?;
+
+    // Split struct lists into one list per field
+    lower_structs(program)?;
+
+    // Turn enum variants into numbers and check match arms
+    lower_enums_and_matches(program)?;
+
+    // Route `===` / `!==` through a generated helper
+    lower_strict_comparisons(program, options.is_stage)?;
+
+    // Add the number formatting helpers that are called
+    add_called_helpers(program, FORMAT_HELPERS);
+
+    // Lower `//`, shifts and bitwise operators
+    lower_bitwise(program);
+
+    // Expand `**` and the math builtins, adding helpers where needed
+    lower_math(program);
+
+    // Make `&&` / `||` lazy in `#[short_circuit]` bodies
+    lower_short_circuit(program);
+
     let mut new_items = Vec::new();
 
     // Collect user procedures for call flattening (now includes merged package procs)
@@ -252,6 +444,7 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
     if !has_ram && needs_transform {
         new_items.push(Item::Variable(VariableDecl {
             name: "_RAM".to_string(),
+            attributes: vec![],
             ty: Type::List,
             init: Expr::List(vec![]),
             visibility: Visibility::Public,
@@ -259,6 +452,7 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         }));
         new_items.push(Item::Variable(VariableDecl {
             name: "_FREE_PAGES".to_string(),
+            attributes: vec![],
             ty: Type::List,
             init: Expr::List(vec![]),
             visibility: Visibility::Public,
@@ -266,6 +460,7 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         }));
         new_items.push(Item::Variable(VariableDecl {
             name: "_HIGH_WATER".to_string(),
+            attributes: vec![],
             ty: Type::Number,
             init: Expr::Number(1.0),
             visibility: Visibility::Public,
@@ -273,6 +468,7 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         }));
         new_items.push(Item::Variable(VariableDecl {
             name: "_RET_VAL".to_string(),
+            attributes: vec![],
             ty: Type::Number,
             init: Expr::Number(0.0),
             visibility: Visibility::Public,
@@ -283,6 +479,8 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         // sys_alloc
         new_items.push(Item::Procedure(ProcedureDef {
             name: "sys_alloc".to_string(),
+            visibility: Visibility::Default,
+            attributes: vec![],
             params: vec![],
             body: vec![Stmt::If(
                 Expr::BinOp(
@@ -359,9 +557,12 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         // sys_free
         new_items.push(Item::Procedure(ProcedureDef {
             name: "sys_free".to_string(),
+            visibility: Visibility::Default,
+            attributes: vec![],
             params: vec![Param {
                 name: "ptr".to_string(),
                 ty: Type::Number,
+                default: None,
             }],
             body: vec![Stmt::Expr(
                 Expr::Call(
@@ -381,18 +582,23 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
         // stack_set
         new_items.push(Item::Procedure(ProcedureDef {
             name: "stack_set".to_string(),
+            visibility: Visibility::Default,
+            attributes: vec![],
             params: vec![
                 Param {
                     name: "ptr".to_string(),
                     ty: Type::Number,
+                    default: None,
                 },
                 Param {
                     name: "offset".to_string(),
                     ty: Type::Number,
+                    default: None,
                 },
                 Param {
                     name: "val".to_string(),
                     ty: Type::Number,
+                    default: None,
                 }, // Scratch variables are dynamic, but type required
             ],
             body: vec![Stmt::Expr(
@@ -442,6 +648,7 @@ pub fn transform_program(program: &mut Program, packages: &HashMap<String, Packa
     }
 
     program.items = new_items;
+    Ok(())
 }
 
 fn is_helper(name: &str) -> bool {
@@ -457,59 +664,30 @@ fn scan_item_for_calls(item: &Item, calls: &mut Vec<String>) {
 }
 
 fn scan_stmts_for_calls(stmts: &[Stmt], calls: &mut Vec<String>) {
-    for stmt in stmts {
-        match stmt {
-            Stmt::Expr(e, _)
-            | Stmt::Let(_, e, _)
-            | Stmt::Assign(_, e, _)
-            | Stmt::Return(Some(e), _) => {
-                scan_expr_for_calls(e, calls);
-            }
-            Stmt::If(c, t, e, _) => {
-                scan_expr_for_calls(c, calls);
-                scan_stmts_for_calls(t, calls);
-                if let Some(eb) = e {
-                    scan_stmts_for_calls(eb, calls);
-                }
-            }
-            Stmt::Repeat(c, b, _) => {
-                scan_expr_for_calls(c, calls);
-                scan_stmts_for_calls(b, calls);
-            }
-            Stmt::Forever(b, _) => scan_stmts_for_calls(b, calls),
-            Stmt::Until(c, b, _) => {
-                scan_expr_for_calls(c, calls);
-                scan_stmts_for_calls(b, calls);
-            }
-            _ => {}
-        }
-    }
-}
-
-fn scan_expr_for_calls(expr: &Expr, calls: &mut Vec<String>) {
-    match expr {
-        Expr::Call(name, args) => {
+    walk_stmts_exprs(stmts, &mut |expr| {
+        if let Expr::Call(name, _) = expr {
             calls.push(name.clone());
-            for arg in args {
-                scan_expr_for_calls(arg, calls);
-            }
-        }
-        Expr::BinOp(l, _, r) => {
-            scan_expr_for_calls(l, calls);
-            scan_expr_for_calls(r, calls);
         }
-        _ => {}
-    }
+    });
 }
 
 fn should_transform_proc(proc: &ProcedureDef, value_procs: &HashSet<String>) -> bool {
     proc.return_type.is_some()
         || contains_let_or_return(&proc.body)
         || contains_call_to_value_proc(&proc.body, value_procs)
+        || contains_if_expr(&proc.body)
 }
 
 fn should_transform_func(func: &Function, value_procs: &HashSet<String>) -> bool {
-    contains_let_or_return(&func.body) || contains_call_to_value_proc(&func.body, value_procs)
+    contains_let_or_return(&func.body)
+        || contains_call_to_value_proc(&func.body, value_procs)
+        || contains_if_expr(&func.body)
+}
+
+fn contains_if_expr(stmts: &[Stmt]) -> bool {
+    let mut found = false;
+    walk_stmts_exprs(stmts, &mut |expr| found |= matches!(expr, Expr::If(..)));
+    found
 }
 
 fn contains_call_to_value_proc(stmts: &[Stmt], value_procs: &HashSet<String>) -> bool {
@@ -521,7 +699,7 @@ fn contains_call_to_value_proc(stmts: &[Stmt], value_procs: &HashSet<String>) ->
 fn contains_let_or_return(stmts: &[Stmt]) -> bool {
     for stmt in stmts {
         match stmt {
-            Stmt::Let(_, _, _) | Stmt::Return(_, _) => return true,
+            Stmt::Let(_, _, _) | Stmt::LetTuple(_, _, _) | Stmt::Return(_, _) => return true,
             Stmt::If(_, t, e, _) => {
                 if contains_let_or_return(t) {
                     return true;
@@ -532,24 +710,12 @@ fn contains_let_or_return(stmts: &[Stmt]) -> bool {
                     }
                 }
             }
-            Stmt::Repeat(_, b, _) => {
-                if contains_let_or_return(b) {
-                    return true;
-                }
-            }
-            Stmt::Forever(b, _) => {
-                if contains_let_or_return(b) {
-                    return true;
-                }
-            }
-            Stmt::Until(_, b, _) => {
-                if contains_let_or_return(b) {
-                    return true;
-                }
-            }
+            Stmt::Repeat(_, b, _) if contains_let_or_return(b) => return true,
+            Stmt::Forever(b, _) if contains_let_or_return(b) => return true,
+            Stmt::Until(_, b, _) if contains_let_or_return(b) => return true,
             Stmt::Match(_, cases, default, _) => {
-                for (_, b) in cases {
-                    if contains_let_or_return(b) {
+                for arm in cases {
+                    if contains_let_or_return(&arm.body) {
                         return true;
                     }
                 }
@@ -559,11 +725,7 @@ fn contains_let_or_return(stmts: &[Stmt]) -> bool {
                     }
                 }
             }
-            Stmt::CBlock(_, _, b, _) => {
-                if contains_let_or_return(b) {
-                    return true;
-                }
-            }
+            Stmt::CBlock(_, _, b, _) if contains_let_or_return(b) => return true,
             _ => {}
         }
     }
@@ -587,8 +749,21 @@ fn transform_procedure(proc: ProcedureDef, user_procs: &HashSet<String>, out: &m
 
     wrapper_body.push(Stmt::Expr(Expr::Call(inner_name.clone(), inner_args), None));
 
+    // Nobody reads the frame of a procedure without a return value, so free it here
+    if proc.return_type.is_none() {
+        wrapper_body.push(Stmt::Expr(
+            Expr::Call(
+                "sys_free".to_string(),
+                vec![Expr::Variable("_RET_VAL".to_string())],
+            ),
+            None,
+        ));
+    }
+
     out.push(Item::Procedure(ProcedureDef {
         name: proc.name.clone(),
+        visibility: Visibility::Default,
+        attributes: proc.attributes.clone(),
         params: proc.params.clone(),
         body: wrapper_body,
         return_type: None,     // Wrapper doesn't return
@@ -600,10 +775,15 @@ fn transform_procedure(proc: ProcedureDef, user_procs: &HashSet<String>, out: &m
     let mut inner_params = vec![Param {
         name: "base".to_string(),
         ty: Type::Number,
+        default: None,
     }];
     inner_params.extend(proc.params.clone());
 
     let mut ctx = TransformContext::new(user_procs.clone());
+    // A tuple is returned in slots 0, 1, .., so locals start after the last one
+    if let Some(Type::Tuple(types)) = &proc.return_type {
+        ctx.scope_offset = types.len() as i32;
+    }
     let transformed_body = transform_stmts(proc.body, &mut ctx);
 
     let mut final_body = transformed_body;
@@ -615,6 +795,13 @@ fn transform_procedure(proc: ProcedureDef, user_procs: &HashSet<String>, out: &m
 
     out.push(Item::Procedure(ProcedureDef {
         name: inner_name,
+        visibility: Visibility::Default,
+        attributes: proc
+            .attributes
+            .iter()
+            .filter(|a| a.name == "shared")
+            .cloned()
+            .collect(),
         params: inner_params,
         body: final_body,
         return_type: None,
@@ -653,6 +840,7 @@ fn transform_function(func: Function, user_procs: &HashSet<String>, out: &mut Ve
     let mut inner_params = vec![Param {
         name: "base".to_string(),
         ty: Type::Number,
+        default: None,
     }];
     inner_params.extend(func.params.clone());
 
@@ -677,6 +865,8 @@ fn transform_function(func: Function, user_procs: &HashSet<String>, out: &mut Ve
 
     out.push(Item::Procedure(ProcedureDef {
         name: inner_name,
+        visibility: Visibility::Default,
+        attributes: vec![],
         params: inner_params,
         body: final_body,
         return_type: None,
@@ -754,24 +944,91 @@ fn transform_stmts(stmts: Vec<Stmt>, ctx: &mut TransformContext) -> Vec<Stmt> {
                     None,
                 ));
             }
+            Stmt::LetTuple(names, expr, _) => {
+                // Every value is computed before any of the names is defined
+                let from_call =
+                    matches!(&expr, Expr::Call(name, _) if ctx.user_procs.contains(name));
+                let values: Vec<Expr> = match expr {
+                    Expr::Call(name, args) if ctx.user_procs.contains(&name) => {
+                        let args = args
+                            .into_iter()
+                            .map(|a| process_expr(a, &mut pre_stmts, ctx))
+                            .collect();
+                        pre_stmts.push(Stmt::Expr(Expr::Call(name, args), None));
+                        (0..names.len())
+                            .map(|i| {
+                                Expr::Call(
+                                    "item_of_list".to_string(),
+                                    vec![
+                                        Expr::String("_RAM".to_string()),
+                                        Expr::BinOp(
+                                            Box::new(Expr::Variable("_RET_VAL".to_string())),
+                                            Op::Add,
+                                            Box::new(Expr::Number(i as f64)),
+                                        ),
+                                    ],
+                                )
+                            })
+                            .collect()
+                    }
+                    Expr::Tuple(items) => items
+                        .into_iter()
+                        .map(|e| process_expr(e, &mut pre_stmts, ctx))
+                        .collect(),
+                    _ => unreachable!("checked by check_tuples"),
+                };
+                new_stmts.extend(pre_stmts);
+                for (name, value) in names.into_iter().zip(values) {
+                    if name == "_" {
+                        continue;
+                    }
+                    let offset = ctx.define_local(name);
+                    new_stmts.push(Stmt::Expr(
+                        Expr::Call(
+                            "stack_set".to_string(),
+                            vec![
+                                Expr::Variable("base".to_string()),
+                                Expr::Number(offset as f64),
+                                value,
+                            ],
+                        ),
+                        None,
+                    ));
+                }
+                if from_call {
+                    new_stmts.push(Stmt::Expr(
+                        Expr::Call(
+                            "sys_free".to_string(),
+                            vec![Expr::Variable("_RET_VAL".to_string())],
+                        ),
+                        None,
+                    ));
+                }
+            }
             Stmt::Return(expr, _) => {
-                let ret_val = if let Some(e) = expr {
-                    process_expr(e, &mut pre_stmts, ctx)
-                } else {
-                    Expr::Number(0.0)
+                // A tuple fills slots 0, 1, ..; anything else uses slot 0
+                let ret_vals = match expr {
+                    Some(Expr::Tuple(items)) => items
+                        .into_iter()
+                        .map(|e| process_expr(e, &mut pre_stmts, ctx))
+                        .collect(),
+                    Some(e) => vec![process_expr(e, &mut pre_stmts, ctx)],
+                    None => vec![Expr::Number(0.0)],
                 };
                 new_stmts.extend(pre_stmts);
-                new_stmts.push(Stmt::Expr(
-                    Expr::Call(
-                        "stack_set".to_string(),
-                        vec![
-                            Expr::Variable("base".to_string()),
-                            Expr::Number(0.0), // Return slot is 0
-                            ret_val,
-                        ],
-                    ),
-                    None,
-                ));
+                for (slot, ret_val) in ret_vals.into_iter().enumerate() {
+                    new_stmts.push(Stmt::Expr(
+                        Expr::Call(
+                            "stack_set".to_string(),
+                            vec![
+                                Expr::Variable("base".to_string()),
+                                Expr::Number(slot as f64),
+                                ret_val,
+                            ],
+                        ),
+                        None,
+                    ));
+                }
                 new_stmts.push(Stmt::Assign(
                     "_RET_VAL".to_string(),
                     Expr::Variable("base".to_string()),
@@ -847,6 +1104,96 @@ fn transform_stmts(stmts: Vec<Stmt>, ctx: &mut TransformContext) -> Vec<Stmt> {
             }
             Stmt::Until(cond, body, _) => {
                 let t_cond = process_expr(cond, &mut pre_stmts, ctx);
+                new_stmts.extend(pre_stmts.iter().cloned());
+
+                ctx.enter_scope();
+                let saved = ctx.scope_offset;
+                let mut t_body = transform_stmts(body, ctx);
+                ctx.leave_scope();
+                ctx.scope_offset = saved;
+                // The condition is checked before every iteration, so whatever it
+                // needed computed must run again at the end of the body.
+                t_body.extend(pre_stmts);
+                new_stmts.push(Stmt::Until(t_cond, t_body, None));
+            }
+            Stmt::Match(expr, arms, default, _) => {
+                let t_expr = process_expr(expr, &mut pre_stmts, ctx);
+                new_stmts.extend(pre_stmts);
+
+                // Patterns and guards are lowered before any arm body claims
+                // stack slots. Each arm keeps what they need computed, so calls
+                // in them only run once that arm is tested.
+                let mut heads = Vec::new();
+                let mut bodies = Vec::new();
+                for arm in arms {
+                    let mut pattern_pre = Vec::new();
+                    let patterns: Vec<Pattern> = arm
+                        .patterns
+                        .into_iter()
+                        .map(|p| match p {
+                            Pattern::Value(v) => {
+                                Pattern::Value(process_expr(v, &mut pattern_pre, ctx))
+                            }
+                            Pattern::Range(lo, hi, inclusive) => Pattern::Range(
+                                process_expr(lo, &mut pattern_pre, ctx),
+                                process_expr(hi, &mut pattern_pre, ctx),
+                                inclusive,
+                            ),
+                            other => other,
+                        })
+                        .collect();
+                    let mut guard_pre = Vec::new();
+                    let guard = arm.guard.map(|g| process_expr(g, &mut guard_pre, ctx));
+                    // Where the guard's result goes when it can only be
+                    // computed after the patterns matched
+                    let catch_all = patterns
+                        .iter()
+                        .any(|p| matches!(p, Pattern::Binding(_) | Pattern::Wildcard));
+                    let flag = (!guard_pre.is_empty() && !catch_all).then(|| ctx.define_temp());
+                    heads.push((patterns, pattern_pre, guard, guard_pre, flag));
+                    bodies.push(arm.body);
+                }
+
+                let mut t_arms = Vec::new();
+                for body in bodies {
+                    ctx.enter_scope();
+                    let saved = ctx.scope_offset;
+                    let t_body = transform_stmts(body, ctx);
+                    ctx.leave_scope();
+                    ctx.scope_offset = saved;
+                    t_arms.push(t_body);
+                }
+                let t_default = default.map(|d| {
+                    ctx.enter_scope();
+                    let saved = ctx.scope_offset;
+                    let b = transform_stmts(d, ctx);
+                    ctx.leave_scope();
+                    ctx.scope_offset = saved;
+                    b
+                });
+
+                if heads.iter().all(|(_, pattern_pre, _, guard_pre, _)| {
+                    pattern_pre.is_empty() && guard_pre.is_empty()
+                }) {
+                    let arms = heads
+                        .into_iter()
+                        .zip(t_arms)
+                        .map(|((patterns, _, guard, _, _), body)| MatchArm {
+                            patterns,
+                            guard,
+                            body,
+                        })
+                        .collect();
+                    new_stmts.push(Stmt::Match(t_expr, arms, t_default, None));
+                } else {
+                    new_stmts.extend(lazy_match_chain(&t_expr, heads, t_arms, t_default));
+                }
+            }
+            Stmt::CBlock(name, args, body, _) => {
+                let t_args = args
+                    .into_iter()
+                    .map(|a| process_expr(a, &mut pre_stmts, ctx))
+                    .collect();
                 new_stmts.extend(pre_stmts);
 
                 ctx.enter_scope();
@@ -854,7 +1201,13 @@ fn transform_stmts(stmts: Vec<Stmt>, ctx: &mut TransformContext) -> Vec<Stmt> {
                 let t_body = transform_stmts(body, ctx);
                 ctx.leave_scope();
                 ctx.scope_offset = saved;
-                new_stmts.push(Stmt::Until(t_cond, t_body, None));
+                new_stmts.push(Stmt::CBlock(name, t_args, t_body, None));
+            }
+            Stmt::AssignPlace(place, expr, _) => {
+                let t_place = process_expr(place, &mut pre_stmts, ctx);
+                let t_expr = process_expr(expr, &mut pre_stmts, ctx);
+                new_stmts.extend(pre_stmts);
+                new_stmts.push(Stmt::AssignPlace(t_place, t_expr, None));
             }
             _ => new_stmts.push(stmt),
         }
@@ -863,6 +1216,85 @@ fn transform_stmts(stmts: Vec<Stmt>, ctx: &mut TransformContext) -> Vec<Stmt> {
     new_stmts
 }
 
+/// A lowered match arm head: its patterns and what they need computed first,
+/// its guard and what that needs, and the stack slot the guard is stored in
+/// when it needs anything.
+type MatchHead = (
+    Vec<Pattern>,
+    Vec<Stmt>,
+    Option<Expr>,
+    Vec<Stmt>,
+    Option<i32>,
+);
+
+/// Lowers a match whose patterns or guards need statements run first into an
+/// if/else chain, so those statements only run when their arm is tested. A
+/// guard's statements also wait for the arm's patterns to match.
+fn lazy_match_chain(
+    expr: &Expr,
+    heads: Vec<MatchHead>,
+    bodies: Vec<Vec<Stmt>>,
+    default: Option<Vec<Stmt>>,
+) -> Vec<Stmt> {
+    let slot = |offset: i32| {
+        Expr::Call(
+            "item_of_list".to_string(),
+            vec![
+                Expr::String("_RAM".to_string()),
+                Expr::BinOp(
+                    Box::new(Expr::Variable("base".to_string())),
+                    Op::Add,
+                    Box::new(Expr::Number(offset as f64)),
+                ),
+            ],
+        )
+    };
+    let set_slot = |offset: i32, value: Expr| {
+        Stmt::Expr(
+            Expr::Call(
+                "stack_set".to_string(),
+                vec![
+                    Expr::Variable("base".to_string()),
+                    Expr::Number(offset as f64),
+                    value,
+                ],
+            ),
+            None,
+        )
+    };
+
+    let mut rest = default;
+    for ((patterns, mut stmts, guard, guard_pre, flag), body) in heads.into_iter().zip(bodies).rev()
+    {
+        let matched = crate::compiler::pattern_condition(expr, &patterns);
+        match (matched, guard, flag) {
+            (Some(matched), Some(guard), Some(flag)) => {
+                stmts.push(set_slot(flag, Expr::Bool(false)));
+                let mut then = guard_pre;
+                then.push(set_slot(flag, guard));
+                stmts.push(Stmt::If(matched, then, None, None));
+                let taken = Expr::BinOp(
+                    Box::new(slot(flag)),
+                    Op::Eq,
+                    Box::new(Expr::String("true".to_string())),
+                );
+                stmts.push(Stmt::If(taken, body, rest, None));
+            }
+            (matched, guard, _) => {
+                stmts.extend(guard_pre);
+                let cond = match (matched, guard) {
+                    (Some(m), Some(g)) => Expr::BinOp(Box::new(m), Op::And, Box::new(g)),
+                    (Some(c), None) | (None, Some(c)) => c,
+                    (None, None) => Expr::Bool(true),
+                };
+                stmts.push(Stmt::If(cond, body, rest, None));
+            }
+        }
+        rest = Some(stmts);
+    }
+    rest.unwrap_or_default()
+}
+
 fn process_expr(expr: Expr, pre_stmts: &mut Vec<Stmt>, ctx: &mut TransformContext) -> Expr {
     match expr {
         Expr::Variable(name) => {
@@ -949,6 +1381,3712 @@ fn process_expr(expr: Expr, pre_stmts: &mut Vec<Stmt>, ctx: &mut TransformContex
             let r_new = process_expr(*r, pre_stmts, ctx);
             Expr::BinOp(Box::new(l_new), op, Box::new(r_new))
         }
-        _ => expr,
+        Expr::UnOp(op, e) => Expr::UnOp(op, Box::new(process_expr(*e, pre_stmts, ctx))),
+        Expr::List(items) => Expr::List(
+            items
+                .into_iter()
+                .map(|e| process_expr(e, pre_stmts, ctx))
+                .collect(),
+        ),
+        Expr::Tuple(items) => Expr::Tuple(
+            items
+                .into_iter()
+                .map(|e| process_expr(e, pre_stmts, ctx))
+                .collect(),
+        ),
+        Expr::Index(base, index) => {
+            let base = process_expr(*base, pre_stmts, ctx);
+            let index = process_expr(*index, pre_stmts, ctx);
+            Expr::Index(Box::new(base), Box::new(index))
+        }
+        Expr::Field(base, field) => {
+            Expr::Field(Box::new(process_expr(*base, pre_stmts, ctx)), field)
+        }
+        Expr::NamedArg(name, value) => {
+            Expr::NamedArg(name, Box::new(process_expr(*value, pre_stmts, ctx)))
+        }
+        Expr::MethodCall(base, method, args) => {
+            let base = process_expr(*base, pre_stmts, ctx);
+            let args = args
+                .into_iter()
+                .map(|a| process_expr(a, pre_stmts, ctx))
+                .collect();
+            Expr::MethodCall(Box::new(base), method, args)
+        }
+        Expr::StructLit(name, fields) => Expr::StructLit(
+            name,
+            fields
+                .into_iter()
+                .map(|(f, e)| (f, process_expr(e, pre_stmts, ctx)))
+                .collect(),
+        ),
+        Expr::If(cond, then, otherwise) => {
+            // Only the chosen branch runs, so each one computes its value into
+            // a shared temp inside its own half of an if/else.
+            let cond = process_expr(*cond, pre_stmts, ctx);
+            let temp_offset = ctx.define_temp();
+            let branch = |value: Expr, ctx: &mut TransformContext| {
+                let mut stmts = Vec::new();
+                let value = process_expr(value, &mut stmts, ctx);
+                stmts.push(Stmt::Expr(
+                    Expr::Call(
+                        "stack_set".to_string(),
+                        vec![
+                            Expr::Variable("base".to_string()),
+                            Expr::Number(temp_offset as f64),
+                            value,
+                        ],
+                    ),
+                    None,
+                ));
+                stmts
+            };
+            let then_stmts = branch(*then, ctx);
+            let else_stmts = branch(*otherwise, ctx);
+            pre_stmts.push(Stmt::If(cond, then_stmts, Some(else_stmts), None));
+
+            Expr::Call(
+                "item_of_list".to_string(),
+                vec![
+                    Expr::String("_RAM".to_string()),
+                    Expr::BinOp(
+                        Box::new(Expr::Variable("base".to_string())),
+                        Op::Add,
+                        Box::new(Expr::Number(temp_offset as f64)),
+                    ),
+                ],
+            )
+        }
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_) | Expr::ProcCall(..) => {
+            expr
+        }
+    }
+}
+
+/// Rewrites every call to a procedure so it passes all parameters in order.
+/// Named arguments are moved to their parameter's position and missing ones
+/// take the parameter's default. Defaults are copied into the call, so they
+/// must be constants: anything else would be evaluated among the caller's
+/// names rather than the procedure's.
+fn resolve_call_arguments(program: &mut Program) -> anyhow::Result<()> {
+    let enums: HashSet<&str> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Enum(def) => Some(def.name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let is_constant = |expr: &Expr| match expr {
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_) => true,
+        Expr::UnOp(UnOp::Neg, e) => matches!(e.as_ref(), Expr::Number(_)),
+        Expr::Variable(path) => path
+            .split_once("::")
+            .is_some_and(|(enum_name, _)| enums.contains(enum_name)),
+        _ => false,
+    };
+
+    let check_defaults = |proc: &ProcedureDef| -> anyhow::Result<()> {
+        for (i, param) in proc.params.iter().enumerate() {
+            match &param.default {
+                Some(default) => {
+                    if !is_constant(default) {
+                        bail!(
+                            "Default for `{}` in `{}` must be a literal or an enum variant",
+                            param.name,
+                            proc.name
+                        );
+                    }
+                }
+                None => {
+                    if let Some(prev) = proc.params[..i].iter().find(|p| p.default.is_some()) {
+                        bail!(
+                            "Parameter `{}` of `{}` needs a default because it follows `{}`, which has one",
+                            param.name,
+                            proc.name,
+                            prev.name
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+    let mut signatures = HashMap::new();
+    for item in &program.items {
+        let Item::Procedure(proc) = item else {
+            continue;
+        };
+        check_defaults(proc).map_err(|e| in_item(item, e))?;
+        signatures.insert(proc.name.clone(), proc.params.clone());
+    }
+
+    let resolve = |expr: &mut Expr, error: &mut Option<anyhow::Error>| {
+        let Expr::Call(name, args) = expr else {
+            return;
+        };
+        let Some(params) = signatures.get(name) else {
+            return;
+        };
+        let given = args.len();
+        let mut slots: Vec<Option<Expr>> = vec![None; params.len()];
+        let mut seen_named = false;
+        for (i, arg) in std::mem::take(args).into_iter().enumerate() {
+            let (slot, value) = match arg {
+                Expr::NamedArg(arg_name, value) => {
+                    seen_named = true;
+                    let Some(slot) = params.iter().position(|p| p.name == arg_name) else {
+                        error.get_or_insert(anyhow::anyhow!(
+                            "`{}` has no parameter named `{}`",
+                            name,
+                            arg_name
+                        ));
+                        return;
+                    };
+                    (slot, *value)
+                }
+                _ if seen_named => {
+                    error.get_or_insert(anyhow::anyhow!(
+                        "Positional arguments must come before named ones in call to `{}`",
+                        name
+                    ));
+                    return;
+                }
+                value if i < params.len() => (i, value),
+                _ => {
+                    error.get_or_insert(anyhow::anyhow!(
+                        "`{}` takes {} arguments but {} were given",
+                        name,
+                        params.len(),
+                        given
+                    ));
+                    return;
+                }
+            };
+            if slots[slot].replace(value).is_some() {
+                error.get_or_insert(anyhow::anyhow!(
+                    "Argument `{}` is passed more than once in call to `{}`",
+                    params[slot].name,
+                    name
+                ));
+                return;
+            }
+        }
+        for (slot, param) in slots.into_iter().zip(params) {
+            match slot.or_else(|| param.default.clone()) {
+                Some(value) => args.push(value),
+                None => {
+                    error.get_or_insert(anyhow::anyhow!(
+                        "Missing argument `{}` in call to `{}`",
+                        param.name,
+                        name
+                    ));
+                    return;
+                }
+            }
+        }
+    };
+    for item in &mut program.items {
+        let mut error = None;
+        let mut resolve = |e: &mut Expr| resolve(e, &mut error);
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs_mut(&mut p.body, &mut resolve),
+            Item::Function(f) => walk_stmts_exprs_mut(&mut f.body, &mut resolve),
+            Item::Stmt(stmt) => walk_stmts_exprs_mut(std::slice::from_mut(stmt), &mut resolve),
+            _ => {}
+        }
+        if let Some(error) = error {
+            return Err(in_item(item, error));
+        }
+    }
+
+    // Anything left is a named argument to a builtin or method
+    for item in &program.items {
+        let body = match item {
+            Item::Procedure(p) => &p.body,
+            Item::Function(f) => &f.body,
+            Item::Stmt(stmt) => std::slice::from_ref(stmt),
+            _ => continue,
+        };
+        let mut leftover = None;
+        walk_stmts_exprs(body, &mut |expr| {
+            if let Expr::Call(name, args) | Expr::MethodCall(_, name, args) = expr {
+                if let Some(Expr::NamedArg(arg, _)) =
+                    args.iter().find(|a| matches!(a, Expr::NamedArg(..)))
+                {
+                    leftover.get_or_insert((name.clone(), arg.clone()));
+                }
+            }
+        });
+        if let Some((name, arg)) = leftover {
+            return Err(in_item(
+                item,
+                anyhow::anyhow!(
+                    "Named argument `{}` can't be used with `{}`; only procedures take named arguments",
+                    arg,
+                    name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that tuples only appear where they can be lowered: as the value
+/// of `return` in a procedure declared `-> (..)`, and as the source of a
+/// `let (a, b) = ..` with the same number of names. Calling a tuple
+/// procedure as a statement, ignoring its values, is also allowed.
+fn check_tuples(program: &Program) -> anyhow::Result<()> {
+    let mut tuple_procs = HashMap::new();
+    for item in &program.items {
+        if let Item::Procedure(proc) = item {
+            if let Some(param) = proc.params.iter().find(|p| matches!(p.ty, Type::Tuple(_))) {
+                return Err(in_item(
+                    item,
+                    anyhow::anyhow!(
+                        "Parameter `{}` of `{}` can't be a tuple",
+                        param.name,
+                        proc.name
+                    ),
+                ));
+            }
+            if let Some(Type::Tuple(types)) = &proc.return_type {
+                tuple_procs.insert(proc.name.clone(), types.len());
+            }
+        }
+    }
+
+    for item in &program.items {
+        match item {
+            Item::Procedure(p) => {
+                let arity = tuple_procs.get(&p.name).map(|&n| (p.name.as_str(), n));
+                check_tuple_stmts(&p.body, arity, &tuple_procs)
+            }
+            Item::Function(f) => check_tuple_stmts(&f.body, None, &tuple_procs),
+            Item::Stmt(stmt) => check_tuple_stmts(std::slice::from_ref(stmt), None, &tuple_procs),
+            _ => Ok(()),
+        }
+        .map_err(|e| in_item(item, e))?;
+    }
+    Ok(())
+}
+
+fn check_tuple_stmts(
+    stmts: &[Stmt],
+    returns: Option<(&str, usize)>,
+    tuple_procs: &HashMap<String, usize>,
+) -> anyhow::Result<()> {
+    let check = |expr: &Expr| check_no_tuples(expr, tuple_procs);
+    for stmt in stmts {
+        match stmt {
+            Stmt::LetTuple(names, value, _) => {
+                let count = match value {
+                    Expr::Tuple(items) => {
+                        items.iter().try_for_each(check)?;
+                        items.len()
+                    }
+                    Expr::Call(name, args) if tuple_procs.contains_key(name) => {
+                        args.iter().try_for_each(check)?;
+                        tuple_procs[name]
+                    }
+                    _ => bail!(
+                        "`let ({})` needs a tuple or a call to a procedure that returns one",
+                        names.join(", ")
+                    ),
+                };
+                if count != names.len() {
+                    bail!(
+                        "`let ({})` unpacks {} values but gets {}",
+                        names.join(", "),
+                        names.len(),
+                        count
+                    );
+                }
+            }
+            Stmt::Return(value, _) if returns.is_some() => {
+                let (name, arity) = returns.unwrap();
+                match value {
+                    Some(Expr::Tuple(items)) if items.len() == arity => {
+                        items.iter().try_for_each(check)?
+                    }
+                    _ => bail!(
+                        "`{}` returns {} values, so it must return a tuple of {}",
+                        name,
+                        arity,
+                        arity
+                    ),
+                }
+            }
+            Stmt::Expr(Expr::Call(name, args), _) if tuple_procs.contains_key(name) => {
+                args.iter().try_for_each(check)?
+            }
+            Stmt::If(cond, then, otherwise, _) => {
+                check(cond)?;
+                check_tuple_stmts(then, returns, tuple_procs)?;
+                if let Some(otherwise) = otherwise {
+                    check_tuple_stmts(otherwise, returns, tuple_procs)?;
+                }
+            }
+            Stmt::Repeat(cond, body, _) | Stmt::Until(cond, body, _) => {
+                check(cond)?;
+                check_tuple_stmts(body, returns, tuple_procs)?;
+            }
+            Stmt::Forever(body, _) => check_tuple_stmts(body, returns, tuple_procs)?,
+            Stmt::CBlock(_, args, body, _) => {
+                args.iter().try_for_each(check)?;
+                check_tuple_stmts(body, returns, tuple_procs)?;
+            }
+            Stmt::Match(value, arms, default, _) => {
+                check(value)?;
+                for arm in arms {
+                    for pattern in &arm.patterns {
+                        match pattern {
+                            Pattern::Value(v) => check(v)?,
+                            Pattern::Range(lo, hi, _) => {
+                                check(lo)?;
+                                check(hi)?;
+                            }
+                            Pattern::Binding(_) | Pattern::Wildcard => {}
+                        }
+                    }
+                    if let Some(guard) = &arm.guard {
+                        check(guard)?;
+                    }
+                    check_tuple_stmts(&arm.body, returns, tuple_procs)?;
+                }
+                if let Some(default) = default {
+                    check_tuple_stmts(default, returns, tuple_procs)?;
+                }
+            }
+            // Everything else holds expressions but no nested blocks
+            _ => {
+                let mut error = None;
+                walk_stmts_exprs(std::slice::from_ref(stmt), &mut |e| {
+                    if let Err(e) = check_no_tuples(e, tuple_procs) {
+                        error.get_or_insert(e);
+                    }
+                });
+                if let Some(error) = error {
+                    return Err(error);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_no_tuples(expr: &Expr, tuple_procs: &HashMap<String, usize>) -> anyhow::Result<()> {
+    let mut error = None;
+    walk_expr(expr, &mut |e| match e {
+        Expr::Tuple(_) => {
+            error.get_or_insert(anyhow::anyhow!(
+                "Tuples can only be returned from a procedure or unpacked with `let (a, b) = ...`"
+            ));
+        }
+        Expr::Call(name, _) if tuple_procs.contains_key(name) => {
+            error.get_or_insert(anyhow::anyhow!(
+                "`{}` returns {} values; unpack them with `let (..) = {}(..);`",
+                name,
+                tuple_procs[name],
+                name
+            ));
+        }
+        _ => {}
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Calls inlined into a procedure that was itself inlined are expanded on
+/// the next round, so this bounds how deep inlining goes.
+const MAX_INLINE_ROUNDS: usize = 4;
+
+/// A procedure whose calls can be replaced by its body.
+struct Inlinable {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+    value: Option<Expr>, // The returned expression, for procedures with a return type
+    locals: HashSet<String>,
+    free_vars: HashSet<String>,
+    has_loops: bool,
+    is_warp: bool,
+}
+
+/// Replaces calls to `#[inline]` procedures, and with a threshold to every
+/// procedure of at most that many blocks, with the procedure's body.
+/// Literal arguments are substituted directly. Other arguments are bound to
+/// fresh `let`s (or, inside an expression, substituted when that can't
+/// change what they evaluate to), and the body's own `let`s are renamed so
+/// they can't clash with the caller's. Procedures left without callers are
+/// removed.
+fn inline_procedures(program: &mut Program, threshold: Option<usize>) -> anyhow::Result<()> {
+    let mut explicit = HashSet::new();
+    let mut user_procs = HashSet::new();
+    for item in &mut program.items {
+        if let Item::Procedure(p) = item {
+            let before = p.attributes.len();
+            p.attributes.retain(|a| a.name != "inline");
+            if p.attributes.len() != before {
+                explicit.insert(p.name.clone());
+            }
+            user_procs.insert(p.name.clone());
+        }
+    }
+
+    let mut inlined = HashSet::new();
+    let mut next_id = 0;
+    for _ in 0..MAX_INLINE_ROUNDS {
+        let mut candidates = HashMap::new();
+        for item in &program.items {
+            let Item::Procedure(p) = item else {
+                continue;
+            };
+            let is_explicit = explicit.contains(&p.name);
+            let is_small = threshold.is_some_and(|n| block_count(&p.body) <= n);
+            if !is_explicit && !is_small {
+                continue;
+            }
+            match inlinable(p, &user_procs) {
+                Ok(candidate) => {
+                    candidates.insert(p.name.clone(), candidate);
+                }
+                Err(reason) if is_explicit => {
+                    return Err(in_item(
+                        item,
+                        anyhow::anyhow!("`{}` can't be inlined because {}", p.name, reason),
+                    ))
+                }
+                Err(_) => {}
+            }
+        }
+
+        let mut changed = false;
+        for item in &mut program.items {
+            let (params, body, is_warp) = match item {
+                Item::Procedure(p) => (&p.params, &mut p.body, p.is_warp),
+                Item::Function(f) => (&f.params, &mut f.body, f.is_warp),
+                _ => continue,
+            };
+            let mut caller_names = let_names(body);
+            caller_names.extend(params.iter().map(|p| p.name.clone()));
+            let site = InlineSite {
+                candidates: &candidates,
+                caller_names: &caller_names,
+                is_warp,
+            };
+
+            let stmts = std::mem::take(body);
+            *body = rewrite_stmts(stmts, &mut |stmt| match stmt {
+                Stmt::Expr(Expr::Call(name, args), comment) => {
+                    match site.inline_stmt(&name, &args, &mut next_id) {
+                        Some(stmts) => {
+                            changed = true;
+                            inlined.insert(name);
+                            Ok(stmts)
+                        }
+                        None => Ok(vec![Stmt::Expr(Expr::Call(name, args), comment)]),
+                    }
+                }
+                other => Ok(vec![other]),
+            })?;
+            walk_stmts_exprs_mut(body, &mut |expr| {
+                let Expr::Call(name, args) = expr else {
+                    return;
+                };
+                if let Some(value) = site.inline_expr(name, args) {
+                    changed = true;
+                    inlined.insert(name.clone());
+                    *expr = value;
+                }
+            });
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Drop inlined procedures that nothing calls anymore
+    let mut called = HashSet::new();
+    for item in &program.items {
+        let body = match item {
+            Item::Procedure(p) => &p.body,
+            Item::Function(f) => &f.body,
+            Item::Stmt(stmt) => std::slice::from_ref(stmt),
+            _ => continue,
+        };
+        walk_stmts_exprs(body, &mut |e| {
+            if let Expr::Call(name, _) = e {
+                called.insert(name.clone());
+            }
+        });
+    }
+    program.items.retain(|item| {
+        !matches!(item, Item::Procedure(p) if inlined.contains(&p.name) && !called.contains(&p.name))
+    });
+    Ok(())
+}
+
+fn inlinable(proc: &ProcedureDef, user_procs: &HashSet<String>) -> Result<Inlinable, String> {
+    if proc
+        .params
+        .iter()
+        .any(|p| !matches!(p.ty, Type::Number | Type::String | Type::Boolean))
+    {
+        return Err("it takes a list or tuple".to_string());
+    }
+    if proc.attributes.iter().any(|a| a.name == "short_circuit") {
+        return Err("it is `#[short_circuit]`".to_string());
+    }
+    let params: Vec<String> = proc.params.iter().map(|p| p.name.clone()).collect();
+    let mut calls = Vec::new();
+    scan_stmts_for_calls(&proc.body, &mut calls);
+    if calls.contains(&proc.name) {
+        return Err("it calls itself".to_string());
+    }
+
+    let mut has_loops = false;
+    let mut has_return = false;
+    let mut error = None;
+    walk_stmts(&proc.body, &mut |stmt| match stmt {
+        Stmt::Repeat(..) | Stmt::Forever(..) | Stmt::Until(..) => has_loops = true,
+        Stmt::Return(..) => has_return = true,
+        Stmt::Assign(name, _, _) if params.contains(name) => {
+            error.get_or_insert(format!("it assigns to parameter `{}`", name));
+        }
+        _ => {}
+    });
+    // Spliced into a caller, this would stop the caller's script instead
+    walk_stmts_exprs(&proc.body, &mut |e| {
+        if matches!(e, Expr::Call(name, args)
+            if name == "stop" && matches!(args.as_slice(), [Expr::String(s)] if s == "this script"))
+        {
+            error.get_or_insert(
+                "it uses `stop(\"this script\")`, which would stop the caller's script".to_string(),
+            );
+        }
+    });
+    let locals = let_names(&proc.body);
+    if let Some(shadowed) = params.iter().find(|p| locals.contains(*p)) {
+        error.get_or_insert(format!("a `let` shadows parameter `{}`", shadowed));
+    }
+    if let Some(error) = error {
+        return Err(error);
+    }
+
+    let value = match &proc.return_type {
+        None if has_return => return Err("it uses `return`".to_string()),
+        None => None,
+        Some(Type::Number | Type::String | Type::Boolean) => match proc.body.as_slice() {
+            [Stmt::Return(Some(value), _)] => {
+                if calls.iter().any(|c| user_procs.contains(c)) {
+                    return Err("its return value calls another procedure".to_string());
+                }
+                Some(value.clone())
+            }
+            _ => return Err("its body is more than a single `return`".to_string()),
+        },
+        Some(_) => return Err("it returns a list or tuple".to_string()),
+    };
+
+    let mut free_vars = HashSet::new();
+    walk_stmts_exprs(&proc.body, &mut |e| {
+        if let Expr::Variable(v) = e {
+            if !params.contains(v) && !locals.contains(v) {
+                free_vars.insert(v.clone());
+            }
+        }
+    });
+    Ok(Inlinable {
+        params,
+        body: proc.body.clone(),
+        value,
+        locals,
+        free_vars,
+        has_loops,
+        is_warp: proc.is_warp,
+    })
+}
+
+struct InlineSite<'a> {
+    candidates: &'a HashMap<String, Inlinable>,
+    caller_names: &'a HashSet<String>,
+    is_warp: bool,
+}
+
+impl InlineSite<'_> {
+    /// The statements replacing the call statement `name(args);`, if it can
+    /// be inlined here.
+    fn inline_stmt(&self, name: &str, args: &[Expr], next_id: &mut usize) -> Option<Vec<Stmt>> {
+        let c = self.candidates.get(name)?;
+        *next_id += 1;
+        let prefix = format!("_inline{}_", next_id);
+        if c.value.is_some() {
+            // The returned value is unused, so only the arguments' side effects remain
+            return Some(
+                c.params
+                    .iter()
+                    .zip(args)
+                    .filter(|(_, arg)| has_calls(arg))
+                    .map(|(param, arg)| {
+                        Stmt::Let(format!("{}{}", prefix, param), arg.clone(), None)
+                    })
+                    .collect(),
+            );
+        }
+        // A global the body reads would be captured by a caller's local of the
+        // same name. A loop in a warp body must stay warp (and vice versa).
+        if !c.free_vars.is_disjoint(self.caller_names) || (c.has_loops && c.is_warp != self.is_warp)
+        {
+            return None;
+        }
+
+        let mut out = Vec::new();
+        let mut subst = HashMap::new();
+        for (param, arg) in c.params.iter().zip(args) {
+            if is_literal(arg) {
+                subst.insert(param.clone(), arg.clone());
+            } else if has_calls(arg) || count_uses(&c.body, param) > 0 {
+                let local = format!("{}{}", prefix, param);
+                out.push(Stmt::Let(local.clone(), arg.clone(), None));
+                subst.insert(param.clone(), Expr::Variable(local));
+            }
+        }
+        for local in &c.locals {
+            subst.insert(
+                local.clone(),
+                Expr::Variable(format!("{}{}", prefix, local)),
+            );
+        }
+
+        let rename = |name: String| {
+            if c.locals.contains(&name) {
+                format!("{}{}", prefix, name)
+            } else {
+                name
+            }
+        };
+        let mut body = rewrite_stmts(c.body.clone(), &mut |stmt| {
+            Ok(vec![match stmt {
+                Stmt::Let(name, e, comment) => Stmt::Let(rename(name), e, comment),
+                Stmt::LetTuple(names, e, comment) => {
+                    Stmt::LetTuple(names.into_iter().map(rename).collect(), e, comment)
+                }
+                Stmt::Assign(name, e, comment) => Stmt::Assign(rename(name), e, comment),
+                other => other,
+            }])
+        })
+        .ok()?;
+        walk_stmts_exprs_mut(&mut body, &mut |e| substitute(e, &subst));
+        out.extend(body);
+        Some(out)
+    }
+
+    /// The expression replacing the call `name(args)`, if it can be inlined here.
+    fn inline_expr(&self, name: &str, args: &[Expr]) -> Option<Expr> {
+        let c = self.candidates.get(name)?;
+        let value = c.value.as_ref()?;
+        if !c.free_vars.is_disjoint(self.caller_names) {
+            return None;
+        }
+        // An argument that calls something must still run exactly once
+        let body = [Stmt::Return(Some(value.clone()), None)];
+        let mut subst = HashMap::new();
+        for (param, arg) in c.params.iter().zip(args) {
+            if has_calls(arg) && count_uses(&body, param) != 1 {
+                return None;
+            }
+            subst.insert(param.clone(), arg.clone());
+        }
+        let mut value = value.clone();
+        walk_expr_mut(&mut value, &mut |e| substitute(e, &subst));
+        Some(value)
+    }
+}
+
+fn substitute(expr: &mut Expr, subst: &HashMap<String, Expr>) {
+    if let Expr::Variable(v) = expr {
+        if let Some(replacement) = subst.get(v) {
+            *expr = replacement.clone();
+        }
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_)
+    )
+}
+
+fn count_uses(stmts: &[Stmt], name: &str) -> usize {
+    let mut count = 0;
+    walk_stmts_exprs(stmts, &mut |e| {
+        if matches!(e, Expr::Variable(v) if v == name) {
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Every name declared with `let` anywhere in `stmts`.
+fn let_names(stmts: &[Stmt]) -> HashSet<String> {
+    let mut names = HashSet::new();
+    walk_stmts(stmts, &mut |stmt| match stmt {
+        Stmt::Let(name, _, _) => {
+            names.insert(name.clone());
+        }
+        Stmt::LetTuple(tuple, _, _) => {
+            names.extend(tuple.iter().filter(|n| *n != "_").cloned());
+        }
+        _ => {}
+    });
+    names
+}
+
+/// Roughly the number of Scratch blocks `stmts` compiles to: one per
+/// statement and one per operator or call.
+fn block_count(stmts: &[Stmt]) -> usize {
+    let mut count = 0;
+    walk_stmts(stmts, &mut |_| count += 1);
+    walk_stmts_exprs(stmts, &mut |e| {
+        if !is_literal(e) && !matches!(e, Expr::Variable(_) | Expr::NamedArg(..)) {
+            count += 1;
+        }
+    });
+    count
+}
+
+fn is_list_type(ty: &Type) -> bool {
+    matches!(ty, Type::List | Type::StructList(_))
+}
+
+fn is_list_generic(proc: &ProcedureDef) -> bool {
+    proc.return_type.as_ref().is_some_and(is_list_type)
+        || proc.params.iter().any(|p| is_list_type(&p.ty))
+}
+
+fn returns_list(name: &str, generics: &HashMap<String, ProcedureDef>) -> bool {
+    generics
+        .get(name)
+        .is_some_and(|p| p.return_type.as_ref().is_some_and(is_list_type))
+}
+
+/// A concrete instance of a list-generic procedure.
+struct ListSpecialization {
+    name: String,
+    generic: String,
+    lists: Vec<String>,
+    into: Option<String>,
+}
+
+/// Scratch can't pass lists around at runtime, so procedures with `list`
+/// parameters are specialized once per combination of lists they are called
+/// with. Procedures returning `list` are also specialized on the list their
+/// result is assigned to, and `return` copies into that list.
+fn monomorphize_list_procs(program: &mut Program) -> anyhow::Result<()> {
+    let generics: HashMap<String, ProcedureDef> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Procedure(p) if is_list_generic(p) => Some((p.name.clone(), p.clone())),
+            _ => None,
+        })
+        .collect();
+    if generics.is_empty() {
+        return Ok(());
+    }
+    program
+        .items
+        .retain(|item| !matches!(item, Item::Procedure(p) if generics.contains_key(&p.name)));
+
+    let procs: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Procedure(p) => Some(p.name.clone()),
+            _ => None,
+        })
+        .collect();
+    // Names that hold a single value, which a list result can't be stored in
+    let variables: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Variable(v) if !is_list_type(&v.ty) => Some(v.name.clone()),
+            _ => None,
+        })
+        .collect();
+    let scalars = |params: &[Param], body: &[Stmt]| {
+        let mut names = variables.clone();
+        names.extend(
+            params
+                .iter()
+                .filter(|p| !is_list_type(&p.ty))
+                .map(|p| p.name.clone()),
+        );
+        names.extend(let_names(body));
+        names
+    };
+
+    let mut queue = Vec::new();
+    for item in &mut program.items {
+        match item {
+            Item::Procedure(p) => {
+                let scalars = scalars(&p.params, &p.body);
+                specialize_list_calls(&mut p.body, &scalars, &generics, &mut queue)
+            }
+            Item::Function(f) => {
+                let scalars = scalars(&f.params, &f.body);
+                specialize_list_calls(&mut f.body, &scalars, &generics, &mut queue)
+            }
+            _ => Ok(()),
+        }
+        .map_err(|e| in_item(item, e))?;
+    }
+
+    let mut done: HashMap<String, (String, Vec<String>, Option<String>)> = HashMap::new();
+    while let Some(spec) = queue.pop() {
+        if procs.contains(&spec.name) {
+            bail!(
+                "Procedure `{}` has the same name as a copy of `{}` generated for a list argument; rename it",
+                spec.name,
+                spec.generic
+            );
+        }
+        let key = (spec.generic.clone(), spec.lists.clone(), spec.into.clone());
+        match done.get(&spec.name) {
+            Some(seen) if *seen == key => continue,
+            Some((other, _, _)) => bail!(
+                "The copies of `{}` and `{}` generated for their list arguments are both named `{}`; rename one of the procedures",
+                other,
+                spec.generic,
+                spec.name
+            ),
+            None => {
+                done.insert(spec.name.clone(), key);
+            }
+        }
+        let generic = &generics[&spec.generic];
+
+        let mut bindings = HashMap::new();
+        let mut params = Vec::new();
+        let mut lists = spec.lists.into_iter();
+        for param in &generic.params {
+            if is_list_type(&param.ty) {
+                bindings.insert(param.name.clone(), lists.next().unwrap());
+            } else {
+                params.push(param.clone());
+            }
+        }
+
+        let in_generic = |e: anyhow::Error| e.context(ItemContext::new("procedure", &generic.name));
+        let mut body = bind_list_params(generic.body.clone(), &bindings).map_err(in_generic)?;
+        let mut return_type = generic.return_type.clone();
+        if return_type.as_ref().is_some_and(is_list_type) {
+            body = lower_list_returns(body, &spec.generic, spec.into.as_deref(), &generics)
+                .map_err(in_generic)?;
+            return_type = None;
+        }
+        let scalars = scalars(&generic.params, &body);
+        specialize_list_calls(&mut body, &scalars, &generics, &mut queue).map_err(in_generic)?;
+
+        program.items.push(Item::Procedure(ProcedureDef {
+            name: spec.name,
+            visibility: Visibility::Default,
+            attributes: generic.attributes.clone(),
+            params,
+            body,
+            return_type,
+            is_warp: generic.is_warp,
+            comment: generic.comment.clone(),
+        }));
+    }
+    Ok(())
+}
+
+/// Rewrites calls to list-generic procedures into calls to their
+/// specializations, queueing every specialization that is needed. `scalars`
+/// are the names in scope that aren't lists.
+fn specialize_list_calls(
+    body: &mut Vec<Stmt>,
+    scalars: &HashSet<String>,
+    generics: &HashMap<String, ProcedureDef>,
+    queue: &mut Vec<ListSpecialization>,
+) -> anyhow::Result<()> {
+    // `dest = f(..);` and `f(..);` are the only places a list result may go
+    *body = rewrite_stmts(std::mem::take(body), &mut |stmt| {
+        Ok(vec![match stmt {
+            Stmt::Assign(dest, Expr::Call(name, args), c) if returns_list(&name, generics) => {
+                if scalars.contains(&dest) {
+                    bail!(
+                        "`{}` returns a list, so its result can't be assigned to `{}`, which isn't a list: `my_list = {}(...);`",
+                        name,
+                        dest,
+                        name
+                    );
+                }
+                let (name, args) = specialize_call(&name, args, generics, Some(&dest), queue)?;
+                Stmt::Expr(Expr::Call(name, args), c)
+            }
+            Stmt::Expr(Expr::Call(name, args), c) if returns_list(&name, generics) => {
+                let (name, args) = specialize_call(&name, args, generics, None, queue)?;
+                Stmt::Expr(Expr::Call(name, args), c)
+            }
+            other => other,
+        }])
+    })?;
+
+    let mut error = None;
+    walk_stmts_exprs_mut(body, &mut |expr| {
+        if error.is_some() {
+            return;
+        }
+        if let Expr::Call(name, args) = expr {
+            if !generics.contains_key(name.as_str()) {
+                return;
+            }
+            if returns_list(name, generics) {
+                error = Some(anyhow::anyhow!(
+                    "`{}` returns a list, so its result must be assigned to a list: `my_list = {}(...);`",
+                    name,
+                    name
+                ));
+                return;
+            }
+            match specialize_call(name, std::mem::take(args), generics, None, queue) {
+                Ok((new_name, new_args)) => {
+                    *name = new_name;
+                    *args = new_args;
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn specialize_call(
+    name: &str,
+    args: Vec<Expr>,
+    generics: &HashMap<String, ProcedureDef>,
+    into: Option<&str>,
+    queue: &mut Vec<ListSpecialization>,
+) -> anyhow::Result<(String, Vec<Expr>)> {
+    let generic = &generics[name];
+    if args.len() != generic.params.len() {
+        bail!(
+            "`{}` expects {} arguments but {} were given",
+            name,
+            generic.params.len(),
+            args.len()
+        );
+    }
+
+    let mut spec_name = name.to_string();
+    let mut lists = Vec::new();
+    let mut rest = Vec::new();
+    for (param, arg) in generic.params.iter().zip(args) {
+        if !is_list_type(&param.ty) {
+            rest.push(arg);
+            continue;
+        }
+        match arg {
+            Expr::Variable(list) | Expr::String(list) => {
+                spec_name.push_str("__");
+                spec_name.push_str(&mangle(&list));
+                lists.push(list);
+            }
+            _ => bail!(
+                "Argument `{}` of `{}` must name a list, e.g. `{}(my_list)`",
+                param.name,
+                name,
+                name
+            ),
+        }
+    }
+    if let Some(into) = into {
+        spec_name.push_str("__into__");
+        spec_name.push_str(&mangle(into));
+    }
+
+    queue.push(ListSpecialization {
+        name: spec_name.clone(),
+        generic: name.to_string(),
+        lists,
+        into: into.map(str::to_string),
+    });
+    Ok((spec_name, rest))
+}
+
+/// Turns a list name into part of an identifier. `_` is escaped as `_u` and
+/// other characters as `_x<hex>x`, so different names never mangle to the same
+/// string and the result never contains the `__` separator.
+fn mangle(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        match c {
+            '_' => out.push_str("_u"),
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            c => out.push_str(&format!("_x{:x}x", c as u32)),
+        }
+    }
+    out
+}
+
+/// Replaces references to `list` parameters with the lists they are bound to.
+fn bind_list_params(
+    body: Vec<Stmt>,
+    bindings: &HashMap<String, String>,
+) -> anyhow::Result<Vec<Stmt>> {
+    let mut body = rewrite_stmts(body, &mut |stmt| {
+        Ok(vec![match stmt {
+            Stmt::Assign(name, e, c) if bindings.contains_key(&name) => {
+                Stmt::Assign(bindings[&name].clone(), e, c)
+            }
+            other => other,
+        }])
+    })?;
+    walk_stmts_exprs_mut(&mut body, &mut |expr| {
+        if let Expr::Variable(name) = expr {
+            if let Some(list) = bindings.get(name.as_str()) {
+                *name = list.clone();
+            }
+        }
+    });
+    Ok(body)
+}
+
+/// Turns `return <list>;` into a copy into `into` followed by a stop, so the
+/// caller sees the result in its own list.
+fn lower_list_returns(
+    body: Vec<Stmt>,
+    proc_name: &str,
+    into: Option<&str>,
+    generics: &HashMap<String, ProcedureDef>,
+) -> anyhow::Result<Vec<Stmt>> {
+    let call = |name: &str, args: Vec<Expr>| Stmt::Expr(Expr::Call(name.to_string(), args), None);
+    let list = |name: &str| Expr::Variable(name.to_string());
+
+    let mut body = rewrite_stmts(body, &mut |stmt| {
+        let Stmt::Return(value, comment) = stmt else {
+            return Ok(vec![stmt]);
+        };
+        let mut out = Vec::new();
+        match (value, into) {
+            (Some(Expr::Call(name, args)), _) if returns_list(&name, generics) => {
+                out.push(match into {
+                    Some(dest) => Stmt::Assign(dest.to_string(), Expr::Call(name, args), comment),
+                    None => Stmt::Expr(Expr::Call(name, args), comment),
+                });
+            }
+            (Some(Expr::Variable(src) | Expr::String(src)), Some(dest)) => {
+                if src != dest {
+                    out.push(call("delete_all_of_list", vec![list(dest)]));
+                    out.push(Stmt::Repeat(
+                        Expr::Call("length_of_list".to_string(), vec![list(&src)]),
+                        vec![call(
+                            "add_to_list",
+                            vec![
+                                list(dest),
+                                Expr::Call(
+                                    "item_of_list".to_string(),
+                                    vec![
+                                        list(&src),
+                                        Expr::BinOp(
+                                            Box::new(Expr::Call(
+                                                "length_of_list".to_string(),
+                                                vec![list(dest)],
+                                            )),
+                                            Op::Add,
+                                            Box::new(Expr::Number(1.0)),
+                                        ),
+                                    ],
+                                ),
+                            ],
+                        )],
+                        comment,
+                    ));
+                }
+            }
+            (Some(Expr::List(items)), Some(dest)) => {
+                out.push(call("delete_all_of_list", vec![list(dest)]));
+                for item in items {
+                    out.push(call("add_to_list", vec![list(dest), item]));
+                }
+            }
+            (Some(Expr::Variable(_) | Expr::String(_) | Expr::List(_)), None) => {}
+            _ => bail!(
+                "`{}` returns a list, so `return` must name a list or use a list literal",
+                proc_name
+            ),
+        }
+        out.push(call("stop", vec![Expr::String("this script".to_string())]));
+        Ok(out)
+    })?;
+
+    // A trailing return doesn't need to stop the script
+    if matches!(body.last(), Some(Stmt::Expr(Expr::Call(name, args), _))
+        if name == "stop" && matches!(args.as_slice(), [Expr::String(s)] if s == "this script"))
+    {
+        body.pop();
+    }
+    Ok(body)
+}
+
+/// Backing list for one field of a struct list, e.g. `enemies_hp`.
+fn field_list(list: &str, field: &str) -> Expr {
+    Expr::Variable(format!("{}_{}", list, field))
+}
+
+/// Lowers `list<Struct>` collections into one Scratch list per field
+/// (`enemies_x`, `enemies_hp`, ...) that are always updated together.
+fn lower_structs(program: &mut Program) -> anyhow::Result<()> {
+    let mut structs = HashMap::new();
+    for item in &program.items {
+        if let Item::Struct(def) = item {
+            // A struct list is stored as one list per field, so a struct
+            // without fields would have nothing to store its length in
+            if def.fields.is_empty() {
+                bail!("Struct `{}` must have at least one field", def.name);
+            }
+            if structs.insert(def.name.clone(), def.clone()).is_some() {
+                bail!("Struct `{}` is declared more than once", def.name);
+            }
+        }
+    }
+    program
+        .items
+        .retain(|item| !matches!(item, Item::Struct(_)));
+
+    let mut declared: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Variable(v) => Some(v.name.clone()),
+            _ => None,
+        })
+        .collect();
+
+    // Split struct list declarations into their backing lists
+    let mut lists: HashMap<String, StructDef> = HashMap::new();
+    let mut items = Vec::new();
+    for item in program.items.drain(..) {
+        let Item::Variable(decl) = item else {
+            items.push(item);
+            continue;
+        };
+        let Type::StructList(struct_name) = &decl.ty else {
+            items.push(Item::Variable(decl));
+            continue;
+        };
+        let in_decl = |e: anyhow::Error| e.context(ItemContext::new("variable", &decl.name));
+        let Some(def) = structs.get(struct_name) else {
+            return Err(in_decl(anyhow::anyhow!(
+                "Unknown struct `{}` in declaration of `{}`",
+                struct_name,
+                decl.name
+            )));
+        };
+
+        let mut columns = vec![Vec::new(); def.fields.len()];
+        match &decl.init {
+            Expr::List(elems) => {
+                for elem in elems {
+                    let values = struct_values(def, elem).map_err(in_decl)?;
+                    for (column, value) in columns.iter_mut().zip(values) {
+                        column.push(value);
+                    }
+                }
+            }
+            _ => {
+                return Err(in_decl(anyhow::anyhow!(
+                    "`{}` must be initialized with a list literal",
+                    decl.name
+                )))
+            }
+        }
+
+        for (i, (field, column)) in def.fields.iter().zip(columns).enumerate() {
+            let name = format!("{}_{}", decl.name, field.name);
+            if !declared.insert(name.clone()) {
+                bail!(
+                    "`{}` is needed for field `{}` of struct list `{}`, but the name is already taken",
+                    name,
+                    field.name,
+                    decl.name
+                );
+            }
+            items.push(Item::Variable(VariableDecl {
+                name,
+                attributes: decl.attributes.clone(),
+                ty: Type::List,
+                init: Expr::List(column),
+                visibility: decl.visibility.clone(),
+                comment: if i == 0 { decl.comment.clone() } else { None },
+            }));
+        }
+        lists.insert(decl.name.clone(), def.clone());
+    }
+    program.items = items;
+
+    let mut temp_counter = 0;
+    for item in &mut program.items {
+        let body = match item {
+            Item::Procedure(p) => &mut p.body,
+            Item::Function(f) => &mut f.body,
+            _ => continue,
+        };
+        rewrite_stmts(std::mem::take(body), &mut |stmt| {
+            lower_struct_stmt(stmt, &lists, &mut temp_counter)
+        })
+        .and_then(|lowered| {
+            *body = lowered;
+            lower_struct_exprs(body, &lists)
+        })
+        .map_err(|e| in_item(item, e))?;
+    }
+    Ok(())
+}
+
+/// Returns the values of a struct literal in declaration order.
+fn struct_values(def: &StructDef, expr: &Expr) -> anyhow::Result<Vec<Expr>> {
+    let Expr::StructLit(name, fields) = expr else {
+        bail!("Expected a `{} {{ .. }}` literal", def.name);
+    };
+    if *name != def.name {
+        bail!("Expected a `{}` but found a `{}`", def.name, name);
+    }
+    for (field, _) in fields {
+        if !def.fields.iter().any(|f| f.name == *field) {
+            bail!("Struct `{}` has no field `{}`", def.name, field);
+        }
+        if fields.iter().filter(|(f, _)| f == field).count() > 1 {
+            bail!("Field `{}` of `{}` is set more than once", field, def.name);
+        }
+    }
+    def.fields
+        .iter()
+        .map(|f| match fields.iter().find(|(name, _)| *name == f.name) {
+            Some((_, value)) => Ok(value.clone()),
+            None => bail!("Missing field `{}` in `{}` literal", f.name, def.name),
+        })
+        .collect()
+}
+
+fn lower_struct_stmt(
+    stmt: Stmt,
+    lists: &HashMap<String, StructDef>,
+    temp_counter: &mut usize,
+) -> anyhow::Result<Vec<Stmt>> {
+    let call = |name: &str, args: Vec<Expr>| Stmt::Expr(Expr::Call(name.to_string(), args), None);
+
+    // Evaluate a non-trivial index once, since it is used for every backing list
+    let mut hoist = |index: Expr, out: &mut Vec<Stmt>| match index {
+        Expr::Number(_) | Expr::Variable(_) => index,
+        _ => {
+            *temp_counter += 1;
+            let name = format!("_struct_index_{}", temp_counter);
+            out.push(Stmt::Let(name.clone(), index, None));
+            Expr::Variable(name)
+        }
+    };
+
+    let mut out = Vec::new();
+    match stmt {
+        Stmt::Expr(Expr::MethodCall(base, method, mut args), comment) => {
+            let list = match base.as_ref() {
+                Expr::Variable(list) if lists.contains_key(list) => list.clone(),
+                _ => {
+                    return Ok(vec![Stmt::Expr(
+                        Expr::MethodCall(base, method, args),
+                        comment,
+                    )])
+                }
+            };
+            let def = &lists[&list];
+            let expected = match method.as_str() {
+                "push" | "remove" => 1,
+                "insert" => 2,
+                "clear" => 0,
+                _ => bail!(
+                    "`{}` has no method `{}`; struct lists support push, insert, remove, clear and len",
+                    list,
+                    method
+                ),
+            };
+            if args.len() != expected {
+                bail!(
+                    "`{}.{}` expects {} arguments but {} were given",
+                    list,
+                    method,
+                    expected,
+                    args.len()
+                );
+            }
+            match method.as_str() {
+                "push" => {
+                    for (field, value) in def.fields.iter().zip(struct_values(def, &args[0])?) {
+                        out.push(call(
+                            "add_to_list",
+                            vec![field_list(&list, &field.name), value],
+                        ));
+                    }
+                }
+                "insert" => {
+                    let values = struct_values(def, &args[1])?;
+                    let index = hoist(args.remove(0), &mut out);
+                    for (field, value) in def.fields.iter().zip(values) {
+                        out.push(call(
+                            "insert_at_list",
+                            vec![field_list(&list, &field.name), index.clone(), value],
+                        ));
+                    }
+                }
+                "remove" => {
+                    let index = hoist(args.remove(0), &mut out);
+                    for field in &def.fields {
+                        out.push(call(
+                            "delete_of_list",
+                            vec![field_list(&list, &field.name), index.clone()],
+                        ));
+                    }
+                }
+                _ => {
+                    for field in &def.fields {
+                        out.push(call(
+                            "delete_all_of_list",
+                            vec![field_list(&list, &field.name)],
+                        ));
+                    }
+                }
+            }
+        }
+        Stmt::AssignPlace(place, value, comment) => match place {
+            Expr::Field(base, field) => match *base {
+                Expr::Index(list, index) if matches!(list.as_ref(), Expr::Variable(l) if lists.contains_key(l)) =>
+                {
+                    let Expr::Variable(list) = *list else {
+                        unreachable!()
+                    };
+                    if !lists[&list].fields.iter().any(|f| f.name == field) {
+                        bail!("Struct `{}` has no field `{}`", lists[&list].name, field);
+                    }
+                    out.push(call(
+                        "replace_item_of_list",
+                        vec![field_list(&list, &field), *index, value],
+                    ));
+                }
+                base => out.push(Stmt::AssignPlace(
+                    Expr::Field(Box::new(base), field),
+                    value,
+                    comment,
+                )),
+            },
+            Expr::Index(list, index) if matches!(list.as_ref(), Expr::Variable(l) if lists.contains_key(l)) =>
+            {
+                let Expr::Variable(list) = *list else {
+                    unreachable!()
+                };
+                let def = &lists[&list];
+                let values = struct_values(def, &value)?;
+                let index = hoist(*index, &mut out);
+                for (field, value) in def.fields.iter().zip(values) {
+                    out.push(call(
+                        "replace_item_of_list",
+                        vec![field_list(&list, &field.name), index.clone(), value],
+                    ));
+                }
+            }
+            place => out.push(Stmt::AssignPlace(place, value, comment)),
+        },
+        other => out.push(other),
+    }
+    Ok(out)
+}
+
+/// Lowers field reads and `len()` on struct lists, then rejects any struct
+/// syntax that is left over.
+fn lower_struct_exprs(body: &mut [Stmt], lists: &HashMap<String, StructDef>) -> anyhow::Result<()> {
+    let is_struct_list = |e: &Expr| matches!(e, Expr::Variable(l) if lists.contains_key(l));
+
+    let mut error = None;
+    walk_stmts_exprs_mut(body, &mut |expr| {
+        let lowered = match expr {
+            Expr::Field(base, field) => match base.as_mut() {
+                Expr::Index(list, index) if is_struct_list(list) => {
+                    let Expr::Variable(list) = list.as_ref() else {
+                        unreachable!()
+                    };
+                    if !lists[list].fields.iter().any(|f| f.name == *field) {
+                        error.get_or_insert(anyhow::anyhow!(
+                            "Struct `{}` has no field `{}`",
+                            lists[list].name,
+                            field
+                        ));
+                        return;
+                    }
+                    let index = std::mem::replace(index.as_mut(), Expr::Number(0.0));
+                    Expr::Call(
+                        "item_of_list".to_string(),
+                        vec![field_list(list, field), index],
+                    )
+                }
+                _ => return,
+            },
+            Expr::MethodCall(list, method, args)
+                if is_struct_list(list) && method == "len" && args.is_empty() =>
+            {
+                let Expr::Variable(list) = list.as_ref() else {
+                    unreachable!()
+                };
+                Expr::Call(
+                    "length_of_list".to_string(),
+                    vec![field_list(list, &lists[list].fields[0].name)],
+                )
+            }
+            _ => return,
+        };
+        *expr = lowered;
+    });
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    walk_stmts_exprs_mut(body, &mut |expr| {
+        if error.is_some() {
+            return;
+        }
+        error = match expr {
+            Expr::Index(list, _) if is_struct_list(list) => Some(anyhow::anyhow!(
+                "An element of a struct list can't be used as a value; read a field instead, e.g. `list[i].field`"
+            )),
+            Expr::MethodCall(list, method, _) if is_struct_list(list) => Some(anyhow::anyhow!(
+                "`{}` can't be used inside an expression; only `len()` returns a value",
+                method
+            )),
+            Expr::StructLit(name, _) => Some(anyhow::anyhow!(
+                "A `{}` literal can only be pushed, inserted or assigned into a struct list",
+                name
+            )),
+            Expr::Field(..) => Some(anyhow::anyhow!(
+                "Fields can only be read from struct list elements, e.g. `list[i].field`"
+            )),
+            _ => None,
+        };
+    });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Returns `(enum, variant)` if `expr` is a path like `State::Idle` into a declared enum.
+fn enum_path<'a>(expr: &'a Expr, enums: &HashMap<String, EnumDef>) -> Option<(&'a str, &'a str)> {
+    let Expr::Variable(path) = expr else {
+        return None;
+    };
+    let (enum_name, variant) = path.split_once("::")?;
+    enums
+        .contains_key(enum_name)
+        .then_some((enum_name, variant))
+}
+
+/// Resolves `enum` variants to numeric constants (in declaration order,
+/// starting at 0) and checks every `match`: duplicate and unreachable arms,
+/// exhaustiveness over enums, and bound names in guards and bodies.
+fn lower_enums_and_matches(program: &mut Program) -> anyhow::Result<()> {
+    let mut enums: HashMap<String, EnumDef> = HashMap::new();
+    for item in &program.items {
+        if let Item::Enum(def) = item {
+            for (i, variant) in def.variants.iter().enumerate() {
+                if def.variants[..i].contains(variant) {
+                    bail!("Enum `{}` declares `{}` more than once", def.name, variant);
+                }
+            }
+            if enums.insert(def.name.clone(), def.clone()).is_some() {
+                bail!("Enum `{}` is declared more than once", def.name);
+            }
+        }
+    }
+    program.items.retain(|item| !matches!(item, Item::Enum(_)));
+
+    // Variables initialized with a variant are treated as enum-typed
+    let mut enum_vars = HashMap::new();
+    for item in &program.items {
+        if let Item::Variable(v) = item {
+            if let Some((enum_name, _)) = enum_path(&v.init, &enums) {
+                enum_vars.insert(v.name.clone(), enum_name.to_string());
+            }
+        }
+    }
+
+    // Names a bare pattern compares against rather than binds
+    let variables: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Variable(v) => Some(v.name.clone()),
+            _ => None,
+        })
+        .collect();
+    let in_scope = |params: &[Param], body: &[Stmt]| {
+        let mut names = variables.clone();
+        names.extend(params.iter().map(|p| p.name.clone()));
+        names.extend(let_names(body));
+        names
+    };
+
+    let mut temp_counter = 0;
+    let mut check = |stmt: Stmt, hoist: bool, names: &HashSet<String>| match stmt {
+        Stmt::Match(expr, mut arms, default, comment) => {
+            compare_variables(&mut arms, names);
+            check_match(
+                expr,
+                arms,
+                default,
+                comment,
+                &enums,
+                &enum_vars,
+                hoist.then_some(&mut temp_counter),
+            )
+        }
+        other => Ok(vec![other]),
+    };
+
+    let mut items = Vec::new();
+    for item in program.items.drain(..) {
+        match item {
+            Item::Procedure(mut p) => {
+                let names = in_scope(&p.params, &p.body);
+                p.body = rewrite_stmts(p.body, &mut |s| check(s, true, &names))
+                    .map_err(|e| e.context(ItemContext::new("procedure", &p.name)))?;
+                items.push(Item::Procedure(p));
+            }
+            Item::Function(mut f) => {
+                let names = in_scope(&f.params, &f.body);
+                f.body = rewrite_stmts(f.body, &mut |s| check(s, true, &names))
+                    .map_err(|e| e.context(ItemContext::new("function", &f.name)))?;
+                items.push(Item::Function(f));
+            }
+            // Top-level scripts have no stack frame to hoist into
+            Item::Stmt(stmt) => items.extend(
+                rewrite_stmts(vec![stmt], &mut |s| check(s, false, &variables))?
+                    .into_iter()
+                    .map(Item::Stmt),
+            ),
+            other => items.push(other),
+        }
+    }
+    program.items = items;
+
+    if enums.is_empty() {
+        return Ok(());
+    }
+    let resolve = |expr: &mut Expr, error: &mut Option<anyhow::Error>| {
+        if let Some((enum_name, variant)) = enum_path(expr, &enums) {
+            match enums[enum_name].variants.iter().position(|v| v == variant) {
+                Some(index) => *expr = Expr::Number(index as f64),
+                None => {
+                    error.get_or_insert(anyhow::anyhow!(
+                        "Enum `{}` has no variant `{}`",
+                        enum_name,
+                        variant
+                    ));
+                }
+            }
+        }
+    };
+    for item in &mut program.items {
+        let mut error = None;
+        let mut resolve = |e: &mut Expr| resolve(e, &mut error);
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs_mut(&mut p.body, &mut resolve),
+            Item::Function(f) => walk_stmts_exprs_mut(&mut f.body, &mut resolve),
+            Item::Stmt(stmt) => walk_stmts_exprs_mut(std::slice::from_mut(stmt), &mut resolve),
+            Item::Variable(v) => walk_expr_mut(&mut v.init, &mut resolve),
+            _ => {}
+        }
+        if let Some(e) = error {
+            return Err(in_item(item, e));
+        }
+    }
+    Ok(())
+}
+
+/// Turns bare names in `arms` that are already in `variables` from bindings
+/// into comparisons against their value.
+fn compare_variables(arms: &mut [MatchArm], variables: &HashSet<String>) {
+    for pattern in arms.iter_mut().flat_map(|arm| &mut arm.patterns) {
+        if let Pattern::Binding(name) = pattern {
+            if variables.contains(name) {
+                *pattern = Pattern::Value(Expr::Variable(name.clone()));
+            }
+        }
+    }
+}
+
+fn check_match(
+    mut scrutinee: Expr,
+    arms: Vec<MatchArm>,
+    default: Option<Vec<Stmt>>,
+    comment: Option<String>,
+    enums: &HashMap<String, EnumDef>,
+    enum_vars: &HashMap<String, String>,
+    temp_counter: Option<&mut usize>,
+) -> anyhow::Result<Vec<Stmt>> {
+    let mut out = Vec::new();
+
+    // Which enum is being matched, if any
+    let mut enum_name = match &scrutinee {
+        Expr::Variable(v) => enum_vars.get(v).cloned(),
+        _ => None,
+    };
+    for arm in &arms {
+        for pattern in &arm.patterns {
+            let Pattern::Value(v) = pattern else {
+                continue;
+            };
+            if let Some((e, _)) = enum_path(v, enums) {
+                match &enum_name {
+                    Some(n) if n != e => {
+                        bail!("This match mixes variants of `{}` and `{}`", n, e)
+                    }
+                    _ => enum_name = Some(e.to_string()),
+                }
+            }
+        }
+    }
+
+    // The scrutinee is compared once per arm, so evaluate anything
+    // non-trivial up front. Bound names need it in a temporary too, so that
+    // an arm changing the matched variable doesn't change the bound value.
+    let binds = arms.iter().any(|arm| {
+        arm.patterns
+            .iter()
+            .any(|p| matches!(p, Pattern::Binding(_)))
+    });
+    let is_literal = matches!(
+        scrutinee,
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_)
+    );
+    if let Some(counter) = temp_counter {
+        if !is_literal && (binds || !matches!(scrutinee, Expr::Variable(_))) {
+            *counter += 1;
+            let name = format!("_match_value_{}", counter);
+            out.push(Stmt::Let(name.clone(), scrutinee, None));
+            scrutinee = Expr::Variable(name);
+        }
+    } else if binds && !is_literal {
+        bail!("Match arms can only bind names inside a procedure or function");
+    }
+
+    let mut seen = HashSet::new();
+    let mut catch_all = default;
+    let mut new_arms = Vec::new();
+    for arm in arms {
+        if catch_all.is_some() {
+            bail!("Unreachable match arm after a catch-all arm");
+        }
+
+        // Bound names refer to the matched value. The guard reads it directly,
+        // the body gets a local holding it.
+        let mut guard = arm.guard;
+        let mut body = arm.body;
+        for pattern in &arm.patterns {
+            if let Pattern::Binding(name) = pattern {
+                if let Some(g) = &mut guard {
+                    walk_expr_mut(g, &mut |e| {
+                        if matches!(e, Expr::Variable(v) if v == name) {
+                            *e = scrutinee.clone();
+                        }
+                    });
+                }
+                body.insert(0, Stmt::Let(name.clone(), scrutinee.clone(), None));
+            }
+        }
+
+        let is_catch_all = arm
+            .patterns
+            .iter()
+            .any(|p| matches!(p, Pattern::Binding(_) | Pattern::Wildcard));
+        let patterns: Vec<Pattern> = arm
+            .patterns
+            .into_iter()
+            .map(|p| match p {
+                Pattern::Binding(_) => Pattern::Wildcard,
+                p => p,
+            })
+            .collect();
+
+        // Guarded arms may fall through, so they don't count as coverage
+        if guard.is_none() {
+            for pattern in &patterns {
+                let key = match pattern {
+                    Pattern::Value(Expr::Variable(path))
+                        if enum_path(&Expr::Variable(path.clone()), enums).is_some() =>
+                    {
+                        path.clone()
+                    }
+                    Pattern::Value(Expr::Number(n)) => n.to_string(),
+                    Pattern::Value(Expr::String(s)) => format!("{:?}", s),
+                    _ => continue,
+                };
+                if !seen.insert(key.clone()) {
+                    bail!("Duplicate match arm for `{}`", key);
+                }
+            }
+            if is_catch_all {
+                catch_all = Some(body);
+                continue;
+            }
+        }
+        new_arms.push(MatchArm {
+            patterns,
+            guard,
+            body,
+        });
+    }
+
+    if let (Some(enum_name), None) = (&enum_name, &catch_all) {
+        let missing: Vec<String> = enums[enum_name]
+            .variants
+            .iter()
+            .map(|v| format!("{}::{}", enum_name, v))
+            .filter(|path| !seen.contains(path))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Non-exhaustive match on `{}`: missing {}",
+                enum_name,
+                missing.join(", ")
+            );
+        }
+    }
+
+    out.push(Stmt::Match(scrutinee, new_arms, catch_all, comment));
+    Ok(out)
+}
+
+const STRICT_EQ_PROC: &str = "_strict_eq";
+const STRICT_EQ_BASE_COSTUME: &str = "_strict_eq";
+
+/// Exact string comparison. Scratch's `=` ignores case, so letters are
+/// compared a second time by switching to costumes named `A`..`Z`: costume
+/// lookup by name is the one case-sensitive check Scratch has. Switching to
+/// a lowercase letter finds no costume and leaves the base costume showing.
+const STRICT_EQ_SOURCE: &str = r#"
+#[warp]
+proc _strict_eq(a: string, b: string) -> boolean {
+    if length_of(a) != length_of(b) {
+        return false;
+    }
+    let costume = costume_number();
+    let same = true;
+    let i = 1;
+    repeat length_of(a) {
+        let x = letter_of(a, i);
+        let y = letter_of(b, i);
+        if x != y {
+            same = false;
+        } else {
+            if contains("abcdefghijklmnopqrstuvwxyz", x) {
+                switch_costume_to("_strict_eq");
+                switch_costume_to(x);
+                let x_case = costume_name();
+                switch_costume_to("_strict_eq");
+                switch_costume_to(y);
+                if x_case != costume_name() {
+                    same = false;
+                }
+            }
+        }
+        i += 1;
+    }
+    switch_costume_to(costume);
+    return same;
+}
+"#;
+
+/// Rewrites `a === b` and `a !== b` into calls to the strict comparison
+/// helper, adding the helper and its costumes only if something uses them.
+fn lower_strict_comparisons(program: &mut Program, is_stage: bool) -> anyhow::Result<()> {
+    let lower = |expr: &mut Expr, used: &mut bool| {
+        let Expr::BinOp(_, op @ (Op::StrictEq | Op::StrictNe), _) = expr else {
+            return;
+        };
+        let negate = matches!(op, Op::StrictNe);
+        let Expr::BinOp(l, _, r) = std::mem::replace(expr, Expr::Bool(false)) else {
+            unreachable!();
+        };
+        let call = Expr::Call(STRICT_EQ_PROC.to_string(), vec![*l, *r]);
+        *expr = if negate {
+            Expr::UnOp(UnOp::Not, Box::new(call))
+        } else {
+            call
+        };
+        *used = true;
+    };
+    let mut first_use = None;
+    for item in &mut program.items {
+        let mut used = false;
+        let mut lower = |e: &mut Expr| lower(e, &mut used);
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs_mut(&mut p.body, &mut lower),
+            Item::Function(f) => walk_stmts_exprs_mut(&mut f.body, &mut lower),
+            Item::Stmt(stmt) => walk_stmts_exprs_mut(std::slice::from_mut(stmt), &mut lower),
+            _ => {}
+        }
+        if used && first_use.is_none() {
+            first_use = Some(&*item);
+        }
+    }
+    let Some(first_use) = first_use else {
+        return Ok(());
+    };
+    if is_stage {
+        return Err(in_item(
+            first_use,
+            anyhow::anyhow!("`===` and `!==` switch costumes to compare letter case, so they can't be used on the Stage"),
+        ));
+    }
+    let reserved = |name: &str| {
+        name == STRICT_EQ_BASE_COSTUME
+            || (name.len() == 1 && name.chars().all(|c| c.is_ascii_uppercase()))
+    };
+    for item in &program.items {
+        if let Item::Costume(c) = item {
+            if reserved(&c.name) {
+                bail!(
+                    "Costume `{}` clashes with the costumes `===` and `!==` use to compare letter case; rename it",
+                    c.name
+                );
+            }
+        }
+    }
+
+    let (_, helper) = parse_program(STRICT_EQ_SOURCE).expect("strict comparison helper parses");
+    program.items.extend(helper.items);
+    let costume = |name: String| {
+        Item::Costume(AssetDecl {
+            name,
+            path: BLANK_COSTUME.to_string(),
+            x: None,
+            y: None,
+        })
+    };
+    program
+        .items
+        .push(costume(STRICT_EQ_BASE_COSTUME.to_string()));
+    program
+        .items
+        .extend(('A'..='Z').map(|c| costume(c.to_string())));
+    Ok(())
+}
+
+/// Number formatting procedures used by f-string format specs. Each is only
+/// added to a program that calls it.
+const FORMAT_HELPERS: &[(&str, &str)] = &[
+    (
+        "format_fixed",
+        r#"
+#[warp]
+proc format_fixed(n: number, digits: number) -> string {
+    let scale = 1;
+    repeat digits {
+        scale = scale * 10;
+    }
+    let scaled = round(abs(n) * scale);
+    let whole = floor(scaled / scale);
+    let out = join(whole, "");
+    if digits > 0 {
+        let frac = join(scaled - whole * scale, "");
+        repeat digits - length_of(frac) {
+            frac = join("0", frac);
+        }
+        out = join(out, join(".", frac));
+    }
+    if n < 0 && scaled > 0 {
+        out = join("-", out);
+    }
+    return out;
+}
+"#,
+    ),
+    (
+        "format_thousands",
+        r#"
+#[warp]
+proc format_thousands(s: string) -> string {
+    let start = 1;
+    if letter_of(s, 1) == "-" {
+        start = 2;
+    }
+    let end = start;
+    until end > length_of(s) || letter_of(s, end) == "." {
+        end += 1;
+    }
+    let out = "";
+    let i = end - 1;
+    let count = 0;
+    repeat end - start {
+        if count > 0 && count % 3 == 0 {
+            out = join(",", out);
+        }
+        out = join(letter_of(s, i), out);
+        i -= 1;
+        count += 1;
+    }
+    if start == 2 {
+        out = join("-", out);
+    }
+    repeat length_of(s) - end + 1 {
+        out = join(out, letter_of(s, end));
+        end += 1;
+    }
+    return out;
+}
+"#,
+    ),
+    (
+        "format_padded",
+        r#"
+#[warp]
+proc format_padded(s: string, width: number) -> string {
+    let sign = "";
+    let digits = s;
+    if letter_of(s, 1) == "-" {
+        sign = "-";
+        digits = "";
+        let i = 2;
+        repeat length_of(s) - 1 {
+            digits = join(digits, letter_of(s, i));
+            i += 1;
+        }
+    }
+    repeat width - length_of(s) {
+        digits = join("0", digits);
+    }
+    return join(sign, digits);
+}
+"#,
+    ),
+];
+
+/// Appends each helper that is called but not defined by the program.
+fn add_called_helpers(program: &mut Program, helpers: &[(&str, &str)]) {
+    let mut called = HashSet::new();
+    let mut scan = |expr: &Expr| {
+        if let Expr::Call(name, _) = expr {
+            called.insert(name.clone());
+        }
+    };
+    for item in &program.items {
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs(&p.body, &mut scan),
+            Item::Function(f) => walk_stmts_exprs(&f.body, &mut scan),
+            Item::Stmt(stmt) => walk_stmts_exprs(std::slice::from_ref(stmt), &mut scan),
+            _ => {}
+        }
+    }
+    let defined: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|i| match i {
+            Item::Procedure(p) => Some(p.name.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for (name, source) in helpers {
+        if called.contains(*name) && !defined.contains(*name) {
+            let (_, helper) = parse_program(source).expect("helper parses");
+            program.items.extend(helper.items);
+        }
+    }
+}
+
+/// Bitwise procedures. They work on the low 32 bits of each operand as an
+/// unsigned number, so `-1 & 255` is `255`, and peel off one bit per
+/// iteration with `% 2`. Each is only added to a program that calls it.
+const BITWISE_HELPERS: &[(&str, &str)] = &[
+    (
+        "_bit_and",
+        r#"
+#[warp]
+proc _bit_and(a: number, b: number) -> number {
+    let x = floor(a) % 4294967296;
+    let y = floor(b) % 4294967296;
+    let result = 0;
+    let bit = 1;
+    until x == 0 || y == 0 {
+        if x % 2 == 1 && y % 2 == 1 {
+            result += bit;
+        }
+        x = floor(x / 2);
+        y = floor(y / 2);
+        bit = bit * 2;
+    }
+    return result;
+}
+"#,
+    ),
+    (
+        "_bit_or",
+        r#"
+#[warp]
+proc _bit_or(a: number, b: number) -> number {
+    let x = floor(a) % 4294967296;
+    let y = floor(b) % 4294967296;
+    let result = 0;
+    let bit = 1;
+    until x == 0 || y == 0 {
+        if x % 2 == 1 || y % 2 == 1 {
+            result += bit;
+        }
+        x = floor(x / 2);
+        y = floor(y / 2);
+        bit = bit * 2;
+    }
+    // One side is out of bits, so the rest of the other is copied as is
+    return result + (x + y) * bit;
+}
+"#,
+    ),
+    (
+        "_bit_xor",
+        r#"
+#[warp]
+proc _bit_xor(a: number, b: number) -> number {
+    let x = floor(a) % 4294967296;
+    let y = floor(b) % 4294967296;
+    let result = 0;
+    let bit = 1;
+    until x == 0 || y == 0 {
+        if x % 2 != y % 2 {
+            result += bit;
+        }
+        x = floor(x / 2);
+        y = floor(y / 2);
+        bit = bit * 2;
+    }
+    // One side is out of bits, so the rest of the other is copied as is
+    return result + (x + y) * bit;
+}
+"#,
+    ),
+];
+
+/// Lowers `idiv(a, b)` to `floor(a / b)`, `a << n` to `a * 2 ** n` and
+/// `a >> n` to `floor(a / 2 ** n)`. `&`, `|` and `^` call the `BITWISE_HELPERS`,
+/// except `x & mask` with a literal mask of `2^k - 1`, which is
+/// `floor(x) % 2^k`.
+fn lower_bitwise(program: &mut Program) {
+    fn power_of_two(n: Expr) -> Expr {
+        match n {
+            Expr::Number(n) => Expr::Number(2f64.powf(n)),
+            n => Expr::BinOp(Box::new(Expr::Number(2.0)), Op::Pow, Box::new(n)),
+        }
+    }
+    fn floor(e: Expr) -> Expr {
+        Expr::Call("floor".to_string(), vec![e])
+    }
+    fn bin(l: Expr, op: Op, r: Expr) -> Expr {
+        Expr::BinOp(Box::new(l), op, Box::new(r))
+    }
+
+    // A procedure the program defines itself named `idiv` is left alone
+    let defines_idiv = program
+        .items
+        .iter()
+        .any(|i| matches!(i, Item::Procedure(p) if p.name == "idiv"));
+
+    let mut lower = |expr: &mut Expr| {
+        if let Expr::Call(name, args) = expr {
+            if name == "idiv" && args.len() == 2 && !defines_idiv {
+                let b = args.pop().unwrap();
+                let a = args.pop().unwrap();
+                *expr = floor(bin(a, Op::Div, b));
+            }
+            return;
+        }
+        let Expr::BinOp(_, op, _) = expr else {
+            return;
+        };
+        let helper = match op {
+            Op::BitAnd => "_bit_and",
+            Op::BitOr => "_bit_or",
+            Op::BitXor => "_bit_xor",
+            Op::Shl | Op::Shr => "",
+            _ => return,
+        };
+        let Expr::BinOp(a, op, b) = std::mem::replace(expr, Expr::Bool(false)) else {
+            unreachable!();
+        };
+        let (a, b) = (*a, *b);
+        *expr = match op {
+            Op::Shl => bin(a, Op::Mul, power_of_two(b)),
+            Op::Shr => floor(bin(a, Op::Div, power_of_two(b))),
+            Op::BitAnd => match b {
+                Expr::Number(mask)
+                    if (1.0..4294967296.0).contains(&mask)
+                        && (mask + 1.0).log2().fract() == 0.0 =>
+                {
+                    bin(floor(a), Op::Mod, Expr::Number(mask + 1.0))
+                }
+                b => Expr::Call(helper.to_string(), vec![a, b]),
+            },
+            _ => Expr::Call(helper.to_string(), vec![a, b]),
+        };
+    };
+    for item in &mut program.items {
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs_mut(&mut p.body, &mut lower),
+            Item::Function(f) => walk_stmts_exprs_mut(&mut f.body, &mut lower),
+            Item::Stmt(stmt) => walk_stmts_exprs_mut(std::slice::from_mut(stmt), &mut lower),
+            _ => {}
+        }
+    }
+    add_called_helpers(program, BITWISE_HELPERS);
+}
+
+/// In procedures and functions marked `#[short_circuit]`, rewrites `a && b`
+/// into `if a { b } else { false }` and `a || b` into `if a { true } else { b }`,
+/// so `b` is only evaluated when needed. Operands without calls or indexing
+/// can't fail or have side effects, so those stay as plain Scratch blocks.
+fn lower_short_circuit(program: &mut Program) {
+    let mut lower = |expr: &mut Expr| {
+        let Expr::BinOp(_, op @ (Op::And | Op::Or), r) = expr else {
+            return;
+        };
+        if !has_calls(r) {
+            return;
+        }
+        let is_and = matches!(op, Op::And);
+        let Expr::BinOp(l, _, r) = std::mem::replace(expr, Expr::Bool(false)) else {
+            unreachable!();
+        };
+        *expr = if is_and {
+            Expr::If(l, r, Box::new(Expr::Bool(false)))
+        } else {
+            Expr::If(l, Box::new(Expr::Bool(true)), r)
+        };
+    };
+    // The attribute is consumed here, so it never reaches the compiler
+    // (which reads a function's first attribute as its hat block).
+    let take_attr = |attributes: &mut Vec<Attribute>| {
+        let before = attributes.len();
+        attributes.retain(|a| a.name != "short_circuit");
+        attributes.len() != before
+    };
+    for item in &mut program.items {
+        let (attributes, body) = match item {
+            Item::Procedure(p) => (&mut p.attributes, &mut p.body),
+            Item::Function(f) => (&mut f.attributes, &mut f.body),
+            _ => continue,
+        };
+        if take_attr(attributes) {
+            walk_stmts_exprs_mut(body, &mut lower);
+        }
+    }
+}
+
+/// Whether evaluating `expr` could fail, have side effects or cost more
+/// than a few operator blocks.
+fn has_calls(expr: &Expr) -> bool {
+    let mut found = false;
+    walk_expr(expr, &mut |e| {
+        found |= matches!(
+            e,
+            Expr::Call(..)
+                | Expr::ProcCall(..)
+                | Expr::Index(..)
+                | Expr::MethodCall(..)
+                | Expr::If(..)
+        )
+    });
+    found
+}
+
+/// Math procedures that can't be written as a single block expression. Each
+/// is only added to a program that calls it. Procedures run to the end even
+/// after `return`, so each has a single `return` at the bottom.
+const MATH_HELPERS: &[(&str, &str)] = &[
+    (
+        "_pow",
+        r#"
+#[warp]
+proc _pow(x: number, y: number) -> number {
+    let result = 1;
+    if round(y) == y && abs(y) < 1e15 {
+        // Squaring keeps integer powers exact and gets the sign right
+        let b = x;
+        let e = abs(y);
+        until e == 0 {
+            if e % 2 == 1 {
+                result = result * b;
+            }
+            b = b * b;
+            e = floor(e / 2);
+        }
+        if y < 0 {
+            result = 1 / result;
+        }
+    } else {
+        // Number inputs read NaN as 0, so `ln` of a negative base
+        // wouldn't propagate; produce NaN directly instead
+        if x < 0 {
+            result = 0 / 0;
+        } else {
+            result = exp(y * ln(x));
+        }
+    }
+    return result;
+}
+"#,
+    ),
+    (
+        "min",
+        r#"
+#[warp]
+proc min(a: number, b: number) -> number {
+    return if a < b { a } else { b };
+}
+"#,
+    ),
+    (
+        "max",
+        r#"
+#[warp]
+proc max(a: number, b: number) -> number {
+    return if a > b { a } else { b };
+}
+"#,
+    ),
+    (
+        "clamp",
+        r#"
+#[warp]
+proc clamp(x: number, low: number, high: number) -> number {
+    return if x < low { low } else if x > high { high } else { x };
+}
+"#,
+    ),
+    (
+        "sign",
+        r#"
+#[warp]
+proc sign(x: number) -> number {
+    return if x > 0 { 1 } else if x < 0 { -1 } else { 0 };
+}
+"#,
+    ),
+    (
+        "lerp",
+        r#"
+#[warp]
+proc lerp(a: number, b: number, t: number) -> number {
+    return a + (b - a) * t;
+}
+"#,
+    ),
+    (
+        "atan2",
+        r#"
+#[warp]
+proc atan2(y: number, x: number) -> number {
+    let angle = if y > 0 { 90 } else if y < 0 { -90 } else { 0 };
+    if x > 0 {
+        angle = atan(y / x);
+    }
+    if x < 0 {
+        angle = atan(y / x) + if y < 0 { -180 } else { 180 };
+    }
+    return angle;
+}
+"#,
+    ),
+];
+
+/// Expands `x ** y` and the math builtins. Cases that fit in a block
+/// expression are inlined:
+///
+/// - `x ** 0` to `x ** 3` become repeated multiplication, and `x ** 0.5` is
+///   `sqrt(x)`
+/// - `log(x, base)` is `ln(x) / ln(base)`
+/// - `lerp(a, b, t)` is `a + (b - a) * t`
+///
+/// Inlining that would evaluate an operand twice only happens when the
+/// operand has no calls. Everything else calls a procedure from
+/// `MATH_HELPERS`. A program's own procedure with the same name wins for
+/// calls by name, but `**` always uses the `_pow` helper.
+fn lower_math(program: &mut Program) {
+    let defined: HashSet<String> = program
+        .items
+        .iter()
+        .filter_map(|i| match i {
+            Item::Procedure(p) => Some(p.name.clone()),
+            _ => None,
+        })
+        .collect();
+    let builtin = |name: &str| !defined.contains(name);
+
+    let mut used = HashSet::new();
+    let mut lower = |expr: &mut Expr| {
+        match expr {
+            Expr::BinOp(_, Op::Pow, _) => {
+                let Expr::BinOp(x, _, y) = std::mem::replace(expr, Expr::Bool(false)) else {
+                    unreachable!();
+                };
+                *expr = Expr::Call("_pow".to_string(), vec![*x, *y]);
+            }
+            Expr::Call(name, args) if name == "pow" && args.len() == 2 && builtin("pow") => {
+                *name = "_pow".to_string();
+            }
+            Expr::Call(name, args) if name == "log" && args.len() == 2 && builtin("log") => {
+                let base = args.pop().unwrap();
+                let x = args.pop().unwrap();
+                *expr = Expr::BinOp(
+                    Box::new(Expr::Call("ln".to_string(), vec![x])),
+                    Op::Div,
+                    Box::new(Expr::Call("ln".to_string(), vec![base])),
+                );
+                return;
+            }
+            _ => {}
+        }
+        let Expr::Call(name, args) = expr else {
+            return;
+        };
+        if !builtin(name) {
+            return;
+        }
+        let inlined = match (name.as_str(), args.as_slice()) {
+            ("_pow", [x, Expr::Number(y)]) => match *y {
+                0.5 => Some(Expr::Call("sqrt".to_string(), vec![x.clone()])),
+                0.0 => Some(Expr::Number(1.0)),
+                y if (1.0..=3.0).contains(&y) && y.fract() == 0.0 && !has_calls(x) => {
+                    let mut product = x.clone();
+                    for _ in 1..y as usize {
+                        product = Expr::BinOp(Box::new(product), Op::Mul, Box::new(x.clone()));
+                    }
+                    Some(product)
+                }
+                _ => None,
+            },
+            ("lerp", [a, b, t]) if !has_calls(a) => Some(Expr::BinOp(
+                Box::new(a.clone()),
+                Op::Add,
+                Box::new(Expr::BinOp(
+                    Box::new(Expr::BinOp(
+                        Box::new(b.clone()),
+                        Op::Sub,
+                        Box::new(a.clone()),
+                    )),
+                    Op::Mul,
+                    Box::new(t.clone()),
+                )),
+            )),
+            _ => None,
+        };
+        match inlined {
+            Some(inlined) => *expr = inlined,
+            None => {
+                if MATH_HELPERS.iter().any(|(n, _)| n == name) {
+                    used.insert(name.clone());
+                }
+            }
+        }
+    };
+    for item in &mut program.items {
+        match item {
+            Item::Procedure(p) => walk_stmts_exprs_mut(&mut p.body, &mut lower),
+            Item::Function(f) => walk_stmts_exprs_mut(&mut f.body, &mut lower),
+            Item::Stmt(stmt) => walk_stmts_exprs_mut(std::slice::from_mut(stmt), &mut lower),
+            _ => {}
+        }
+    }
+
+    for (name, source) in MATH_HELPERS {
+        if used.contains(*name) {
+            let (_, helper) = parse_program(source).expect("math helper parses");
+            program.items.extend(helper.items);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(src: &str) -> anyhow::Result<Program> {
+        let (_, mut program) = parse_program(src).unwrap();
+        transform_program(&mut program, &HashMap::new(), &TransformOptions::default())?;
+        Ok(program)
+    }
+
+    fn proc_names(program: &Program) -> Vec<&str> {
+        program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Procedure(p) => Some(p.name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_list_params_are_monomorphized() {
+        let program = transform(
+            r#"
+            list a = [];
+            list b = [];
+            proc push_twice(l: list, v: number) {
+                add_to_list(l, v);
+                add_to_list(l, v);
+            }
+            proc copy(src: list) -> list {
+                return src;
+            }
+            #[on_flag_clicked]
+            fn go() {
+                push_twice(a, 1);
+                push_twice(b, 2);
+                b = copy(a);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut names = proc_names(&program);
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["copy__a__into__b", "push_twice__a", "push_twice__b"]
+        );
+        let Some(Item::Procedure(p)) = program
+            .items
+            .iter()
+            .find(|i| matches!(i, Item::Procedure(p) if p.name == "push_twice__b"))
+        else {
+            unreachable!()
+        };
+        assert_eq!(p.params.len(), 1);
+        assert!(matches!(
+            &p.body[0],
+            Stmt::Expr(Expr::Call(_, args), _) if matches!(&args[0], Expr::Variable(l) if l == "b")
+        ));
+    }
+
+    #[test]
+    fn test_list_specialization_names_are_distinct() {
+        let program = transform(
+            r#"
+            list a_b = [];
+            list a__b = [];
+            proc show(l: list) {
+                say(length_of_list(l));
+            }
+            #[on_flag_clicked]
+            fn go() {
+                show(a_b);
+                show(a__b);
+            }
+            "#,
+        )
+        .unwrap();
+        let mut names = proc_names(&program);
+        names.sort();
+        assert_eq!(names, vec!["show__a_u_ub", "show__a_ub"]);
+
+        let err = transform(
+            r#"
+            list a = [];
+            proc show(l: list) {
+                say(length_of_list(l));
+            }
+            proc show__a() {
+                say(1);
+            }
+            #[on_flag_clicked]
+            fn go() {
+                show(a);
+                show__a();
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("same name as a copy of `show`"));
+    }
+
+    #[test]
+    fn test_list_param_requires_list_name() {
+        let err = transform(
+            r#"
+            proc show(l: list) {
+                say(length_of_list(l));
+            }
+            #[on_flag_clicked]
+            fn go() {
+                show(1 + 2);
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.root_cause().to_string().contains("must name a list"));
+    }
+
+    #[test]
+    fn test_list_result_requires_list_destination() {
+        for (decl, dest) in [("var x = 0;", "x"), ("", "n"), ("", "total")] {
+            let err = transform(&format!(
+                r#"
+                {}
+                list a = [];
+                proc copy(src: list) -> list {{
+                    return src;
+                }}
+                proc go(n: number) {{
+                    let total = 0;
+                    {} = copy(a);
+                }}
+                "#,
+                decl, dest
+            ))
+            .unwrap_err();
+            assert!(
+                err.root_cause().to_string().contains(&format!(
+                    "can't be assigned to `{}`, which isn't a list",
+                    dest
+                )),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_struct_lists_become_parallel_lists() {
+        let program = transform(
+            r#"
+            struct Enemy { x: number, hp: number }
+            list<Enemy> enemies = [Enemy { x: 1, hp: 3 }];
+            #[on_flag_clicked]
+            fn go() {
+                enemies.push(Enemy { hp: 5, x: 2 });
+                enemies[1].hp -= 1;
+                say(enemies.len());
+            }
+            "#,
+        )
+        .unwrap();
+
+        let lists: Vec<&str> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Variable(v) => Some(v.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lists, vec!["enemies_x", "enemies_hp"]);
+
+        let Some(Item::Function(go)) = program
+            .items
+            .iter()
+            .find(|i| matches!(i, Item::Function(_)))
+        else {
+            unreachable!()
+        };
+        let calls: Vec<&str> = go
+            .body
+            .iter()
+            .filter_map(|s| match s {
+                Stmt::Expr(Expr::Call(name, _), _) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            calls,
+            vec!["add_to_list", "add_to_list", "replace_item_of_list", "say"]
+        );
+    }
+
+    #[test]
+    fn test_struct_list_name_collision() {
+        let err = transform(
+            r#"
+            struct Enemy { hp: number }
+            list enemies_hp = [];
+            list<Enemy> enemies = [];
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("already taken"));
+    }
+
+    #[test]
+    fn test_struct_without_fields() {
+        let err = transform("struct Empty {}\nlist<Empty> e = [];\nsay(e.len());").unwrap_err();
+        assert!(err.to_string().contains("at least one field"));
+    }
+
+    #[test]
+    fn test_enum_variants_become_numbers() {
+        let program = transform(
+            r#"
+            enum State { Idle, Running }
+            var state = State::Running;
+            match state {
+                State::Idle => { say("idle"); }
+                State::Running => { say("running"); }
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(!program.items.iter().any(|i| matches!(i, Item::Enum(_))));
+        assert!(matches!(
+            &program.items[0],
+            Item::Variable(v) if matches!(v.init, Expr::Number(n) if n == 1.0)
+        ));
+        match &program.items[1] {
+            Item::Stmt(Stmt::Match(_, arms, None, _)) => {
+                assert!(matches!(
+                    arms[1].patterns[..],
+                    [Pattern::Value(Expr::Number(n))] if n == 1.0
+                ));
+            }
+            other => panic!("Expected match, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_non_exhaustive_enum_match() {
+        let err = transform(
+            r#"
+            enum State { Idle, Running, Done }
+            var state = State::Idle;
+            match state {
+                State::Idle => {}
+                State::Running if x > 1 => {}
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("missing State::Running, State::Done"));
+    }
+
+    #[test]
+    fn test_duplicate_and_unreachable_arms() {
+        let err = transform("match x { 1 | 2 => {} 2 => {} _ => {} }").unwrap_err();
+        assert!(err.to_string().contains("Duplicate match arm"));
+
+        let err = transform("fn go() { match x { n => { say(n); } 1 => {} } }").unwrap_err();
+        assert!(err.root_cause().to_string().contains("Unreachable"));
+    }
+
+    #[test]
+    fn test_match_bindings_copy_the_value() {
+        let (_, mut program) = parse_program(
+            "var x = 1;\nfn go() { match x { n if n > 0 => { x = 5; say(n); } _ => {} } }",
+        )
+        .unwrap();
+        lower_enums_and_matches(&mut program).unwrap();
+        let Item::Function(f) = &program.items[1] else {
+            panic!("Expected function");
+        };
+        let is_temp = |e: &Expr| matches!(e, Expr::Variable(v) if v == "_match_value_1");
+        assert!(matches!(
+            &f.body[0],
+            Stmt::Let(name, Expr::Variable(v), _) if name == "_match_value_1" && v == "x"
+        ));
+        let Stmt::Match(value, arms, _, _) = &f.body[1] else {
+            panic!("Expected match");
+        };
+        assert!(is_temp(value));
+        assert!(matches!(&arms[0].guard, Some(Expr::BinOp(l, Op::Gt, _)) if is_temp(l)));
+        assert!(
+            matches!(&arms[0].body[0], Stmt::Let(name, value, _) if name == "n" && is_temp(value))
+        );
+
+        let err = transform("match x { n => { say(n); } }").unwrap_err();
+        assert!(err.to_string().contains("inside a procedure or function"));
+    }
+
+    #[test]
+    fn test_match_names_of_variables_compare() {
+        let (_, mut program) = parse_program(
+            "var target = 3;\n\
+             proc go(limit: number) {\n\
+                 let best = 2;\n\
+                 match x { target => { say(1); } limit => { say(2); } best => { say(3); } n => { say(n); } }\n\
+             }",
+        )
+        .unwrap();
+        lower_enums_and_matches(&mut program).unwrap();
+        let Item::Procedure(p) = &program.items[1] else {
+            panic!("Expected procedure");
+        };
+        let Some(Stmt::Match(_, arms, Some(default), _)) = p.body.last() else {
+            panic!("Expected match with a default, got {:?}", p.body);
+        };
+        let compared: Vec<&str> = arms
+            .iter()
+            .map(|arm| match &arm.patterns[..] {
+                [Pattern::Value(Expr::Variable(v))] => v.as_str(),
+                other => panic!("Expected a comparison, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(compared, vec!["target", "limit", "best"]);
+        assert!(matches!(&default[0], Stmt::Let(name, _, _) if name == "n"));
+    }
+
+    #[test]
+    fn test_strict_comparison_helper_only_when_used() {
+        let program = transform("say(1 == 1);").unwrap();
+        assert!(!proc_names(&program).contains(&STRICT_EQ_PROC));
+        assert!(!program.items.iter().any(|i| matches!(i, Item::Costume(_))));
+
+        let program = transform(r#"if answer() !== "Yes" { say("no"); }"#).unwrap();
+        assert!(proc_names(&program).contains(&STRICT_EQ_PROC));
+        let costumes: Vec<&str> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Costume(c) => Some(c.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(costumes.len(), 27);
+        assert!(costumes.contains(&"Z"));
+    }
+
+    #[test]
+    fn test_strict_comparison_errors() {
+        let (_, mut stage) = parse_program(r#"say("a" === "A");"#).unwrap();
+        let options = TransformOptions {
+            is_stage: true,
+            ..TransformOptions::default()
+        };
+        let err = transform_program(&mut stage, &HashMap::new(), &options).unwrap_err();
+        assert!(err.to_string().contains("can't be used on the Stage"));
+
+        let err = transform("costume \"B\" \"b.svg\";\nsay(\"a\" === \"A\");").unwrap_err();
+        assert!(err.to_string().contains("Costume `B` clashes"));
+
+        // Lowercase names don't clash, and nothing is checked without `===`
+        transform("costume \"b\" \"b.svg\";\nsay(\"a\" === \"A\");").unwrap();
+        transform("costume \"B\" \"b.svg\";\nsay(\"a\" == \"A\");").unwrap();
+    }
+
+    #[test]
+    fn test_format_helpers_only_when_used() {
+        let program = transform(r#"say(f"{x}!");"#).unwrap();
+        assert!(!proc_names(&program)
+            .iter()
+            .any(|n| n.starts_with("format_")));
+
+        let program = transform(r#"say(f"{x:.2}");"#).unwrap();
+        let names = proc_names(&program);
+        assert!(names.contains(&"format_fixed"));
+        assert!(!names.contains(&"format_padded"));
+    }
+
+    /// Runs the number formatting helpers the way Scratch would: every value
+    /// is a string, read as a number where an operator needs one.
+    struct FormatEval {
+        procs: HashMap<String, ProcedureDef>,
+    }
+
+    impl FormatEval {
+        fn num(s: &str) -> f64 {
+            s.trim().parse().unwrap_or(0.0)
+        }
+
+        fn string(n: f64) -> String {
+            if n == 0.0 {
+                "0".to_string()
+            } else {
+                n.to_string()
+            }
+        }
+
+        /// Scratch compares numerically only when both sides are numbers.
+        fn compare(a: &str, b: &str) -> std::cmp::Ordering {
+            match (a.trim().parse::<f64>(), b.trim().parse::<f64>()) {
+                (Ok(x), Ok(y)) => x.total_cmp(&y),
+                _ => a.to_lowercase().cmp(&b.to_lowercase()),
+            }
+        }
+
+        fn expr(&self, expr: &Expr, vars: &HashMap<String, String>) -> String {
+            let num = |e: &Expr| Self::num(&self.expr(e, vars));
+            let truth = |b: bool| b.to_string();
+            match expr {
+                Expr::Number(n) => Self::string(*n),
+                Expr::String(s) => s.clone(),
+                Expr::Variable(v) => vars[v].clone(),
+                Expr::UnOp(UnOp::Neg, e) => Self::string(-num(e)),
+                Expr::BinOp(l, op, r) => {
+                    let (a, b) = (self.expr(l, vars), self.expr(r, vars));
+                    let (x, y) = (Self::num(&a), Self::num(&b));
+                    match op {
+                        Op::Add => Self::string(x + y),
+                        Op::Sub => Self::string(x - y),
+                        Op::Mul => Self::string(x * y),
+                        Op::Div => Self::string(x / y),
+                        Op::Mod => Self::string(x - y * (x / y).floor()),
+                        Op::Lt => truth(Self::compare(&a, &b).is_lt()),
+                        Op::Gt => truth(Self::compare(&a, &b).is_gt()),
+                        Op::Eq => truth(Self::compare(&a, &b).is_eq()),
+                        Op::Ne => truth(!Self::compare(&a, &b).is_eq()),
+                        Op::And => truth(a == "true" && b == "true"),
+                        Op::Or => truth(a == "true" || b == "true"),
+                        other => panic!("Unexpected operator {:?}", other),
+                    }
+                }
+                Expr::Call(name, args) => {
+                    let args: Vec<String> = args.iter().map(|a| self.expr(a, vars)).collect();
+                    if let Some(proc) = self.procs.get(name) {
+                        let mut locals = proc
+                            .params
+                            .iter()
+                            .map(|p| p.name.clone())
+                            .zip(args)
+                            .collect();
+                        return self.run(&proc.body, &mut locals).unwrap_or_default();
+                    }
+                    let x = Self::num(&args[0]);
+                    match name.as_str() {
+                        "join" => format!("{}{}", args[0], args[1]),
+                        "letter_of" => {
+                            let i = Self::num(&args[1]) as usize;
+                            args[0]
+                                .chars()
+                                .nth(i.wrapping_sub(1))
+                                .map_or(String::new(), String::from)
+                        }
+                        "length_of" => args[0].chars().count().to_string(),
+                        "abs" => Self::string(x.abs()),
+                        "floor" => Self::string(x.floor()),
+                        "round" => Self::string((x + 0.5).floor()),
+                        other => panic!("Unexpected call to {}", other),
+                    }
+                }
+                other => panic!("Unexpected expression {:?}", other),
+            }
+        }
+
+        /// Runs `stmts`, returning the value of a `return` if one is reached.
+        fn run(&self, stmts: &[Stmt], vars: &mut HashMap<String, String>) -> Option<String> {
+            for stmt in stmts {
+                let returned = match stmt {
+                    Stmt::Let(name, e, _) | Stmt::Assign(name, e, _) => {
+                        let v = self.expr(e, vars);
+                        vars.insert(name.clone(), v);
+                        None
+                    }
+                    Stmt::If(c, t, e, _) => {
+                        if self.expr(c, vars) == "true" {
+                            self.run(t, vars)
+                        } else {
+                            e.as_ref().and_then(|e| self.run(e, vars))
+                        }
+                    }
+                    Stmt::Repeat(count, body, _) => {
+                        let count = (Self::num(&self.expr(count, vars)) + 0.5).floor();
+                        (0..count.max(0.0) as usize).find_map(|_| self.run(body, vars))
+                    }
+                    Stmt::Until(c, body, _) => {
+                        let mut returned = None;
+                        while returned.is_none() && self.expr(c, vars) != "true" {
+                            returned = self.run(body, vars);
+                        }
+                        returned
+                    }
+                    Stmt::Return(Some(e), _) => Some(self.expr(e, vars)),
+                    other => panic!("Unexpected statement {:?}", other),
+                };
+                if returned.is_some() {
+                    return returned;
+                }
+            }
+            None
+        }
+    }
+
+    /// Formats `x` with the f-string spec `spec`, running the helpers.
+    fn format_number(x: f64, spec: &str) -> String {
+        let src = format!("say(f\"{{x:{}}}\");", spec);
+        let (_, mut program) = parse_program(&src).unwrap();
+        add_called_helpers(&mut program, FORMAT_HELPERS);
+        let procs = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Procedure(p) => Some((p.name.clone(), p.clone())),
+                _ => None,
+            })
+            .collect();
+        let Some(Item::Stmt(Stmt::Expr(Expr::Call(_, args), _))) = program.items.first() else {
+            panic!("Expected say call");
+        };
+        let vars = HashMap::from([("x".to_string(), FormatEval::string(x))]);
+        FormatEval { procs }.expr(&args[0], &vars)
+    }
+
+    #[test]
+    fn test_format_helpers_output() {
+        let cases = [
+            // Rounding is half away from zero, like Scratch's `round`
+            (12.34567, ".2", "12.35"),
+            (2.5, ".0", "3"),
+            (-2.5, ".0", "-3"),
+            (0.125, ".2", "0.13"),
+            (1.999, ".2", "2.00"),
+            (1.05, ".2", "1.05"),
+            (1234.5678, ".0", "1235"),
+            // Zero and negative numbers
+            (0.0, ".3", "0.000"),
+            (-7.0, ".1", "-7.0"),
+            (-0.001, ".2", "0.00"),
+            (-0.5, ".0", "-1"),
+            // Thousands separators
+            (1234567.0, ",", "1,234,567"),
+            (999.0, ",", "999"),
+            (1000.0, ",", "1,000"),
+            (-1234.5, ",", "-1,234.5"),
+            (-100000.254, ",.2", "-100,000.25"),
+            (0.0, ",.2", "0.00"),
+            // Zero padding keeps the sign in front
+            (7.0, "03", "007"),
+            (-7.0, "04", "-007"),
+            (1234.0, "02", "1234"),
+            (3.5, "05", "003.5"),
+            (-4.256, "06.2", "-04.26"),
+            (1234.5, "010,.1", "0001,234.5"),
+        ];
+        for (x, spec, want) in cases {
+            assert_eq!(format_number(x, spec), want, "{{{}:{}}}", x, spec);
+        }
+    }
+
+    /// Evaluates lowered math the way Scratch would: number inputs read NaN
+    /// as 0, trig is in degrees and `return` doesn't stop a procedure.
+    struct MathEval<'a> {
+        procs: HashMap<&'a str, &'a ProcedureDef>,
+    }
+
+    impl MathEval<'_> {
+        fn expr(&self, expr: &Expr, vars: &HashMap<String, f64>) -> f64 {
+            let num = |e: &Expr| {
+                let v = self.expr(e, vars);
+                if v.is_nan() {
+                    0.0
+                } else {
+                    v
+                }
+            };
+            let truth = |b: bool| if b { 1.0 } else { 0.0 };
+            match expr {
+                Expr::Number(n) => *n,
+                Expr::Variable(v) => vars[v],
+                Expr::UnOp(UnOp::Neg, e) => 0.0 - num(e),
+                Expr::BinOp(l, op, r) => {
+                    let (a, b) = (num(l), num(r));
+                    match op {
+                        Op::Add => a + b,
+                        Op::Sub => a - b,
+                        Op::Mul => a * b,
+                        Op::Div => a / b,
+                        Op::Mod => {
+                            let m = a % b;
+                            if m / b < 0.0 {
+                                m + b
+                            } else {
+                                m
+                            }
+                        }
+                        Op::Lt => truth(a < b),
+                        Op::Gt => truth(a > b),
+                        Op::Eq => truth(a == b),
+                        Op::Ne => truth(a != b),
+                        Op::And => truth(a != 0.0 && b != 0.0),
+                        Op::Or => truth(a != 0.0 || b != 0.0),
+                        other => panic!("Unexpected operator {:?}", other),
+                    }
+                }
+                Expr::If(c, t, e) => {
+                    if num(c) != 0.0 {
+                        self.expr(t, vars)
+                    } else {
+                        self.expr(e, vars)
+                    }
+                }
+                Expr::Call(name, args) => {
+                    if let Some(proc) = self.procs.get(name.as_str()) {
+                        let mut locals: HashMap<String, f64> = proc
+                            .params
+                            .iter()
+                            .zip(args)
+                            .map(|(p, a)| (p.name.clone(), self.expr(a, vars)))
+                            .collect();
+                        let mut ret = 0.0;
+                        self.run(&proc.body, &mut locals, &mut ret);
+                        return ret;
+                    }
+                    let x = num(&args[0]);
+                    match name.as_str() {
+                        "abs" => x.abs(),
+                        "floor" => x.floor(),
+                        "round" => (x + 0.5).floor(),
+                        "sqrt" => x.sqrt(),
+                        "ln" => x.ln(),
+                        "exp" => x.exp(),
+                        "atan" => x.atan() * 180.0 / std::f64::consts::PI,
+                        other => panic!("Unexpected call to {}", other),
+                    }
+                }
+                other => panic!("Unexpected expression {:?}", other),
+            }
+        }
+
+        fn run(&self, stmts: &[Stmt], vars: &mut HashMap<String, f64>, ret: &mut f64) {
+            for stmt in stmts {
+                match stmt {
+                    Stmt::Let(name, e, _) | Stmt::Assign(name, e, _) => {
+                        let v = self.expr(e, vars);
+                        vars.insert(name.clone(), v);
+                    }
+                    Stmt::If(c, t, e, _) => {
+                        if self.expr(c, vars) != 0.0 {
+                            self.run(t, vars, ret);
+                        } else if let Some(e) = e {
+                            self.run(e, vars, ret);
+                        }
+                    }
+                    Stmt::Until(c, body, _) => {
+                        while self.expr(c, vars) == 0.0 {
+                            self.run(body, vars, ret);
+                        }
+                    }
+                    Stmt::Return(Some(e), _) => *ret = self.expr(e, vars),
+                    other => panic!("Unexpected statement {:?}", other),
+                }
+            }
+        }
+    }
+
+    /// Lowers `say(<src>);` and evaluates its argument.
+    fn eval_math(src: &str, vars: &[(&str, f64)]) -> f64 {
+        let (_, mut program) = parse_program(&format!("say({});", src)).unwrap();
+        lower_bitwise(&mut program);
+        lower_math(&mut program);
+        let procs = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Procedure(p) => Some((p.name.as_str(), p)),
+                _ => None,
+            })
+            .collect();
+        let Some(Item::Stmt(Stmt::Expr(Expr::Call(_, args), _))) = program.items.first() else {
+            panic!("Expected say call");
+        };
+        let vars = vars.iter().map(|(n, v)| (n.to_string(), *v)).collect();
+        MathEval { procs }.expr(&args[0], &vars)
+    }
+
+    fn assert_close(got: f64, want: f64, what: &str) {
+        let ok = (got.is_nan() && want.is_nan())
+            || got == want
+            || (got - want).abs() <= 1e-12 * got.abs().max(want.abs());
+        assert!(ok, "{}: got {}, want {}", what, got, want);
+    }
+
+    #[test]
+    fn test_default_and_named_arguments() {
+        let program = transform(
+            "proc spawn(x: number, y: number = 0, speed: number = 5) {}\n\
+             spawn(10, speed: 8);\n\
+             spawn(speed: 2, x: 1, y: 3);\n",
+        )
+        .unwrap();
+        let calls: Vec<Vec<f64>> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Stmt(Stmt::Expr(Expr::Call(_, args), _)) => Some(
+                    args.iter()
+                        .map(|a| match a {
+                            Expr::Number(n) => *n,
+                            other => panic!("Expected a number, got {:?}", other),
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, vec![vec![10.0, 0.0, 8.0], vec![1.0, 3.0, 2.0]]);
+
+        let error = |call: &str| {
+            transform(&format!(
+                "proc spawn(x: number, y: number = 0) {{}}\n{}\n",
+                call
+            ))
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(
+            error("spawn(y: 1);"),
+            "Missing argument `x` in call to `spawn`"
+        );
+        assert_eq!(
+            error("spawn(1, 2, 3);"),
+            "`spawn` takes 2 arguments but 3 were given"
+        );
+        assert_eq!(
+            error("spawn(1, z: 2);"),
+            "`spawn` has no parameter named `z`"
+        );
+        assert_eq!(
+            error("spawn(1, x: 2);"),
+            "Argument `x` is passed more than once in call to `spawn`"
+        );
+        assert!(error("say(message: 1);").contains("only procedures take named arguments"));
+
+        let err = transform("proc f(a: number = 1, b: number) {}").unwrap_err();
+        assert!(err.root_cause().to_string().contains("needs a default"));
+
+        // Defaults are copied into each call, so only constants are allowed
+        for default in ["-1", "\"left\"", "true", "Dir::Up"] {
+            transform(&format!(
+                "enum Dir {{ Up, Down }}\nproc f(a: number = {}) {{}}\nf();",
+                default
+            ))
+            .unwrap();
+        }
+        for default in ["b", "speed * 2", "random(1, 5)"] {
+            let err = transform(&format!(
+                "var speed = 1;\nproc f(b: number, a: number = {}) {{}}\nf(1);",
+                default
+            ))
+            .unwrap_err();
+            assert!(
+                err.root_cause()
+                    .to_string()
+                    .contains("must be a literal or an enum variant"),
+                "{}",
+                default
+            );
+        }
+    }
+
+    #[test]
+    fn test_math_matches_f64() {
+        let xs = [-3.5, -2.0, -1.0, 0.0, 0.5, 2.0, 10.0];
+        let ys = [
+            -3.0, -2.0, -1.0, 0.0, 1.0, 2.0, 3.0, 0.5, 1.5, -0.5, 2.25, 7.0, 100.0,
+        ];
+        for x in xs {
+            for y in ys {
+                let want = f64::powf(x, y);
+                assert_close(
+                    eval_math("x ** y", &[("x", x), ("y", y)]),
+                    want,
+                    &format!("{} ** {}", x, y),
+                );
+                assert_close(
+                    eval_math(&format!("x ** {}", y), &[("x", x)]),
+                    want,
+                    &format!("{} ** literal {}", x, y),
+                );
+            }
+            assert_close(
+                eval_math("sign(x)", &[("x", x)]),
+                if x == 0.0 { 0.0 } else { x.signum() },
+                "sign",
+            );
+            assert_close(eval_math("min(x, 1)", &[("x", x)]), x.min(1.0), "min");
+            assert_close(eval_math("max(x, 1)", &[("x", x)]), x.max(1.0), "max");
+            assert_close(
+                eval_math("clamp(x, -1, 2)", &[("x", x)]),
+                x.clamp(-1.0, 2.0),
+                "clamp",
+            );
+            assert_close(
+                eval_math("lerp(x, 4, 0.25)", &[("x", x)]),
+                x + (4.0 - x) * 0.25,
+                "lerp",
+            );
+            // Like Scratch's own `ln`, a negative input gives 0 rather than NaN
+            // once it reaches the division.
+            if x >= 0.0 {
+                assert_close(eval_math("log(x, 2)", &[("x", x)]), x.log(2.0), "log");
+            }
+            for y in xs {
+                let want = y.atan2(x).to_degrees();
+                assert_close(
+                    eval_math("atan2(y, x)", &[("x", x), ("y", y)]),
+                    want,
+                    &format!("atan2({}, {})", y, x),
+                );
+            }
+        }
+        assert_close(eval_math("-x ** 2", &[("x", 3.0)]), -9.0, "-x ** 2");
+        assert_close(eval_math("2 ** 3 ** 2", &[]), 512.0, "2 ** 3 ** 2");
+    }
+
+    #[test]
+    fn test_bitwise_matches_u32() {
+        let u32_of = |x: f64| x.floor() as i64 as u32;
+        let values = [
+            0.0,
+            1.0,
+            5.0,
+            12.0,
+            255.0,
+            3.7,
+            -1.0,
+            -8.0,
+            123456789.0,
+            -123456789.0,
+            4294967295.0,
+            4294967299.0,
+        ];
+        for a in values {
+            for b in values {
+                let vars = [("a", a), ("b", b)];
+                let (x, y) = (u32_of(a), u32_of(b));
+                assert_close(
+                    eval_math("a & b", &vars),
+                    (x & y) as f64,
+                    &format!("{} & {}", a, b),
+                );
+                assert_close(
+                    eval_math("a | b", &vars),
+                    (x | y) as f64,
+                    &format!("{} | {}", a, b),
+                );
+                assert_close(
+                    eval_math("a ^ b", &vars),
+                    (x ^ y) as f64,
+                    &format!("{} ^ {}", a, b),
+                );
+            }
+            assert_close(
+                eval_math("a & 255", &[("a", a)]),
+                (u32_of(a) & 255) as f64,
+                "mask",
+            );
+            if a.fract() == 0.0 && a.abs() < 1e9 {
+                let n = a as i64;
+                assert_close(eval_math("a << 3", &[("a", a)]), (n << 3) as f64, "<<");
+                assert_close(eval_math("a >> 2", &[("a", a)]), (n >> 2) as f64, ">>");
+                assert_close(
+                    eval_math("a >> s", &[("a", a), ("s", 2.0)]),
+                    (n >> 2) as f64,
+                    ">> var",
+                );
+                assert_close(
+                    eval_math("idiv(a, 7)", &[("a", a)]),
+                    n.div_euclid(7) as f64,
+                    "idiv",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_math_inlines_simple_cases() {
+        let (_, mut program) =
+            parse_program("say(x ** 2 + sqrt_me ** 0.5 + lerp(a, b, t));").unwrap();
+        lower_math(&mut program);
+        assert!(proc_names(&program).is_empty());
+
+        // `random` must not be evaluated twice, so this goes through the helper
+        let (_, mut program) = parse_program("say(random(1, 5) ** 2);").unwrap();
+        lower_math(&mut program);
+        assert_eq!(proc_names(&program), vec!["_pow"]);
+
+        let (_, mut program) = parse_program("say((x & 15) + (x >> 4) + idiv(x, 2));").unwrap();
+        lower_bitwise(&mut program);
+        assert!(proc_names(&program).is_empty());
+
+        let (_, mut program) = parse_program("say(x & 12 | y);").unwrap();
+        lower_bitwise(&mut program);
+        assert_eq!(proc_names(&program), vec!["_bit_and", "_bit_or"]);
+
+        // A program's own `bit_and` doesn't replace the operator
+        let (_, mut program) = parse_program(
+            "proc bit_and(a: number, b: number) -> number { return 0; }\nsay(x & y);",
+        )
+        .unwrap();
+        lower_bitwise(&mut program);
+        assert_eq!(proc_names(&program), vec!["bit_and", "_bit_and"]);
+    }
+
+    #[test]
+    fn test_pow_operator_ignores_user_pow() {
+        let (_, mut program) = parse_program(
+            "proc pow(a: number, b: number) -> number { return 7; }\n\
+             say(random(1, 5) ** 2);\n\
+             say(pow(2, 3));",
+        )
+        .unwrap();
+        lower_math(&mut program);
+        assert_eq!(proc_names(&program), vec!["pow", "_pow"]);
+        let calls: Vec<&str> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Stmt(Stmt::Expr(Expr::Call(_, args), _)) => match &args[0] {
+                    Expr::Call(name, _) => Some(name.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, vec!["_pow", "pow"]);
+    }
+
+    fn inner_body<'a>(program: &'a Program, name: &str) -> &'a [Stmt] {
+        program
+            .items
+            .iter()
+            .find_map(|i| match i {
+                Item::Procedure(p) if p.name == name => Some(p.body.as_slice()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_if_expression_lowers_to_if_else() {
+        let program =
+            transform("fn f() { say(if x > 1 { \"big\" } else { \"small\" }); }").unwrap();
+        match inner_body(&program, "_inner_f") {
+            [Stmt::If(_, then, Some(otherwise), _), Stmt::Expr(Expr::Call(say, args), _), ..] => {
+                assert!(matches!(&then[0], Stmt::Expr(Expr::Call(n, _), _) if n == "stack_set"));
+                assert!(
+                    matches!(&otherwise[0], Stmt::Expr(Expr::Call(n, _), _) if n == "stack_set")
+                );
+                assert_eq!(say, "say");
+                assert!(matches!(&args[0], Expr::Call(n, _) if n == "item_of_list"));
+            }
+            other => panic!("Expected if/else then say, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_match_guards_are_lazy() {
+        let program = transform(
+            "proc big(n: number) -> boolean { return n > 10; }\n\
+             proc small(n: number) -> boolean { return n < 0; }\n\
+             fn f() { match x { 1 if big(x) => { say(1); } n if small(n) => { say(2); } _ => { say(3); } } }",
+        )
+        .unwrap();
+        let body = inner_body(&program, "_inner_f");
+        let calls = |stmts: &[Stmt]| {
+            let mut calls = Vec::new();
+            scan_stmts_for_calls(stmts, &mut calls);
+            calls
+        };
+        // Nothing is called before the first arm is tested
+        let first = body.iter().position(|s| matches!(s, Stmt::If(..))).unwrap();
+        assert!(!calls(&body[..first])
+            .iter()
+            .any(|c| c == "big" || c == "small"));
+
+        // `big` only runs once `x == 1`, and `small` only once that arm failed
+        let [Stmt::If(Expr::BinOp(_, Op::Eq, _), then, None, _), Stmt::If(_, _, Some(otherwise), _), ..] =
+            &body[first..]
+        else {
+            panic!(
+                "Expected the pattern test, then the guard test, got {:?}",
+                &body[first..]
+            );
+        };
+        assert!(calls(then).iter().any(|c| c == "big"));
+        assert!(!calls(then).iter().any(|c| c == "small"));
+        assert!(calls(otherwise).iter().any(|c| c == "small"));
+    }
+
+    #[test]
+    fn test_short_circuit_is_opt_in() {
+        let src = "proc check(i: number) { if i > 0 && item_of_list(\"l\", i) == 2 { say(1); } }";
+        let program = transform(src).unwrap();
+        assert_eq!(proc_names(&program), vec!["check"]);
+
+        let program = transform(&format!("#[short_circuit]\n{}", src)).unwrap();
+        let names = proc_names(&program);
+        assert!(names.contains(&"_inner_check"));
+        let wrapper = program
+            .items
+            .iter()
+            .find_map(|i| match i {
+                Item::Procedure(p) if p.name == "check" => Some(p),
+                _ => None,
+            })
+            .unwrap();
+        assert!(wrapper.attributes.is_empty());
+        assert!(matches!(
+            inner_body(&program, "_inner_check"),
+            [Stmt::If(_, _, Some(_), _), Stmt::If(_, _, None, _), ..]
+        ));
+
+        // Operands that can't fail or have side effects stay as `and` blocks
+        let program =
+            transform("#[short_circuit]\nproc check(i: number) { if i > 0 && i < 5 { say(1); } }")
+                .unwrap();
+        assert_eq!(proc_names(&program), vec!["check"]);
+    }
+
+    #[test]
+    fn test_tuples_use_consecutive_slots() {
+        // `stack_set(base, slot, ..)` slots in order
+        let slots = |body: &[Stmt]| -> Vec<f64> {
+            body.iter()
+                .filter_map(|s| match s {
+                    Stmt::Expr(Expr::Call(n, args), _) if n == "stack_set" => match &args[1] {
+                        Expr::Number(slot) => Some(*slot),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect()
+        };
+        let program = transform(
+            "proc divmod(a: number, b: number) -> (number, number) {\n\
+                 let q = floor(a / b);\n\
+                 return (q, a - q * b);\n\
+             }\n\
+             fn f() { let (q, _, r) = (1, 2, 3); let (x, y) = divmod(7, 2); say(x + y + q + r); }",
+        )
+        .unwrap();
+        // Slots 0 and 1 hold the results, so `q` lives in slot 2
+        assert_eq!(
+            slots(inner_body(&program, "_inner_divmod")),
+            vec![2.0, 0.0, 1.0]
+        );
+        let body = inner_body(&program, "_inner_f");
+        assert_eq!(slots(body), vec![1.0, 2.0, 3.0, 4.0]);
+        assert!(body
+            .iter()
+            .any(|s| matches!(s, Stmt::Expr(Expr::Call(n, _), _) if n == "sys_free")));
+
+        let error = |body: &str| {
+            transform(&format!(
+                "proc divmod(a: number, b: number) -> (number, number) {{ return (a, b); }}\n\
+                 fn f() {{ {} }}",
+                body
+            ))
+            .unwrap_err()
+            .root_cause()
+            .to_string()
+        };
+        assert_eq!(
+            error("let (a, b, c) = divmod(1, 2);"),
+            "`let (a, b, c)` unpacks 3 values but gets 2"
+        );
+        assert!(error("say(divmod(1, 2));").contains("returns 2 values"));
+        assert!(error("let x = (1, 2);").starts_with("Tuples can only be returned"));
+        let err = transform("proc d(a: number) -> (number, number) { return a; }").unwrap_err();
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("must return a tuple of 2"));
+    }
+
+    #[test]
+    fn test_inline_procedures() {
+        let src = "#[inline]\n\
+                   proc clamp01(x: number) -> number { return if x < 0 { 0 } else if x > 1 { 1 } else { x }; }\n\
+                   #[inline]\n\
+                   proc jump(h: number) { let t = h * 2; change_y_by(t); }\n\
+                   proc twice(x: number) -> number { return x * 2; }\n\
+                   proc main(t: number) { say(clamp01(t / 10)); jump(3); jump(t); say(twice(t)); }\n";
+        let program = transform(src).unwrap();
+        let names = proc_names(&program);
+        assert!(names.contains(&"twice"));
+        assert!(!names.contains(&"clamp01") && !names.contains(&"jump"));
+        let mut calls = Vec::new();
+        scan_stmts_for_calls(inner_body(&program, "_inner_main"), &mut calls);
+        assert!(!calls.iter().any(|c| c == "clamp01" || c == "jump"));
+        assert_eq!(calls.iter().filter(|c| *c == "change_y_by").count(), 2);
+
+        // The body's `t` is renamed, so it doesn't capture the caller's parameter
+        let (_, mut program) = parse_program(src).unwrap();
+        inline_procedures(&mut program, None).unwrap();
+        let Some(Item::Procedure(main)) = program.items.last() else {
+            panic!("Expected main");
+        };
+        let mut lets: Vec<String> = let_names(&main.body).into_iter().collect();
+        lets.sort();
+        assert_eq!(lets, vec!["_inline1_t", "_inline2_h", "_inline2_t"]);
+        assert!(
+            matches!(&main.body[1], Stmt::Let(_, Expr::BinOp(l, ..), _) if matches!(l.as_ref(), Expr::Number(n) if *n == 3.0))
+        );
+
+        // With a threshold, small procedures are inlined too
+        let (_, mut program) = parse_program(src).unwrap();
+        let options = TransformOptions {
+            inline_threshold: Some(3),
+            ..TransformOptions::default()
+        };
+        transform_program(&mut program, &HashMap::new(), &options).unwrap();
+        assert!(!proc_names(&program).contains(&"twice"));
+
+        // A global the body reads must not be captured by a caller's local
+        let program = transform(
+            "#[inline]\nproc show() { say(g); }\nproc main() { let g = 1; show(); say(g); }",
+        )
+        .unwrap();
+        assert!(proc_names(&program).contains(&"show"));
+
+        let err = transform("#[inline]\nproc f(n: number) { f(n - 1); }").unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "`f` can't be inlined because it calls itself"
+        );
+
+        // Stopping "this script" would stop the caller once inlined
+        let src = "proc halt(n: number) { if n > 3 { stop(\"this script\"); } }\n\
+                   proc main() { halt(5); say(1); }";
+        let err = transform(&format!("#[inline]\n{}", src)).unwrap_err();
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("`halt` can't be inlined because it uses `stop"));
+        let (_, mut program) = parse_program(src).unwrap();
+        inline_procedures(&mut program, Some(10)).unwrap();
+        assert!(proc_names(&program).contains(&"halt"));
+    }
+
+    #[test]
+    fn test_package_data_is_namespaced() {
+        let (_, mut pkg_program) = parse_program(
+            "package ui {}\n\
+             costume \"button\" \"/ui/button.svg\";\n\
+             var clicks = 0;\n\
+             public var theme = 1;\n\
+             list log = [];\n\
+             pub proc click(log: string) { clicks = clicks + 1; say(log); switch_costume_to(\"button\"); }\n\
+             proc record(x: number) { log.push(x); add_to_list(\"log\", theme); }\n",
+        )
+        .unwrap();
+        let Item::Package(mut pkg) = pkg_program.items.remove(0) else {
+            panic!("Expected package");
+        };
+        pkg.items = pkg_program.items;
+        let packages = HashMap::from([("ui".to_string(), pkg)]);
+
+        let (_, mut program) = parse_program(
+            "use ui;\n#[on_flag_clicked]\nfn start() { ui::click(\"hi\"); say(ui::theme); }\n",
+        )
+        .unwrap();
+        transform_program(&mut program, &packages, &TransformOptions::default()).unwrap();
+
+        let data: Vec<(&str, bool)> = program
+            .items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Variable(v) => Some((v.name.as_str(), v.visibility == Visibility::Public)),
+                Item::Costume(c) => Some((c.name.as_str(), false)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            data,
+            vec![
+                ("ui::button", false),
+                ("ui::clicks", false),
+                ("ui::theme", true),
+                ("ui::log", false)
+            ]
+        );
+
+        let Some(Item::Procedure(click)) = program
+            .items
+            .iter()
+            .find(|i| matches!(i, Item::Procedure(p) if p.name.starts_with("pkg_proc_")))
+        else {
+            panic!("Expected the merged procedure");
+        };
+        assert!(matches!(&click.body[0], Stmt::Assign(name, _, _) if name == "ui::clicks"));
+        // The parameter shadows the list
+        assert!(
+            matches!(&click.body[1], Stmt::Expr(Expr::Call(_, args), _) if matches!(&args[0], Expr::Variable(v) if v == "log"))
+        );
+        assert!(
+            matches!(&click.body[2], Stmt::Expr(Expr::Call(_, args), _) if matches!(&args[0], Expr::String(s) if s == "ui::button"))
+        );
+    }
+
+    #[test]
+    fn test_package_privacy() {
+        let (_, mut pkg_program) = parse_program(
+            "package geo {}\n\
+             var cache = 0;\n\
+             list log = [];\n\
+             proc helper() -> number { return cache; }\n\
+             pub proc area(r: number) -> number { return helper() * r; }\n",
+        )
+        .unwrap();
+        let Item::Package(mut pkg) = pkg_program.items.remove(0) else {
+            panic!("Expected package");
+        };
+        pkg.items = pkg_program.items;
+        let packages = HashMap::from([("geo".to_string(), pkg)]);
+        let transform = |src: &str| {
+            let (_, mut program) = parse_program(src).unwrap();
+            transform_program(&mut program, &packages, &TransformOptions::default())
+                .map(|_| program)
+        };
+
+        // Private procedures may still be called from inside the package
+        let program = transform("proc main() { say(geo::area(2)); }").unwrap();
+        assert!(program
+            .items
+            .iter()
+            .all(|i| !matches!(i, Item::Procedure(p) if p.visibility == Visibility::Public)));
+
+        let err = transform("proc main() { say(geo::helper()); }").unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "In procedure `main`: `geo::helper` is private to package `geo`. Mark it `pub proc` to call it from outside"
+        );
+        let err = transform("proc main() { say(geo::cache); }").unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "`geo::cache` is private to package `geo`. Only `public` variables and lists can be used from outside"
+        );
+
+        // Writes and data builtins are checked like reads
+        for src in [
+            "proc main() { geo::cache = 99; }",
+            "proc main() { if true { geo::cache = 99; } }",
+            "proc main() { geo::log[1] = 5; }",
+            "proc main() { geo::log.push(5); }",
+            "proc main() { add_to_list(\"geo::log\", 5); }",
+            "proc main() { set_variable(\"geo::cache\", 5); }",
+        ] {
+            let err = transform(src).unwrap_err();
+            assert!(
+                err.root_cause()
+                    .to_string()
+                    .contains("is private to package `geo`"),
+                "{}: {}",
+                src,
+                err
+            );
+        }
     }
 }
@@ -0,0 +1,279 @@
+//! Generic AST traversal helpers shared by the transform passes.
+use crate::ast::*;
+
+/// Calls `f` on `expr` and every sub-expression, children first.
+pub fn walk_expr_mut(expr: &mut Expr, f: &mut dyn FnMut(&mut Expr)) {
+    match expr {
+        Expr::Call(_, args) | Expr::ProcCall(_, args) | Expr::List(args) | Expr::Tuple(args) => {
+            for arg in args {
+                walk_expr_mut(arg, f);
+            }
+        }
+        Expr::BinOp(l, _, r) => {
+            walk_expr_mut(l, f);
+            walk_expr_mut(r, f);
+        }
+        Expr::UnOp(_, e) | Expr::Field(e, _) | Expr::NamedArg(_, e) => walk_expr_mut(e, f),
+        Expr::Index(base, index) => {
+            walk_expr_mut(base, f);
+            walk_expr_mut(index, f);
+        }
+        Expr::MethodCall(base, _, args) => {
+            walk_expr_mut(base, f);
+            for arg in args {
+                walk_expr_mut(arg, f);
+            }
+        }
+        Expr::StructLit(_, fields) => {
+            for (_, value) in fields {
+                walk_expr_mut(value, f);
+            }
+        }
+        Expr::If(cond, then, otherwise) => {
+            walk_expr_mut(cond, f);
+            walk_expr_mut(then, f);
+            walk_expr_mut(otherwise, f);
+        }
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_) | Expr::Variable(_) => {}
+    }
+    f(expr);
+}
+
+/// Calls `f` on every expression reachable from `stmts`, including nested blocks.
+pub fn walk_stmts_exprs_mut(stmts: &mut [Stmt], f: &mut dyn FnMut(&mut Expr)) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(_, e, _)
+            | Stmt::Expr(e, _)
+            | Stmt::Let(_, e, _)
+            | Stmt::LetTuple(_, e, _)
+            | Stmt::Return(Some(e), _) => walk_expr_mut(e, f),
+            Stmt::If(c, t, e, _) => {
+                walk_expr_mut(c, f);
+                walk_stmts_exprs_mut(t, f);
+                if let Some(e) = e {
+                    walk_stmts_exprs_mut(e, f);
+                }
+            }
+            Stmt::Repeat(c, b, _) | Stmt::Until(c, b, _) => {
+                walk_expr_mut(c, f);
+                walk_stmts_exprs_mut(b, f);
+            }
+            Stmt::Forever(b, _) => walk_stmts_exprs_mut(b, f),
+            Stmt::Match(e, arms, default, _) => {
+                walk_expr_mut(e, f);
+                for arm in arms {
+                    for pattern in &mut arm.patterns {
+                        match pattern {
+                            Pattern::Value(v) => walk_expr_mut(v, f),
+                            Pattern::Range(lo, hi, _) => {
+                                walk_expr_mut(lo, f);
+                                walk_expr_mut(hi, f);
+                            }
+                            Pattern::Binding(_) | Pattern::Wildcard => {}
+                        }
+                    }
+                    if let Some(guard) = &mut arm.guard {
+                        walk_expr_mut(guard, f);
+                    }
+                    walk_stmts_exprs_mut(&mut arm.body, f);
+                }
+                if let Some(d) = default {
+                    walk_stmts_exprs_mut(d, f);
+                }
+            }
+            Stmt::CBlock(_, args, body, _) => {
+                for arg in args {
+                    walk_expr_mut(arg, f);
+                }
+                walk_stmts_exprs_mut(body, f);
+            }
+            Stmt::AssignPlace(place, e, _) => {
+                walk_expr_mut(place, f);
+                walk_expr_mut(e, f);
+            }
+            Stmt::Return(None, _) | Stmt::Comment(_) => {}
+        }
+    }
+}
+
+/// Calls `f` on `expr` and every sub-expression, children first.
+pub fn walk_expr(expr: &Expr, f: &mut dyn FnMut(&Expr)) {
+    match expr {
+        Expr::Call(_, args) | Expr::ProcCall(_, args) | Expr::List(args) | Expr::Tuple(args) => {
+            for arg in args {
+                walk_expr(arg, f);
+            }
+        }
+        Expr::BinOp(l, _, r) => {
+            walk_expr(l, f);
+            walk_expr(r, f);
+        }
+        Expr::UnOp(_, e) | Expr::Field(e, _) | Expr::NamedArg(_, e) => walk_expr(e, f),
+        Expr::Index(base, index) => {
+            walk_expr(base, f);
+            walk_expr(index, f);
+        }
+        Expr::MethodCall(base, _, args) => {
+            walk_expr(base, f);
+            for arg in args {
+                walk_expr(arg, f);
+            }
+        }
+        Expr::StructLit(_, fields) => {
+            for (_, value) in fields {
+                walk_expr(value, f);
+            }
+        }
+        Expr::If(cond, then, otherwise) => {
+            walk_expr(cond, f);
+            walk_expr(then, f);
+            walk_expr(otherwise, f);
+        }
+        Expr::Number(_) | Expr::String(_) | Expr::Bool(_) | Expr::Color(_) | Expr::Variable(_) => {}
+    }
+    f(expr);
+}
+
+/// Calls `f` on every expression reachable from `stmts`, including nested blocks.
+pub fn walk_stmts_exprs(stmts: &[Stmt], f: &mut dyn FnMut(&Expr)) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(_, e, _)
+            | Stmt::Expr(e, _)
+            | Stmt::Let(_, e, _)
+            | Stmt::LetTuple(_, e, _)
+            | Stmt::Return(Some(e), _) => walk_expr(e, f),
+            Stmt::If(c, t, e, _) => {
+                walk_expr(c, f);
+                walk_stmts_exprs(t, f);
+                if let Some(e) = e {
+                    walk_stmts_exprs(e, f);
+                }
+            }
+            Stmt::Repeat(c, b, _) | Stmt::Until(c, b, _) => {
+                walk_expr(c, f);
+                walk_stmts_exprs(b, f);
+            }
+            Stmt::Forever(b, _) => walk_stmts_exprs(b, f),
+            Stmt::Match(e, arms, default, _) => {
+                walk_expr(e, f);
+                for arm in arms {
+                    for pattern in &arm.patterns {
+                        match pattern {
+                            Pattern::Value(v) => walk_expr(v, f),
+                            Pattern::Range(lo, hi, _) => {
+                                walk_expr(lo, f);
+                                walk_expr(hi, f);
+                            }
+                            Pattern::Binding(_) | Pattern::Wildcard => {}
+                        }
+                    }
+                    if let Some(guard) = &arm.guard {
+                        walk_expr(guard, f);
+                    }
+                    walk_stmts_exprs(&arm.body, f);
+                }
+                if let Some(d) = default {
+                    walk_stmts_exprs(d, f);
+                }
+            }
+            Stmt::CBlock(_, args, body, _) => {
+                for arg in args {
+                    walk_expr(arg, f);
+                }
+                walk_stmts_exprs(body, f);
+            }
+            Stmt::AssignPlace(place, e, _) => {
+                walk_expr(place, f);
+                walk_expr(e, f);
+            }
+            Stmt::Return(None, _) | Stmt::Comment(_) => {}
+        }
+    }
+}
+
+/// Calls `f` on every statement in `stmts` and in their nested blocks,
+/// children first.
+pub fn walk_stmts(stmts: &[Stmt], f: &mut dyn FnMut(&Stmt)) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::If(_, t, e, _) => {
+                walk_stmts(t, f);
+                if let Some(e) = e {
+                    walk_stmts(e, f);
+                }
+            }
+            Stmt::Repeat(_, b, _)
+            | Stmt::Forever(b, _)
+            | Stmt::Until(_, b, _)
+            | Stmt::CBlock(_, _, b, _) => walk_stmts(b, f),
+            Stmt::Match(_, arms, default, _) => {
+                for arm in arms {
+                    walk_stmts(&arm.body, f);
+                }
+                if let Some(d) = default {
+                    walk_stmts(d, f);
+                }
+            }
+            _ => {}
+        }
+        f(stmt);
+    }
+}
+
+/// Calls `f` on the body of every procedure, script and top-level statement.
+pub fn for_each_body_mut(program: &mut Program, f: &mut dyn FnMut(&mut [Stmt])) {
+    for item in &mut program.items {
+        match item {
+            Item::Procedure(p) => f(&mut p.body),
+            Item::Function(func) => f(&mut func.body),
+            Item::Stmt(stmt) => f(std::slice::from_mut(stmt)),
+            _ => {}
+        }
+    }
+}
+
+/// Rebuilds `stmts` bottom-up: nested blocks are rewritten first, then `f`
+/// replaces each statement with zero or more statements.
+pub fn rewrite_stmts(
+    stmts: Vec<Stmt>,
+    f: &mut dyn FnMut(Stmt) -> anyhow::Result<Vec<Stmt>>,
+) -> anyhow::Result<Vec<Stmt>> {
+    let mut out = Vec::new();
+    for stmt in stmts {
+        let stmt = match stmt {
+            Stmt::If(c, t, e, comment) => {
+                let t = rewrite_stmts(t, f)?;
+                let e = match e {
+                    Some(e) => Some(rewrite_stmts(e, f)?),
+                    None => None,
+                };
+                Stmt::If(c, t, e, comment)
+            }
+            Stmt::Repeat(c, b, comment) => Stmt::Repeat(c, rewrite_stmts(b, f)?, comment),
+            Stmt::Forever(b, comment) => Stmt::Forever(rewrite_stmts(b, f)?, comment),
+            Stmt::Until(c, b, comment) => Stmt::Until(c, rewrite_stmts(b, f)?, comment),
+            Stmt::Match(e, arms, default, comment) => {
+                let mut new_arms = Vec::new();
+                for arm in arms {
+                    new_arms.push(MatchArm {
+                        body: rewrite_stmts(arm.body, f)?,
+                        ..arm
+                    });
+                }
+                let default = match default {
+                    Some(d) => Some(rewrite_stmts(d, f)?),
+                    None => None,
+                };
+                Stmt::Match(e, new_arms, default, comment)
+            }
+            Stmt::CBlock(n, args, b, comment) => {
+                Stmt::CBlock(n, args, rewrite_stmts(b, f)?, comment)
+            }
+            other => other,
+        };
+        out.extend(f(stmt)?);
+    }
+    Ok(out)
+}